@@ -3,8 +3,13 @@ extern crate criterion;
 
 use criterion::{BenchmarkId, Criterion};
 use rand::prelude::*;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
-use unifier::{KvStore, KvsEngine};
+use unifier::{IndexKind, KvStore, KvStoreOptions, KvsEngine, KvsReader, LogEncoding};
 use walkdir::WalkDir;
 
 const SCALE: [u32; 7] = [4, 6, 8, 10, 12, 14, 16];
@@ -92,9 +97,7 @@ pub fn full_bench(c: &mut Criterion) {
                     let mut rng = rand::thread_rng();
                     for _i in 0..(1 << n) {
                         let key = format!("key{}", rng.gen_range(0, 1 << n));
-                        match kvs.remove(key) {
-                            _ => (),
-                        }
+                        let _ = kvs.remove(key);
                     }
 
                     size += dir_size(&dir);
@@ -128,9 +131,7 @@ pub fn full_bench(c: &mut Criterion) {
                     let mut rng = rand::thread_rng();
                     for _i in 0..(1 << n) {
                         let key = format!("key{}", rng.gen_range(0, 1 << n));
-                        match sled.remove(key) {
-                            _ => (),
-                        }
+                        let _ = sled.remove(key);
                     }
 
                     size += dir_size(&dir);
@@ -142,5 +143,348 @@ pub fn full_bench(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, set_bench, full_bench);
+pub fn bulk_load_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_load");
+
+    for i in SCALE.iter() {
+        group.bench_with_input(BenchmarkId::new("set", i), i, |b, n| {
+            b.iter(|| {
+                let dir = TempDir::new().unwrap();
+                let kvs = KvStore::open(dir.path()).unwrap();
+                for i in 0..(1 << n) {
+                    kvs.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("set_deferred", i), i, |b, n| {
+            b.iter(|| {
+                let dir = TempDir::new().unwrap();
+                let kvs = KvStore::open(dir.path()).unwrap();
+                for i in 0..(1 << n) {
+                    kvs.set_deferred(format!("key{}", i), "value".to_string())
+                        .unwrap();
+                }
+                kvs.flush().unwrap();
+            })
+        });
+    }
+}
+
+pub fn reopen_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reopen");
+
+    for i in SCALE.iter() {
+        let dir = TempDir::new().unwrap();
+        {
+            let kvs = KvStore::open(dir.path()).unwrap();
+            for i in 0..(1 << i) {
+                kvs.set_deferred(format!("key{}", i), "value".to_string())
+                    .unwrap();
+            }
+        }
+
+        // `open` replays every generation to rebuild the index
+        group.bench_with_input(BenchmarkId::new("kvs", i), i, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::default();
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    let kvs = KvStore::open(dir.path()).unwrap();
+                    elapsed += start.elapsed();
+                    drop(kvs);
+                    remove_empty_generations(&dir);
+                }
+                elapsed
+            })
+        });
+    }
+}
+
+pub fn bulk_remove_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_remove");
+
+    for i in SCALE.iter() {
+        let fill = |n: u32| {
+            let dir = TempDir::new().unwrap();
+            let kvs = KvStore::open(dir.path()).unwrap();
+            for i in 0..(1 << n) {
+                kvs.set_deferred(format!("key{}", i), "value".to_string())
+                    .unwrap();
+            }
+            kvs.flush().unwrap();
+            (dir, kvs)
+        };
+        let keys = |n: u32| (0..(1 << n)).map(|i| format!("key{}", i));
+
+        group.bench_with_input(BenchmarkId::new("remove", i), i, |b, n| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::default();
+                for _ in 0..iters {
+                    let (_dir, kvs) = fill(*n);
+                    let start = Instant::now();
+                    for key in keys(*n) {
+                        kvs.remove(key).unwrap();
+                    }
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("remove_many", i), i, |b, n| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::default();
+                for _ in 0..iters {
+                    let (_dir, kvs) = fill(*n);
+                    let start = Instant::now();
+                    kvs.remove_many(keys(*n).collect()).unwrap();
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+}
+
+pub fn contended_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contended_get");
+    let keys = 1 << 10;
+
+    for writers in [0, 2, 4].iter() {
+        let dir = TempDir::new().unwrap();
+        let kvs = KvStore::open(dir.path()).unwrap();
+        for i in 0..keys {
+            kvs.set(format!("key{}", i), "value".to_string()).unwrap();
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let handles = (0..*writers)
+            .map(|_| {
+                let (kvs, stop) = (kvs.clone(), Arc::clone(&stop));
+                thread::spawn(move || {
+                    let mut rng = SmallRng::from_seed([1; 16]);
+                    while !stop.load(Ordering::SeqCst) {
+                        let key = format!("key{}", rng.gen_range(0, keys));
+                        kvs.set(key, "value".to_string()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        group.bench_with_input(BenchmarkId::new("writers", writers), writers, |b, _| {
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                let key = format!("key{}", rng.gen_range(0, keys));
+                kvs.get(key).unwrap();
+            })
+        });
+
+        stop.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+pub fn encoding_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encoding");
+    let encodings = [("json", LogEncoding::Json), ("binary", LogEncoding::Binary)];
+    let open = |dir: &TempDir, log_encoding| {
+        let options = KvStoreOptions {
+            log_encoding,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(dir.path(), options).unwrap()
+    };
+
+    for i in SCALE.iter() {
+        for (name, encoding) in encodings.iter() {
+            group.bench_with_input(BenchmarkId::new(format!("set_{}", name), i), i, |b, n| {
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::default();
+                    for _ in 0..iters {
+                        let dir = TempDir::new().unwrap();
+                        let kvs = open(&dir, *encoding);
+                        let start = Instant::now();
+                        for i in 0..(1 << n) {
+                            kvs.set_deferred(format!("key{}", i), "value".to_string())
+                                .unwrap();
+                        }
+                        kvs.flush().unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+
+            let dir = TempDir::new().unwrap();
+            {
+                let kvs = open(&dir, *encoding);
+                for i in 0..(1 << i) {
+                    kvs.set_deferred(format!("key{}", i), "value".to_string())
+                        .unwrap();
+                }
+            }
+            println!(
+                "{} log with {} keys: {} bytes",
+                name,
+                1 << i,
+                dir_size(&dir)
+            );
+
+            group.bench_with_input(
+                BenchmarkId::new(format!("reopen_{}", name), i),
+                i,
+                |b, _| {
+                    b.iter_custom(|iters| {
+                        let mut elapsed = Duration::default();
+                        for _ in 0..iters {
+                            let start = Instant::now();
+                            let kvs = open(&dir, *encoding);
+                            elapsed += start.elapsed();
+                            drop(kvs);
+                            remove_empty_generations(&dir);
+                        }
+                        elapsed
+                    })
+                },
+            );
+        }
+    }
+}
+
+pub fn compression_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compression");
+    let values = [
+        ("small", "value".to_string()),
+        ("large", "{\"name\":\"value\",\"tags\":[1,2,3]}".repeat(100)),
+    ];
+    let open = |dir: &TempDir, compress_values| {
+        let options = KvStoreOptions {
+            compress_values,
+            log_encoding: LogEncoding::Binary,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(dir.path(), options).unwrap()
+    };
+
+    for (size, value) in values.iter() {
+        for compress in [false, true].iter() {
+            // Compressed values need the `compression` feature
+            if *compress && !cfg!(feature = "compression") {
+                continue;
+            }
+            let name = if *compress { "compressed" } else { "plain" };
+            let id = format!("set_{}_{}", size, name);
+            group.bench_with_input(BenchmarkId::new(id, 1 << 10), &(1 << 10), |b, n| {
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::default();
+                    for _ in 0..iters {
+                        let dir = TempDir::new().unwrap();
+                        let kvs = open(&dir, *compress);
+                        let start = Instant::now();
+                        for i in 0..*n {
+                            kvs.set_deferred(format!("key{}", i), value.clone())
+                                .unwrap();
+                        }
+                        kvs.flush().unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+
+            let dir = TempDir::new().unwrap();
+            let kvs = open(&dir, *compress);
+            for i in 0..(1 << 10) {
+                kvs.set_deferred(format!("key{}", i), value.clone())
+                    .unwrap();
+            }
+            kvs.flush().unwrap();
+            println!("{} log of {} values: {} bytes", name, size, dir_size(&dir));
+            let id = format!("get_{}_{}", size, name);
+            group.bench_with_input(BenchmarkId::new(id, 1 << 10), &(1 << 10), |b, n| {
+                let mut rng = SmallRng::from_seed([0; 16]);
+                b.iter(|| {
+                    let key = format!("key{}", rng.gen_range(0, *n));
+                    kvs.get(key).unwrap();
+                })
+            });
+        }
+    }
+}
+
+pub fn index_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index");
+    let kinds = [("hash", IndexKind::Hash), ("btree", IndexKind::BTree)];
+    let open = |dir: &TempDir, index| {
+        let options = KvStoreOptions {
+            index,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(dir.path(), options).unwrap()
+    };
+
+    for i in [10, 14, 16].iter() {
+        for (name, kind) in kinds.iter() {
+            // Deferred sets leave the syncs out, so the index weighs more
+            group.bench_with_input(BenchmarkId::new(format!("set_{}", name), i), i, |b, n| {
+                b.iter_custom(|iters| {
+                    let mut elapsed = Duration::default();
+                    for _ in 0..iters {
+                        let dir = TempDir::new().unwrap();
+                        let kvs = open(&dir, *kind);
+                        let start = Instant::now();
+                        for i in 0..(1 << n) {
+                            kvs.set_deferred(format!("key{}", i), "value".to_string())
+                                .unwrap();
+                        }
+                        kvs.flush().unwrap();
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+
+            let dir = TempDir::new().unwrap();
+            let kvs = open(&dir, *kind);
+            for i in 0..(1 << i) {
+                kvs.set_deferred(format!("key{}", i), "value".to_string())
+                    .unwrap();
+            }
+            kvs.flush().unwrap();
+            group.bench_with_input(BenchmarkId::new(format!("get_{}", name), i), i, |b, n| {
+                let mut rng = SmallRng::from_seed([0; 16]);
+                b.iter(|| {
+                    let key = format!("key{}", rng.gen_range(0, 1 << n));
+                    kvs.get(key).unwrap();
+                })
+            });
+        }
+    }
+}
+
+// Every open starts a new empty generation, remove them so they do not pile up
+// across iterations.
+fn remove_empty_generations(dir: &TempDir) {
+    for entry in fs::read_dir(dir.path().join("kvs.db")).unwrap() {
+        let entry = entry.unwrap();
+        if entry.metadata().unwrap().len() == 0 {
+            fs::remove_file(entry.path()).unwrap();
+        }
+    }
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    full_bench,
+    bulk_load_bench,
+    reopen_bench,
+    bulk_remove_bench,
+    contended_get_bench,
+    encoding_bench,
+    compression_bench,
+    index_bench
+);
 criterion_main!(benches);
@@ -0,0 +1,38 @@
+//! Runs an `AsyncKvsServer` and awaits a sequence of operations through an
+//! `AsyncKvsClient` and a `TokioEngine`.
+//!
+//! `cargo run --features tokio --example async_ops`
+
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use unifier::{AsyncKvsClient, AsyncKvsEngine, AsyncKvsServer, KvStore, Result, TokioEngine};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(AsyncKvsServer::new(store.clone()).serve(listener));
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("lang".to_owned(), "rust".to_owned()).await?;
+    client.set("runtime".to_owned(), "tokio".to_owned()).await?;
+    println!("lang = {:?}", client.get("lang".to_owned()).await?);
+    println!(
+        "removed runtime: {}",
+        client.remove("runtime".to_owned()).await?
+    );
+    println!("runtime = {:?}", client.get("runtime".to_owned()).await?);
+
+    // The same store, used in process without blocking the runtime
+    let engine = TokioEngine::new(store);
+    engine
+        .set("lang".to_owned(), "rust 2018".to_owned())
+        .await?;
+    println!("lang = {:?}", client.get("lang".to_owned()).await?);
+    engine.remove("lang".to_owned()).await?;
+    println!("lang = {:?}", engine.get("lang".to_owned()).await?);
+    Ok(())
+}
@@ -0,0 +1,56 @@
+//! Wires a `Metrics` implementation that prints every hook to a `KvStore`.
+//!
+//! `cargo run --example print_metrics`
+
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use unifier::{EngineOp, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsReader, Metrics, Result};
+
+#[derive(Debug)]
+struct PrintMetrics;
+
+impl Metrics for PrintMetrics {
+    fn on_get(&self, latency: Duration, found: bool) {
+        println!("get    {:>10?} found={}", latency, found);
+    }
+
+    fn on_set(&self, latency: Duration) {
+        println!("set    {:>10?}", latency);
+    }
+
+    fn on_remove(&self, latency: Duration) {
+        println!("remove {:>10?}", latency);
+    }
+
+    fn on_compaction(&self, bytes_before: u64, bytes_after: u64, duration: Duration) {
+        println!(
+            "compact {:>9?} {} -> {} bytes",
+            duration, bytes_before, bytes_after
+        );
+    }
+
+    fn on_error(&self, op: EngineOp, err: &KvsError) {
+        println!("{:?} failed: {}", op, err);
+    }
+}
+
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            metrics: Some(Arc::new(PrintMetrics)),
+            ..KvStoreOptions::default()
+        },
+    )?;
+
+    store.set("lang".to_owned(), "rust".to_owned())?;
+    store.set("lang".to_owned(), "rust 2021".to_owned())?;
+    store.get("lang".to_owned())?;
+    store.get("missing".to_owned())?;
+    store.remove("lang".to_owned())?;
+    let _ = store.remove("lang".to_owned());
+    store.compact()?;
+    Ok(())
+}
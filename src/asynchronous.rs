@@ -0,0 +1,406 @@
+//! An async API on Tokio, enabled by the `tokio` feature.
+//!
+//! Engines block on disk, so `TokioEngine` runs them on the blocking pool of
+//! the runtime. `AsyncKvsClient` and `AsyncKvsServer` speak the protocol of
+//! `KvsClient` and `KvsServer`, so either side can talk to the other.
+
+use crate::protocol::{
+    Frame, GetResponse, HelloResponse, RemoveResponse, Request, ServerError, ServerInfo,
+    SetResponse, Traced, PROTOCOL_VERSION,
+};
+use crate::{KvsEngine, KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Deserializer;
+use std::future::Future;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task;
+use uuid::Uuid;
+
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 64 * 1024 * 1024;
+
+// ========================= Engine =========================
+
+/// Trait for a key value storage engine used from async code.
+///
+/// Implementations may use `async fn` for each method.
+pub trait AsyncKvsEngine: Clone + Send + 'static {
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// Runs a blocking `KvsEngine` on the blocking pool of the Tokio runtime, so
+/// its calls do not stall the tasks of the runtime.
+///
+/// It must be used from within a Tokio runtime.
+#[derive(Clone)]
+pub struct TokioEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> TokioEngine<E> {
+    /// Wraps `engine`.
+    pub fn new(engine: E) -> Self {
+        TokioEngine { engine }
+    }
+
+    /// Returns the wrapped engine.
+    pub fn get_ref(&self) -> &E {
+        &self.engine
+    }
+
+    /// Runs `op` on a clone of the engine on the blocking pool.
+    fn spawn<T, F>(&self, op: F) -> impl Future<Output = Result<T>> + Send
+    where
+        T: Send + 'static,
+        F: FnOnce(E) -> Result<T> + Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            task::spawn_blocking(move || op(engine))
+                .await
+                .map_err(io::Error::from)?
+        }
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine for TokioEngine<E> {
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.spawn(move |engine| engine.get(key))
+    }
+
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn(move |engine| engine.set(key, value))
+    }
+
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn(move |engine| engine.remove(key))
+    }
+}
+
+// ========================= Client =========================
+
+/// Key value store client on Tokio.
+///
+/// It covers the plain reads and writes of `KvsClient`, without retries,
+/// timeouts, watches or compression.
+pub struct AsyncKvsClient {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    server_info: Option<ServerInfo>,
+}
+
+impl AsyncKvsClient {
+    /// Connect to `addr` to access `KvsServer` or `AsyncKvsServer`
+    ///
+    /// It fetches the `ServerInfo` of the server, and fails if the server speaks
+    /// another protocol version.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let mut client = AsyncKvsClient {
+            stream: TcpStream::connect(addr).await?,
+            buf: vec![],
+            server_info: None,
+        };
+        let info = match client
+            .request(Request::Hello {
+                compression: vec![],
+            })
+            .await?
+        {
+            HelloResponse::Ok(info) => info,
+            HelloResponse::Err(e) => return Err(e.into()),
+        };
+        if info.protocol_version != PROTOCOL_VERSION {
+            return Err(KvsError::Unsupported(format!(
+                "protocol version {}",
+                info.protocol_version
+            )));
+        }
+        client.server_info = Some(info);
+        Ok(client)
+    }
+
+    /// Returns what the server reported on connect.
+    pub fn server_info(&self) -> &ServerInfo {
+        self.server_info.as_ref().expect("fetched by connect")
+    }
+
+    /// Get the value of a given key from the server
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(Request::Get { key }).await? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Set the value of a string key in the server
+    ///
+    /// Returns the new version of the key. Servers whose engine does not
+    /// version keys return 0.
+    pub async fn set(&mut self, key: String, value: String) -> Result<u64> {
+        match self.request(Request::Set { key, value }).await? {
+            SetResponse::Ok(version) => Ok(version),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove a given key in the server
+    ///
+    /// Returns whether the key existed.
+    pub async fn remove(&mut self, key: String) -> Result<bool> {
+        match self.request(Request::Remove { key }).await? {
+            RemoveResponse::Ok(_) => Ok(true),
+            RemoveResponse::NotFound => Ok(false),
+            RemoveResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn request<R: DeserializeOwned>(&mut self, body: Request) -> Result<R> {
+        let req = serde_json::to_vec(&Traced {
+            trace_id: Uuid::new_v4().to_string(),
+            deadline: None,
+            dedup_id: None,
+            body,
+        })?;
+        if let Some(info) = &self.server_info {
+            let size = req.len() as u64;
+            if size > info.max_request_bytes {
+                return Err(KvsError::RequestTooLarge {
+                    size,
+                    max: info.max_request_bytes,
+                });
+            }
+        }
+        self.stream.write_all(&req).await?;
+        loop {
+            // Nothing is ever watched, so any notification is stale
+            match read_frame(&mut self.stream, &mut self.buf, u64::MAX).await? {
+                Some(Frame::Response(resp)) => return Ok(resp.body),
+                Some(Frame::Notification(_)) => {}
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "connection closed",
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+}
+
+// ========================= Server =========================
+
+/// The server of a key value store on Tokio.
+///
+/// It serves `Hello`, `Get`, `Set`, `SetIfVersion` and `Remove` requests, the
+/// ones of `AsyncKvsClient` and the plain calls of `KvsClient`, and honours
+/// their deadlines. Other requests fail with `KvsError::Unsupported`. Engine
+/// calls run on the blocking pool like `TokioEngine`.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: TokioEngine<E>,
+    max_request_bytes: u64,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    /// Create an `AsyncKvsServer` with a given storage engine.
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            engine: TokioEngine::new(engine),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+        }
+    }
+
+    /// Sets the largest encoded request the server accepts, reported to clients
+    /// on connect. Larger requests fail with `KvsError::RequestTooLarge`.
+    ///
+    /// Defaults to 64 MiB.
+    pub fn with_max_request_bytes(mut self, max: u64) -> Self {
+        self.max_request_bytes = max;
+        self
+    }
+
+    /// Run the server listening on the given address.
+    ///
+    /// It only returns if the address cannot be bound.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr).await?).await
+    }
+
+    /// Run the server on a bound `listener`, e.g. one bound to port 0 whose
+    /// address is read first.
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let engine = self.engine.clone();
+            let max_request_bytes = self.max_request_bytes;
+            tokio::spawn(async move {
+                if let Err(e) = serve_requests(engine, max_request_bytes, stream).await {
+                    error!("Error on serving client: {}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn serve_requests<E: KvsEngine>(
+    engine: TokioEngine<E>,
+    max_request_bytes: u64,
+    mut tcp: TcpStream,
+) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    let mut buf = vec![];
+    while let Some(req) = read_frame(&mut tcp, &mut buf, max_request_bytes).await? {
+        let Traced {
+            trace_id,
+            deadline,
+            dedup_id: _,
+            body: req,
+        } = req;
+        debug!(
+            "[{}] Receive request from {}: {:?}",
+            trace_id, peer_addr, req
+        );
+        let deadline = deadline.map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+
+        macro_rules! send_resp {
+            ($resp:expr) => {{
+                let resp = $resp;
+                let frame = Frame::Response(Traced {
+                    trace_id: trace_id.clone(),
+                    deadline: None,
+                    dedup_id: None,
+                    body: &resp,
+                });
+                tcp.write_all(&serde_json::to_vec(&frame)?).await?;
+                debug!("[{}] Response sent to {}: {:?}", trace_id, peer_addr, resp);
+            }};
+        }
+
+        match req {
+            Request::Hello { .. } => send_resp!(HelloResponse::Ok(ServerInfo {
+                protocol_version: PROTOCOL_VERSION,
+                max_request_bytes,
+                auth_required: false,
+                engine: engine.get_ref().name().to_owned(),
+                compression: None,
+            })),
+            Request::Get { key } => {
+                send_resp!(match within(deadline, engine.get(key)).await {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(e) => GetResponse::Err(e.into()),
+                })
+            }
+            Request::Set { key, value } => {
+                let op = engine.spawn(move |engine| engine.set_versioned(key, value));
+                send_resp!(match within(deadline, op).await {
+                    Ok(version) => SetResponse::Ok(version),
+                    Err(e) => SetResponse::Err(e.into()),
+                })
+            }
+            Request::SetIfVersion {
+                key,
+                value,
+                version,
+            } => {
+                let op = engine.spawn(move |engine| engine.set_if_version(key, value, version));
+                send_resp!(match within(deadline, op).await {
+                    Ok(version) => SetResponse::Ok(version),
+                    Err(e) => SetResponse::Err(e.into()),
+                })
+            }
+            Request::Remove { key } => {
+                send_resp!(match within(deadline, engine.remove(key)).await {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(KvsError::KeyNotFound) => RemoveResponse::NotFound,
+                    Err(e) => RemoveResponse::Err(e.into()),
+                })
+            }
+            req => {
+                let e = KvsError::Unsupported(req.op().to_owned());
+                send_resp!(Rejected::Err(e.into()))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The response to a request the server does not serve, which every response
+/// type decodes as its `Err` variant.
+#[derive(Debug, Serialize)]
+enum Rejected {
+    Err(ServerError),
+}
+
+/// Runs `op` unless `deadline` has passed, and fails it if the deadline passed
+/// while it ran.
+async fn within<T>(deadline: Option<SystemTime>, op: impl Future<Output = Result<T>>) -> Result<T> {
+    let expired = || deadline.is_some_and(|deadline| SystemTime::now() > deadline);
+    if expired() {
+        return Err(KvsError::DeadlineExceeded);
+    }
+    let res = op.await?;
+    if expired() {
+        return Err(KvsError::DeadlineExceeded);
+    }
+    Ok(res)
+}
+
+/// Reads the next frame from `stream` into `buf`, returning `None` if the
+/// stream ends between frames.
+///
+/// Frames are self-delimiting JSON values, so bytes are read until one parses.
+/// Bytes past the frame stay in `buf` for the next call. It fails once more
+/// than `max` bytes are buffered without a whole frame.
+async fn read_frame<T: DeserializeOwned>(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    max: u64,
+) -> Result<Option<T>> {
+    loop {
+        let mut frames = Deserializer::from_slice(buf).into_iter::<T>();
+        match frames.next() {
+            Some(Ok(frame)) => {
+                let end = frames.byte_offset();
+                buf.drain(..end);
+                return Ok(Some(frame));
+            }
+            Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+            _ => {}
+        }
+        let size = buf.len() as u64;
+        if size > max {
+            return Err(KvsError::RequestTooLarge { size, max });
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return if buf.iter().all(u8::is_ascii_whitespace) {
+                Ok(None)
+            } else {
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            };
+        }
+    }
+}
@@ -2,16 +2,15 @@ use clap::AppSettings;
 use std::net::SocketAddr;
 use std::process::exit;
 use structopt::StructOpt;
-use unifier::{KvsClient, Result};
+use unifier::{KvsClient, KvsError, Result};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const ADDRESS_FORMAT: &str = "IP:PORT";
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "unifier-client", 
+#[structopt(name = "unifier-client",
             global_settings = &
         [AppSettings::DisableHelpSubcommand, AppSettings::VersionlessSubcommands])]
-
 struct Opt {
     #[structopt(subcommand)]
     command: Command,
@@ -60,13 +59,48 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(name = "admin", about = "Run an administrative command on the server")]
+    Admin {
+        #[structopt(subcommand)]
+        command: AdminCommand,
+    },
 }
 
+#[derive(StructOpt, Debug)]
+enum AdminCommand {
+    #[structopt(name = "compact", about = "Compact the storage of the server")]
+    Compact {
+        #[structopt(long, help = "The admin token of the server", value_name = "TOKEN")]
+        token: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = ADDRESS_FORMAT,
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+}
+
+/// Exit code of a `get` or `rm` of a missing key, which prints the message of
+/// `KvsError::KeyNotFound` to stdout, as the server words it.
+const EXIT_KEY_NOT_FOUND: i32 = 1;
+/// Exit code of a command that failed otherwise, e.g. on a connection failure.
+const EXIT_FAILURE: i32 = 2;
+
 fn main() {
     let opt = Opt::from_args();
-    if let Err(e) = run(opt) {
-        eprintln!("{}", e);
-        exit(1);
+    match run(opt) {
+        Ok(()) => {}
+        Err(e @ KvsError::KeyNotFound) => {
+            println!("{}", e);
+            exit(EXIT_KEY_NOT_FOUND);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(EXIT_FAILURE);
+        }
     }
 }
 
@@ -74,11 +108,8 @@ fn run(opt: Opt) -> Result<()> {
     match opt.command {
         Command::Get { key, addr } => {
             let mut client = KvsClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
-            }
+            let value = client.get(key)?.ok_or(KvsError::KeyNotFound)?;
+            println!("{}", value);
         }
         Command::Set { key, value, addr } => {
             let mut client = KvsClient::connect(addr)?;
@@ -86,7 +117,16 @@ fn run(opt: Opt) -> Result<()> {
         }
         Command::Remove { key, addr } => {
             let mut client = KvsClient::connect(addr)?;
-            client.remove(key)?;
+            if !client.remove(key)? {
+                return Err(KvsError::KeyNotFound);
+            }
+        }
+        Command::Admin {
+            command: AdminCommand::Compact { token, addr },
+        } => {
+            let mut client = KvsClient::connect(addr)?;
+            let reclaimed = client.admin_compact(token)?;
+            println!("Reclaimed {} bytes", reclaimed);
         }
     }
     Ok(())
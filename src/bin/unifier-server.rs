@@ -7,6 +7,7 @@ use log::LevelFilter;
 use std::env::{self, current_dir};
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
 use unifier::thread_pool::*;
@@ -14,6 +15,8 @@ use unifier::*;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+/// Size at which the access log is rotated.
+const ACCESS_LOG_MAX_BYTES: u64 = 64 * 1024 * 1024;
 
 arg_enum! {
     #[allow(non_camel_case_types)]
@@ -24,6 +27,15 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Protocol {
+        binary,
+        text
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "unifier-server")]
 struct Opt {
@@ -42,51 +54,85 @@ struct Opt {
         possible_values = &Engine::variants()
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Writes a structured access log to the file",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    access_log: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Sets the wire protocol, text is for debugging over telnet",
+        value_name = "PROTOCOL",
+        default_value = "binary",
+        possible_values = &Protocol::variants()
+    )]
+    protocol: Protocol,
 }
 
 fn main() {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
-    let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
-        }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
-        }
-        run(opt)
-    });
-    if let Err(e) = res {
+    env_logger::builder()
+        .filter_level(LevelFilter::Info)
+        .parse_default_env()
+        .init();
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
         error!("{}", e);
         exit(1);
     }
 }
 
 fn run(opt: Opt) -> Result<()> {
-    let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
+    let engine = select_engine(opt.engine)?;
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
 
-    // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    // The marker is written before any data, so later starts know the format
+    let marker = current_dir()?.join("engine");
+    if !marker.exists() {
+        fs::write(marker, format!("{}", engine))?;
+    }
 
     let pool = RayonThreadPool::new(num_cpus::get() as u32)?;
 
     match engine {
-        Engine::kvs => run_with(KvStore::open(env::current_dir()?)?, pool, opt.addr),
+        Engine::kvs => run_with(KvStore::open(env::current_dir()?)?, pool, opt),
         Engine::sled => run_with(
             SledKvsEngine::new(sled::open(env::current_dir()?)?),
             pool,
-            opt.addr,
+            opt,
         ),
     }
 }
 
-pub fn run_with<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine, pool);
-    server.run(addr)
+/// Returns the engine to start: the one the data directory was first started
+/// with, which `requested` must match, or `requested` for a new directory.
+fn select_engine(requested: Option<Engine>) -> Result<Engine> {
+    match (current_engine()?, requested) {
+        (Some(current), Some(requested)) if current != requested => {
+            Err(KvsError::StringError(format!(
+                "Wrong engine: the data directory was created with the {} engine, \
+                 refusing to start with {}",
+                current, requested
+            )))
+        }
+        (current, requested) => Ok(current.or(requested).unwrap_or(DEFAULT_ENGINE)),
+    }
+}
+
+fn run_with<E: KvsEngine, P: ThreadPool>(engine: E, pool: P, opt: Opt) -> Result<()> {
+    let mut server = KvsServer::new(engine, pool);
+    if let Some(path) = opt.access_log {
+        info!("Access log: {}", path.display());
+        server = server.with_access_log(path, ACCESS_LOG_MAX_BYTES)?;
+    }
+    if opt.protocol == Protocol::text {
+        info!("Speaking the text protocol");
+        server = server.with_protocol(WireProtocol::Text);
+    }
+    server.run(opt.addr)
 }
 
 fn current_engine() -> Result<Option<Engine>> {
@@ -95,11 +141,13 @@ fn current_engine() -> Result<Option<Engine>> {
         return Ok(None);
     }
 
-    match fs::read_to_string(engine)?.parse() {
+    // Guessing could open the data with the wrong engine
+    match fs::read_to_string(&engine)?.trim().parse() {
         Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
-        }
+        Err(e) => Err(KvsError::StringError(format!(
+            "Invalid engine file {}: {}",
+            engine.display(),
+            e
+        ))),
     }
 }
@@ -1,57 +1,989 @@
-use crate::protocol::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
+use crate::protocol::{
+    AdminResponse, ApplyResponse, ChangesResponse, CompactResponse, Compression, Frame,
+    GetResponse, HelloResponse, IterCloseResponse, IterNextResponse, IterOpenResponse,
+    Notification, RemoveResponse, Request, ScanResponse, ServerInfo, ServerStats, SetResponse,
+    StatsResponse, Traced, UnwatchResponse, WatchResponse, PROTOCOL_VERSION,
+};
+use crate::wire::{CompressionSwitch, WireReader, WireWriter};
+use crate::{Change, KvsError, LogPosition, Result};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+type FrameReader = Deserializer<IoRead<WireReader<BufReader<TcpStream>>>>;
+type FrameWriter = WireWriter<BufWriter<TcpStream>>;
+type Watches = Arc<Mutex<HashMap<String, Sender<Notification>>>>;
+
+/// Most changes fetched by a `ChangeStream` per request.
+const FOLLOW_BATCH: usize = 1000;
+/// How long a `ChangeStream` waits before asking again once it has caught up.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Key value store client
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: Inbound,
+    writer: FrameWriter,
+    watches: Watches,
+    next_trace_id: Option<String>,
+    next_deadline: Option<SystemTime>,
+    next_dedup_id: Option<String>,
+    last_trace_id: Option<String>,
+    addrs: Vec<SocketAddr>,
+    disconnected: bool,
+    server_info: Option<ServerInfo>,
+    /// The compression offered on connect.
+    compression: Option<Compression>,
+    /// The compression of the current connection.
+    switch: CompressionSwitch,
+    /// How long to wait for a response.
+    timeout: Option<Duration>,
+    stats: ClientStats,
+}
+
+/// Counters of the requests made by a `KvsClient`, as seen from the client.
+///
+/// Compared with the `ServerStats` of the server, they tell whether failures
+/// happen on the way to the server or in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Requests sent, retries and handshakes included.
+    pub requests: u64,
+    /// Responses received, error responses included.
+    pub responses: u64,
+    /// Requests left without a response past the timeout.
+    pub timeouts: u64,
+    /// Requests sent again on a new connection after the previous one dropped.
+    pub retries: u64,
+    /// Connections opened after the first one.
+    pub reconnects: u64,
+}
+
+/// Where responses are read from.
+///
+/// Frames are read directly from the connection until the first watch. Then a
+/// background thread takes over the connection, routing notifications to their
+/// `NotificationStream` and responses back to the client.
+enum Inbound {
+    Direct(FrameReader),
+    Demuxed(Receiver<Result<Traced<Value>>>),
+    Closed,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`
+    ///
+    /// It fetches the `ServerInfo` of the server, and fails if the server speaks
+    /// another protocol version.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_reader = TcpStream::connect(addr)?;
-        let tcp_writer = tcp_reader.try_clone()?;
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-            writer: BufWriter::new(tcp_writer),
-        })
+        Self::open(addr, None)
+    }
+
+    /// Connect to `addr` like `connect`, offering to compress the connection
+    /// with `compression`, e.g. over a slow link.
+    ///
+    /// The connection stays uncompressed if the server does not accept it, which
+    /// `ServerInfo::compression` tells.
+    pub fn connect_compressed<A: ToSocketAddrs>(addr: A, compression: Compression) -> Result<Self> {
+        Self::open(addr, Some(compression))
+    }
+
+    fn open<A: ToSocketAddrs>(addr: A, compression: Option<Compression>) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let switch = CompressionSwitch::default();
+        let (reader, writer) = open_streams(&addrs, &switch, None)?;
+        let mut client = KvsClient {
+            reader,
+            writer,
+            watches: Arc::new(Mutex::new(HashMap::new())),
+            next_trace_id: None,
+            next_deadline: None,
+            next_dedup_id: None,
+            last_trace_id: None,
+            addrs,
+            disconnected: false,
+            server_info: None,
+            compression,
+            switch,
+            timeout: None,
+            stats: ClientStats::default(),
+        };
+        client.hello()?;
+        Ok(client)
+    }
+
+    /// Fetches the `ServerInfo` and switches to the compression the server
+    /// accepted, if any.
+    fn hello(&mut self) -> Result<()> {
+        let req = serde_json::to_vec(&Traced {
+            trace_id: Uuid::new_v4().to_string(),
+            deadline: None,
+            dedup_id: None,
+            body: Request::Hello {
+                compression: self.compression.into_iter().collect(),
+            },
+        })?;
+        let info = match self.exchange(&req)?.body {
+            HelloResponse::Ok(info) => info,
+            HelloResponse::Err(e) => return Err(e.into()),
+        };
+        if info.protocol_version != PROTOCOL_VERSION {
+            return Err(KvsError::Unsupported(format!(
+                "protocol version {}",
+                info.protocol_version
+            )));
+        }
+        if let Some(compression) = info.compression {
+            let _ = self.switch.set(compression);
+        }
+        self.server_info = Some(info);
+        Ok(())
+    }
+
+    /// Returns what the server reported on connect.
+    pub fn server_info(&self) -> &ServerInfo {
+        self.server_info.as_ref().expect("fetched by connect")
+    }
+
+    /// Sets the trace id attached to the next request.
+    ///
+    /// The server includes it in its log lines for that request. Requests without
+    /// a supplied trace id get a random UUID.
+    pub fn trace(&mut self, trace_id: impl Into<String>) -> &mut Self {
+        self.next_trace_id = Some(trace_id.into());
+        self
+    }
+
+    /// Sets the deadline of the next request.
+    ///
+    /// The server checks it before and after running the request, and fails the
+    /// request with "deadline exceeded" once it has passed. A write that is only
+    /// late afterwards has still been applied. The deadline is compared against
+    /// the clock of the server.
+    pub fn deadline(&mut self, deadline: SystemTime) -> &mut Self {
+        self.next_deadline = Some(deadline);
+        self
+    }
+
+    /// Sets how long requests wait for their response, `None` to wait forever,
+    /// the default.
+    ///
+    /// A request past the timeout fails with an `io::ErrorKind::TimedOut` error
+    /// and is not retried, since a slow server would not answer a retry faster.
+    /// Its response may still arrive, so the next request uses a new
+    /// connection.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        if let Inbound::Direct(_) = self.reader {
+            self.writer.get_ref().get_ref().set_read_timeout(timeout)?;
+        }
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Returns the counters of the requests made by this client so far.
+    ///
+    /// Unlike `stats`, it does not ask the server.
+    pub fn client_stats(&self) -> ClientStats {
+        self.stats
+    }
+
+    /// Returns the trace id echoed by the server in the last response.
+    pub fn last_trace_id(&self) -> Option<&str> {
+        self.last_trace_id.as_deref()
+    }
+
+    /// Marks the next request as safe to retry on a new connection.
+    ///
+    /// `get` is always retried if the connection drops before its response
+    /// arrives. A write is only retried when marked, in which case it carries a
+    /// dedup id: the server remembers the outcome of recent dedup ids and
+    /// answers a retry with it instead of applying the write again. Nothing is
+    /// retried once a key is watched, since the watches would be lost.
+    pub fn idempotent(&mut self) -> &mut Self {
+        self.next_dedup_id = Some(Uuid::new_v4().to_string());
+        self
+    }
+
+    fn request<R: DeserializeOwned>(&mut self, body: Request) -> Result<R> {
+        let trace_id = self
+            .next_trace_id
+            .take()
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        let deadline = self.next_deadline.take().map(|deadline| {
+            deadline
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+        let dedup_id = self.next_dedup_id.take();
+        let retryable = dedup_id.is_some()
+            || matches!(
+                body,
+                Request::Hello { .. }
+                    | Request::Get { .. }
+                    | Request::Stats
+                    | Request::Changes { .. }
+            );
+        let req = serde_json::to_vec(&Traced {
+            trace_id,
+            deadline,
+            dedup_id,
+            body,
+        })?;
+        // The server would refuse it anyway, after receiving all of it
+        if let Some(info) = &self.server_info {
+            let size = req.len() as u64;
+            if size > info.max_request_bytes {
+                return Err(KvsError::RequestTooLarge {
+                    size,
+                    max: info.max_request_bytes,
+                });
+            }
+        }
+
+        // Nothing has been sent yet, so any request may restore the connection
+        if self.disconnected {
+            self.reconnect()?;
+        }
+        let resp = match self.exchange(&req) {
+            Err(e) if is_disconnect(&e) && !is_timeout(&e) => {
+                self.disconnected = true;
+                if !retryable || !matches!(self.reader, Inbound::Direct(_)) {
+                    return Err(e);
+                }
+                self.reconnect()?;
+                self.stats.retries += 1;
+                self.exchange(&req)?
+            }
+            resp => resp?,
+        };
+        self.last_trace_id = Some(resp.trace_id);
+        Ok(resp.body)
+    }
+
+    /// Sends an encoded request and reads its response.
+    fn exchange<R: DeserializeOwned>(&mut self, req: &[u8]) -> Result<Traced<R>> {
+        self.stats.requests += 1;
+        let resp = self.send(req);
+        match &resp {
+            Ok(_) => self.stats.responses += 1,
+            Err(e) if is_timeout(e) => {
+                self.stats.timeouts += 1;
+                self.disconnected = true;
+            }
+            Err(_) => {}
+        }
+        resp
+    }
+
+    fn send<R: DeserializeOwned>(&mut self, req: &[u8]) -> Result<Traced<R>> {
+        self.writer.write_all(req)?;
+        self.writer.flush()?;
+        match &mut self.reader {
+            Inbound::Direct(reader) => loop {
+                // Nothing is watched yet, so any notification is stale
+                match Frame::<R>::deserialize(&mut *reader) {
+                    Ok(Frame::Response(resp)) => break Ok(resp),
+                    Ok(Frame::Notification(_)) => {}
+                    Err(e) if is_timeout_kind(e.io_error_kind()) => break Err(timed_out().into()),
+                    Err(e) => break Err(e.into()),
+                }
+            },
+            Inbound::Demuxed(responses) => {
+                let resp = match self.timeout {
+                    Some(timeout) => responses.recv_timeout(timeout).map_err(|e| match e {
+                        RecvTimeoutError::Timeout => timed_out(),
+                        RecvTimeoutError::Disconnected => connection_closed(),
+                    })??,
+                    None => responses.recv().map_err(|_| connection_closed())??,
+                };
+                Ok(Traced {
+                    trace_id: resp.trace_id,
+                    deadline: None,
+                    dedup_id: None,
+                    body: serde_json::from_value(resp.body)?,
+                })
+            }
+            Inbound::Closed => Err(connection_closed().into()),
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let switch = CompressionSwitch::default();
+        let (reader, writer) = open_streams(&self.addrs, &switch, self.timeout)?;
+        self.stats.reconnects += 1;
+        let _ = self.writer.get_ref().get_ref().shutdown(Shutdown::Both);
+        self.reader = reader;
+        self.writer = writer;
+        self.switch = switch;
+        self.disconnected = false;
+        // A new connection starts uncompressed
+        if self.compression.is_some() {
+            self.hello()?;
+        }
+        Ok(())
     }
 
     /// Get the value of a given key from the server
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush().expect("writer!");
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        let resp: GetResponse = self.request(Request::Get { key })?;
         match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// Set the value of a string key in the server
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+    ///
+    /// Returns the new version of the key, for a later `set_if_version`. Servers
+    /// whose engine does not version keys return 0.
+    pub fn set(&mut self, key: String, value: String) -> Result<u64> {
+        let resp: SetResponse = self.request(Request::Set { key, value })?;
         match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Ok(version) => Ok(version),
+            SetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Set the value of a string key in the server if its current version is
+    /// `version`, 0 for a missing key.
+    ///
+    /// Returns the new version of the key. It fails if the key has been written
+    /// since `version` was returned.
+    pub fn set_if_version(&mut self, key: String, value: String, version: u64) -> Result<u64> {
+        let resp: SetResponse = self.request(Request::SetIfVersion {
+            key,
+            value,
+            version,
+        })?;
+        match resp {
+            SetResponse::Ok(version) => Ok(version),
+            SetResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// Remove a string key in the server
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush().expect("writer!");
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+    ///
+    /// Returns `false` if the key did not exist.
+    pub fn remove(&mut self, key: String) -> Result<bool> {
+        let resp: RemoveResponse = self.request(Request::Remove { key })?;
         match resp {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Ok(_) => Ok(true),
+            RemoveResponse::NotFound => Ok(false),
+            RemoveResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Apply the transform registered on the server as `fn_name` to a key.
+    ///
+    /// Returns the new value, `None` if the transform removed the key.
+    pub fn apply(&mut self, key: String, fn_name: String) -> Result<Option<String>> {
+        let resp: ApplyResponse = self.request(Request::Apply { key, fn_name })?;
+        match resp {
+            ApplyResponse::Ok(value) => Ok(value),
+            ApplyResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetch the statistics of the server.
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        let resp: StatsResponse = self.request(Request::Stats)?;
+        match resp {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Ask the server to stop accepting connections, authorized by the admin
+    /// token of the server.
+    ///
+    /// Open connections, this one included, are still served until they close.
+    pub fn admin_drain(&mut self, token: impl Into<String>) -> Result<()> {
+        let token = token.into();
+        let resp: AdminResponse = self.request(Request::AdminDrain { token })?;
+        match resp {
+            AdminResponse::Ok(_) => Ok(()),
+            AdminResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Switch the server between serving writes and rejecting them, authorized
+    /// by the admin token of the server.
+    pub fn admin_read_only(&mut self, token: impl Into<String>, read_only: bool) -> Result<()> {
+        let token = token.into();
+        let resp: AdminResponse = self.request(Request::AdminReadOnly { token, read_only })?;
+        match resp {
+            AdminResponse::Ok(_) => Ok(()),
+            AdminResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Ask the server to compact its storage, authorized by the admin token of
+    /// the server. Returns the number of bytes reclaimed.
+    pub fn admin_compact(&mut self, token: impl Into<String>) -> Result<u64> {
+        let token = token.into();
+        let resp: CompactResponse = self.request(Request::AdminCompact { token })?;
+        match resp {
+            CompactResponse::Ok(reclaimed) => Ok(reclaimed),
+            CompactResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Open a server-side cursor over a snapshot of the keys in the server.
+    ///
+    /// The cursor outlives this connection, so it can be resumed from another
+    /// client until it is closed or left idle past the server's cursor timeout.
+    pub fn iter_open(&mut self) -> Result<u64> {
+        let resp: IterOpenResponse = self.request(Request::IterOpen)?;
+        match resp {
+            IterOpenResponse::Ok(cursor) => Ok(cursor),
+            IterOpenResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetch the next batch of at most `count` key/value pairs from a cursor.
+    ///
+    /// An empty batch means the cursor is exhausted.
+    pub fn iter_next(&mut self, cursor: u64, count: usize) -> Result<Vec<(String, String)>> {
+        let resp: IterNextResponse = self.request(Request::IterNext { cursor, count })?;
+        match resp {
+            IterNextResponse::Ok(pairs) => Ok(pairs),
+            IterNextResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Close a cursor, releasing its server-side state.
+    pub fn iter_close(&mut self, cursor: u64) -> Result<()> {
+        let resp: IterCloseResponse = self.request(Request::IterClose { cursor })?;
+        match resp {
+            IterCloseResponse::Ok(_) => Ok(()),
+            IterCloseResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetch the pairs whose key starts with `prefix` and whose value contains
+    /// `value_contains`, ordered by key.
+    ///
+    /// The server filters the values, so only the matching pairs are sent. An
+    /// empty `value_contains` matches every value.
+    pub fn scan(
+        &mut self,
+        prefix: String,
+        value_contains: String,
+    ) -> Result<Vec<(String, String)>> {
+        let resp: ScanResponse = self.request(Request::ScanFilter {
+            prefix,
+            value_contains,
+        })?;
+        match resp {
+            ScanResponse::Ok(pairs) => Ok(pairs),
+            ScanResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Watch a key for changes made through the server.
+    ///
+    /// The server pushes a `Notification` on this connection whenever the key is
+    /// set or removed. The returned stream yields them until the key is unwatched
+    /// or the connection is closed. Watching a key again replaces its stream.
+    pub fn watch(&mut self, key: String) -> Result<NotificationStream> {
+        if self.disconnected {
+            self.reconnect()?;
+        }
+        if let Inbound::Direct(_) = self.reader {
+            // Notifications may come after any idle time, the demux thread waits
+            // for them and requests time out on their channel instead
+            self.writer.get_ref().get_ref().set_read_timeout(None)?;
+            if let Inbound::Direct(reader) = mem::replace(&mut self.reader, Inbound::Closed) {
+                let (tx, rx) = mpsc::channel();
+                let watches = Arc::clone(&self.watches);
+                thread::spawn(move || demux(reader, tx, watches));
+                self.reader = Inbound::Demuxed(rx);
+            }
+        }
+
+        // Registered before the request, so that a notification sent right after
+        // the response is not dropped
+        let (tx, rx) = mpsc::channel();
+        let replaced = self.watches.lock().unwrap().insert(key.clone(), tx);
+        let resp = self.request(Request::Watch { key: key.clone() });
+        let err = match resp {
+            Ok(WatchResponse::Ok(_)) => return Ok(NotificationStream { notifications: rx }),
+            Ok(WatchResponse::Err(e)) => e.into(),
+            Err(e) => e,
+        };
+        let mut watches = self.watches.lock().unwrap();
+        match replaced {
+            Some(replaced) => watches.insert(key, replaced),
+            None => watches.remove(&key),
+        };
+        Err(err)
+    }
+
+    /// Stop watching a key, ending its `NotificationStream`.
+    pub fn unwatch(&mut self, key: String) -> Result<()> {
+        let resp: UnwatchResponse = self.request(Request::Unwatch { key: key.clone() })?;
+        self.watches.lock().unwrap().remove(&key);
+        match resp {
+            UnwatchResponse::Ok(_) => Ok(()),
+            UnwatchResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Follow the changes written to the server, starting at `from`.
+    ///
+    /// The returned stream reads the log of the server on a connection of its
+    /// own, reconnecting if it drops. Start from `LogPosition::default()` to
+    /// read the whole log, or from a `ChangeStream::position` saved earlier to
+    /// resume where a previous stream stopped.
+    ///
+    /// # Errors
+    ///
+    /// It fails if the engine of the server keeps no log to follow.
+    pub fn follow(&mut self, from: LogPosition) -> Result<ChangeStream> {
+        let mut client = KvsClient::open(&self.addrs[..], self.compression)?;
+        client.set_timeout(self.timeout)?;
+        let mut stream = ChangeStream {
+            client,
+            next: from,
+            position: from,
+            pending: VecDeque::new(),
+        };
+        match stream.fetch() {
+            Ok(()) => Ok(stream),
+            Err(KvsError::LogCompacted) => {
+                // The first read fails the same way, for the consumer to see it
+                stream.next = from;
+                Ok(stream)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for KvsClient {
+    fn drop(&mut self) {
+        // The demux thread holds its own handle of the connection
+        let _ = self.writer.get_ref().get_ref().shutdown(Shutdown::Both);
+    }
+}
+
+/// Notifications for a key watched by `KvsClient::watch`.
+pub struct NotificationStream {
+    notifications: Receiver<Notification>,
+}
+
+impl NotificationStream {
+    /// Waits at most `timeout` for the next notification.
+    ///
+    /// Returns `None` if the timeout elapsed or the stream has ended.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Notification> {
+        match self.notifications.recv_timeout(timeout) {
+            Ok(notification) => Some(notification),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+impl Iterator for NotificationStream {
+    type Item = Notification;
+
+    fn next(&mut self) -> Option<Notification> {
+        self.notifications.recv().ok()
+    }
+}
+
+/// Changes of the server followed by `KvsClient::follow`.
+///
+/// It yields every set and removal in the order they were written, polling the
+/// server once it has caught up. A failed request is yielded as an error, and
+/// the next call resumes from the last change returned.
+///
+/// If compaction removes the part of the log the stream was reading, it yields
+/// `KvsError::LogCompacted` once, then starts over from the beginning of the
+/// log. The consumer should drop what it has built and rebuild it from the
+/// changes that follow, which replay to the current state of the server.
+pub struct ChangeStream {
+    client: KvsClient,
+    /// Where the next batch is read from.
+    next: LogPosition,
+    /// The position after the last change returned.
+    position: LogPosition,
+    pending: VecDeque<Change>,
+}
+
+impl ChangeStream {
+    /// Returns the position after the last change returned, to save as a
+    /// checkpoint and pass to `KvsClient::follow` later.
+    pub fn position(&self) -> LogPosition {
+        if self.pending.is_empty() {
+            // Every change fetched has been returned, so none lies in between
+            self.next
+        } else {
+            self.position
+        }
+    }
+
+    /// Waits at most `timeout` for the next change.
+    ///
+    /// Returns `None` if the timeout elapsed without a change.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Change>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                self.position = change.position;
+                return Ok(Some(change));
+            }
+            self.fetch()?;
+            if self.pending.is_empty() {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                thread::sleep(FOLLOW_POLL_INTERVAL.min(deadline - now));
+            }
+        }
+    }
+
+    fn fetch(&mut self) -> Result<()> {
+        let resp: ChangesResponse = self.client.request(Request::Changes {
+            from: self.next,
+            max: FOLLOW_BATCH,
+        })?;
+        match resp {
+            ChangesResponse::Ok(changes, next) => {
+                self.pending.extend(changes);
+                self.next = next;
+                Ok(())
+            }
+            ChangesResponse::Compacted => {
+                self.next = LogPosition::default();
+                self.position = LogPosition::default();
+                Err(KvsError::LogCompacted)
+            }
+            ChangesResponse::Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<Change>;
+
+    /// Blocks until the next change, it never returns `None`.
+    fn next(&mut self) -> Option<Result<Change>> {
+        loop {
+            match self.recv_timeout(FOLLOW_POLL_INTERVAL) {
+                Ok(Some(change)) => return Some(Ok(change)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Connects to the first reachable address, compressing frames once `switch`
+/// is set and waiting at most `timeout` for each read.
+fn open_streams(
+    addrs: &[SocketAddr],
+    switch: &CompressionSwitch,
+    timeout: Option<Duration>,
+) -> Result<(Inbound, FrameWriter)> {
+    let tcp_reader = TcpStream::connect(addrs)?;
+    tcp_reader.set_read_timeout(timeout)?;
+    let tcp_writer = tcp_reader.try_clone()?;
+    let reader = WireReader::new(BufReader::new(tcp_reader), Arc::clone(switch));
+    Ok((
+        Inbound::Direct(Deserializer::from_reader(reader)),
+        WireWriter::new(BufWriter::new(tcp_writer), Arc::clone(switch)),
+    ))
+}
+
+fn demux(mut reader: FrameReader, responses: Sender<Result<Traced<Value>>>, watches: Watches) {
+    loop {
+        match Frame::<Value>::deserialize(&mut reader) {
+            Ok(Frame::Response(resp)) => {
+                if responses.send(Ok(resp)).is_err() {
+                    break;
+                }
+            }
+            Ok(Frame::Notification(notification)) => {
+                if let Some(tx) = watches.lock().unwrap().get(&notification.key) {
+                    let _ = tx.send(notification);
+                }
+            }
+            Err(e) => {
+                let _ = responses.send(Err(e.into()));
+                break;
+            }
+        }
+    }
+    // Dropping the senders ends every stream
+    watches.lock().unwrap().clear();
+}
+
+/// Whether `e` means the connection dropped, as opposed to a failed request.
+fn is_disconnect(e: &KvsError) -> bool {
+    match e {
+        KvsError::Io(_) => true,
+        KvsError::Serde(e) => e.is_io() || e.is_eof(),
+        _ => false,
+    }
+}
+
+/// Whether `e` means the response did not arrive in time.
+fn is_timeout(e: &KvsError) -> bool {
+    matches!(e, KvsError::Io(e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
+/// Whether a read failed with `kind` because its timeout elapsed, which some
+/// platforms report as `WouldBlock`.
+fn is_timeout_kind(kind: Option<io::ErrorKind>) -> bool {
+    matches!(
+        kind,
+        Some(io::ErrorKind::TimedOut) | Some(io::ErrorKind::WouldBlock)
+    )
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "no response within the timeout")
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed")
+}
+
+/// A client shared by threads that turns concurrent `get`s of a key into one
+/// request.
+///
+/// Requests go through one connection, one at a time. A `get` of a key that is
+/// already being fetched waits for that request and returns its value instead
+/// of sending another, which saves round trips on hot keys. A `get` never
+/// shares a request sent before a `set` or `remove` of the key made through
+/// this client returned, so a thread still reads its own writes.
+pub struct CoalescingClient {
+    client: Mutex<KvsClient>,
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+/// A `get` sent on behalf of every thread waiting for its key.
+#[derive(Default)]
+struct Flight {
+    // Errors are shared by their message, as `KvsError` can't be cloned
+    outcome: Mutex<Option<std::result::Result<Option<String>, String>>>,
+    done: Condvar,
+}
+
+impl CoalescingClient {
+    /// Wraps a connected client.
+    pub fn new(client: KvsClient) -> Self {
+        CoalescingClient {
+            client: Mutex::new(client),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the value of a given key from the server, sharing the request of
+    /// any other thread getting the same key.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let (flight, leader) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(key.clone(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+        if !leader {
+            let outcome = flight.outcome.lock().unwrap();
+            let outcome = flight.done.wait_while(outcome, |o| o.is_none()).unwrap();
+            return outcome.clone().unwrap().map_err(KvsError::StringError);
+        }
+
+        let res = self.client.lock().unwrap().get(key.clone());
+        self.land(&key, &flight);
+        *flight.outcome.lock().unwrap() = Some(match &res {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err(e.to_string()),
+        });
+        flight.done.notify_all();
+        res
+    }
+
+    /// Set the value of a string key in the server
+    ///
+    /// Returns the new version of the key, as `KvsClient::set` does.
+    pub fn set(&self, key: String, value: String) -> Result<u64> {
+        let res = self.client.lock().unwrap().set(key.clone(), value);
+        self.detach(&key);
+        res
+    }
+
+    /// Remove a string key in the server
+    ///
+    /// Returns `false` if the key did not exist.
+    pub fn remove(&self, key: String) -> Result<bool> {
+        let res = self.client.lock().unwrap().remove(key.clone());
+        self.detach(&key);
+        res
+    }
+
+    /// Removes `flight` from the in-flight gets, unless a write replaced it.
+    fn land(&self, key: &str, flight: &Arc<Flight>) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(key).is_some_and(|f| Arc::ptr_eq(f, flight)) {
+            in_flight.remove(key);
+        }
+    }
+
+    /// Makes later gets of `key` send a new request, since a get in flight may
+    /// have been sent before the write.
+    fn detach(&self, key: &str) {
+        self.in_flight.lock().unwrap().remove(key);
+    }
+}
+
+/// A fixed set of connections shared by threads.
+///
+/// A thread checks a connection out for the duration of its requests, and it
+/// goes back to the pool when the `PooledClient` is dropped. Threads wanting a
+/// connection while all are checked out wait for one to come back. Connections
+/// that drop are reconnected by `KvsClient` itself on their next request.
+pub struct KvsClientPool {
+    idle: Mutex<Vec<KvsClient>>,
+    returned: Condvar,
+    size: usize,
+}
+
+impl KvsClientPool {
+    /// Opens `size` connections to `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A, size: usize) -> Result<Self> {
+        if size == 0 {
+            return Err(KvsError::StringError(
+                "a client pool needs at least one connection".to_owned(),
+            ));
+        }
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let idle = (0..size)
+            .map(|_| KvsClient::connect(&addrs[..]))
+            .collect::<Result<_>>()?;
+        Ok(KvsClientPool {
+            idle: Mutex::new(idle),
+            returned: Condvar::new(),
+            size,
+        })
+    }
+
+    /// Returns the number of connections of the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of connections not checked out.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Checks out a connection, waiting as long as it takes for one to be
+    /// checked in.
+    pub fn checkout(&self) -> PooledClient<'_> {
+        let idle = self.idle.lock().unwrap();
+        let mut idle = self
+            .returned
+            .wait_while(idle, |idle| idle.is_empty())
+            .unwrap();
+        self.lend(idle.pop().unwrap())
+    }
+
+    /// Checks out a connection, waiting at most `timeout` for one to be
+    /// checked in.
+    ///
+    /// # Errors
+    ///
+    /// It returns an `io::ErrorKind::TimedOut` error if no connection came back
+    /// in time.
+    pub fn checkout_timeout(&self, timeout: Duration) -> Result<PooledClient<'_>> {
+        let idle = self.idle.lock().unwrap();
+        let (mut idle, _) = self
+            .returned
+            .wait_timeout_while(idle, timeout, |idle| idle.is_empty())
+            .unwrap();
+        match idle.pop() {
+            Some(client) => Ok(self.lend(client)),
+            None => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no connection of the pool was checked in in time",
+            )
+            .into()),
+        }
+    }
+
+    /// Get the value of a given key from the server, on a connection of the
+    /// pool.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.checkout().get(key)
+    }
+
+    /// Set the value of a string key in the server, on a connection of the
+    /// pool.
+    pub fn set(&self, key: String, value: String) -> Result<u64> {
+        self.checkout().set(key, value)
+    }
+
+    /// Remove a string key in the server, on a connection of the pool.
+    pub fn remove(&self, key: String) -> Result<bool> {
+        self.checkout().remove(key)
+    }
+
+    fn lend(&self, client: KvsClient) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+
+    fn checkin(&self, client: KvsClient) {
+        self.idle.lock().unwrap().push(client);
+        self.returned.notify_one();
+    }
+}
+
+/// A connection checked out of a `KvsClientPool`, checked back in on drop.
+pub struct PooledClient<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().expect("checked in on drop")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().expect("checked in on drop")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.checkin(client);
         }
     }
 }
@@ -0,0 +1,502 @@
+//! File operations of `KvStore`, behind a trait so tests can inject faults.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The file operations a `KvStore` needs, opened with `KvStore::open_with_fs`.
+///
+/// `OsFileSystem` is the real one. `MemFileSystem` keeps files in memory and
+/// can fail writes or lose unsynced data, for crash tests.
+pub trait FileSystem: Send + Sync {
+    /// Creates a directory and its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Opens a file for reading and writing, creating it if missing. Existing
+    /// contents are kept.
+    fn open_rw(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Creates an empty file for writing, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Opens a file bypassing the page cache, for `KvStoreOptions::direct_io`.
+    ///
+    /// File systems without direct I/O return an error.
+    fn open_direct(&self, _path: &Path, _write: bool) -> io::Result<Box<dyn FileHandle>> {
+        Err(io::Error::other(
+            "direct I/O is not supported by this file system",
+        ))
+    }
+
+    /// Removes a file.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Renames a file, replacing `to` if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Returns the paths of the files in a directory, in no particular order.
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns whether a file exists.
+    fn exists(&self, path: &Path) -> bool {
+        self.open(path).is_ok()
+    }
+
+    /// Reads the whole contents of a file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Returns the length of a file.
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        self.open(path)?.size()
+    }
+
+    /// Returns the type of the file system holding `path`, such as `"nfs"`.
+    ///
+    /// Only the types that matter to `KvStoreOptions::strict_durability` are
+    /// told apart, others are `None`.
+    fn fs_type(&self, _path: &Path) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Returns the bytes available to unprivileged writes on the file system
+    /// holding `path`, or `None` if it cannot tell.
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// An open file of a `FileSystem`.
+pub trait FileHandle: Read + Write + Seek + Send {
+    /// Flushes the data written to the file to durable storage.
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Truncates or extends the file to `len`.
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Returns the length of the file.
+    fn size(&self) -> io::Result<u64>;
+
+    /// Returns a new handle to the same file, with its own position.
+    fn try_clone(&self) -> io::Result<Box<dyn FileHandle>>;
+}
+
+/// The file system of the operating system.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_rw(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open_direct(&self, path: &Path, write: bool) -> io::Result<Box<dyn FileHandle>> {
+        let file = open_direct(OpenOptions::new().read(true).write(write), path)?;
+        Ok(Box::new(file))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn fs_type(&self, path: &Path) -> io::Result<Option<String>> {
+        fs_type(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        available_space(path)
+    }
+}
+
+impl FileHandle for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(options: &mut OpenOptions, path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_DIRECT).open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_options: &mut OpenOptions, _path: &Path) -> io::Result<File> {
+    Err(io::Error::other("direct I/O is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn fs_type(path: &Path) -> io::Result<Option<String>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `statfs` only writes to the struct, for which zeroes are valid
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Magic numbers from statfs(2)
+    let name = match stat.f_type as u32 {
+        0x6969 => "nfs",
+        0x517b => "smb",
+        0xfe53_4d42 => "smb2",
+        0xff53_4d42 => "cifs",
+        0x0102_1997 => "9p",
+        _ => return Ok(None),
+    };
+    Ok(Some(name.to_owned()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fs_type(_path: &Path) -> io::Result<Option<String>> {
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `statvfs` only writes to the struct, for which zeroes are valid
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+// ========================= MemFileSystem =========================
+
+/// An in-memory `FileSystem` that injects faults, for crash tests.
+///
+/// Each file remembers how much of it was synced. `crash` throws away what was
+/// not, as a power loss would. Writes are taken to append, so the synced part
+/// of a file is a prefix of it. Directory operations such as `rename` and
+/// `remove_file` are durable at once.
+///
+/// Clones share the same files, so a store can be reopened on the crashed file
+/// system.
+#[derive(Clone, Default)]
+pub struct MemFileSystem {
+    state: Arc<Mutex<MemState>>,
+}
+
+#[derive(Default)]
+struct MemState {
+    files: HashMap<PathBuf, Arc<Mutex<MemFile>>>,
+    /// Bytes left to write before writes fail, if limited.
+    write_budget: Option<u64>,
+    fs_type: Option<String>,
+    available_space: Option<u64>,
+}
+
+#[derive(Default)]
+struct MemFile {
+    data: Vec<u8>,
+    synced: usize,
+}
+
+impl MemFileSystem {
+    /// Creates an empty file system.
+    pub fn new() -> Self {
+        MemFileSystem::default()
+    }
+
+    /// Lets only `bytes` more bytes be written, after which writes fail as if
+    /// the disk was full. A write crossing the limit is cut short, leaving a
+    /// partial write behind. `None` lifts the limit.
+    pub fn set_write_budget(&self, bytes: Option<u64>) {
+        self.state.lock().unwrap().write_budget = bytes;
+    }
+
+    /// Sets the type reported by `FileSystem::fs_type`, to pose as another file
+    /// system.
+    pub fn set_fs_type(&self, fs_type: Option<&str>) {
+        self.state.lock().unwrap().fs_type = fs_type.map(str::to_owned);
+    }
+
+    /// Sets the free space reported by `FileSystem::available_space`, which
+    /// does not limit writes, see `set_write_budget`.
+    pub fn set_available_space(&self, bytes: Option<u64>) {
+        self.state.lock().unwrap().available_space = bytes;
+    }
+
+    /// Marks everything written so far as synced, as the kernel writing back
+    /// dirty pages would.
+    pub fn sync_all(&self) {
+        for file in self.state.lock().unwrap().files.values() {
+            let mut file = file.lock().unwrap();
+            file.synced = file.data.len();
+        }
+    }
+
+    /// Simulates a power loss: every file is cut back to its synced part, plus
+    /// up to `keep_unsynced` of the bytes written after it, which leaves torn
+    /// records behind.
+    ///
+    /// Handles opened before the crash keep working on the files, so stores
+    /// opened on this file system have to be dropped first.
+    pub fn crash(&self, keep_unsynced: usize) {
+        for file in self.state.lock().unwrap().files.values() {
+            let mut file = file.lock().unwrap();
+            let len = file.data.len().min(file.synced + keep_unsynced);
+            file.data.truncate(len);
+            file.synced = len;
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Arc<Mutex<MemFile>>> {
+        match self.state.lock().unwrap().files.get(path) {
+            Some(file) => Ok(Arc::clone(file)),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn handle(&self, file: Arc<Mutex<MemFile>>, writable: bool) -> Box<dyn FileHandle> {
+        Box::new(MemHandle {
+            state: Arc::clone(&self.state),
+            file,
+            pos: 0,
+            writable,
+        })
+    }
+}
+
+impl FileSystem for MemFileSystem {
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Ok(self.handle(self.file(path)?, false))
+    }
+
+    fn open_rw(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let file = Arc::clone(
+            self.state
+                .lock()
+                .unwrap()
+                .files
+                .entry(path.to_owned())
+                .or_default(),
+        );
+        Ok(self.handle(file, true))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let file = Arc::new(Mutex::new(MemFile::default()));
+        self.state
+            .lock()
+            .unwrap()
+            .files
+            .insert(path.to_owned(), Arc::clone(&file));
+        Ok(self.handle(file, true))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.state.lock().unwrap().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let file = state
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        state.files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn fs_type(&self, _path: &Path) -> io::Result<Option<String>> {
+        Ok(self.state.lock().unwrap().fs_type.clone())
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(self.state.lock().unwrap().available_space)
+    }
+}
+
+/// An open file of a `MemFileSystem`. Like on Unix, it keeps working after the
+/// file is removed or renamed.
+struct MemHandle {
+    state: Arc<Mutex<MemState>>,
+    file: Arc<Mutex<MemFile>>,
+    pos: u64,
+    writable: bool,
+}
+
+impl Read for MemHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.file.lock().unwrap();
+        let start = (self.pos as usize).min(file.data.len());
+        let n = buf.len().min(file.data.len() - start);
+        buf[..n].copy_from_slice(&file.data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is opened read only",
+            ));
+        }
+        let n = match &mut self.state.lock().unwrap().write_budget {
+            Some(0) => return Err(io::Error::from(io::ErrorKind::StorageFull)),
+            Some(budget) => {
+                let n = buf.len().min(*budget as usize);
+                *budget -= n as u64;
+                n
+            }
+            None => buf.len(),
+        };
+        let mut file = self.file.lock().unwrap();
+        let pos = self.pos as usize;
+        if file.data.len() < pos + n {
+            file.data.resize(pos + n, 0);
+        }
+        file.data[pos..pos + n].copy_from_slice(&buf[..n]);
+        // Rewritten bytes are no longer synced
+        file.synced = file.synced.min(pos);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.lock().unwrap().data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl FileHandle for MemHandle {
+    fn sync_data(&self) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.synced = file.data.len();
+        Ok(())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.data.resize(len as usize, 0);
+        file.synced = file.synced.min(len as usize);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.lock().unwrap().data.len() as u64)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(MemHandle {
+            state: Arc::clone(&self.state),
+            file: Arc::clone(&self.file),
+            pos: self.pos,
+            writable: self.writable,
+        }))
+    }
+}
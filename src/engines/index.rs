@@ -0,0 +1,809 @@
+//! Index backends of `KvStore`.
+
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem::size_of;
+use std::ops::{Bound, Range, RangeBounds};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Data structure used for the index of a `KvStore`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    /// A `HashMap`. Ordered queries such as `KvStore::scan` walk and sort the
+    /// whole index.
+    Hash,
+    /// A `BTreeMap`, keeps keys ordered for efficient range and prefix scans.
+    ///
+    /// The default: point reads and writes are as fast as with `Hash`, since
+    /// the log dominates them.
+    #[default]
+    BTree,
+    /// A prefix trie, compact for keys sharing long prefixes.
+    Trie,
+    /// Keeps at most `max_entries` recently used entries in memory and spills
+    /// the rest to disk, for stores with more keys than fit in RAM.
+    Spill {
+        /// Maximum number of entries kept in memory.
+        max_entries: usize,
+    },
+    /// Hash maps over disjoint sets of keys, picked by hashing the key.
+    ///
+    /// The shards share the lock of the index for now, each one only bounds
+    /// the pause of growing its map. `KvStore::shard_key_counts` shows how
+    /// evenly `hash` spreads the keys.
+    Sharded {
+        /// Number of shards, at least 1.
+        shards: usize,
+        /// Hash picking the shard of a key.
+        hash: ShardHash,
+    },
+}
+
+/// Hash picking the shard of a key in an `IndexKind::Sharded` index.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ShardHash {
+    /// aHash, fast and well mixed even for sequential keys.
+    #[default]
+    AHash,
+    /// SipHash 1-3, the hash of the standard `HashMap`.
+    Sip,
+    /// FNV-1a, cheap for short keys but weaker.
+    Fnv,
+}
+
+impl ShardHash {
+    fn hash(self, key: &str) -> u64 {
+        match self {
+            // Fixed seeds, so that a store shards its keys the same way on
+            // every open
+            ShardHash::AHash => ahash::RandomState::with_seeds(1, 2, 3, 4).hash_one(key),
+            ShardHash::Sip => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                hasher.finish()
+            }
+            ShardHash::Fnv => key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            }),
+        }
+    }
+}
+
+impl IndexKind {
+    /// Builds an empty index. `dir` is the directory of the store.
+    pub(super) fn build(self, dir: &Path) -> Result<Box<dyn Index>> {
+        let inner: Box<dyn Index> = match self {
+            IndexKind::Hash => Box::new(HashMap::new()),
+            IndexKind::BTree => Box::new(BTreeMap::new()),
+            IndexKind::Trie => Box::new(TrieIndex::default()),
+            IndexKind::Spill { max_entries } => Box::new(SpillIndex::open(dir, max_entries)?),
+            IndexKind::Sharded { shards, hash } => Box::new(ShardedIndex::new(shards, hash)),
+        };
+        Ok(Box::new(ExpiringIndex::new(inner)))
+    }
+}
+
+/// Location of a command in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct CommandOffset {
+    pub gen: u64,
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl From<(u64, Range<u64>)> for CommandOffset {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandOffset {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+        }
+    }
+}
+
+/// Maps every live key to the offset of its latest `Set` command.
+///
+/// Unordered backends fall back to sorting in the ordered queries.
+///
+/// Backends keeping entries on disk fail with the errors of their storage.
+pub(super) trait Index: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>>;
+
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>>;
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>>;
+
+    /// Iterates over all entries, ordered by key for ordered backends.
+    fn entries(&self) -> Entries<'_>;
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.entries().map(|entry| Ok(entry?.0)).collect()
+    }
+
+    /// Returns the number of keys.
+    fn len(&self) -> Result<usize> {
+        Ok(self.keys()?.len())
+    }
+
+    /// Returns the entries whose keys fall in `range`, ordered by key.
+    fn range(&self, range: (Bound<&str>, Bound<&str>)) -> Result<Vec<(String, CommandOffset)>> {
+        let mut entries = Vec::new();
+        for entry in self.entries() {
+            let entry = entry?;
+            if RangeBounds::<str>::contains(&range, entry.0.as_str()) {
+                entries.push(entry);
+            }
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        Ok(self.keys()?.into_iter().min())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        Ok(self.keys()?.into_iter().max())
+    }
+
+    /// Estimates the memory taken by the entries: key bytes plus the size of
+    /// an entry, ignoring the allocator and the slack of the backend.
+    fn memory_bytes(&self) -> Result<usize> {
+        let mut bytes = 0;
+        for entry in self.entries() {
+            bytes += entry?.0.len() + size_of::<(String, CommandOffset)>();
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the number of keys in each shard, for sharded backends.
+    fn shard_key_counts(&self) -> Option<Vec<usize>> {
+        None
+    }
+
+    /// Sets when `key` expires, in milliseconds since the Unix epoch, or makes
+    /// it permanent. Only `ExpiringIndex` keeps expirations, other backends
+    /// are wrapped in one.
+    fn set_expiry(&mut self, _key: &str, _expires_at: Option<u64>) {}
+
+    /// Returns the keys that expire, with their expiration times.
+    fn expiries(&self) -> Vec<(String, u64)> {
+        vec![]
+    }
+
+    /// Returns when `key` expires, expired or not, if it is indexed with an
+    /// expiration.
+    fn expiry(&self, _key: &str) -> Option<u64> {
+        None
+    }
+
+    /// Removes the expired entries, returning them.
+    fn purge_expired(&mut self) -> Result<Vec<(String, CommandOffset)>> {
+        Ok(vec![])
+    }
+}
+
+/// Entries of an `Index`, read lazily from backends keeping them on disk.
+pub(super) type Entries<'a> = Box<dyn Iterator<Item = Result<(String, CommandOffset)>> + 'a>;
+
+/// Milliseconds since the Unix epoch.
+pub(super) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// An index whose keys may expire, wrapping the index of another backend.
+///
+/// Expired entries are hidden from every query as soon as they expire, and
+/// stay in the inner index until `purge_expired` removes them.
+pub(super) struct ExpiringIndex {
+    inner: Box<dyn Index>,
+    expiries: HashMap<String, u64>,
+}
+
+impl ExpiringIndex {
+    fn new(inner: Box<dyn Index>) -> Self {
+        ExpiringIndex {
+            inner,
+            expiries: HashMap::new(),
+        }
+    }
+
+    fn live(&self, key: &str, now: u64) -> bool {
+        self.expiries.get(key).is_none_or(|&at| at > now)
+    }
+
+    /// Drops the expired entries from `entries`.
+    fn retain_live(
+        &self,
+        mut entries: Vec<(String, CommandOffset)>,
+    ) -> Vec<(String, CommandOffset)> {
+        if !self.expiries.is_empty() {
+            let now = now_millis();
+            entries.retain(|(key, _)| self.live(key, now));
+        }
+        entries
+    }
+}
+
+impl Index for ExpiringIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>> {
+        if self.expiries.is_empty() || self.live(key, now_millis()) {
+            self.inner.get(key)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Keeps the expiration of `key`, a record moved by compaction expires
+    /// like the original.
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>> {
+        self.inner.insert(key, offset)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>> {
+        self.expiries.remove(key);
+        self.inner.remove(key)
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        if self.expiries.is_empty() {
+            return self.inner.entries();
+        }
+        let now = now_millis();
+        Box::new(self.inner.entries().filter(move |entry| match entry {
+            Ok((key, _)) => self.live(key, now),
+            Err(_) => true,
+        }))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        if self.expiries.is_empty() {
+            return self.inner.keys();
+        }
+        self.entries().map(|entry| Ok(entry?.0)).collect()
+    }
+
+    fn len(&self) -> Result<usize> {
+        if self.expiries.is_empty() {
+            return self.inner.len();
+        }
+        Ok(self.keys()?.len())
+    }
+
+    fn range(&self, range: (Bound<&str>, Bound<&str>)) -> Result<Vec<(String, CommandOffset)>> {
+        Ok(self.retain_live(self.inner.range(range)?))
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        if self.expiries.is_empty() {
+            return self.inner.first_key();
+        }
+        Ok(self.keys()?.into_iter().min())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        if self.expiries.is_empty() {
+            return self.inner.last_key();
+        }
+        Ok(self.keys()?.into_iter().max())
+    }
+
+    fn memory_bytes(&self) -> Result<usize> {
+        let expiries = self
+            .expiries
+            .keys()
+            .map(|key| key.len() + size_of::<(String, u64)>())
+            .sum::<usize>();
+        Ok(self.inner.memory_bytes()? + expiries)
+    }
+
+    fn shard_key_counts(&self) -> Option<Vec<usize>> {
+        self.inner.shard_key_counts()
+    }
+
+    fn set_expiry(&mut self, key: &str, expires_at: Option<u64>) {
+        match expires_at {
+            Some(at) => {
+                self.expiries.insert(key.to_owned(), at);
+            }
+            None if self.expiries.is_empty() => {}
+            None => {
+                self.expiries.remove(key);
+            }
+        }
+    }
+
+    fn expiries(&self) -> Vec<(String, u64)> {
+        let now = now_millis();
+        self.expiries
+            .iter()
+            .filter(|(_, &at)| at > now)
+            .map(|(key, &at)| (key.clone(), at))
+            .collect()
+    }
+
+    fn expiry(&self, key: &str) -> Option<u64> {
+        self.expiries.get(key).copied()
+    }
+
+    fn purge_expired(&mut self) -> Result<Vec<(String, CommandOffset)>> {
+        let now = now_millis();
+        let expired = self
+            .expiries
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let mut purged = Vec::with_capacity(expired.len());
+        for key in expired {
+            self.expiries.remove(&key);
+            if let Some(offset) = self.inner.remove(&key)? {
+                purged.push((key, offset));
+            }
+        }
+        Ok(purged)
+    }
+}
+
+impl Index for HashMap<String, CommandOffset> {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>> {
+        Ok(HashMap::get(self, key).copied())
+    }
+
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>> {
+        Ok(HashMap::insert(self, key, offset))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>> {
+        Ok(HashMap::remove(self, key))
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.iter().map(|(key, offset)| Ok((key.clone(), *offset))))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(HashMap::keys(self).cloned().collect())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(HashMap::len(self))
+    }
+}
+
+impl Index for BTreeMap<String, CommandOffset> {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>> {
+        Ok(BTreeMap::get(self, key).copied())
+    }
+
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>> {
+        Ok(BTreeMap::insert(self, key, offset))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>> {
+        Ok(BTreeMap::remove(self, key))
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.iter().map(|(key, offset)| Ok((key.clone(), *offset))))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(BTreeMap::keys(self).cloned().collect())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(BTreeMap::len(self))
+    }
+
+    fn range(&self, range: (Bound<&str>, Bound<&str>)) -> Result<Vec<(String, CommandOffset)>> {
+        Ok(BTreeMap::range::<str, _>(self, range)
+            .map(|(key, offset)| (key.clone(), *offset))
+            .collect())
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        Ok(BTreeMap::keys(self).next().cloned())
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        Ok(BTreeMap::keys(self).next_back().cloned())
+    }
+}
+
+/// A prefix trie over the characters of the keys.
+///
+/// Children are kept in a `BTreeMap`, so a depth-first walk yields keys in
+/// order.
+#[derive(Default)]
+pub(super) struct TrieIndex {
+    root: TrieNode,
+    len: usize,
+}
+
+#[derive(Default)]
+struct TrieNode {
+    offset: Option<CommandOffset>,
+    children: BTreeMap<char, TrieNode>,
+}
+
+impl TrieNode {
+    fn is_empty(&self) -> bool {
+        self.offset.is_none() && self.children.is_empty()
+    }
+
+    /// Counts this node and the nodes below it.
+    fn nodes(&self) -> usize {
+        1 + self.children.values().map(TrieNode::nodes).sum::<usize>()
+    }
+
+    fn collect(&self, prefix: &mut String, entries: &mut Vec<(String, CommandOffset)>) {
+        if let Some(offset) = self.offset {
+            entries.push((prefix.clone(), offset));
+        }
+        for (c, child) in self.children.iter() {
+            prefix.push(*c);
+            child.collect(prefix, entries);
+            prefix.pop();
+        }
+    }
+
+    /// Removes the offset at the end of `chars`, pruning emptied nodes.
+    fn remove(&mut self, mut chars: std::str::Chars) -> Option<CommandOffset> {
+        match chars.next() {
+            None => self.offset.take(),
+            Some(c) => {
+                let child = self.children.get_mut(&c)?;
+                let offset = child.remove(chars);
+                if child.is_empty() {
+                    self.children.remove(&c);
+                }
+                offset
+            }
+        }
+    }
+}
+
+impl TrieIndex {
+    fn to_vec(&self) -> Vec<(String, CommandOffset)> {
+        let mut entries = Vec::with_capacity(self.len);
+        self.root.collect(&mut String::new(), &mut entries);
+        entries
+    }
+}
+
+impl Index for TrieIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>> {
+        let mut node = &self.root;
+        for c in key.chars() {
+            match node.children.get(&c) {
+                Some(child) => node = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(node.offset)
+    }
+
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>> {
+        let mut node = &mut self.root;
+        for c in key.chars() {
+            node = node.children.entry(c).or_default();
+        }
+        let old = node.offset.replace(offset);
+        if old.is_none() {
+            self.len += 1;
+        }
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>> {
+        let old = self.root.remove(key.chars());
+        if old.is_some() {
+            self.len -= 1;
+        }
+        Ok(old)
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.to_vec().into_iter().map(Ok))
+    }
+
+    fn range(&self, range: (Bound<&str>, Bound<&str>)) -> Result<Vec<(String, CommandOffset)>> {
+        Ok(self
+            .to_vec()
+            .into_iter()
+            .filter(|(key, _)| RangeBounds::<str>::contains(&range, key.as_str()))
+            .collect())
+    }
+
+    fn first_key(&self) -> Result<Option<String>> {
+        let mut key = String::new();
+        let mut node = &self.root;
+        while node.offset.is_none() {
+            match node.children.iter().next() {
+                Some((c, child)) => {
+                    key.push(*c);
+                    node = child;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(key))
+    }
+
+    fn last_key(&self) -> Result<Option<String>> {
+        let mut key = String::new();
+        let mut node = &self.root;
+        while let Some((c, child)) = node.children.iter().next_back() {
+            key.push(*c);
+            node = child;
+        }
+        Ok(node.offset.map(|_| key))
+    }
+
+    /// Keys are not stored, each character below the root takes a node.
+    fn memory_bytes(&self) -> Result<usize> {
+        Ok((self.root.nodes() - 1) * size_of::<(char, TrieNode)>())
+    }
+}
+
+/// An index split into hash maps by the hash of the key.
+pub(super) struct ShardedIndex {
+    shards: Vec<HashMap<String, CommandOffset>>,
+    hash: ShardHash,
+}
+
+impl ShardedIndex {
+    fn new(shards: usize, hash: ShardHash) -> Self {
+        ShardedIndex {
+            shards: vec![HashMap::new(); shards.max(1)],
+            hash,
+        }
+    }
+
+    fn shard(&self, key: &str) -> usize {
+        (self.hash.hash(key) % self.shards.len() as u64) as usize
+    }
+}
+
+impl Index for ShardedIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>> {
+        Ok(self.shards[self.shard(key)].get(key).copied())
+    }
+
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>> {
+        let shard = self.shard(&key);
+        Ok(self.shards[shard].insert(key, offset))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>> {
+        let shard = self.shard(key);
+        Ok(self.shards[shard].remove(key))
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.shards.iter().flat_map(Index::entries))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self
+            .shards
+            .iter()
+            .flat_map(HashMap::keys)
+            .cloned()
+            .collect())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.shards.iter().map(HashMap::len).sum())
+    }
+
+    fn shard_key_counts(&self) -> Option<Vec<usize>> {
+        Some(self.shards.iter().map(HashMap::len).collect())
+    }
+}
+
+/// An index holding its least recently used entries on disk.
+///
+/// Up to `capacity` entries live in memory. Older entries are moved to a sled
+/// tree and moved back when they are accessed. The index is rebuilt from the log
+/// on open, so the tree is temporary and dropped with the index.
+pub(super) struct SpillIndex {
+    capacity: usize,
+    hot: Mutex<LruEntries>,
+    cold: sled::Db,
+}
+
+/// In-memory entries with their last access tick.
+pub(super) struct LruEntries<V = CommandOffset> {
+    tick: u64,
+    entries: HashMap<String, (V, u64)>,
+    by_tick: BTreeMap<u64, String>,
+}
+
+impl<V> Default for LruEntries<V> {
+    fn default() -> Self {
+        LruEntries {
+            tick: 0,
+            entries: HashMap::new(),
+            by_tick: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V: Clone> LruEntries<V> {
+    pub(super) fn get(&mut self, key: &str) -> Option<V> {
+        let (value, tick) = self.entries.get_mut(key)?;
+        self.by_tick.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.by_tick.insert(self.tick, key.to_owned());
+        Some(value.clone())
+    }
+
+    pub(super) fn insert(&mut self, key: String, value: V) -> Option<V> {
+        self.tick += 1;
+        self.by_tick.insert(self.tick, key.clone());
+        let (old, tick) = self.entries.insert(key, (value, self.tick))?;
+        self.by_tick.remove(&tick);
+        Some(old)
+    }
+
+    pub(super) fn remove(&mut self, key: &str) -> Option<V> {
+        let (value, tick) = self.entries.remove(key)?;
+        self.by_tick.remove(&tick);
+        Some(value)
+    }
+
+    pub(super) fn least_recent(&self) -> Option<(String, V)> {
+        let key = self.by_tick.values().next()?;
+        let (value, _) = self.entries.get(key)?;
+        Some((key.clone(), value.clone()))
+    }
+
+    pub(super) fn pop_least_recent(&mut self) -> Option<(String, V)> {
+        let tick = *self.by_tick.keys().next()?;
+        let key = self.by_tick.remove(&tick)?;
+        let (value, _) = self.entries.remove(&key)?;
+        Some((key, value))
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl SpillIndex {
+    fn open(dir: &Path, capacity: usize) -> Result<Self> {
+        let cold = sled::Config::new()
+            .path(dir.join(format!("{}.spill", Uuid::new_v4())))
+            .temporary(true)
+            .open()?;
+        Ok(SpillIndex {
+            capacity,
+            hot: Mutex::new(LruEntries::default()),
+            cold,
+        })
+    }
+}
+
+/// Moves the least recently used entries to disk until `hot` fits `capacity`.
+fn spill(hot: &mut LruEntries, cold: &sled::Db, capacity: usize) -> Result<()> {
+    while hot.entries.len() > capacity {
+        // Only dropped from memory once on disk, so a failed insert loses nothing
+        let (key, offset) = hot.least_recent().unwrap();
+        cold.insert(key, &encode(offset)[..])?;
+        hot.pop_least_recent();
+    }
+    Ok(())
+}
+
+/// Removes the spilled entries left behind by a crashed store in `dir`.
+///
+/// The index is rebuilt from the log on open, so they are stale.
+pub(super) fn remove_stale_spills(dir: &Path) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        // A store on another `FileSystem` has nothing on disk
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        entries => entries?,
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() && path.extension() == Some("spill".as_ref()) {
+            fs::remove_dir_all(path)?;
+        }
+    }
+    Ok(())
+}
+
+impl Index for SpillIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandOffset>> {
+        let mut hot = self.hot.lock().unwrap();
+        if let Some(offset) = hot.get(key) {
+            return Ok(Some(offset));
+        }
+        let offset = match self.cold.remove(key)? {
+            Some(offset) => decode(&offset),
+            None => return Ok(None),
+        };
+        hot.insert(key.to_owned(), offset);
+        spill(&mut hot, &self.cold, self.capacity)?;
+        Ok(Some(offset))
+    }
+
+    fn insert(&mut self, key: String, offset: CommandOffset) -> Result<Option<CommandOffset>> {
+        let hot = self.hot.get_mut().unwrap();
+        let old = match hot.insert(key.clone(), offset) {
+            Some(old) => Some(old),
+            None => self.cold.remove(&key)?.map(|old| decode(&old)),
+        };
+        spill(hot, &self.cold, self.capacity)?;
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandOffset>> {
+        match self.hot.get_mut().unwrap().remove(key) {
+            Some(old) => Ok(Some(old)),
+            None => Ok(self.cold.remove(key)?.map(|old| decode(&old))),
+        }
+    }
+
+    /// The entries held in memory are copied, the spilled ones are read as the
+    /// iteration reaches them.
+    fn entries(&self) -> Entries<'_> {
+        let hot = self
+            .hot
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|(key, (offset, _))| Ok((key.clone(), *offset)))
+            .collect::<Vec<_>>();
+        let cold = self.cold.iter().map(|entry| {
+            let (key, offset) = entry?;
+            Ok((String::from_utf8(key.to_vec())?, decode(&offset)))
+        });
+        Box::new(hot.into_iter().chain(cold))
+    }
+
+    /// Only the entries held in memory count, each key is also kept by the
+    /// access order.
+    fn memory_bytes(&self) -> Result<usize> {
+        let hot = self.hot.lock().unwrap();
+        let entry = size_of::<(String, (CommandOffset, u64))>() + size_of::<(u64, String)>();
+        Ok(hot.entries.keys().map(|key| 2 * key.len() + entry).sum())
+    }
+}
+
+fn encode(offset: CommandOffset) -> [u8; 24] {
+    let mut bytes = [0; 24];
+    bytes[..8].copy_from_slice(&offset.gen.to_be_bytes());
+    bytes[8..16].copy_from_slice(&offset.pos.to_be_bytes());
+    bytes[16..].copy_from_slice(&offset.len.to_be_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> CommandOffset {
+    let word = |i: usize| u64::from_be_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+    CommandOffset {
+        gen: word(0),
+        pos: word(1),
+        len: word(2),
+    }
+}
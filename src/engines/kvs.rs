@@ -1,29 +1,72 @@
+use super::file_system::{FileHandle, FileSystem, OsFileSystem};
+use super::index::{now_millis, remove_stale_spills, CommandOffset, Index, IndexKind, LruEntries};
+use super::{EngineOp, Metrics, ReadSource};
 use crate::error::{KvsError, Result};
-use crate::KvsEngine;
+use crate::protocol::WatchEvent;
+use crate::{KvsEngine, KvsReader, Op, OpResult, Transform};
+use bincode::Options;
+#[cfg(feature = "compression")]
+use flate2::read::DeflateDecoder;
+#[cfg(feature = "compression")]
+use flate2::write::DeflateEncoder;
+#[cfg(feature = "compression")]
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
-use std::io;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Take};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // ========================= KvStore =========================
 const COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
+/// Share of the log taken by stale records at which it is compacted.
+const COMPACTION_RATIO: f64 = 0.5;
+/// Longest chain of deltas before a `set` writes the full value again.
+const MAX_DELTA_DEPTH: u32 = 16;
+/// Smallest value `KvStoreOptions::compress_values` deflates, shorter ones
+/// hardly shrink.
+const MIN_COMPRESSED_VALUE: usize = 128;
+/// Default prefix of the keys reserved for internal bookkeeping.
+const RESERVED_PREFIX: &str = "__kvs:";
+/// Number of entries `KvStore::import` writes under the writer lock at once.
+const IMPORT_BATCH: usize = 1000;
+
+/// One line written by `KvStore::export`. A binary value is written as
+/// `bytes` instead of `value`.
+#[derive(Serialize, Deserialize)]
+struct ExportEntry {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bytes: Option<Vec<u8>>,
+}
 
 /// Used to store a string key to a string value.
 ///
 /// # Example
 ///
 /// ```
-/// # use kvs::KvStore;
-/// # use kvs::KvsEngine;
-/// # use std::env::current_dir;
-/// let mut kvs = KvStore::open(current_dir().unwrap()).unwrap();
+/// # use unifier::KvStore;
+/// # use unifier::{KvsEngine, KvsReader};
+/// # use tempfile::TempDir;
+/// # let temp_dir = TempDir::new().unwrap();
+/// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
 ///
 /// kvs.set("key".to_string(), "value".to_string());
 ///
@@ -34,443 +77,5579 @@ const COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
 /// let val = kvs.get("key".to_string()).unwrap();
 /// assert_eq!(val, None);
 /// ```
+///
+/// Clones share the index and the writer, so a write is visible to reads
+/// through every clone once the call making it returns, see `barrier`.
 pub struct KvStore {
     path: Arc<PathBuf>,
+    fs: Arc<dyn FileSystem>,
     writer: Arc<Mutex<KvStoreWriter>>,
     reader: KvStoreReader,
-    index: Arc<RwLock<HashMap<String, CommandOffset>>>,
+    index: Arc<RwLock<Box<dyn Index>>>,
+    unflushed: Arc<AtomicBool>,
+    options: Arc<KvStoreOptions>,
+    recovery: Arc<RecoveryReport>,
+    /// Signaled, with the writer lock, when a background compaction ends.
+    compaction_done: Arc<Condvar>,
 }
 
 impl KvStore {
     /// Open the KvStore at a given path.
     /// Return the KvStore.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_with_fs(path, options, Arc::new(OsFileSystem))
+    }
+
+    /// Open the KvStore at a given path, doing its file operations through `fs`.
+    ///
+    /// This is meant for tests injecting faults with a `MemFileSystem`. The
+    /// entries spilled by `IndexKind::Spill` still go to the real file system.
+    pub fn open_with_fs(
+        path: impl Into<PathBuf>,
+        mut options: KvStoreOptions,
+        fs: Arc<dyn FileSystem>,
+    ) -> Result<KvStore> {
+        if (options.compress_values || options.compress_cold_generations)
+            && !cfg!(feature = "compression")
+        {
+            return Err(KvsError::CompressionDisabled);
+        }
         let path = path.into();
         let path = path.join("kvs.db");
-        fs::create_dir_all(&path)?;
+        fs.create_dir_all(&path)?;
+        check_durability(&*fs, &path, options.sync_policy, options.strict_durability)?;
+        remove_stale_spills(&path)?;
+        migrate_extensions(&*fs, &path)?;
+        finish_clear(&*fs, &path)?;
+        check_generations(&*fs, &path, options.repair_generations)?;
+        let mut recovery = RecoveryReport::default();
+        match options.recovery {
+            RecoveryMode::Fail => {}
+            RecoveryMode::Quarantine => {
+                recovery.quarantined =
+                    quarantine_corrupt(&*fs, &path, read_encoding(&*fs, &path)?)?;
+            }
+            RecoveryMode::Resync => {
+                resync_corrupt(&*fs, &path, read_encoding(&*fs, &path)?, &mut recovery)?;
+            }
+        }
+        // An existing log keeps its encoding
+        options.log_encoding = check_format(&*fs, &path, &options)?;
+        remove_stale_compressions(&*fs, &path)?;
 
         let path = Arc::new(path);
-        let index = Arc::new(RwLock::new(HashMap::new()));
-        let reader = KvStoreReader::new(Arc::clone(&path), Arc::clone(&index));
+        let index = Arc::new(RwLock::new(options.index.build(&path)?));
+        let cache = Arc::new(ValueCache::new(options.value_cache));
+        let reader = KvStoreReader::new(
+            Arc::clone(&path),
+            Arc::clone(&fs),
+            Arc::clone(&index),
+            cache,
+            options.direct_io,
+            options.log_encoding,
+        );
 
-        let gens = generations(&path)?;
-        for gen in gens.iter() {
+        let gens = generations(&*fs, &path)?;
+        // The generations covered by the snapshot are not replayed
+        let (covered, mut last_version) = match load_snapshot(&*fs, &path, &gens, &options) {
+            Some(snapshot) => {
+                let mut index = index.write().unwrap();
+                for (key, offset) in snapshot.entries {
+                    index.insert(key, offset)?;
+                }
+                for (key, expires_at) in snapshot.expiries {
+                    index.set_expiry(&key, Some(expires_at));
+                }
+                (snapshot.generations.len(), snapshot.last_version)
+            }
+            None => (0, 0),
+        };
+        for (i, gen) in gens.iter().enumerate() {
             let path = db_path(&path, *gen);
-            let mut new_reader = BufReader::new(File::open(path)?);
+            let mut new_reader = BufReader::new(open_log(&*fs, &path)?);
 
-            load_index(*gen, &mut new_reader, &mut index.write().unwrap())?;
+            if i >= covered {
+                let (end, gen_version) = load_index(
+                    *gen,
+                    &mut new_reader,
+                    &mut **index.write().unwrap(),
+                    &options,
+                )?;
+                last_version = last_version.max(gen_version);
+                let len = new_reader.get_ref().size()?;
+                if end < len {
+                    warn!(
+                        "Truncating {} bytes of torn record at the end of {}",
+                        len - end,
+                        path.display()
+                    );
+                    fs.open_rw(&path)?.set_len(end)?;
+                }
+            }
             reader.add_reader(gen, new_reader);
         }
 
         let current_gen = gens.last().unwrap_or(&0) + 1;
-        let (new_writer, new_reader) = new_db_log(&db_path(&path, current_gen))?;
+        let (new_writer, new_reader) =
+            new_db_log(&*fs, &db_path(&path, current_gen), options.direct_io)?;
         reader.add_reader(&current_gen, new_reader);
 
-        let writer = KvStoreWriter::new(
+        let unflushed = Arc::new(AtomicBool::new(false));
+        let mut writer = KvStoreWriter::new(
             Arc::clone(&path),
             new_writer,
             reader.clone(),
             Arc::clone(&index),
+            options.clone(),
+            Arc::clone(&unflushed),
             current_gen,
         )?;
+        // Compaction may have removed the records of the highest versions
+        writer.last_version = last_version.max(read_version_mark(&*fs, &path)?);
         let writer = Arc::new(Mutex::new(writer));
+        if options.compress_cold_generations && !options.direct_io {
+            let compress = spawn_compressor(Arc::downgrade(&writer), Arc::clone(&fs), &path);
+            writer.lock().unwrap().compress = Some(compress);
+        }
+        if let Some(file) = &options.stats_file {
+            let stats_writer =
+                spawn_stats_writer(Arc::downgrade(&writer), Arc::clone(&fs), file.clone());
+            writer.lock().unwrap().stats_writer = Some(stats_writer);
+        }
+        if let Some(check) = &options.disk_space_check {
+            let mut writer = writer.lock().unwrap();
+            check_disk_space(&*fs, &path, check, &writer.low_space);
+            writer.space_checker = Some(spawn_space_checker(
+                Arc::clone(&fs),
+                &path,
+                check.clone(),
+                Arc::clone(&writer.low_space),
+            ));
+        }
+        if let SyncPolicy::Interval(interval) = options.sync_policy {
+            let syncer = spawn_syncer(Arc::downgrade(&writer), interval);
+            writer.lock().unwrap().syncer = Some(syncer);
+        }
+        let compaction_done = Arc::new(Condvar::new());
+        if options.compaction == CompactionPolicy::Background {
+            let compactor = spawn_compactor(Arc::downgrade(&writer), Arc::clone(&compaction_done));
+            writer.lock().unwrap().compactor = Some(compactor);
+        }
 
         Ok(KvStore {
             path: Arc::clone(&path),
+            fs,
             writer,
             reader,
             index,
+            unflushed,
+            options: Arc::new(options),
+            recovery: Arc::new(recovery),
+            compaction_done,
         })
     }
 
-    /// Compacting the Error file.
-    /// To support concurrent, use generation to maintain the Error files.
-    pub fn compact(&self) -> Result<()> {
-        self.writer.lock().unwrap().compact()
+    /// Returns what recovering the log did when the store was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
     }
-}
 
-impl Clone for KvStore {
-    fn clone(&self) -> Self {
-        KvStore {
-            path: Arc::clone(&self.path),
-            writer: Arc::clone(&self.writer),
-            reader: self.reader.clone(),
-            index: Arc::clone(&self.index),
-        }
+    /// Sets the value of a string key to a string without flushing the log.
+    ///
+    /// This is much faster than `set` for bulk loads. The record is persisted by a
+    /// later `set`, `remove`, `flush`, or when the last handle to the store is
+    /// dropped. Reads of a deferred key flush the log first, so they always see
+    /// the new value.
+    pub fn set_deferred(&self, key: String, value: String) -> Result<()> {
+        self.measured(
+            EngineOp::Set,
+            || {
+                self.options.check_key(&key)?;
+                self.writer.lock().unwrap().set_deferred(key, value)
+            },
+            |metrics, _, latency| metrics.on_set(latency),
+        )
     }
-}
 
-impl KvsEngine for KvStore {
-    /// Sets the value of a string key to a string.
-    /// Return an error if the value is not written successfully.
+    /// Sets the value of a string key to bytes that may not be UTF-8, such as a
+    /// serialized message.
     ///
-    /// # Example
+    /// A UTF-8 value is written exactly like `set` writes it. Other values are
+    /// written as `SetBytes` records, which JSON logs hold as arrays of numbers
+    /// several times the size of the value, so prefer `LogEncoding::Binary` for
+    /// stores of blobs. Versions of the store from before this method fail to
+    /// open a log holding such a record.
     ///
-    /// ```
-    /// # use kvs::KvStore;
-    /// # use kvs::KvsEngine;
-    /// # use std::env::current_dir;
-    /// let mut kvs = KvStore::open(current_dir().unwrap()).unwrap();
-    /// kvs.set("key".to_string(), "value".to_string());
-    /// ```
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    /// String methods such as `get` and `scan` fail with `KvsError::Utf8` on a
+    /// binary value, read it with `get_bytes`. Keys are still strings.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.options.check_key(&key)?;
+        self.writer
+            .lock()
+            .unwrap()
+            .set_bytes(key, value)
+            .map(|_| ())
     }
 
-    /// Gets the string value of the a string key.
-    /// If the key does not exist, return None.
-    /// Return an error if the value is not read successfully.
+    /// Gets the value of a string key as bytes, whether it was set by `set` or
+    /// by `set_bytes`. Values are read from the log, never from the value cache.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        loop {
+            {
+                let index = self.index.read().unwrap();
+                match index.get(&key)? {
+                    Some(offset) if !self.unflushed.load(Ordering::SeqCst) => {
+                        return self.reader.read_bytes(&offset).map(Some);
+                    }
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+            self.flush_deferred()?;
+        }
+    }
+
+    /// Makes every write made through this handle before the call visible to
+    /// reads through any clone of the store, and returns once it is.
     ///
-    /// # Example
+    /// The store already guarantees it without a barrier: `set`, `remove` and
+    /// the other writes point the shared index at their record before they
+    /// return, so a read that starts afterwards, through any clone, finds it.
+    /// Records written by `set_deferred` are indexed too, and reads flush them
+    /// before reading. The barrier flushes them up front, for readers of the
+    /// log files outside the store, such as tools copying them. It does not
+    /// sync, see `KvsEngine::flush` for durability.
+    pub fn barrier(&self) -> Result<()> {
+        self.flush_deferred()
+    }
+
+    /// Sets the value of a string key that expires after `ttl`.
     ///
-    /// ```
-    /// # use kvs::KvStore;
-    /// # use kvs::KvsEngine;
-    /// # use std::env::current_dir;
-    /// let mut kvs = KvStore::open(current_dir().unwrap()).unwrap();
-    /// let value = kvs.get("non-exist-key".to_string()).unwrap();
+    /// Once expired, the key reads as absent and is left out of `keys`, `scan`
+    /// and the other queries. Its record is dropped by the next compaction. A
+    /// later `set` of the key makes it permanent again.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.options.check_key(&key)?;
+        let ttl = ttl.as_millis().min(u128::from(u64::MAX)) as u64;
+        self.writer
+            .lock()
+            .unwrap()
+            .set_expiring(key, value, now_millis().saturating_add(ttl))
+            .map(|_| ())
+    }
+
+    /// Removes many keys at once, with one flush of the log.
     ///
-    /// assert_eq!(value, None);
-    /// ```
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(offset) = self.index.read().unwrap().get(&key) {
-            let command = self.reader.read_command(offset)?;
-            if let Command::Set { key: _, value } = command {
-                Ok(Some(value))
-            } else {
-                unreachable!()
-            }
-        } else {
-            Ok(None)
+    /// Keys that do not exist are skipped. Returns the number of keys removed.
+    /// If writing the tombstones fails, none of them is kept and no key is
+    /// removed.
+    pub fn remove_many(&self, keys: Vec<String>) -> Result<usize> {
+        for key in &keys {
+            self.options.check_key(key)?;
         }
+        self.writer.lock().unwrap().remove_many(keys)
     }
 
-    /// Removes a given key.
-    /// Return an error if the key does not exist or is not removed successfully.
+    /// Sets the value of a key unless it already holds `value`, returning
+    /// whether a record was written.
     ///
-    /// # Example
+    /// The comparison and the write happen under the writer lock. Skipping
+    /// unchanged values keeps periodic full-state syncs from growing the log.
+    /// A key with an expiration is always written, since the `set` makes it
+    /// permanent.
     ///
-    /// ```
-    /// # use kvs::KvStore;
-    /// # use kvs::KvsEngine;
-    /// # use std::env::current_dir;
-    /// let mut kvs = KvStore::open(current_dir().unwrap()).unwrap();
-    /// kvs.set("key".to_string(), "value".to_string());
-    /// kvs.remove("key".to_string());
+    /// # Errors
     ///
-    /// let value = kvs.get("key".to_string()).unwrap();
-    /// assert_eq!(value, None);
-    /// ```
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    /// It returns `KvsError::AppendOnly` for an append-only store.
+    pub fn set_if_changed(&self, key: String, value: String) -> Result<bool> {
+        self.options.check_key(&key)?;
+        self.writer.lock().unwrap().set_if_changed(key, value)
     }
-}
-
-// ========================= KvStoreReader =========================
-
-/// A single thread key value reader.
-///
-/// Each thread own its reader for concurrently reading.
-/// And `RefCell` provide inner mutability and `RwLock` for more reading operations than writing.
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    readers: RefCell<HashMap<u64, BufReader<File>>>,
-    index: Arc<RwLock<HashMap<String, CommandOffset>>>,
-}
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> Self {
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            readers: RefCell::new(HashMap::new()),
-            index: Arc::clone(&self.index),
-        }
+    /// Atomically adds `delta` to the integer value of a key, returning the new
+    /// value. A missing key counts as 0.
+    ///
+    /// The sum is written like a `set`, under the writer lock, so concurrent
+    /// increments are never lost.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the current value does not parse
+    /// as an `i64`, and fails without writing if the sum overflows.
+    pub fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        self.options.check_key(&key)?;
+        self.writer.lock().unwrap().increment(key, delta)
     }
-}
 
-impl KvStoreReader {
-    fn new(path: Arc<PathBuf>, index: Arc<RwLock<HashMap<String, CommandOffset>>>) -> Self {
-        let readers = RefCell::new(HashMap::new());
-        KvStoreReader {
-            path: Arc::clone(&path),
-            readers,
-            index,
+    /// Returns the value of a key, or sets it to the value returned by `f` if
+    /// the key is absent and returns that.
+    ///
+    /// `f` is only called when the key is absent, and runs under the writer
+    /// lock, so concurrent callers never set the key twice. It blocks the
+    /// other writes meanwhile, so it should be quick. A present key is read
+    /// like `get`, without taking the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AppendOnly` for an append-only store.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(&self, key: String, f: F) -> Result<String> {
+        self.options.check_key(&key)?;
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
         }
+        self.writer.lock().unwrap().get_or_insert_with(key, f)
     }
 
-    fn read<F, R>(&self, gen: &u64, func: F) -> Result<R>
-    where
-        F: FnOnce(&mut BufReader<File>) -> Result<R> + Send,
-    {
-        let mut readers = self.readers.borrow_mut();
+    /// Returns the next id of a counter, starting from 1. Each namespace has its
+    /// own counter.
+    ///
+    /// The counter is stored under a reserved key, and the log is synced to disk
+    /// before the id is returned, so an id is never handed out twice, even after
+    /// a crash.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if `KvStoreOptions::reserved_prefix` is
+    /// empty, since the counter would share its key with user data.
+    pub fn next_id(&self, namespace: String) -> Result<u64> {
+        self.writer.lock().unwrap().next_id(namespace)
+    }
 
-        if !readers.contains_key(gen) {
-            let path = db_path(&self.path, *gen);
-            let reader = BufReader::new(File::open(path)?);
-            readers.insert(*gen, reader);
-        }
+    /// Flushes records written by `set_deferred` to the log, without syncing
+    /// it.
+    fn flush_deferred(&self) -> Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
 
-        let reader = readers.get_mut(gen).unwrap();
-        func(reader)
+    /// Compacting the log files.
+    /// To support concurrent, use generation to maintain the log files.
+    ///
+    /// Returns the number of bytes reclaimed on disk. It does nothing for an
+    /// append-only store, whose records are all live.
+    ///
+    /// The old generation files are removed once the index points past them.
+    /// No read can be using them by then, since reads find their offset and
+    /// read the record under the read lock of the index, which compaction
+    /// takes to update it. Clones drop their handles to removed files at their
+    /// next read. Until then the file system keeps the file: on Unix its space
+    /// is freed when the last handle closes, so an idle clone holds it until
+    /// it reads again or is dropped. On Windows files are opened with
+    /// `FILE_SHARE_DELETE`, so removing one that is open succeeds too, and
+    /// its name goes away with the last handle.
+    pub fn compact(&self) -> Result<u64> {
+        Ok(self.compact_full()?.reclaimed())
     }
 
-    fn add_reader(&self, gen: &u64, reader: BufReader<File>) {
-        self.readers.borrow_mut().insert(*gen, reader);
+    /// Compacts the log like `compact`, reporting what each removed generation
+    /// carried forward and what was reclaimed from it, and the size of the log
+    /// before and after. `KvStore::stats` adds up the reports.
+    ///
+    /// It first waits for a background compaction in progress to end.
+    pub fn compact_full(&self) -> Result<CompactionReport> {
+        self.lock_idle_writer().compact()
     }
 
-    fn remove_reader(&self, gen: &u64) {
-        self.readers.borrow_mut().remove(gen);
+    /// Runs `op`, reporting its latency to `on_ok` or its error to the
+    /// `Metrics` of the options, if any.
+    fn measured<T>(
+        &self,
+        op: EngineOp,
+        f: impl FnOnce() -> Result<T>,
+        on_ok: impl FnOnce(&dyn Metrics, &T, Duration),
+    ) -> Result<T> {
+        let metrics = match &self.options.metrics {
+            Some(metrics) => metrics,
+            None => return f(),
+        };
+        let started = Instant::now();
+        let res = f();
+        match &res {
+            Ok(value) => on_ok(&**metrics, value, started.elapsed()),
+            Err(e) => metrics.on_error(op, e),
+        }
+        res
     }
 
-    fn read_command(&self, offset: &CommandOffset) -> Result<Command> {
-        let CommandOffset { gen, pos, len } = offset;
-        self.read(gen, |reader| {
-            reader.seek(SeekFrom::Start(*pos))?;
+    /// Reads a key for `get_with_source`, which reports it to the metrics.
+    fn read_with_source(&self, key: String) -> Result<Option<(String, ReadSource)>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        // Cache hits read no file, which would leave removed ones open
+        self.reader.drop_removed_handles();
+        loop {
+            {
+                // The writer invalidates cached values under the write lock, so
+                // values cached under the read lock are never stale.
+                let index = self.index.read().unwrap();
+                if let Some(value) = self.reader.cache.get(&key) {
+                    return Ok(Some((value, ReadSource::Cache)));
+                }
+                match index.get(&key)? {
+                    // The writer raises `unflushed` before indexing a deferred record,
+                    // so a clear flag means the offset is readable from the file.
+                    Some(offset) if !self.unflushed.load(Ordering::SeqCst) => {
+                        let value = self.reader.read_value(&offset)?;
+                        // The cache would serve an expiring value past its expiry
+                        if index.expiry(&key).is_none() {
+                            self.reader.cache.insert(key, value.clone());
+                        }
+                        return Ok(Some((value, ReadSource::Disk)));
+                    }
+                    Some(_) => {}
+                    None => {
+                        // Only expired keys are indexed but not found. Removing
+                        // them is left for later if a write holds the writer.
+                        if index.expiry(&key).is_some() {
+                            drop(index);
+                            if let Ok(mut writer) = self.writer.try_lock() {
+                                if let Err(e) = writer.purge_expired() {
+                                    error!("Error on purging expired keys: {}", e);
+                                }
+                            }
+                        }
+                        return Ok(None);
+                    }
+                }
+            }
+            self.flush_deferred()?;
+        }
+    }
 
-            let mut buffer = vec![0u8; *len as usize];
-            reader.read_exact(&mut buffer)?;
-            Ok(serde_json::from_slice(&buffer)?)
-        })
+    /// Locks the writer once no background compaction is in progress, since
+    /// it still reads the generations it will remove.
+    fn lock_idle_writer(&self) -> MutexGuard<'_, KvStoreWriter> {
+        let writer = self.writer.lock().unwrap();
+        self.compaction_done
+            .wait_while(writer, |writer| writer.compacting)
+            .unwrap()
     }
-}
 
-// ========================= KvStoreWriter =========================
+    /// Estimates the memory taken by the index, in bytes.
+    ///
+    /// It counts the keys and the per-entry overhead of the index backend, for
+    /// deciding when `IndexKind::Spill` is worth it. Allocator overhead is not
+    /// counted, so the real footprint is somewhat larger.
+    pub fn index_memory_bytes(&self) -> Result<usize> {
+        self.index.read().unwrap().memory_bytes()
+    }
 
-struct PosBufWriter<T: Write + Seek> {
-    writer: BufWriter<T>,
-    pos: u64,
-}
+    /// Returns the number of keys in each shard of an `IndexKind::Sharded`
+    /// index, to spot a hash spreading the keys unevenly. `None` for the other
+    /// backends.
+    pub fn shard_key_counts(&self) -> Option<Vec<usize>> {
+        self.index.read().unwrap().shard_key_counts()
+    }
 
-impl<T: Write + Seek> PosBufWriter<T> {
-    fn new(mut writer: BufWriter<T>) -> Result<Self> {
-        let pos = writer.seek(SeekFrom::End(0))?;
-        Ok(PosBufWriter { writer, pos })
+    /// Returns a summary of the size of the store.
+    ///
+    /// `KvStoreOptions::stats_file` writes the same summary to a file.
+    pub fn stats(&self) -> Result<KvStats> {
+        self.writer.lock().unwrap().stats()
     }
-}
 
-impl<T: Write + Seek> Write for PosBufWriter<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let size = self.writer.write(buf)?;
-        self.pos += size as u64;
-        Ok(size)
+    /// Returns the number of bytes taken by stale records, which compaction
+    /// would reclaim.
+    pub fn uncompacted_bytes(&self) -> u64 {
+        self.writer.lock().unwrap().uncompacted
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+    /// Merges a run of adjacent small generations into one, leaving large
+    /// generations alone.
+    ///
+    /// Which generations are merged is decided by `KvStoreOptions::tiered_compaction`.
+    /// Nothing happens if no run qualifies, or if the store is append-only.
+    pub fn compact_tiered(&self) -> Result<()> {
+        self.lock_idle_writer().compact_tiered()
     }
-}
 
-impl<T: Write + Seek> Seek for PosBufWriter<T> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
-        Ok(self.pos)
+    /// Returns the key/value pairs whose keys fall in `range`, ordered by key.
+    ///
+    /// Ordered index backends (`IndexKind::BTree`, `IndexKind::Trie`) answer this
+    /// without sorting the whole index.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AppendOnly` for an append-only store, use `iter`.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let start = range
+            .start_bound()
+            .map(|key| self.options.normalize_key(key.clone()));
+        let end = range
+            .end_bound()
+            .map(|key| self.options.normalize_key(key.clone()));
+        let bounds = (
+            start.as_ref().map(String::as_str),
+            end.as_ref().map(String::as_str),
+        );
+        loop {
+            {
+                let index = self.index.read().unwrap();
+                if !self.unflushed.load(Ordering::SeqCst) {
+                    return index
+                        .range(bounds)?
+                        .into_iter()
+                        .map(|(key, offset)| Ok((key, self.reader.read_value(&offset)?)))
+                        .collect();
+                }
+            }
+            self.flush_deferred()?;
+        }
     }
-}
 
-struct KvStoreWriter {
-    path: Arc<PathBuf>,
-    writer: PosBufWriter<File>,
-    reader: KvStoreReader,
-    index: Arc<RwLock<HashMap<String, CommandOffset>>>,
-    current_gen: u64,
-    uncompacted: u64,
-}
+    /// Computes `agg` over the keys starting with `prefix`, without sending
+    /// their values anywhere.
+    ///
+    /// `Aggregation::Count` only walks the index, the others read the values
+    /// and parse them as integers.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` for a value that does not parse
+    /// under `NonInteger::Error`, and `KvsError::AppendOnly` for an append-only
+    /// store.
+    pub fn aggregate(&self, prefix: String, agg: Aggregation) -> Result<AggResult> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let prefix = self.options.normalize_key(prefix);
+        let bounds = (Bound::Included(prefix.as_str()), Bound::Unbounded);
+        loop {
+            {
+                let index = self.index.read().unwrap();
+                if !self.unflushed.load(Ordering::SeqCst) {
+                    let entries = index
+                        .range(bounds)?
+                        .into_iter()
+                        .take_while(|(key, _)| key.starts_with(&prefix));
+                    let (on_error, mut acc) = match agg {
+                        Aggregation::Count => return Ok(AggResult::Count(entries.count() as u64)),
+                        Aggregation::Sum(on_error) => (on_error, AggResult::Sum(0)),
+                        Aggregation::Min(on_error) => (on_error, AggResult::Min(None)),
+                        Aggregation::Max(on_error) => (on_error, AggResult::Max(None)),
+                    };
+                    for (key, offset) in entries {
+                        let n = match self.reader.read_value(&offset)?.trim().parse::<i64>() {
+                            Ok(n) => n,
+                            Err(_) if on_error == NonInteger::Skip => continue,
+                            Err(_) => return Err(KvsError::NotAnInteger(key)),
+                        };
+                        acc = acc.add(n).ok_or_else(|| {
+                            KvsError::StringError(format!("sum over {} overflows", prefix))
+                        })?;
+                    }
+                    return Ok(acc);
+                }
+            }
+            self.flush_deferred()?;
+        }
+    }
 
-impl KvStoreWriter {
-    fn new(
-        path: Arc<PathBuf>,
-        writer: BufWriter<File>,
-        reader: KvStoreReader,
-        index: Arc<RwLock<HashMap<String, CommandOffset>>>,
-        current_gen: u64,
-    ) -> Result<Self> {
-        Ok(KvStoreWriter {
-            path,
-            writer: PosBufWriter::new(writer)?,
-            reader,
-            index,
-            current_gen,
-            uncompacted: 0,
-        })
+    /// Returns the smallest key in the store.
+    pub fn first_key(&self) -> Result<Option<String>> {
+        self.index.read().unwrap().first_key()
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::Set {
-            key: key.clone(),
-            value,
-        };
+    /// Returns the largest key in the store.
+    pub fn last_key(&self) -> Result<Option<String>> {
+        self.index.read().unwrap().last_key()
+    }
 
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
+    /// Returns an iterator over the key/value pairs in the log, in write order.
+    ///
+    /// It reads the log from disk, so memory stays bounded regardless of its
+    /// size. Writes made after the call are not visible. Removed keys are
+    /// skipped, but values superseded by a later `set` of the same key are
+    /// yielded too, so this is meant for append-only stores, where every key is
+    /// written once.
+    pub fn iter(&self) -> Result<LogIter> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let mut files = vec![];
+        for gen in generations(&*self.fs, &self.path)? {
+            let file = open_log(&*self.fs, &db_path(&self.path, gen))?;
+            let len = if gen == writer.current_gen {
+                writer.writer.pos
+            } else {
+                file.size()?
+            };
+            files.push(BufReader::new(file).take(len));
+        }
+        Ok(LogIter {
+            reader: self.reader.clone(),
+            files: files.into_iter(),
+            records: None,
+        })
+    }
 
-        {
-            let new_pos = self.writer.pos;
-            let offset = CommandOffset::from((self.current_gen, pos..new_pos));
-            let mut index = self.index.write().unwrap();
-            if let Some(offset) = index.insert(key, offset) {
-                self.uncompacted += offset.len;
+    /// Writes every live key and its value to `writer`, one JSON object per
+    /// line, such as `{"key":"a","value":"1"}`. Values set by `set_bytes` that
+    /// are not UTF-8 are written as an array of bytes, `{"key":"a","bytes":[255]}`.
+    ///
+    /// The entries are read under the read lock of the index, so the export is
+    /// the store at one point in time: writes made during the call wait for it
+    /// to finish and are not in it. Reserved keys, such as the counters of
+    /// `next_id`, are exported too. Expirations are not, see `import`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AppendOnly` for an append-only store, use `iter`
+    /// there.
+    pub fn export<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        loop {
+            {
+                let index = self.index.read().unwrap();
+                if !self.unflushed.load(Ordering::SeqCst) {
+                    for entry in index.entries() {
+                        let (key, offset) = entry?;
+                        let entry = match String::from_utf8(self.reader.read_bytes(&offset)?) {
+                            Ok(value) => ExportEntry {
+                                key,
+                                value: Some(value),
+                                bytes: None,
+                            },
+                            Err(e) => ExportEntry {
+                                key,
+                                value: None,
+                                bytes: Some(e.into_bytes()),
+                            },
+                        };
+                        serde_json::to_writer(&mut *writer, &entry)?;
+                        writer.write_all(b"\n")?;
+                    }
+                    writer.flush()?;
+                    return Ok(());
+                }
             }
+            self.flush_deferred()?;
         }
+    }
 
-        if self.uncompacted >= COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Sets the keys written by `export`, in batches of `IMPORT_BATCH` like
+    /// `set_many`. Keys already in the store are overwritten, the others are
+    /// kept, and imported keys never expire.
+    ///
+    /// Reserved keys are restored as they were exported, so `next_id` does not
+    /// hand out an id twice after restoring a backup into an empty store.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Serde` for a line that is not an exported entry,
+    /// and `KvsError::StringError` for an entry without a value.
+    /// The batches before it are kept.
+    pub fn import<R: Read>(&self, reader: &mut R) -> Result<()> {
+        let mut batch = Vec::with_capacity(IMPORT_BATCH);
+        for entry in Deserializer::from_reader(reader).into_iter::<ExportEntry>() {
+            let entry = entry?;
+            match (entry.value, entry.bytes) {
+                (Some(value), _) => batch.push((entry.key, value)),
+                (None, Some(bytes)) => {
+                    let mut writer = self.writer.lock().unwrap();
+                    writer.set_many(mem::take(&mut batch))?;
+                    writer.set_bytes(entry.key, bytes)?;
+                }
+                (None, None) => {
+                    return Err(KvsError::StringError(format!(
+                        "exported entry of {} has no value",
+                        entry.key
+                    )))
+                }
+            }
+            if batch.len() == IMPORT_BATCH {
+                self.writer
+                    .lock()
+                    .unwrap()
+                    .set_many(mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            self.writer.lock().unwrap().set_many(batch)?;
         }
-
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if !self.index.read().unwrap().contains_key(&key) {
-            Err(KvsError::KeyNotFound)
-        } else {
-            let command = Command::Remove { key: key.clone() };
-
-            serde_json::to_writer(&mut self.writer, &command)?;
-            self.writer.flush()?;
-
-            let offset = self
-                .index
-                .write()
-                .unwrap()
-                .remove(&key)
-                .expect("Unreachable: key not found");
-            self.uncompacted += offset.len;
+    /// Returns when a key was last set.
+    ///
+    /// Returns `None` if the key does not exist or was written before
+    /// modification times were recorded.
+    pub fn modified_at(&self, key: String) -> Result<Option<SystemTime>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        loop {
+            {
+                let index = self.index.read().unwrap();
+                match index.get(&key)? {
+                    Some(offset) if !self.unflushed.load(Ordering::SeqCst) => {
+                        let millis = self.reader.read_command(&offset)?.modified_at();
+                        return Ok(Some(millis)
+                            .filter(|millis| *millis != 0)
+                            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)));
+                    }
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+            self.flush_deferred()?;
+        }
+    }
 
-            if self.uncompacted >= COMPACTION_THRESHOLD {
-                self.compact()?;
+    /// Calls `f` with every write committed from now on, e.g. to forward writes
+    /// to another system. It replaces the previous callback.
+    ///
+    /// A write is committed once it is flushed to the log and visible in the
+    /// index, so a `set_deferred` is passed on by the next flush. `f` runs on a
+    /// thread of its own, one write at a time in commit order. Writers never
+    /// wait for it: the queue is unbounded, so `f` may write back to the store,
+    /// but a callback slower than the writes makes the queue grow.
+    pub fn set_on_commit<F>(&self, f: F)
+    where
+        F: Fn(&str, &Mutation) + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<(String, Mutation)>();
+        thread::spawn(move || {
+            for (key, mutation) in rx {
+                f(&key, &mutation);
             }
+        });
+        self.writer.lock().unwrap().on_commit = Some(tx);
+    }
 
-            Ok(())
+    /// Starts a background thread that reads back the record of every key,
+    /// reading at most `records_per_sec` of them per second.
+    ///
+    /// A record is corrupt if it no longer parses as a write of its key, or if
+    /// its value can't be rebuilt. Corruptions are logged and counted in
+    /// `Scrubber::stats`. The thread only takes the index lock to look up each
+    /// key, so foreground operations are barely slowed down. It walks the index
+    /// over and over until the `Scrubber` is dropped.
+    pub fn spawn_scrubber(&self, records_per_sec: u32) -> Scrubber {
+        let store = self.clone();
+        let stats = Arc::new(ScrubCounters::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let pause = Duration::from_secs(1) / records_per_sec.max(1);
+        let thread = {
+            let (stats, stop) = (Arc::clone(&stats), Arc::clone(&stop));
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    let keys = store.index.read().unwrap().keys();
+                    let keys = keys.unwrap_or_else(|e| {
+                        error!("Scrubber cannot list the keys: {}", e);
+                        vec![]
+                    });
+                    for key in keys {
+                        if stop.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        store.scrub_key(&key, &stats);
+                        thread::sleep(pause);
+                    }
+                    stats.passes.fetch_add(1, Ordering::SeqCst);
+                    // Don't spin on an empty store
+                    thread::sleep(pause);
+                }
+            })
+        };
+        Scrubber {
+            stats,
+            stop,
+            thread: Some(thread),
         }
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let (compact_writer, compact_reader) =
-            new_db_log(&db_path(&self.path, self.current_gen + 1))?;
-        let (new_writer, new_reader) = new_db_log(&db_path(&self.path, self.current_gen + 2))?;
-        let mut compact_writer = PosBufWriter::new(compact_writer)?;
+    fn scrub_key(&self, key: &str, stats: &ScrubCounters) {
+        let offset = match self.index.read().unwrap().get(key) {
+            Ok(Some(offset)) => offset,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Scrubber cannot look up {}: {}", key, e);
+                return;
+            }
+        };
+        let res = self.reader.read_command(&offset).and_then(|cmd| match cmd {
+            Command::Set { key: ref found, .. }
+            | Command::Delta { key: ref found, .. }
+            | Command::SetBytes { key: ref found, .. }
+            | Command::SetCompressed { key: ref found, .. }
+                if self.options.normalize_key(found.clone()) == key =>
+            {
+                self.reader.read_bytes(&offset).map(|_| ())
+            }
+            _ => Err(KvsError::UnexpectedCommandType),
+        });
+        stats.records.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = res {
+            // Compaction may have moved the record while it was read
+            if self.index.read().unwrap().get(key).ok().flatten() != Some(offset) {
+                return;
+            }
+            error!(
+                "Corrupt record of {} in generation {} at {}: {}",
+                key, offset.gen, offset.pos, e
+            );
+            stats.corruptions.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Records the current state of the log, to read it later with `get_at`.
+    pub fn checkpoint(&self) -> Result<Checkpoint> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        Ok(Checkpoint {
+            position: LogPosition {
+                gen: writer.current_gen,
+                offset: writer.writer.pos,
+            },
+            generations: generations(&*self.fs, &self.path)?,
+        })
+    }
+
+    /// Gets the value a key had when `checkpoint` was taken.
+    ///
+    /// It scans the log up to the checkpoint and blocks writes meanwhile, so it
+    /// is meant for debugging rather than serving reads.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::LogCompacted` if compaction has removed a generation
+    /// the checkpoint covers.
+    pub fn get_at(&self, key: String, checkpoint: &Checkpoint) -> Result<Option<String>> {
+        let key = self.options.normalize_key(key);
+        // Compaction would remove generations under the scan
+        let _writer = self.writer.lock().unwrap();
+        let gens = generations(&*self.fs, &self.path)?;
+        if checkpoint.generations.iter().any(|gen| !gens.contains(gen)) {
+            return Err(KvsError::LogCompacted);
+        }
+
+        let mut found = None;
+        for &gen in checkpoint.generations.iter() {
+            let file = BufReader::new(open_log(&*self.fs, &db_path(&self.path, gen))?);
+            let end = if gen == checkpoint.position.gen {
+                checkpoint.position.offset
+            } else {
+                u64::MAX
+            };
+            let mut stream = Records::new(self.options.log_encoding, file.take(end));
+            let mut pos = 0;
+            while let Some(cmd) = stream.next() {
+                let new_pos = stream.byte_offset();
+                let (cmd_key, offset) = match cmd? {
+                    Command::Set { key, .. }
+                    | Command::Delta { key, .. }
+                    | Command::SetBytes { key, .. }
+                    | Command::SetCompressed { key, .. } => {
+                        (key, Some(CommandOffset::from((gen, pos..new_pos))))
+                    }
+                    Command::Remove { key } => (key, None),
+                };
+                if self.options.normalize_key(cmd_key) == key {
+                    found = offset;
+                }
+                pos = new_pos;
+            }
+        }
+        found
+            .map(|offset| self.reader.read_value(&offset))
+            .transpose()
+    }
+
+    /// Gets the value a key had `n_back` writes ago, 0 being its current value.
+    ///
+    /// Compaction keeps `KvStoreOptions::keep_versions` versions of each key, so
+    /// older ones are only found until the next compaction. Versions written
+    /// before the key was last removed are never returned. Like `get_at`, it
+    /// scans the log and blocks writes meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AppendOnly` for an append-only store.
+    pub fn get_version(&self, key: String, n_back: usize) -> Result<Option<String>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        // An expired key has no current version to count back from
+        if !self.index.read().unwrap().contains_key(&key)? {
+            return Ok(None);
+        }
+
+        let mut versions = vec![];
+        for gen in generations(&*self.fs, &self.path)? {
+            let file = BufReader::new(open_log(&*self.fs, &db_path(&self.path, gen))?);
+            let mut stream = Records::new(self.options.log_encoding, file);
+            let mut pos = 0;
+            while let Some(cmd) = stream.next() {
+                let new_pos = stream.byte_offset();
+                match cmd? {
+                    Command::Set { key: cmd_key, .. }
+                    | Command::Delta { key: cmd_key, .. }
+                    | Command::SetBytes { key: cmd_key, .. }
+                    | Command::SetCompressed { key: cmd_key, .. } => {
+                        if self.options.normalize_key(cmd_key) == key {
+                            versions.push(CommandOffset::from((gen, pos..new_pos)));
+                        }
+                    }
+                    Command::Remove { key: cmd_key } => {
+                        if self.options.normalize_key(cmd_key) == key {
+                            versions.clear();
+                        }
+                    }
+                }
+                pos = new_pos;
+            }
+        }
+        versions
+            .iter()
+            .rev()
+            .nth(n_back)
+            .map(|offset| self.reader.read_value(offset))
+            .transpose()
+    }
+
+    /// Reads the log records written at or after `from`.
+    ///
+    /// Returns the records, encoded for `ReplicaKvStore::apply`, and the position
+    /// to ship from next time. Start from `LogPosition::default()` to ship the
+    /// whole log. Records are shipped as JSON whatever the encoding of the log,
+    /// so a replica may use another encoding.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::LogCompacted` if the generation `from` points into has
+    /// been removed by compaction. The follower has to resync into a fresh replica.
+    pub fn ship_log(&self, from: LogPosition) -> Result<(Vec<u8>, LogPosition)> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let end = LogPosition {
+            gen: writer.current_gen,
+            offset: writer.writer.pos,
+        };
+
+        let gens = generations(&*self.fs, &self.path)?;
+        if from.gen != 0 && !gens.contains(&from.gen) {
+            return Err(KvsError::LogCompacted);
+        }
+
+        let mut records = Vec::new();
+        for gen in gens.into_iter().filter(|gen| *gen >= from.gen) {
+            let start = if gen == from.gen { from.offset } else { 0 };
+            let mut file = open_log(&*self.fs, &db_path(&self.path, gen))?;
+            file.seek(SeekFrom::Start(start))?;
+            let len = if gen == end.gen {
+                end.offset.saturating_sub(start)
+            } else {
+                u64::MAX
+            };
+            match self.options.log_encoding {
+                LogEncoding::Json => {
+                    file.take(len).read_to_end(&mut records)?;
+                }
+                LogEncoding::Binary => {
+                    for cmd in Records::new(LogEncoding::Binary, BufReader::new(file).take(len)) {
+                        serde_json::to_writer(&mut records, &cmd?)?;
+                    }
+                }
+            }
+        }
+
+        Ok((records, end))
+    }
+
+    /// Replays shipped log records into this store, in order.
+    pub(crate) fn replay(&self, records: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for command in Deserializer::from_slice(records).into_iter::<Command>() {
+            match command? {
+                Command::Set {
+                    key,
+                    value,
+                    expires_at: Some(expires_at),
+                    ..
+                } => {
+                    writer.set_expiring(key, value, expires_at)?;
+                }
+                Command::Set { key, value, .. } => {
+                    writer.set(key, value)?;
+                }
+                // The base offset is only meaningful in the shipping store
+                Command::Delta { key, diff, .. } => writer.set_delta(key, &diff)?,
+                Command::SetBytes { key, value, .. } => {
+                    writer.set_bytes(key, value)?;
+                }
+                Command::SetCompressed { key, value, .. } => {
+                    writer.set_bytes(key, inflate_value(&value)?)?;
+                }
+                Command::Remove { key } => match writer.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => {}
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Options for opening a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// Data structure used for the index.
+    pub index: IndexKind,
+    /// Thresholds used by `KvStore::compact_tiered`.
+    pub tiered_compaction: TieredCompaction,
+    /// Stores a `set` as a diff against the previous value when the diff is
+    /// smaller, for values that change incrementally. Compaction writes full
+    /// values again.
+    pub deltas: bool,
+    /// Compares keys case-insensitively.
+    ///
+    /// Keys are normalized with `str::to_lowercase`, the Unicode lowercase
+    /// mapping without locale tailoring. This is simpler than full case folding:
+    /// `"Foo"` and `"FOO"` match, but `"Straße"` and `"STRASSE"` do not. Keys are
+    /// stored, listed and scanned in their normalized form. A store written
+    /// without this option may be reopened with it, its keys are normalized while
+    /// the index is loaded and the last write wins among keys that collide.
+    pub case_insensitive: bool,
+    /// Renumbers log files claiming the same generation number at open, instead
+    /// of failing with `KvsError::DuplicateGeneration`.
+    ///
+    /// Files are ordered by generation number, then by file name, and renamed to
+    /// fresh generations above the largest one in that order.
+    pub repair_generations: bool,
+    /// Skips the in-memory index, for logs of events where every key is unique.
+    ///
+    /// `set` only appends to the log, and records are read back with
+    /// `KvStore::iter`. Lookups, removals and `scan` fail with
+    /// `KvsError::AppendOnly`, and compaction does nothing.
+    pub append_only: bool,
+    /// Reads every record rewritten by `KvStore::compact` back before switching
+    /// to the compacted log, failing with `KvsError::Corruption` on a mismatch.
+    ///
+    /// On failure the compacted log is discarded and the store keeps serving
+    /// from the old generations.
+    pub verify_compaction: bool,
+    /// Number of versions of each key `KvStore::compact` keeps, the latest
+    /// included, for `KvStore::get_version` to read back. 0 and 1 keep only
+    /// the latest.
+    ///
+    /// Versions written before the last removal of a key are not kept, and
+    /// `KvStore::compact_tiered` keeps only the latest version of the records
+    /// it merges.
+    pub keep_versions: usize,
+    /// Number of recently read values kept in memory, 0 disables the cache.
+    pub value_cache: usize,
+    /// When `set` and `remove` compact the log.
+    pub compaction: CompactionPolicy,
+    /// Bytes of stale records below which `CompactionPolicy::Automatic` never
+    /// compacts, whatever `compaction_ratio` says.
+    ///
+    /// Defaults to 4 MiB. A compaction rewrites every live record, so a small
+    /// threshold keeps the log close to its live size at the cost of frequent
+    /// rewrites, which stall the writer when the live data is large. A large
+    /// threshold compacts rarely but lets the log grow by that much on disk,
+    /// and makes each compaction drop more stale records at once.
+    pub compaction_threshold: u64,
+    /// Share of the log, from 0 to 1, that stale records must take up for
+    /// `CompactionPolicy::Automatic` to compact, once they also reach
+    /// `compaction_threshold` bytes.
+    ///
+    /// Defaults to 0.5, so a compaction at least halves the log whatever the
+    /// size of the live data. A store with much live data then waits for as
+    /// many stale bytes before rewriting it all. 0 compacts on
+    /// `compaction_threshold` alone.
+    pub compaction_ratio: f64,
+    /// Keys starting with this prefix are kept for internal bookkeeping, writing
+    /// them fails with `KvsError::ReservedKey`. Reading them is allowed.
+    ///
+    /// Defaults to `"__kvs:"`. An empty prefix reserves nothing.
+    pub reserved_prefix: String,
+    /// Writes and reads records with direct I/O, bypassing the page cache, for
+    /// steadier latency on hosts where the cache competes for memory.
+    ///
+    /// Only supported on Linux, on file systems that support `O_DIRECT`. Every
+    /// flush writes the last partial block of the log again, so it suits
+    /// batched writes better than many small `set`s.
+    pub direct_io: bool,
+    /// Rewrites a log of an older format in the current one at open.
+    ///
+    /// The rewrite is crash-safe: an interrupted upgrade is either finished or
+    /// discarded by the next open. Without this option an older log is served
+    /// as is, with a warning.
+    pub allow_upgrade: bool,
+    /// Compresses the generations written by compaction in the background, to
+    /// save disk on logs that are mostly read.
+    ///
+    /// The generation being written stays uncompressed. A generation is
+    /// compressed in blocks of 64 KiB, and a read only decompresses the blocks
+    /// its record spans. Ignored with `direct_io`.
+    ///
+    /// Needs the `compression` feature, without it the store fails to open
+    /// with `KvsError::CompressionDisabled`, and so do reads from a compressed
+    /// generation.
+    pub compress_cold_generations: bool,
+    /// Deflates each value of at least 128 bytes as it is written, for stores
+    /// of large, repetitive values such as JSON documents.
+    ///
+    /// A value is kept as is unless its compressed record is the smaller one.
+    /// Compressed values are binary, so a JSON log holds them as arrays of
+    /// numbers: prefer `LogEncoding::Binary`, or only large values shrink.
+    /// Values that expire are not compressed.
+    ///
+    /// Every record says whether its value is compressed, so the option may
+    /// change between opens. Versions of the store from before it fail to
+    /// open a log holding a compressed value.
+    ///
+    /// Needs the `compression` feature, without it the store fails to open
+    /// with `KvsError::CompressionDisabled`, and so do reads of a compressed
+    /// value.
+    pub compress_values: bool,
+    /// Refuses to open a store on a file system known not to honor `fsync`,
+    /// such as NFS or SMB mounts, where synced writes may still be lost.
+    ///
+    /// Only `SyncPolicy::EveryWrite` promises every write to be durable, so
+    /// only it is refused. Without this option, or with another policy, such
+    /// a store opens with a warning. Only detected on Linux.
+    pub strict_durability: bool,
+    /// When the log is synced to disk, trading durability for throughput.
+    ///
+    /// `KvsEngine::flush` syncs under every policy.
+    pub sync_policy: SyncPolicy,
+    /// Saves a snapshot of the index when the store is closed, and loads it at
+    /// the next open instead of replaying the generations it covers.
+    ///
+    /// Generations written after the snapshot, by a store that crashed before
+    /// saving a new one, are replayed on top of it. A snapshot that no longer
+    /// matches the log, after a compaction for example, is ignored. Ignored
+    /// with `append_only`.
+    pub index_snapshot: bool,
+    /// Compresses the index snapshot with LZ4, for smaller snapshots of large
+    /// indexes that are faster to read from disk, at a small CPU cost.
+    ///
+    /// Snapshots are read whether they are compressed or not, so the option may
+    /// change between opens.
+    pub compress_index_snapshot: bool,
+    /// What to do with a generation holding a record that does not parse.
+    pub recovery: RecoveryMode,
+    /// Writes `KvStore::stats` to a JSON file periodically, for monitoring
+    /// tools that read files rather than call into the store.
+    ///
+    /// A background thread rewrites the file atomically every interval, and
+    /// stops when the last handle to the store is dropped.
+    pub stats_file: Option<StatsFile>,
+    /// How a new store encodes its records. An existing store keeps the
+    /// encoding it was created with.
+    pub log_encoding: LogEncoding,
+    /// Checks the free space of the file system holding the log at open, then
+    /// periodically, to warn about or refuse writes before one runs out of
+    /// space mid-record. `None` never checks.
+    ///
+    /// File systems that cannot tell their free space are never low.
+    pub disk_space_check: Option<DiskSpaceCheck>,
+    /// Hooks called on gets, sets, removes and compactions, e.g. an
+    /// `AtomicMetrics`. `None` skips the timing entirely.
+    pub metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            index: IndexKind::default(),
+            tiered_compaction: TieredCompaction::default(),
+            deltas: false,
+            case_insensitive: false,
+            repair_generations: false,
+            append_only: false,
+            verify_compaction: false,
+            keep_versions: 1,
+            value_cache: 0,
+            compaction: CompactionPolicy::default(),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_ratio: COMPACTION_RATIO,
+            reserved_prefix: RESERVED_PREFIX.to_owned(),
+            direct_io: false,
+            allow_upgrade: false,
+            compress_cold_generations: false,
+            compress_values: false,
+            strict_durability: false,
+            sync_policy: SyncPolicy::Never,
+            index_snapshot: false,
+            compress_index_snapshot: false,
+            recovery: RecoveryMode::default(),
+            stats_file: None,
+            log_encoding: LogEncoding::default(),
+            disk_space_check: None,
+            metrics: None,
+        }
+    }
+}
+
+/// When a `KvStore` compacts its log on its own.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionPolicy {
+    /// Compacts once stale records take up both
+    /// `KvStoreOptions::compaction_threshold` bytes and
+    /// `KvStoreOptions::compaction_ratio` of the log.
+    #[default]
+    Automatic,
+    /// Never compacts on its own, `KvStore::compact` still works.
+    Disabled,
+    /// Compacts at the same threshold as `Automatic`, but in a background
+    /// thread, so writes do not stall while the live records are copied.
+    ///
+    /// The generations to compact are sealed behind a new one taking the
+    /// writes. Once their live records are copied, the index is swapped under
+    /// the writer lock, keeping the entries of keys written meanwhile.
+    Background,
+}
+
+/// How `KvStore::open` handles a corrupt generation.
+///
+/// A torn record at the end of a generation, left by a crash during a write,
+/// is not corruption: it is truncated in every mode.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Fails to open the store.
+    #[default]
+    Fail,
+    /// Moves corrupt generations to the `corrupt` subdirectory of the log for
+    /// later analysis, and opens with the others. Their records are lost, so a
+    /// key reads as it was before the generation was written.
+    ///
+    /// Every generation is read once more at open to find the corrupt ones.
+    Quarantine,
+    /// Drops the bytes that do not parse, from each corrupt record up to the
+    /// next offset where a record parses again, and keeps the records around
+    /// them. Logs left with junk between records, e.g. by an older writer
+    /// whose records were not cleanly delimited, lose only the junk.
+    ///
+    /// A corrupt generation is rewritten without the dropped bytes, so it
+    /// parses on later opens. A record that happens to parse inside the junk
+    /// is kept, which `Quarantine` is safer against.
+    Resync,
+}
+
+/// When a `KvStore` syncs its log to disk, see `KvStoreOptions::sync_policy`.
+///
+/// Writes are always handed to the operating system before they return, so
+/// only a power loss or an OS crash can lose them, not a crash of the process.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never syncs by itself, only `KvsEngine::flush` does. The fastest
+    /// policy, for caches that can be rebuilt from elsewhere.
+    #[default]
+    Never,
+    /// Syncs every write before it returns, so every acknowledged write is
+    /// crash-safe, at the cost of a disk sync per write, which is much
+    /// slower. For systems of record. Records written by `set_deferred` are
+    /// still synced by the next write or `flush` only.
+    EveryWrite,
+    /// Syncs the log every interval from a background thread, if anything was
+    /// written since the last sync. A power loss loses at most the last
+    /// interval of writes, for stores that can afford to lose a few seconds
+    /// but not hours. The thread flushes `set_deferred` records too.
+    Interval(Duration),
+}
+
+/// How the records of a `KvStore` log are encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogEncoding {
+    /// One JSON object per record, readable with a text editor.
+    #[default]
+    Json,
+    /// Length-prefixed bincode records, smaller and faster to load than
+    /// JSON, for logs of many small records.
+    Binary,
+}
+
+/// What recovering the log did when a `KvStore` was opened.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// The generations moved aside by `RecoveryMode::Quarantine`.
+    pub quarantined: Vec<u64>,
+    /// The generations rewritten by `RecoveryMode::Resync`.
+    pub resynced: Vec<u64>,
+    /// The bytes dropped by `RecoveryMode::Resync`, over all generations.
+    pub skipped_bytes: u64,
+}
+
+/// Where and how often `KvStoreOptions::stats_file` writes the stats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsFile {
+    /// The file to write, replaced on every write.
+    pub path: PathBuf,
+    /// Time between two writes. The first write happens one interval after
+    /// the store is opened.
+    pub interval: Duration,
+}
+
+/// How `KvStoreOptions::disk_space_check` checks the free space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskSpaceCheck {
+    /// Free bytes under which the space is low.
+    pub min_free_bytes: u64,
+    /// What the store does while the space is low.
+    pub action: LowSpaceAction,
+    /// Time between two checks after the one at open.
+    pub interval: Duration,
+}
+
+/// What a `KvStore` does while the free space of its file system is low.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LowSpaceAction {
+    /// Logs a warning when the space becomes low, and lets writes go on.
+    #[default]
+    Warn,
+    /// Refuses writes with `KvsError::DiskFull` until the next check finds
+    /// enough space. Reads and compaction still run, so compacting may free
+    /// the space.
+    RefuseWrites,
+}
+
+/// A summary of the state of a `KvStore`, returned by `KvStore::stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KvStats {
+    /// When the stats were taken, in milliseconds since the Unix epoch.
+    pub taken_at: u64,
+    /// Number of live keys.
+    pub keys: usize,
+    /// Number of generation files.
+    pub generations: usize,
+    /// Size of the generation files on disk, in bytes.
+    pub log_bytes: u64,
+    /// See `KvStore::uncompacted_bytes`.
+    pub uncompacted_bytes: u64,
+    /// See `KvStore::index_memory_bytes`.
+    pub index_memory_bytes: usize,
+    /// The version of the last record written.
+    pub last_version: u64,
+    /// Totals of the compactions since the store was opened.
+    #[serde(default)]
+    pub compaction: CompactionStats,
+}
+
+impl KvStoreOptions {
+    fn normalize_key(&self, key: String) -> String {
+        if self.case_insensitive {
+            key.to_lowercase()
+        } else {
+            key
+        }
+    }
+
+    /// Rejects user writes to reserved keys. Internal writes go to
+    /// `KvStoreWriter` directly and are not checked.
+    fn check_key(&self, key: &str) -> Result<()> {
+        if self.reserved_prefix.is_empty() {
+            return Ok(());
+        }
+        let prefix = self.normalize_key(self.reserved_prefix.clone());
+        if self.normalize_key(key.to_owned()).starts_with(&prefix) {
+            Err(KvsError::ReservedKey(key.to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Picking policy of `KvStore::compact_tiered`.
+#[derive(Debug, Clone)]
+pub struct TieredCompaction {
+    /// Generations smaller than this many bytes are candidates for merging.
+    pub small_generation_size: u64,
+    /// Minimum number of adjacent small generations worth merging.
+    pub min_generations: usize,
+}
+
+impl Default for TieredCompaction {
+    fn default() -> Self {
+        TieredCompaction {
+            small_generation_size: COMPACTION_THRESHOLD / 4,
+            min_generations: 4,
+        }
+    }
+}
+
+/// An aggregate over the values of the keys sharing a prefix, computed by
+/// `KvStore::aggregate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Number of keys.
+    Count,
+    /// Sum of the integer values.
+    Sum(NonInteger),
+    /// Smallest integer value.
+    Min(NonInteger),
+    /// Largest integer value.
+    Max(NonInteger),
+}
+
+/// What `KvStore::aggregate` does with a value that is not an integer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NonInteger {
+    /// Fails the aggregation.
+    #[default]
+    Error,
+    /// Leaves the value out.
+    Skip,
+}
+
+/// The result of `KvStore::aggregate`, matching the `Aggregation` asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggResult {
+    /// Number of keys.
+    Count(u64),
+    /// Sum of the values, 0 if there are none.
+    Sum(i64),
+    /// Smallest value, `None` if there are none.
+    Min(Option<i64>),
+    /// Largest value, `None` if there are none.
+    Max(Option<i64>),
+}
+
+impl AggResult {
+    /// Folds in one more value, or returns `None` if a sum overflows.
+    fn add(self, n: i64) -> Option<Self> {
+        Some(match self {
+            AggResult::Count(count) => AggResult::Count(count + 1),
+            AggResult::Sum(sum) => AggResult::Sum(sum.checked_add(n)?),
+            AggResult::Min(min) => AggResult::Min(Some(min.map_or(n, |min| min.min(n)))),
+            AggResult::Max(max) => AggResult::Max(Some(max.map_or(n, |max| max.max(n)))),
+        })
+    }
+}
+
+/// What `KvStore::compact_full` did to the generations it removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// The removed generations, by generation number.
+    pub generations: BTreeMap<u64, GenerationReclaim>,
+    /// Size of the compacted generation. Deltas are rewritten as full values,
+    /// so it can exceed the live bytes carried forward.
+    pub written: u64,
+    /// Size of all the generation files before the compaction, in bytes.
+    pub bytes_before: u64,
+    /// Size of all the generation files after the compaction, in bytes.
+    pub bytes_after: u64,
+    /// Number of index entries copied to the compacted generation.
+    pub records_retained: usize,
+    /// How long the compaction took.
+    pub duration: Duration,
+}
+
+impl CompactionReport {
+    /// Number of bytes reclaimed on disk, as returned by `KvStore::compact`.
+    pub fn reclaimed(&self) -> u64 {
+        self.generations
+            .values()
+            .map(|gen| gen.live + gen.dead)
+            .sum::<u64>()
+            .saturating_sub(self.written)
+    }
+}
+
+/// Totals over the full compactions of a `KvStore` since it was opened,
+/// foreground or background, reported by `KvStore::stats`. Merges of
+/// `KvStore::compact_tiered` are not counted.
+///
+/// The ratio of `bytes_after` to `bytes_before` tells how much of the log
+/// compaction keeps, a guide to tuning `KvStoreOptions::compaction_threshold`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionStats {
+    /// Number of compactions that completed.
+    pub compactions: u64,
+    /// Sum of the log sizes before each compaction, in bytes.
+    pub bytes_before: u64,
+    /// Sum of the log sizes after each compaction, in bytes.
+    pub bytes_after: u64,
+    /// Sum of the index entries each compaction copied.
+    pub records_retained: u64,
+    /// Time spent compacting. A background compaction counts from when it
+    /// began to when it ended, though it only held the writer at both ends.
+    pub duration: Duration,
+}
+
+impl CompactionStats {
+    fn add(&mut self, bytes_before: u64, bytes_after: u64, records: usize, duration: Duration) {
+        self.compactions += 1;
+        self.bytes_before += bytes_before;
+        self.bytes_after += bytes_after;
+        self.records_retained += records as u64;
+        self.duration += duration;
+    }
+}
+
+/// The bytes of a generation removed by compaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GenerationReclaim {
+    /// Bytes of the live records, copied to the compacted generation.
+    pub live: u64,
+    /// Bytes of the stale records, dropped. For a compressed generation this
+    /// counts its compressed size, less the uncompressed live bytes.
+    pub dead: u64,
+}
+
+/// The state of the log of a `KvStore` at some point, returned by
+/// `KvStore::checkpoint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    position: LogPosition,
+    generations: Vec<u64>,
+}
+
+impl Checkpoint {
+    /// The end of the log when the checkpoint was taken.
+    pub fn position(&self) -> LogPosition {
+        self.position
+    }
+}
+
+/// A write passed to the callback of `KvStore::set_on_commit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    /// The key was set to `value`, at `version`.
+    Set {
+        /// The new value.
+        value: String,
+        /// The new version of the key.
+        version: u64,
+    },
+    /// The key was set to a binary `value` by `KvStore::set_bytes`, at
+    /// `version`.
+    SetBytes {
+        /// The new value, which is not UTF-8.
+        value: Vec<u8>,
+        /// The new version of the key.
+        version: u64,
+    },
+    /// The key was removed.
+    Remove,
+}
+
+/// A background scrub of a `KvStore`, started by `KvStore::spawn_scrubber`.
+///
+/// Dropping it stops the scrub.
+pub struct Scrubber {
+    stats: Arc<ScrubCounters>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scrubber {
+    /// Returns the progress of the scrub so far.
+    pub fn stats(&self) -> ScrubStats {
+        ScrubStats {
+            passes: self.stats.passes.load(Ordering::SeqCst),
+            records: self.stats.records.load(Ordering::SeqCst),
+            corruptions: self.stats.corruptions.load(Ordering::SeqCst),
+        }
+    }
+}
+
+impl Drop for Scrubber {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default)]
+struct ScrubCounters {
+    passes: AtomicU64,
+    records: AtomicU64,
+    corruptions: AtomicU64,
+}
+
+/// Progress of a `Scrubber`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// Number of complete walks over the index.
+    pub passes: u64,
+    /// Number of records read back.
+    pub records: u64,
+    /// Number of corrupt records found. A record still corrupt in a later
+    /// pass is counted again.
+    pub corruptions: u64,
+}
+
+/// A position in the log of a `KvStore`, used to resume log shipping.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// The generation of the log file.
+    pub gen: u64,
+    /// The byte offset inside the generation.
+    pub offset: u64,
+}
+
+/// A write read back from the log by `KvsEngine::changes`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// The key written.
+    pub key: String,
+    /// What happened to the key.
+    pub event: WatchEvent,
+    /// The position right after the record, to read the next changes from.
+    pub position: LogPosition,
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        KvStore {
+            path: Arc::clone(&self.path),
+            fs: Arc::clone(&self.fs),
+            writer: Arc::clone(&self.writer),
+            reader: self.reader.clone(),
+            index: Arc::clone(&self.index),
+            unflushed: Arc::clone(&self.unflushed),
+            options: Arc::clone(&self.options),
+            recovery: Arc::clone(&self.recovery),
+            compaction_done: Arc::clone(&self.compaction_done),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Sets the value of a string key to a string.
+    /// Return an error if the value is not written successfully.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use unifier::KvStore;
+    /// # use unifier::KvsEngine;
+    /// # use tempfile::TempDir;
+    /// # let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("key".to_string(), "value".to_string());
+    /// ```
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_versioned(key, value).map(|_| ())
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    /// Flushes records written by `set_deferred` and syncs the current
+    /// generation to disk.
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    fn compact(&self) -> Result<u64> {
+        KvStore::compact(self)
+    }
+
+    /// Starts a new generation and removes all the others, under the writer
+    /// lock. A marker file naming the new generation is synced first, so
+    /// opening the store after a crash finishes removing them.
+    ///
+    /// Reserved keys, such as the counters of `KvStore::next_id`, are copied
+    /// to the new generation before the marker is written, so ids are not
+    /// handed out again after a clear.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AppendOnly` for an append-only store, whose
+    /// records are never removed.
+    fn clear(&self) -> Result<()> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        self.lock_idle_writer().clear()
+    }
+
+    /// Reads the records like `ship_log`, holding the writer lock so compaction
+    /// cannot remove a generation under the scan. The values of deltas are
+    /// rebuilt from their base.
+    fn changes(&self, from: LogPosition, max: usize) -> Result<(Vec<Change>, LogPosition)> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        let end = LogPosition {
+            gen: writer.current_gen,
+            offset: writer.writer.pos,
+        };
+
+        let gens = generations(&*self.fs, &self.path)?;
+        if from.gen != 0 && !gens.contains(&from.gen) {
+            return Err(KvsError::LogCompacted);
+        }
+
+        let mut changes = vec![];
+        for gen in gens.into_iter().filter(|gen| *gen >= from.gen) {
+            let start = if gen == from.gen { from.offset } else { 0 };
+            let mut file = open_log(&*self.fs, &db_path(&self.path, gen))?;
+            file.seek(SeekFrom::Start(start))?;
+            let len = if gen == end.gen {
+                end.offset.saturating_sub(start)
+            } else {
+                u64::MAX
+            };
+            let mut stream =
+                Records::new(self.options.log_encoding, BufReader::new(file).take(len));
+            let mut pos = start;
+            while let Some(cmd) = stream.next() {
+                if changes.len() == max {
+                    return Ok((changes, LogPosition { gen, offset: pos }));
+                }
+                let new_pos = start + stream.byte_offset();
+                let (key, event) = match cmd? {
+                    Command::Set { key, value, .. } => (key, WatchEvent::Set(value)),
+                    Command::Delta { key, .. } => {
+                        let offset = CommandOffset::from((gen, pos..new_pos));
+                        (key, WatchEvent::Set(self.reader.read_value(&offset)?))
+                    }
+                    Command::SetBytes { key, value, .. } => (key, WatchEvent::SetBytes(value)),
+                    Command::SetCompressed { key, value, .. } => {
+                        match String::from_utf8(inflate_value(&value)?) {
+                            Ok(value) => (key, WatchEvent::Set(value)),
+                            Err(e) => (key, WatchEvent::SetBytes(e.into_bytes())),
+                        }
+                    }
+                    Command::Remove { key } => (key, WatchEvent::Removed),
+                };
+                pos = new_pos;
+                changes.push(Change {
+                    key,
+                    event,
+                    position: LogPosition { gen, offset: pos },
+                });
+            }
+        }
+        Ok((changes, end))
+    }
+
+    /// The records are appended without flushing, then flushed once.
+    /// Compaction still runs as soon as the stale records reach its threshold,
+    /// flushing the records appended so far.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, _) in &pairs {
+            self.options.check_key(key)?;
+        }
+        self.writer.lock().unwrap().set_many(pairs)
+    }
+
+    /// Versions are a sequence shared by all keys of the store. Keys written
+    /// before versions were recorded have version 0.
+    fn set_versioned(&self, key: String, value: String) -> Result<u64> {
+        self.measured(
+            EngineOp::Set,
+            || {
+                self.options.check_key(&key)?;
+                self.writer.lock().unwrap().set(key, value)
+            },
+            |metrics, _, latency| metrics.on_set(latency),
+        )
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: u64) -> Result<u64> {
+        self.measured(
+            EngineOp::Set,
+            || {
+                self.options.check_key(&key)?;
+                self.writer
+                    .lock()
+                    .unwrap()
+                    .set_if_version(key, value, version)
+            },
+            |metrics, _, latency| metrics.on_set(latency),
+        )
+    }
+
+    /// Removes a given key.
+    /// Return an error if the key does not exist or is not removed successfully.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use unifier::KvStore;
+    /// # use unifier::{KvsEngine, KvsReader};
+    /// # use tempfile::TempDir;
+    /// # let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// kvs.set("key".to_string(), "value".to_string());
+    /// kvs.remove("key".to_string());
+    ///
+    /// let value = kvs.get("key".to_string()).unwrap();
+    /// assert_eq!(value, None);
+    /// ```
+    fn remove(&self, key: String) -> Result<()> {
+        self.measured(
+            EngineOp::Remove,
+            || {
+                self.options.check_key(&key)?;
+                self.writer.lock().unwrap().remove(key)
+            },
+            |metrics, _, latency| metrics.on_remove(latency),
+        )
+    }
+
+    /// Runs under the writer lock, so no other write interleaves.
+    fn set_returning(&self, key: String, value: String) -> Result<Option<String>> {
+        self.options.check_key(&key)?;
+        self.writer.lock().unwrap().set_returning(key, value)
+    }
+
+    /// Runs under the writer lock, so no other write interleaves.
+    fn remove_returning(&self, key: String) -> Result<Option<String>> {
+        self.options.check_key(&key)?;
+        self.writer.lock().unwrap().remove_returning(key)
+    }
+
+    /// Copies the keys out of the index under its read lock, so the list is
+    /// the store at one point in time: every write is either fully in it or
+    /// not at all. It fails with `KvsError::AppendOnly` on an append-only
+    /// store, use `iter` there.
+    fn keys(&self) -> Result<Vec<String>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        self.index.read().unwrap().keys()
+    }
+
+    /// Counts the entries of the index, under its read lock.
+    fn len(&self) -> Result<usize> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        self.index.read().unwrap().len()
+    }
+
+    /// Reads the matching entries of the index under its read lock, like
+    /// `scan`.
+    ///
+    /// Ordered index backends, such as the default `IndexKind::BTree`, seek to
+    /// the prefix and stop past it. `IndexKind::Hash` walks every entry and
+    /// sorts the matches, so a scan costs as much as the whole index.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let prefix = self.options.normalize_key(prefix.to_owned());
+        let bounds = (Bound::Included(prefix.as_str()), Bound::Unbounded);
+        loop {
+            {
+                let index = self.index.read().unwrap();
+                if !self.unflushed.load(Ordering::SeqCst) {
+                    return index
+                        .range(bounds)?
+                        .into_iter()
+                        .take_while(|(key, _)| key.starts_with(&prefix))
+                        .map(|(key, offset)| Ok((key, self.reader.read_value(&offset)?)))
+                        .collect();
+                }
+            }
+            self.flush_deferred()?;
+        }
+    }
+
+    /// Runs under the writer lock, so no other write interleaves.
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        self.options.check_key(&key)?;
+        self.writer.lock().unwrap().update(key, f)
+    }
+
+    /// Runs under the writer lock, so no other write interleaves, and flushes
+    /// the sets between two reads or removals together. A reserved key fails
+    /// the batch before any op runs.
+    fn batch(&self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        for op in &ops {
+            if let Op::Set(key, _) | Op::Remove(key) = op {
+                self.options.check_key(key)?;
+            }
+        }
+        self.writer.lock().unwrap().batch(ops)
+    }
+
+    /// Runs under the writer lock, so no other write interleaves.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.options.check_key(&key)?;
+        self.writer
+            .lock()
+            .unwrap()
+            .compare_and_swap(key, expected, new)
+    }
+}
+
+impl KvsReader for KvStore {
+    /// Gets the string value of the a string key.
+    /// If the key does not exist, return None.
+    /// Return an error if the value is not read successfully.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use unifier::KvStore;
+    /// # use unifier::KvsReader;
+    /// # use tempfile::TempDir;
+    /// # let temp_dir = TempDir::new().unwrap();
+    /// let mut kvs = KvStore::open(temp_dir.path()).unwrap();
+    /// let value = kvs.get("non-exist-key".to_string()).unwrap();
+    ///
+    /// assert_eq!(value, None);
+    /// ```
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_with_source(key)?.map(|(value, _)| value))
+    }
+
+    fn get_with_source(&self, key: String) -> Result<Option<(String, ReadSource)>> {
+        self.measured(
+            EngineOp::Get,
+            || self.read_with_source(key),
+            |metrics, value, latency| metrics.on_get(latency, value.is_some()),
+        )
+    }
+
+    /// Answers from the index alone, without reading the log.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        self.index.read().unwrap().contains_key(&key)
+    }
+}
+
+/// Iterator over the key/value pairs in the log of a `KvStore`, returned by
+/// `KvStore::iter`.
+pub struct LogIter {
+    reader: KvStoreReader,
+    files: std::vec::IntoIter<Take<LogReader>>,
+    records: Option<Records<Take<LogReader>>>,
+}
+
+impl Iterator for LogIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(records) = &mut self.records {
+                match records.next() {
+                    Some(Ok(Command::Set { key, value, .. })) => return Some(Ok((key, value))),
+                    Some(Ok(Command::Delta {
+                        key, base, diff, ..
+                    })) => {
+                        let value = self.reader.read_value(&base);
+                        return Some(value.and_then(|base| Ok((key, diff.apply(&base)?))));
+                    }
+                    // Binary values fail with `KvsError::Utf8`
+                    Some(Ok(Command::SetBytes { key, value, .. })) => {
+                        return Some(
+                            String::from_utf8(value)
+                                .map(|value| (key, value))
+                                .map_err(From::from),
+                        );
+                    }
+                    Some(Ok(Command::SetCompressed { key, value, .. })) => {
+                        return Some(
+                            inflate_value(&value)
+                                .and_then(|value| Ok((key, String::from_utf8(value)?))),
+                        );
+                    }
+                    Some(Ok(Command::Remove { .. })) => continue,
+                    Some(Err(e)) => return Some(Err(e.into())),
+                    None => {}
+                }
+            }
+            let file = self.files.next()?;
+            self.records = Some(Records::new(self.reader.encoding, file));
+        }
+    }
+}
+
+/// The most recently read values, evicted least recently used first.
+struct ValueCache {
+    capacity: usize,
+    values: Mutex<LruEntries<String>>,
+}
+
+impl ValueCache {
+    fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            values: Mutex::new(LruEntries::default()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.values.lock().unwrap().get(key)
+    }
+
+    fn insert(&self, key: String, value: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut values = self.values.lock().unwrap();
+        values.insert(key, value);
+        while values.len() > self.capacity {
+            values.pop_least_recent();
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        self.values.lock().unwrap().remove(key);
+    }
+
+    fn clear(&self) {
+        *self.values.lock().unwrap() = LruEntries::default();
+    }
+}
+
+// ========================= KvStoreReader =========================
+
+/// A single thread key value reader.
+///
+/// Each thread own its reader for concurrently reading.
+/// And `RefCell` provide inner mutability and `RwLock` for more reading operations than writing.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    fs: Arc<dyn FileSystem>,
+    readers: RefCell<HashMap<u64, LogReader>>,
+    index: Arc<RwLock<Box<dyn Index>>>,
+    cache: Arc<ValueCache>,
+    /// Files opened for direct I/O, which records are read from when
+    /// `KvStoreOptions::direct_io` is set.
+    direct_readers: Option<RefCell<HashMap<u64, Box<dyn FileHandle>>>>,
+    encoding: LogEncoding,
+    /// Counts the generations removed, shared by all the readers. A reader
+    /// that sees it move drops its handles, which may be to removed files.
+    removed_gens: Arc<AtomicU64>,
+    /// The value of `removed_gens` when this reader last dropped its handles.
+    seen_removed_gens: Cell<u64>,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            fs: Arc::clone(&self.fs),
+            readers: RefCell::new(HashMap::new()),
+            index: Arc::clone(&self.index),
+            cache: Arc::clone(&self.cache),
+            direct_readers: self.direct_readers.as_ref().map(|_| RefCell::default()),
+            encoding: self.encoding,
+            removed_gens: Arc::clone(&self.removed_gens),
+            seen_removed_gens: Cell::new(self.removed_gens.load(Ordering::SeqCst)),
+        }
+    }
+}
+
+impl KvStoreReader {
+    fn new(
+        path: Arc<PathBuf>,
+        fs: Arc<dyn FileSystem>,
+        index: Arc<RwLock<Box<dyn Index>>>,
+        cache: Arc<ValueCache>,
+        direct_io: bool,
+        encoding: LogEncoding,
+    ) -> Self {
+        let readers = RefCell::new(HashMap::new());
+        KvStoreReader {
+            path: Arc::clone(&path),
+            fs,
+            readers,
+            index,
+            cache,
+            direct_readers: if direct_io {
+                Some(RefCell::default())
+            } else {
+                None
+            },
+            encoding,
+            removed_gens: Arc::default(),
+            seen_removed_gens: Cell::new(0),
+        }
+    }
+
+    fn read<F, R>(&self, gen: &u64, func: F) -> Result<R>
+    where
+        F: FnOnce(&mut LogReader) -> Result<R> + Send,
+    {
+        self.drop_removed_handles();
+        let mut readers = self.readers.borrow_mut();
+
+        if !readers.contains_key(gen) {
+            let reader = BufReader::new(open_log(&*self.fs, &db_path(&self.path, *gen))?);
+            readers.insert(*gen, reader);
+        }
+
+        let reader = readers.get_mut(gen).unwrap();
+        func(reader)
+    }
+
+    fn add_reader(&self, gen: &u64, reader: LogReader) {
+        self.readers.borrow_mut().insert(*gen, reader);
+    }
+
+    /// Drops the handles to `gen`, before its file is removed, and makes the
+    /// readers of the other clones drop theirs at their next read.
+    fn remove_reader(&self, gen: &u64) {
+        self.readers.borrow_mut().remove(gen);
+        if let Some(direct_readers) = &self.direct_readers {
+            direct_readers.borrow_mut().remove(gen);
+        }
+        self.removed_gens.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Drops every open handle if a generation was removed since the last
+    /// call. Live generations are opened again when they are next read.
+    fn drop_removed_handles(&self) {
+        let removed_gens = self.removed_gens.load(Ordering::SeqCst);
+        if removed_gens != self.seen_removed_gens.get() {
+            self.readers.borrow_mut().clear();
+            if let Some(direct_readers) = &self.direct_readers {
+                direct_readers.borrow_mut().clear();
+            }
+            self.seen_removed_gens.set(removed_gens);
+        }
+    }
+
+    fn read_command(&self, offset: &CommandOffset) -> Result<Command> {
+        let CommandOffset { gen, pos, len } = offset;
+        if let Some(direct_readers) = &self.direct_readers {
+            self.drop_removed_handles();
+            let mut direct_readers = direct_readers.borrow_mut();
+            let file = match direct_readers.entry(*gen) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let path = db_path(&self.path, *gen);
+                    entry.insert(self.fs.open_direct(&path, false)?)
+                }
+            };
+            return self.encoding.decode(&read_direct(&mut **file, *pos, *len)?);
+        }
+        let encoding = self.encoding;
+        self.read(gen, |reader| {
+            reader.seek(SeekFrom::Start(*pos))?;
+
+            let mut buffer = vec![0u8; *len as usize];
+            reader.read_exact(&mut buffer)?;
+            encoding.decode(&buffer)
+        })
+    }
+
+    /// Reads the value set by the command at `offset`, applying deltas on top of
+    /// their base value.
+    fn read_value(&self, offset: &CommandOffset) -> Result<String> {
+        self.read_chain(offset).map(|(value, _)| value)
+    }
+
+    /// Reads the value at `offset` and the number of deltas it was built from.
+    ///
+    /// A binary value fails with `KvsError::Utf8`, read it with `read_bytes`.
+    fn read_chain(&self, offset: &CommandOffset) -> Result<(String, u32)> {
+        match self.read_command(offset)? {
+            Command::Set { value, .. } => Ok((value, 0)),
+            Command::Delta {
+                base, depth, diff, ..
+            } => Ok((diff.apply(&self.read_value(&base)?)?, depth)),
+            Command::SetBytes { value, .. } => Ok((String::from_utf8(value)?, 0)),
+            Command::SetCompressed { value, .. } => {
+                Ok((String::from_utf8(inflate_value(&value)?)?, 0))
+            }
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Reads the value set by the command at `offset` as bytes, whether it is
+    /// binary or not.
+    fn read_bytes(&self, offset: &CommandOffset) -> Result<Vec<u8>> {
+        match self.read_command(offset)? {
+            Command::Set { value, .. } => Ok(value.into_bytes()),
+            Command::Delta { base, diff, .. } => {
+                Ok(diff.apply(&self.read_value(&base)?)?.into_bytes())
+            }
+            Command::SetBytes { value, .. } => Ok(value),
+            Command::SetCompressed { value, .. } => inflate_value(&value),
+            Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Copies the live records at `entries` to the compacted generation, and
+    /// syncs it so the stale generations can be removed. The older versions of
+    /// a key in `history` are copied before its live record. The bytes copied
+    /// out of each generation are added up in `live`.
+    ///
+    /// Returns the new offsets of the live records, in the order of `entries`.
+    fn copy_live(
+        &self,
+        entries: impl IntoIterator<Item = Result<(String, CommandOffset)>>,
+        history: &HashMap<String, Vec<CommandOffset>>,
+        verify: bool,
+        compact_gen: u64,
+        compact_writer: &mut PosBufWriter<LogFile>,
+        live: &mut HashMap<u64, u64>,
+    ) -> Result<Vec<(String, CommandOffset, Option<u64>)>> {
+        let mut moved = vec![];
+        let mut kept = vec![];
+        for entry in entries {
+            let (key, offset) = entry?;
+            for old in history.get(&key).into_iter().flatten() {
+                kept.push(self.copy_record(
+                    &key,
+                    old,
+                    verify,
+                    compact_gen,
+                    compact_writer,
+                    live,
+                )?);
+            }
+            moved.push(self.copy_record(
+                &key,
+                &offset,
+                verify,
+                compact_gen,
+                compact_writer,
+                live,
+            )?);
+        }
+        compact_writer.flush()?;
+        compact_writer.writer.get_ref().sync_data()?;
+        self.verify_compacted(&kept)?;
+        self.verify_compacted(&moved)?;
+        Ok(moved)
+    }
+
+    /// Copies the record of `key` at `offset` to the compacted generation.
+    fn copy_record(
+        &self,
+        key: &str,
+        offset: &CommandOffset,
+        verify: bool,
+        compact_gen: u64,
+        compact_writer: &mut PosBufWriter<LogFile>,
+        live: &mut HashMap<u64, u64>,
+    ) -> Result<(String, CommandOffset, Option<u64>)> {
+        let CommandOffset { gen, pos, len } = *offset;
+        *live.entry(gen).or_default() += len;
+        let buffer = self.read(&gen, |reader| -> Result<Vec<u8>> {
+            reader.seek(SeekFrom::Start(pos))?;
+            let mut buffer = vec![0; len as usize];
+            reader.read_exact(&mut buffer)?;
+            Ok(buffer)
+        })?;
+        // Deltas may be based on stale records, so they are written out in full
+        let encoding = self.encoding;
+        let buffer = match encoding.decode(&buffer)? {
+            delta @ Command::Delta { .. } => {
+                encoding.encode(&delta.into_set(key.to_owned(), self.read_value(offset)?))?
+            }
+            _ => buffer,
+        };
+
+        let new_pos = compact_writer.pos;
+        compact_writer.write_all(&buffer)?;
+        let new_offset = CommandOffset::from((compact_gen, new_pos..compact_writer.pos));
+        let checksum = verify.then(|| checksum(&buffer));
+        Ok((key.to_owned(), new_offset, checksum))
+    }
+
+    /// Finds the older versions of every key to keep through a compaction of
+    /// `gens`, per `KvStoreOptions::keep_versions`: up to `keep_versions - 1`
+    /// records before the latest one, oldest first.
+    ///
+    /// A removal drops the versions before it.
+    fn version_history(
+        &self,
+        gens: &[u64],
+        options: &KvStoreOptions,
+    ) -> Result<HashMap<String, Vec<CommandOffset>>> {
+        let mut history: HashMap<String, Vec<CommandOffset>> = HashMap::new();
+        if options.keep_versions <= 1 {
+            return Ok(history);
+        }
+        for &gen in gens {
+            let file = BufReader::new(open_log(&*self.fs, &db_path(&self.path, gen))?);
+            let mut stream = Records::new(self.encoding, file);
+            let mut pos = 0;
+            while let Some(cmd) = stream.next() {
+                let new_pos = stream.byte_offset();
+                match cmd? {
+                    Command::Set { key, .. }
+                    | Command::Delta { key, .. }
+                    | Command::SetBytes { key, .. }
+                    | Command::SetCompressed { key, .. } => {
+                        let versions = history.entry(options.normalize_key(key)).or_default();
+                        if versions.len() == options.keep_versions {
+                            versions.remove(0);
+                        }
+                        versions.push(CommandOffset::from((gen, pos..new_pos)));
+                    }
+                    Command::Remove { key } => {
+                        history.remove(&options.normalize_key(key));
+                    }
+                }
+                pos = new_pos;
+            }
+        }
+        // The latest version is the live record, copied on its own
+        for versions in history.values_mut() {
+            versions.pop();
+        }
+        history.retain(|_, versions| !versions.is_empty());
+        Ok(history)
+    }
+
+    /// Checks that the records at the offsets in `moved` match their checksums.
+    fn verify_compacted(&self, moved: &[(String, CommandOffset, Option<u64>)]) -> Result<()> {
+        for (_, offset, expected) in moved {
+            let expected = match expected {
+                Some(expected) => *expected,
+                None => continue,
+            };
+            let CommandOffset { gen, pos, len } = *offset;
+            let buffer = self.read(&gen, |reader| -> Result<Vec<u8>> {
+                reader.seek(SeekFrom::Start(pos))?;
+                let mut buffer = vec![0; len as usize];
+                reader.read_exact(&mut buffer)?;
+                Ok(buffer)
+            });
+            match buffer {
+                Ok(buffer) if checksum(&buffer) == expected => {}
+                _ => return Err(KvsError::Corruption { gen, pos }),
+            }
+        }
+        Ok(())
+    }
+}
+
+// ========================= KvStoreWriter =========================
+
+struct PosBufWriter<T: Write + Seek> {
+    writer: BufWriter<T>,
+    pos: u64,
+}
+
+impl<T: Write + Seek> PosBufWriter<T> {
+    fn new(mut writer: BufWriter<T>) -> Result<Self> {
+        let pos = writer.seek(SeekFrom::End(0))?;
+        Ok(PosBufWriter { writer, pos })
+    }
+}
+
+impl PosBufWriter<LogFile> {
+    /// Discards any buffered bytes and truncates the file back to `pos`.
+    fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        self.writer.get_mut().truncate(pos)?;
+        let file = self.writer.get_ref().try_clone()?;
+        // `into_parts` drops the buffer without trying to flush it again.
+        let _ = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
+// ========================= Direct I/O =========================
+
+/// Alignment of offsets, lengths and buffers of direct I/O.
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// A generation file being written, through the page cache or around it.
+///
+/// Direct I/O only writes whole aligned blocks, so the bytes of the last
+/// partial block are kept in memory and written again, padded, by every flush.
+/// The file is then cut back to its real length.
+struct LogFile {
+    file: Box<dyn FileHandle>,
+    tail: Option<DirectTail>,
+}
+
+#[derive(Clone)]
+struct DirectTail {
+    /// Offset of the last partial block.
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl LogFile {
+    fn buffered(file: Box<dyn FileHandle>) -> Self {
+        LogFile { file, tail: None }
+    }
+
+    fn open_direct(fs: &dyn FileSystem, path: &Path) -> io::Result<Self> {
+        let file = fs.open_direct(path, true)?;
+        let mut file = LogFile {
+            file,
+            tail: Some(DirectTail {
+                start: 0,
+                bytes: vec![],
+            }),
+        };
+        file.seek(SeekFrom::End(0))?;
+        Ok(file)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(LogFile {
+            file: self.file.try_clone()?,
+            tail: self.tail.clone(),
+        })
+    }
+
+    /// Cuts the file back to `len`, discarding unflushed bytes.
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        if self.tail.is_some() {
+            self.seek(SeekFrom::Start(len))?;
+        }
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.tail {
+            Some(tail) => {
+                tail.bytes.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            None => self.file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let tail = match &mut self.tail {
+            Some(tail) => tail,
+            None => return self.file.flush(),
+        };
+        if tail.bytes.is_empty() {
+            return Ok(());
+        }
+        let len = tail.bytes.len();
+        let mut buffer = AlignedBuffer::new(align_up(len));
+        buffer.as_mut()[..len].copy_from_slice(&tail.bytes);
+        self.file.seek(SeekFrom::Start(tail.start))?;
+        self.file.write_all(buffer.as_mut())?;
+        self.file.set_len(tail.start + len as u64)?;
+        // Only the last partial block has to be written again
+        let full = len / DIRECT_IO_ALIGN * DIRECT_IO_ALIGN;
+        tail.bytes.drain(..full);
+        tail.start += full as u64;
+        Ok(())
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let tail = match &mut self.tail {
+            Some(tail) => tail,
+            None => return self.file.seek(pos),
+        };
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos,
+            SeekFrom::End(0) => self.file.size()?,
+            SeekFrom::Current(0) => tail.start + tail.bytes.len() as u64,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "unsupported seek in direct I/O",
+                ))
+            }
+        };
+        let start = pos / DIRECT_IO_ALIGN as u64 * DIRECT_IO_ALIGN as u64;
+        tail.bytes = read_direct(&mut *self.file, start, pos - start)?;
+        tail.start = start;
+        Ok(pos)
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        // Deferred records are persisted when the store is dropped
+        if let Err(e) = self.flush() {
+            error!("Failed to flush the log: {}", e);
+        }
+    }
+}
+
+/// A zeroed buffer whose start is aligned for direct I/O.
+struct AlignedBuffer {
+    bytes: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let bytes = vec![0; len + DIRECT_IO_ALIGN];
+        let offset = bytes.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        AlignedBuffer { bytes, offset, len }
+    }
+
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.offset..self.offset + self.len]
+    }
+}
+
+fn align_up(len: usize) -> usize {
+    len.div_ceil(DIRECT_IO_ALIGN) * DIRECT_IO_ALIGN
+}
+
+/// Reads `len` bytes at `pos` from a file opened for direct I/O.
+fn read_direct(file: &mut dyn FileHandle, pos: u64, len: u64) -> io::Result<Vec<u8>> {
+    let start = pos / DIRECT_IO_ALIGN as u64 * DIRECT_IO_ALIGN as u64;
+    let skip = (pos - start) as usize;
+    let mut buffer = AlignedBuffer::new(align_up(skip + len as usize));
+    let buffer = buffer.as_mut();
+    file.seek(SeekFrom::Start(start))?;
+    let mut filled = 0;
+    while filled < skip + len as usize {
+        let n = file.read(&mut buffer[filled..])?;
+        filled += n;
+        // A short read ends at the end of the file
+        if n == 0 || n % DIRECT_IO_ALIGN != 0 {
+            break;
+        }
+    }
+    if filled < skip + len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buffer[skip..skip + len as usize].to_vec())
+}
+
+impl<T: Write + Seek> Write for PosBufWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.writer.write(buf)?;
+        self.pos += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<T: Write + Seek> Seek for PosBufWriter<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    fs: Arc<dyn FileSystem>,
+    writer: PosBufWriter<LogFile>,
+    reader: KvStoreReader,
+    index: Arc<RwLock<Box<dyn Index>>>,
+    options: KvStoreOptions,
+    unflushed: Arc<AtomicBool>,
+    current_gen: u64,
+    uncompacted: u64,
+    /// Bytes of the generations before the current one, read from disk when
+    /// first needed after a switch.
+    sealed_bytes: Option<u64>,
+    durable_pos: u64,
+    /// Whether the current generation has flushed records not synced yet.
+    unsynced: bool,
+    /// The version of the last record written.
+    last_version: u64,
+    on_commit: Option<Sender<(String, Mutation)>>,
+    /// Writes not yet passed to `on_commit` because they are not flushed.
+    uncommitted: Vec<(String, Mutation)>,
+    /// Sealed generations to compress, if `compress_cold_generations` is set.
+    compress: Option<Sender<u64>>,
+    /// The thread writing `stats_file`, stopped when the writer is dropped.
+    stats_writer: Option<Periodic>,
+    /// Whether the last check of `disk_space_check` found the space low.
+    low_space: Arc<AtomicBool>,
+    /// The thread running `disk_space_check`, stopped when the writer is
+    /// dropped.
+    space_checker: Option<Periodic>,
+    /// The thread running `SyncPolicy::Interval`, stopped when the writer is
+    /// dropped.
+    syncer: Option<Periodic>,
+    /// The thread running `CompactionPolicy::Background` compactions.
+    compactor: Option<Compactor>,
+    /// Whether a background compaction was requested and has not ended yet.
+    compacting: bool,
+    /// Totals of the compactions that completed, for `KvStats::compaction`.
+    compaction_stats: CompactionStats,
+}
+
+impl KvStoreWriter {
+    fn new(
+        path: Arc<PathBuf>,
+        writer: BufWriter<LogFile>,
+        reader: KvStoreReader,
+        index: Arc<RwLock<Box<dyn Index>>>,
+        options: KvStoreOptions,
+        unflushed: Arc<AtomicBool>,
+        current_gen: u64,
+    ) -> Result<Self> {
+        let writer = PosBufWriter::new(writer)?;
+        Ok(KvStoreWriter {
+            path,
+            // The writer opens the same files as its reader
+            fs: Arc::clone(&reader.fs),
+            durable_pos: writer.pos,
+            unsynced: false,
+            writer,
+            reader,
+            index,
+            options,
+            unflushed,
+            current_gen,
+            uncompacted: 0,
+            sealed_bytes: None,
+            last_version: 0,
+            on_commit: None,
+            uncommitted: vec![],
+            compress: None,
+            stats_writer: None,
+            low_space: Arc::default(),
+            space_checker: None,
+            syncer: None,
+            compactor: None,
+            compacting: false,
+            compaction_stats: CompactionStats::default(),
+        })
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<u64> {
+        self.append_set(key, value, true)
+    }
+
+    fn set_deferred(&mut self, key: String, value: String) -> Result<()> {
+        self.append_set(key, value, false).map(|_| ())
+    }
+
+    fn set_expiring(&mut self, key: String, value: String, expires_at: u64) -> Result<u64> {
+        let key = self.options.normalize_key(key);
+        self.append_expiring(key, value, Some(expires_at), true)
+    }
+
+    /// Removes the expired keys from the index, their records are stale.
+    fn purge_expired(&mut self) -> Result<()> {
+        let expired = self.index.write().unwrap().purge_expired()?;
+        for (key, offset) in expired {
+            self.uncompacted += offset.len;
+            self.reader.cache.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.append_set(key, value, false)?;
+        }
+        self.flush()
+    }
+
+    fn set_if_version(&mut self, key: String, value: String, version: u64) -> Result<u64> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        let current = match offset {
+            Some(offset) => self.reader.read_command(&offset)?.version(),
+            None => 0,
+        };
+        if current != version {
+            return Err(KvsError::VersionMismatch { current });
+        }
+        self.set(key, value)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Err(e) = self.writer.flush() {
+            return Err(self.rollback(e.into()));
+        }
+        if self.writer.pos != self.durable_pos {
+            if self.options.sync_policy == SyncPolicy::EveryWrite {
+                if let Err(e) = self.writer.writer.get_ref().sync_data() {
+                    return Err(self.rollback(e.into()));
+                }
+            } else {
+                self.unsynced = true;
+            }
+        }
+        self.durable_pos = self.writer.pos;
+        self.unflushed.store(false, Ordering::SeqCst);
+        self.commit();
+        Ok(())
+    }
+
+    /// Flushes the log and syncs the current generation to disk, unless it
+    /// has nothing unsynced.
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        if self.unsynced {
+            self.writer.writer.get_ref().sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Syncs the current generation before writes move to a new one, which
+    /// the thread of `SyncPolicy::Interval` syncs instead.
+    fn sync_before_switch(&mut self) -> Result<()> {
+        if let SyncPolicy::Interval(_) = self.options.sync_policy {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Queues a write for `on_commit`, passed on by the next `commit`.
+    fn record_commit(&mut self, key: String, mutation: Mutation) {
+        if self.on_commit.is_some() {
+            self.uncommitted.push((key, mutation));
+        }
+    }
+
+    /// Passes the flushed and indexed writes to `on_commit`.
+    fn commit(&mut self) {
+        if let Some(on_commit) = &self.on_commit {
+            for write in self.uncommitted.drain(..) {
+                if on_commit.send(write).is_err() {
+                    break;
+                }
+            }
+        }
+        self.uncommitted.clear();
+    }
+
+    fn write_command(&mut self, command: &Command) -> Result<()> {
+        if self.low_space.load(Ordering::SeqCst)
+            && matches!(
+                self.options.disk_space_check,
+                Some(DiskSpaceCheck {
+                    action: LowSpaceAction::RefuseWrites,
+                    ..
+                })
+            )
+        {
+            // Earlier records of a batch are dropped too
+            return Err(self.rollback(KvsError::DiskFull));
+        }
+        match self.options.log_encoding.write(&mut self.writer, command) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.rollback(e)),
+        }
+    }
+
+    /// Truncates the log back to the last flushed record after a failed write,
+    /// so a torn record never reaches `load_index`. Returns the original error.
+    ///
+    /// Deferred records lost by the truncation may already be indexed, in which
+    /// case the index is rebuilt from the log.
+    fn rollback(&mut self, err: KvsError) -> KvsError {
+        let result = self
+            .writer
+            .truncate(self.durable_pos)
+            .map_err(KvsError::from)
+            .and_then(|_| {
+                // Deferred writes lost by the truncation were never committed
+                self.uncommitted.clear();
+                if self.unflushed.swap(false, Ordering::SeqCst) {
+                    self.reload_index()
+                } else {
+                    Ok(())
+                }
+            });
+        if let Err(e) = result {
+            error!("Failed to roll back a torn write: {}", e);
+        }
+        err
+    }
+
+    fn reload_index(&mut self) -> Result<()> {
+        let mut index = self.options.index.build(&self.path)?;
+        for gen in generations(&*self.fs, &self.path)? {
+            let mut reader = BufReader::new(open_log(&*self.fs, &db_path(&self.path, gen))?);
+            load_index(gen, &mut reader, &mut *index, &self.options)?;
+        }
+        let mut current = self.index.write().unwrap();
+        *current = index;
+        self.reader.cache.clear();
+        Ok(())
+    }
+
+    fn append_set(&mut self, key: String, value: String, flush: bool) -> Result<u64> {
+        let key = self.options.normalize_key(key);
+        self.append_expiring(key, value, None, flush)
+    }
+
+    /// Appends a `set` like `append_set` that expires at `expires_at`, or
+    /// never. Any `set` replaces the expiration of the key.
+    fn append_expiring(
+        &mut self,
+        key: String,
+        value: String,
+        expires_at: Option<u64>,
+        flush: bool,
+    ) -> Result<u64> {
+        self.last_version += 1;
+        let version = self.last_version;
+        let committed = self.on_commit.as_ref().map(|_| Mutation::Set {
+            value: value.clone(),
+            version,
+        });
+        let command = self.encode_set(key.clone(), value, version, expires_at)?;
+        self.append_indexed(key, &command, expires_at, committed, flush)?;
+        Ok(version)
+    }
+
+    /// Appends a `set` of the normalized `key` to a binary value. UTF-8 values
+    /// are set like `set`, so their records are the same.
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<u64> {
+        let value = match String::from_utf8(value) {
+            Ok(value) => return self.set(key, value),
+            Err(e) => e.into_bytes(),
+        };
+        let key = self.options.normalize_key(key);
+        self.last_version += 1;
+        let version = self.last_version;
+        let committed = self.on_commit.as_ref().map(|_| Mutation::SetBytes {
+            value: value.clone(),
+            version,
+        });
+        let command = self.compress(Command::SetBytes {
+            key: key.clone(),
+            value,
+            version,
+            modified_at: now_millis(),
+        })?;
+        self.append_indexed(key, &command, None, committed, true)?;
+        Ok(version)
+    }
+
+    /// Appends the `set` record `command` of the normalized `key`, points the
+    /// index at it and passes `committed` to `on_commit`.
+    fn append_indexed(
+        &mut self,
+        key: String,
+        command: &Command,
+        expires_at: Option<u64>,
+        committed: Option<Mutation>,
+        flush: bool,
+    ) -> Result<()> {
+        let pos = self.writer.pos;
+        self.write_command(command)?;
+        if flush {
+            self.flush()?;
+        } else {
+            self.unflushed.store(true, Ordering::SeqCst);
+        }
+
+        if !self.options.append_only {
+            let new_pos = self.writer.pos;
+            let offset = CommandOffset::from((self.current_gen, pos..new_pos));
+            let mut index = self.index.write().unwrap();
+            self.reader.cache.remove(&key);
+            if let Some(offset) = index.insert(key.clone(), offset)? {
+                self.uncompacted += offset.len;
+            }
+            index.set_expiry(&key, expires_at);
+        }
+        if let Some(mutation) = committed {
+            self.record_commit(key, mutation);
+        }
+        if flush {
+            self.commit();
+        }
+
+        self.maybe_compact()
+    }
+
+    /// Encodes a `set` as a delta against the current value of `key` if deltas
+    /// are enabled and the delta is the smaller record. A `set` that expires is
+    /// always written in full, and so is one written during a background
+    /// compaction, which would remove the generation of its base.
+    fn encode_set(
+        &mut self,
+        key: String,
+        value: String,
+        version: u64,
+        expires_at: Option<u64>,
+    ) -> Result<Command> {
+        let modified_at = now_millis();
+        let base = self.index.read().unwrap().get(&key)?;
+        let base = match base {
+            Some(base) if self.options.deltas && expires_at.is_none() && !self.compacting => base,
+            _ => {
+                return self.compress(Command::Set {
+                    key,
+                    value,
+                    version,
+                    modified_at,
+                    expires_at,
+                })
+            }
+        };
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let (old, depth) = match self.reader.read_chain(&base) {
+            Ok(chain) => chain,
+            // A binary value is no base for a delta
+            Err(KvsError::Utf8(_)) => (String::new(), MAX_DELTA_DEPTH),
+            Err(e) => return Err(e),
+        };
+        if depth >= MAX_DELTA_DEPTH {
+            return self.compress(Command::Set {
+                key,
+                value,
+                version,
+                modified_at,
+                expires_at: None,
+            });
+        }
+        let delta = Command::Delta {
+            key: key.clone(),
+            base,
+            depth: depth + 1,
+            diff: Diff::new(&old, &value),
+            version,
+            modified_at,
+        };
+        let set = self.compress(Command::Set {
+            key,
+            value,
+            version,
+            modified_at,
+            expires_at: None,
+        })?;
+        let encoding = self.options.log_encoding;
+        if encoding.encode(&delta)?.len() < encoding.encode(&set)?.len() {
+            Ok(delta)
+        } else {
+            Ok(set)
+        }
+    }
+
+    /// Turns a full `set` record into a `SetCompressed` one if
+    /// `compress_values` is set and the compressed record is the smaller.
+    fn compress(&self, command: Command) -> Result<Command> {
+        if !self.options.compress_values {
+            return Ok(command);
+        }
+        let compressed = match &command {
+            Command::Set {
+                key,
+                value,
+                version,
+                modified_at,
+                expires_at: None,
+            } if value.len() >= MIN_COMPRESSED_VALUE => Command::SetCompressed {
+                key: key.clone(),
+                value: deflate_value(value.as_bytes())?,
+                version: *version,
+                modified_at: *modified_at,
+            },
+            Command::SetBytes {
+                key,
+                value,
+                version,
+                modified_at,
+            } if value.len() >= MIN_COMPRESSED_VALUE => Command::SetCompressed {
+                key: key.clone(),
+                value: deflate_value(value)?,
+                version: *version,
+                modified_at: *modified_at,
+            },
+            _ => return Ok(command),
+        };
+        let encoding = self.options.log_encoding;
+        if encoding.encode(&compressed)?.len() < encoding.encode(&command)?.len() {
+            Ok(compressed)
+        } else {
+            Ok(command)
+        }
+    }
+
+    fn update(&mut self, key: String, f: Transform) -> Result<Option<String>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        let value = offset
+            .map(|offset| self.reader.read_value(&offset))
+            .transpose()?;
+        let existed = value.is_some();
+        match f(value) {
+            Some(value) => {
+                self.set(key, value.clone())?;
+                Ok(Some(value))
+            }
+            None if existed => {
+                self.remove(key)?;
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Runs `ops` in order. Sets are appended without flushing, the next read,
+    /// removal or the end of the batch flushes them.
+    fn batch(&mut self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        if self.options.append_only && ops.iter().any(|op| !matches!(op, Op::Set(..))) {
+            return Err(KvsError::AppendOnly);
+        }
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match op {
+                Op::Get(key) => {
+                    if self.unflushed.load(Ordering::SeqCst) {
+                        self.flush()?;
+                    }
+                    let key = self.options.normalize_key(key);
+                    let offset = self.index.read().unwrap().get(&key)?;
+                    let value = offset
+                        .map(|offset| self.reader.read_value(&offset))
+                        .transpose()?;
+                    OpResult::Value(value)
+                }
+                Op::Set(key, value) => {
+                    self.append_set(key, value, false)?;
+                    OpResult::Set
+                }
+                Op::Remove(key) => match self.remove(key) {
+                    Ok(()) => OpResult::Removed(true),
+                    Err(KvsError::KeyNotFound) => OpResult::Removed(false),
+                    Err(e) => return Err(e),
+                },
+            };
+            results.push(result);
+        }
+        self.flush()?;
+        Ok(results)
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        let current = offset
+            .map(|offset| self.reader.read_value(&offset))
+            .transpose()?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                self.set(key, value)?;
+            }
+            None if current.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// Sets `key` like `set`, reading the value of its current record first.
+    /// `set` looks the record up again when it writes a delta against it.
+    fn set_returning(&mut self, key: String, value: String) -> Result<Option<String>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        let previous = offset
+            .map(|offset| self.reader.read_value(&offset))
+            .transpose()?;
+        self.set(key, value)?;
+        Ok(previous)
+    }
+
+    fn remove_returning(&mut self, key: String) -> Result<Option<String>> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        match offset {
+            Some(offset) => {
+                let previous = self.reader.read_value(&offset)?;
+                self.remove(key)?;
+                Ok(Some(previous))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Sets `key` to its current value patched by `diff`.
+    fn set_delta(&mut self, key: String, diff: &Diff) -> Result<()> {
+        let key = self.options.normalize_key(key);
+        let base = self.index.read().unwrap().get(&key)?;
+        let base = base.ok_or(KvsError::KeyNotFound)?;
+        let value = diff.apply(&self.reader.read_value(&base)?)?;
+        self.set(key, value).map(|_| ())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if !self.index.read().unwrap().contains_key(&key)? {
+            Err(KvsError::KeyNotFound)
+        } else {
+            let command = Command::Remove { key: key.clone() };
+
+            self.write_command(&command)?;
+            self.flush()?;
+
+            let offset = {
+                let mut index = self.index.write().unwrap();
+                self.reader.cache.remove(&key);
+                index.remove(&key)?.expect("Unreachable: key not found")
+            };
+            self.uncompacted += offset.len;
+            self.record_commit(key, Mutation::Remove);
+            self.commit();
+
+            self.maybe_compact()?;
+
+            Ok(())
+        }
+    }
+
+    fn next_id(&mut self, namespace: String) -> Result<u64> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        if self.options.reserved_prefix.is_empty() {
+            return Err(KvsError::Unsupported(
+                "ids without a reserved prefix".to_owned(),
+            ));
+        }
+        let key = format!("{}id:{}", self.options.reserved_prefix, namespace);
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        let last = match offset {
+            Some(offset) => self
+                .reader
+                .read_value(&offset)?
+                .parse::<u64>()
+                .map_err(|e| KvsError::StringError(format!("bad id counter {}: {}", key, e)))?,
+            None => 0,
+        };
+        let id = last + 1;
+        self.set(key, id.to_string())?;
+        self.sync()?;
+        Ok(id)
+    }
+
+    fn set_if_changed(&mut self, key: String, value: String) -> Result<bool> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let (offset, expiry) = {
+            let index = self.index.read().unwrap();
+            (index.get(&key)?, index.expiry(&key))
+        };
+        if let (Some(offset), None) = (offset, expiry) {
+            if self.reader.read_value(&offset)? == value {
+                return Ok(false);
+            }
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        let current = match offset {
+            Some(offset) => self
+                .reader
+                .read_value(&offset)?
+                .parse::<i64>()
+                .map_err(|_| KvsError::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| KvsError::StringError(format!("increment of {} overflows", key)))?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    fn get_or_insert_with<F: FnOnce() -> String>(&mut self, key: String, f: F) -> Result<String> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        let key = self.options.normalize_key(key);
+        if self.unflushed.load(Ordering::SeqCst) {
+            self.flush()?;
+        }
+        let offset = self.index.read().unwrap().get(&key)?;
+        if let Some(offset) = offset {
+            return self.reader.read_value(&offset);
+        }
+        let value = f();
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+
+    fn remove_many(&mut self, keys: Vec<String>) -> Result<usize> {
+        if self.options.append_only {
+            return Err(KvsError::AppendOnly);
+        }
+        // In the order of `keys`, so `on_commit` sees the removals in that order
+        let mut seen = HashSet::new();
+        let mut removed = vec![];
+        {
+            let index = self.index.read().unwrap();
+            for key in keys {
+                let key = self.options.normalize_key(key);
+                if index.contains_key(&key)? && seen.insert(key.clone()) {
+                    removed.push(key);
+                }
+            }
+        }
+        if removed.is_empty() {
+            return Ok(0);
+        }
+
+        // A failed write or flush rolls back every tombstone of the batch, so
+        // the index is only updated once all of them are durable
+        for key in &removed {
+            self.write_command(&Command::Remove { key: key.clone() })?;
+        }
+        self.flush()?;
+
+        {
+            let mut index = self.index.write().unwrap();
+            for key in &removed {
+                self.reader.cache.remove(key);
+                let offset = index.remove(key)?.expect("Unreachable: key not found");
+                self.uncompacted += offset.len;
+            }
+        }
+        let count = removed.len();
+        for key in removed {
+            self.record_commit(key, Mutation::Remove);
+        }
+        self.commit();
+
+        self.maybe_compact()?;
+        Ok(count)
+    }
+
+    /// Compacts once stale records take up the compaction threshold and ratio,
+    /// or hands the compaction to the background compactor.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.uncompacted < self.options.compaction_threshold {
+            return Ok(());
+        }
+        let ratio = self.uncompacted as f64 / self.log_size()? as f64;
+        if ratio < self.options.compaction_ratio {
+            return Ok(());
+        }
+        match (self.options.compaction, &self.compactor) {
+            (CompactionPolicy::Automatic, _) => {
+                self.compact()?;
+            }
+            (CompactionPolicy::Background, Some(compactor)) if !self.compacting => {
+                compactor.signal();
+                self.compacting = true;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn compact_tiered(&mut self) -> Result<()> {
+        self.flush()?;
+        self.sync_before_switch()?;
+        if self.options.append_only {
+            return Ok(());
+        }
+        let policy = &self.options.tiered_compaction;
+        let mut runs = vec![];
+        let mut run = vec![];
+        // The active generation may join a run too, a fresh one replaces it below
+        for gen in generations(&*self.fs, &self.path)? {
+            if self.fs.size(&db_path(&self.path, gen))? < policy.small_generation_size {
+                run.push(gen);
+            } else {
+                runs.push(mem::take(&mut run));
+            }
+        }
+        runs.push(run);
+        // Prefer the longest run, and the oldest one among equals
+        let run = match runs
+            .into_iter()
+            .filter(|run| run.len() >= policy.min_generations.max(2))
+            .rev()
+            .max_by_key(Vec::len)
+        {
+            Some(run) => run,
+            None => return Ok(()),
+        };
+
+        let merged_gen = self.current_gen + 1;
+        let direct = self.options.direct_io;
+        let (merge_writer, merge_reader) =
+            new_db_log(&*self.fs, &db_path(&self.path, merged_gen), direct)?;
+        let (new_writer, new_reader) =
+            new_db_log(&*self.fs, &db_path(&self.path, merged_gen + 1), direct)?;
+        let mut merge_writer = PosBufWriter::new(merge_writer)?;
+
+        self.current_gen = merged_gen + 1;
+        self.sealed_bytes = None;
+        self.writer = PosBufWriter::new(new_writer)?;
+        self.durable_pos = 0;
+        self.reader.add_reader(&merged_gen, merge_reader);
+        self.reader.add_reader(&self.current_gen, new_reader);
+
+        // Live records are copied as is. A removal is kept only while its key is
+        // absent, since an older generation outside the run may still set it.
+        // Deltas are written out in full, as are keys whose latest delta lives
+        // outside the run, because its base may be one of the merged records.
+        let mut index = self.index.write().unwrap();
+        index.purge_expired()?;
+        let now = now_millis();
+        let mut removed = HashSet::new();
+        for gen in run.iter().copied() {
+            let mut data = vec![];
+            open_log(&*self.fs, &db_path(&self.path, gen))?.read_to_end(&mut data)?;
+            let encoding = self.options.log_encoding;
+            let mut stream = Records::new(encoding, &data[..]);
+            let mut pos = 0;
+            while let Some(cmd) = stream.next() {
+                let end = stream.byte_offset() as usize;
+                let cmd = cmd?;
+                let is_set = matches!(
+                    cmd,
+                    Command::Set { .. } | Command::SetBytes { .. } | Command::SetCompressed { .. }
+                );
+                let expired = matches!(
+                    cmd,
+                    Command::Set { expires_at: Some(expires_at), .. } if expires_at <= now
+                );
+                match cmd {
+                    Command::Set { key, .. }
+                    | Command::Delta { key, .. }
+                    | Command::SetBytes { key, .. }
+                    | Command::SetCompressed { key, .. } => {
+                        let key = self.options.normalize_key(key);
+                        let (offset, live) = match index.get(&key)? {
+                            Some(offset) if offset.gen == gen && offset.pos == pos as u64 => {
+                                (offset, true)
+                            }
+                            Some(offset)
+                                if offset.gen != merged_gen
+                                    && !run.contains(&offset.gen)
+                                    && self.reader.read_command(&offset)?.is_delta() =>
+                            {
+                                (offset, false)
+                            }
+                            _ => {
+                                // An expired key is removed, since an older
+                                // generation outside the run may still set it
+                                if expired
+                                    && !index.contains_key(&key)?
+                                    && removed.insert(key.clone())
+                                {
+                                    encoding.write(&mut merge_writer, &Command::Remove { key })?;
+                                }
+                                pos = end;
+                                continue;
+                            }
+                        };
+                        let new_pos = merge_writer.pos;
+                        if live && is_set {
+                            merge_writer.write_all(&data[pos..end])?;
+                        } else {
+                            let value = self.reader.read_value(&offset)?;
+                            let set = self.reader.read_command(&offset)?;
+                            encoding.write(&mut merge_writer, &set.into_set(key.clone(), value))?;
+                        }
+                        index.insert(
+                            key,
+                            CommandOffset::from((merged_gen, new_pos..merge_writer.pos)),
+                        )?;
+                    }
+                    Command::Remove { key } => {
+                        let key = self.options.normalize_key(key);
+                        if !index.contains_key(&key)? && removed.insert(key) {
+                            merge_writer.write_all(&data[pos..end])?;
+                        }
+                    }
+                }
+                pos = end;
+            }
+        }
+        merge_writer.flush()?;
+        merge_writer.writer.get_ref().sync_data()?;
+        drop(index);
+
+        self.save_version_mark()?;
+        for gen in run.iter() {
+            self.reader.remove_reader(gen);
+            self.fs.remove_file(&db_path(&self.path, *gen))?;
+        }
+        self.seal(merged_gen);
+
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport> {
+        let started = Instant::now();
+        self.flush()?;
+        self.sync_before_switch()?;
+        // Nothing to reclaim, and the empty index would drop every record
+        if self.options.append_only {
+            return Ok(CompactionReport::default());
+        }
+        let bytes_before = self.log_bytes()?;
+        let direct = self.options.direct_io;
+        let (compact_writer, compact_reader) = new_db_log(
+            &*self.fs,
+            &db_path(&self.path, self.current_gen + 1),
+            direct,
+        )?;
+        let (new_writer, new_reader) = new_db_log(
+            &*self.fs,
+            &db_path(&self.path, self.current_gen + 2),
+            direct,
+        )?;
+        let mut compact_writer = PosBufWriter::new(compact_writer)?;
 
         let current_gen = self.current_gen + 2;
         self.current_gen = current_gen;
+        self.sealed_bytes = None;
         self.writer = PosBufWriter::new(new_writer)?;
+        self.durable_pos = 0;
         self.reader
             .add_reader(&(self.current_gen - 1), compact_reader);
         self.reader.add_reader(&self.current_gen, new_reader);
 
-        for (_, value) in self.index.write().unwrap().iter_mut() {
-            let CommandOffset { gen, pos, len } = value;
-            let buffer = self
-                .reader
-                .read(&gen.clone(), |reader| -> Result<Vec<u8>> {
-                    reader.seek(SeekFrom::Start(*pos))?;
-                    let mut buffer = vec![0; *len as usize];
-                    reader.read_exact(&mut buffer)?;
+        let compact_gen = current_gen - 1;
+        let stale_gens = generations(&*self.fs, &self.path)?
+            .into_iter()
+            .filter(|gen| *gen < compact_gen)
+            .collect::<Vec<u64>>();
+        let history = self.reader.version_history(&stale_gens, &self.options);
+        let mut index = self.index.write().unwrap();
+        index.purge_expired()?;
+        // A failed compaction leaves the old generations in use, the partial
+        // compacted one would only shadow them with stale copies on reopen
+        let mut live = HashMap::new();
+        let moved = match history.and_then(|history| {
+            self.reader.copy_live(
+                index.entries(),
+                &history,
+                self.options.verify_compaction,
+                compact_gen,
+                &mut compact_writer,
+                &mut live,
+            )
+        }) {
+            Ok(moved) => moved,
+            Err(e) => {
+                self.reader.remove_reader(&compact_gen);
+                self.fs.remove_file(&db_path(&self.path, compact_gen))?;
+                return Err(e);
+            }
+        };
+        let records_retained = moved.len();
+        for (key, offset, _) in moved {
+            index.insert(key, offset)?;
+        }
+        drop(index);
+        self.uncompacted = 0;
+
+        let mut report = CompactionReport {
+            generations: BTreeMap::new(),
+            written: compact_writer.pos,
+            bytes_before,
+            bytes_after: 0,
+            records_retained,
+            duration: Duration::ZERO,
+        };
+        self.save_version_mark()?;
+        for ref gen in stale_gens {
+            let path = db_path(&self.path, *gen);
+            let size = self.fs.size(&path)?;
+            let live = live.get(gen).copied().unwrap_or(0);
+            self.reader.remove_reader(gen);
+            self.fs.remove_file(&path)?;
+            report.generations.insert(
+                *gen,
+                GenerationReclaim {
+                    live,
+                    dead: size.saturating_sub(live),
+                },
+            );
+        }
+        self.seal(compact_gen);
+
+        report.bytes_after = self.log_bytes()?;
+        report.duration = started.elapsed();
+        self.record_compaction(
+            report.bytes_before,
+            report.bytes_after,
+            report.records_retained,
+            report.duration,
+        );
+        Ok(report)
+    }
+
+    /// Starts a background compaction: seals the generations to compact behind
+    /// a new one taking the writes, and takes the index entries to copy.
+    ///
+    /// Returns `None` if there is nothing to compact.
+    fn begin_compaction(&mut self) -> Result<Option<PendingCompaction>> {
+        self.compacting = false;
+        let started = Instant::now();
+        self.flush()?;
+        self.sync_before_switch()?;
+        if self.options.append_only {
+            return Ok(None);
+        }
+        let bytes_before = self.log_bytes()?;
+        let direct = self.options.direct_io;
+        let compact_gen = self.current_gen + 1;
+        let (compact_writer, compact_reader) =
+            new_db_log(&*self.fs, &db_path(&self.path, compact_gen), direct)?;
+        let (new_writer, new_reader) =
+            new_db_log(&*self.fs, &db_path(&self.path, compact_gen + 1), direct)?;
+        let compact_writer = PosBufWriter::new(compact_writer)?;
+
+        self.current_gen = compact_gen + 1;
+        self.sealed_bytes = None;
+        self.writer = PosBufWriter::new(new_writer)?;
+        self.durable_pos = 0;
+        self.reader.add_reader(&compact_gen, compact_reader);
+        self.reader.add_reader(&self.current_gen, new_reader);
+
+        self.purge_expired()?;
+        let entries = self
+            .index
+            .read()
+            .unwrap()
+            .entries()
+            .collect::<Result<_>>()?;
+        let gens = generations(&*self.fs, &self.path)?
+            .into_iter()
+            .filter(|gen| *gen < compact_gen)
+            .collect();
+        // The records overwritten from now on were already counted when their
+        // compacted copy is left behind
+        self.uncompacted = 0;
+        self.compacting = true;
+        Ok(Some(PendingCompaction {
+            gen: compact_gen,
+            writer: compact_writer,
+            reader: self.reader.clone(),
+            entries,
+            gens,
+            options: self.options.clone(),
+            started,
+            bytes_before,
+        }))
+    }
+
+    /// Ends a background compaction once its live records are `moved`.
+    ///
+    /// The index only points to a compacted record if the key still has the
+    /// entry it had when the compaction began. A key written or removed
+    /// meanwhile keeps its newer entry, whose record lives in a later
+    /// generation, so reopening the log agrees with the index.
+    fn finish_compaction(
+        &mut self,
+        compaction: PendingCompaction,
+        moved: Result<Vec<(String, CommandOffset, Option<u64>)>>,
+    ) -> Result<()> {
+        self.compacting = false;
+        let compact_gen = compaction.gen;
+        let moved = match moved {
+            Ok(moved) => moved,
+            Err(e) => {
+                self.reader.remove_reader(&compact_gen);
+                self.fs.remove_file(&db_path(&self.path, compact_gen))?;
+                return Err(e);
+            }
+        };
+        let records_retained = moved.len();
+        {
+            let mut index = self.index.write().unwrap();
+            for ((key, old_offset), (_, new_offset, _)) in compaction.entries.into_iter().zip(moved)
+            {
+                if index.get(&key)? == Some(old_offset) {
+                    index.insert(key, new_offset)?;
+                }
+            }
+        }
+
+        self.save_version_mark()?;
+        for gen in generations(&*self.fs, &self.path)? {
+            if gen < compact_gen {
+                self.reader.remove_reader(&gen);
+                self.fs.remove_file(&db_path(&self.path, gen))?;
+            }
+        }
+        self.seal(compact_gen);
+        let bytes_after = self.log_bytes()?;
+        self.record_compaction(
+            compaction.bytes_before,
+            bytes_after,
+            records_retained,
+            compaction.started.elapsed(),
+        );
+        Ok(())
+    }
+
+    /// Adds a completed compaction to the totals and reports it to the
+    /// `Metrics` of the options.
+    fn record_compaction(
+        &mut self,
+        bytes_before: u64,
+        bytes_after: u64,
+        records_retained: usize,
+        duration: Duration,
+    ) {
+        self.compaction_stats
+            .add(bytes_before, bytes_after, records_retained, duration);
+        if let Some(metrics) = &self.options.metrics {
+            metrics.on_compaction(bytes_before, bytes_after, duration);
+        }
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.flush()?;
+        let reserved = self.reserved_entries()?;
+        let new_gen = self.current_gen + 1;
+        let (new_writer, new_reader) = new_db_log(
+            &*self.fs,
+            &db_path(&self.path, new_gen),
+            self.options.direct_io,
+        )?;
+
+        self.current_gen = new_gen;
+        self.sealed_bytes = None;
+        self.writer = PosBufWriter::new(new_writer)?;
+        self.durable_pos = 0;
+        self.reader.add_reader(&new_gen, new_reader);
+        {
+            let mut index = self.index.write().unwrap();
+            *index = self.options.index.build(&self.path)?;
+            self.reader.cache.clear();
+        }
+        self.uncompacted = 0;
+        for (key, value) in reserved {
+            self.append_set(key, value, false)?;
+        }
+        self.sync()?;
+        self.save_version_mark()?;
+        // From here on, opening the store removes the older generations
+        replace_file(
+            &*self.fs,
+            &clear_marker(&self.path),
+            new_gen.to_string().as_bytes(),
+        )?;
+
+        for gen in generations(&*self.fs, &self.path)? {
+            if gen < new_gen {
+                self.reader.remove_reader(&gen);
+            }
+        }
+        finish_clear(&*self.fs, &self.path)
+    }
+
+    /// Saves `last_version` before generations are removed, since they may
+    /// hold the records of the highest versions. A reopened store then never
+    /// hands out a version again.
+    fn save_version_mark(&self) -> Result<()> {
+        replace_file(
+            &*self.fs,
+            &version_mark(&self.path),
+            self.last_version.to_string().as_bytes(),
+        )
+    }
+
+    /// Returns the entries of the reserved keys, which `clear` keeps.
+    fn reserved_entries(&self) -> Result<Vec<(String, String)>> {
+        if self.options.reserved_prefix.is_empty() {
+            return Ok(vec![]);
+        }
+        let prefix = self
+            .options
+            .normalize_key(self.options.reserved_prefix.clone());
+        let mut offsets = vec![];
+        for entry in self.index.read().unwrap().entries() {
+            let (key, offset) = entry?;
+            if key.starts_with(&prefix) {
+                offsets.push((key, offset));
+            }
+        }
+        offsets
+            .into_iter()
+            .map(|(key, offset)| Ok((key, self.reader.read_value(&offset)?)))
+            .collect()
+    }
+
+    /// Hands a generation that is no longer written to the compressor.
+    fn seal(&self, gen: u64) {
+        if let Some(compress) = &self.compress {
+            // The compressor only stops when the writer is dropped
+            let _ = compress.send(gen);
+        }
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        // Waits for a background compaction, which then removes its partial
+        // generation instead of swapping it in
+        self.compactor.take();
+        if !self.options.index_snapshot || self.options.append_only {
+            return;
+        }
+        if let Err(e) = self.flush().and_then(|_| self.save_snapshot()) {
+            error!("Failed to save the index snapshot: {}", e);
+        }
+    }
+}
+
+impl KvStoreWriter {
+    fn stats(&self) -> Result<KvStats> {
+        let sizes = generation_sizes(&*self.fs, &self.path)?;
+        let index = self.index.read().unwrap();
+        Ok(KvStats {
+            taken_at: now_millis(),
+            keys: index.len()?,
+            generations: sizes.len(),
+            log_bytes: sizes.iter().map(|(_, size)| size).sum(),
+            uncompacted_bytes: self.uncompacted,
+            index_memory_bytes: index.memory_bytes()?,
+            last_version: self.last_version,
+            compaction: self.compaction_stats.clone(),
+        })
+    }
+
+    /// Size of all the generation files, in bytes.
+    /// Returns the size of the log, the records still buffered included.
+    fn log_size(&mut self) -> Result<u64> {
+        let sealed_bytes = match self.sealed_bytes {
+            Some(sealed_bytes) => sealed_bytes,
+            None => {
+                let sealed_bytes = generation_sizes(&*self.fs, &self.path)?
+                    .iter()
+                    .filter(|(gen, _)| *gen != self.current_gen)
+                    .map(|(_, size)| size)
+                    .sum();
+                *self.sealed_bytes.insert(sealed_bytes)
+            }
+        };
+        Ok(sealed_bytes + self.writer.pos)
+    }
+
+    fn log_bytes(&self) -> Result<u64> {
+        Ok(generation_sizes(&*self.fs, &self.path)?
+            .iter()
+            .map(|(_, size)| size)
+            .sum())
+    }
+
+    fn save_snapshot(&self) -> Result<()> {
+        let index = self.index.read().unwrap();
+        let snapshot = IndexSnapshot {
+            case_insensitive: self.options.case_insensitive,
+            last_version: self.last_version,
+            generations: generation_sizes(&*self.fs, &self.path)?,
+            entries: index.entries().collect::<Result<_>>()?,
+            expiries: index.expiries(),
+        };
+        drop(index);
+        write_snapshot(
+            &*self.fs,
+            &self.path,
+            &snapshot,
+            self.options.compress_index_snapshot,
+        )
+    }
+}
+
+fn checksum(buffer: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buffer.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A reader of a generation file.
+type LogReader = BufReader<Box<dyn FileHandle>>;
+
+fn db_path(path: &Path, gen: u64) -> PathBuf {
+    let file_name = format!("{}.log", gen);
+    path.join(file_name)
+}
+
+fn new_db_log(
+    fs: &dyn FileSystem,
+    path: &Path,
+    direct: bool,
+) -> Result<(BufWriter<LogFile>, LogReader)> {
+    let file = fs.open_rw(path)?;
+
+    // The reader opens the file again rather than cloning the handle, since a
+    // cloned handle shares its offset and a read would move the next append
+    let writer = if direct {
+        BufWriter::new(LogFile::open_direct(fs, path)?)
+    } else {
+        BufWriter::new(LogFile::buffered(file))
+    };
+    let reader = BufReader::new(fs.open(path)?);
+
+    Ok((writer, reader))
+}
+
+fn generations(fs: &dyn FileSystem, path: &Path) -> Result<Vec<u64>> {
+    Ok(generation_files(fs, path)?
+        .into_iter()
+        .map(|(gen, _)| gen)
+        .collect())
+}
+
+/// Returns the log files in `path` with their generation numbers, ordered by
+/// generation and then by file name.
+fn generation_files(fs: &dyn FileSystem, path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files = fs
+        .list_files(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            let gen = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .map(|str| str.trim_end_matches(".log"))
+                .map(str::parse::<u64>)?
+                .ok()?;
+            Some((gen, path))
+        })
+        .collect::<Vec<_>>();
+
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Renames log files left with the old `.Error` extension to `.log`, keeping
+/// the rest of the file name. A legacy file whose new name is already taken is
+/// left in place, since renaming it would replace a live log.
+fn migrate_extensions(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    let files = fs.list_files(path)?;
+    for file in &files {
+        if file.extension() != Some("Error".as_ref()) {
+            continue;
+        }
+        let renamed = file.with_extension("log");
+        if files.contains(&renamed) {
+            warn!(
+                "Not migrating {}, {} already exists",
+                file.display(),
+                renamed.display()
+            );
+            continue;
+        }
+        warn!(
+            "Renaming legacy log {} to {}",
+            file.display(),
+            renamed.display()
+        );
+        fs.rename(file, &renamed)?;
+    }
+    Ok(())
+}
+
+/// File system types whose `fsync` may return before the data is durable.
+const UNRELIABLE_FSYNC: [&str; 5] = ["nfs", "smb", "smb2", "cifs", "9p"];
+
+/// Warns about a log on a file system that does not honor `fsync`, or fails if
+/// `strict` is set and `sync_policy` promises every write to be durable.
+///
+/// The other policies may lose recent writes anyway, so they only get the
+/// warning.
+fn check_durability(
+    fs: &dyn FileSystem,
+    path: &Path,
+    sync_policy: SyncPolicy,
+    strict: bool,
+) -> Result<()> {
+    let fs_type = match fs.fs_type(path)? {
+        Some(fs_type) if UNRELIABLE_FSYNC.contains(&fs_type.as_str()) => fs_type,
+        _ => return Ok(()),
+    };
+    if strict && sync_policy == SyncPolicy::EveryWrite {
+        return Err(KvsError::Unsupported(format!(
+            "durable writes on a {} file system",
+            fs_type
+        )));
+    }
+    warn!(
+        "{} is on a {} file system, synced writes may be lost",
+        path.display(),
+        fs_type
+    );
+    Ok(())
+}
+
+/// Subdirectory of the log holding the generations set aside by
+/// `RecoveryMode::Quarantine`.
+const QUARANTINE_DIR: &str = "corrupt";
+
+/// Moves the generations holding a record that does not parse to the
+/// quarantine directory, returning their numbers.
+fn quarantine_corrupt(fs: &dyn FileSystem, path: &Path, encoding: LogEncoding) -> Result<Vec<u64>> {
+    let mut quarantined = vec![];
+    for (gen, file) in generation_files(fs, path)? {
+        if !is_corrupt(fs, &file, encoding)? {
+            continue;
+        }
+        let dir = path.join(QUARANTINE_DIR);
+        fs.create_dir_all(&dir)?;
+        // An earlier quarantine of the same generation number is kept
+        let mut target = dir.join(format!("{}.log", gen));
+        let mut n = 1;
+        while fs.exists(&target) {
+            target = dir.join(format!("{}.{}.log", gen, n));
+            n += 1;
+        }
+        error!(
+            "Quarantining corrupt generation {} to {}",
+            file.display(),
+            target.display()
+        );
+        fs.rename(&file, &target)?;
+        quarantined.push(gen);
+    }
+    Ok(quarantined)
+}
+
+/// Rewrites the generations holding records that do not parse without the
+/// bytes from each such record to the next one that parses, for
+/// `RecoveryMode::Resync`. A torn record at the end is left to `load_index`.
+fn resync_corrupt(
+    fs: &dyn FileSystem,
+    path: &Path,
+    encoding: LogEncoding,
+    report: &mut RecoveryReport,
+) -> Result<()> {
+    for (gen, file) in generation_files(fs, path)? {
+        let mut data = vec![];
+        open_log(fs, &file)?.read_to_end(&mut data)?;
+        let mut kept = Vec::with_capacity(data.len());
+        let mut skipped = 0;
+        let mut pos = 0;
+        while pos < data.len() {
+            let mut stream = Records::new(encoding, &data[pos..]);
+            let mut end = 0;
+            let err = loop {
+                match stream.next() {
+                    Some(Ok(_)) => end = stream.byte_offset() as usize,
+                    Some(Err(e)) => break Some(e),
+                    None => break None,
+                }
+            };
+            kept.extend_from_slice(&data[pos..pos + end]);
+            pos += end;
+            match err {
+                None => break,
+                Some(e) if e.is_eof() => {
+                    kept.extend_from_slice(&data[pos..]);
+                    break;
+                }
+                Some(e) if e.is_io() => return Err(e.into()),
+                Some(_) => {
+                    let next = (pos + 1..data.len())
+                        .find(|&next| starts_record(encoding, &data[next..]))
+                        .unwrap_or(data.len());
+                    skipped += next - pos;
+                    pos = next;
+                }
+            }
+        }
+        if skipped == 0 {
+            continue;
+        }
+        warn!(
+            "Skipped {} bytes of corrupt records in {}",
+            skipped,
+            file.display()
+        );
+        replace_file(fs, &file, &kept)?;
+        report.resynced.push(gen);
+        report.skipped_bytes += skipped as u64;
+    }
+    Ok(())
+}
+
+/// Whether a whole record parses at the start of `data`.
+fn starts_record(encoding: LogEncoding, data: &[u8]) -> bool {
+    // JSON records are objects, no need to parse from anywhere else
+    if encoding == LogEncoding::Json && data.first() != Some(&b'{') {
+        return false;
+    }
+    matches!(Records::new(encoding, data).next(), Some(Ok(_)))
+}
+
+/// Returns whether a generation holds a record that does not parse, or does
+/// not decompress. A torn record at its end does not count.
+fn is_corrupt(fs: &dyn FileSystem, path: &Path, encoding: LogEncoding) -> Result<bool> {
+    let file = match open_log(fs, path) {
+        Ok(file) => file,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+            ) =>
+        {
+            return Ok(true)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    for cmd in Records::new(encoding, BufReader::new(file)) {
+        match cmd {
+            Ok(_) => {}
+            Err(e) if e.is_eof() => break,
+            Err(e) if e.is_io() => return Err(e.into()),
+            Err(_) => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+/// Fails if two log files parse to the same generation number, e.g. `3.log`
+/// and `03.log`, or renumbers all of them if `repair` is set.
+///
+/// Files are renamed newest first to fresh generations, so after a crash the
+/// renamed files still order after the ones left to rename.
+fn check_generations(fs: &dyn FileSystem, path: &Path, repair: bool) -> Result<()> {
+    let files = generation_files(fs, path)?;
+    let duplicate = files
+        .windows(2)
+        .find(|pair| pair[0].0 == pair[1].0)
+        .map(|pair| pair[0].0);
+    let gen = match duplicate {
+        Some(gen) => gen,
+        None => return Ok(()),
+    };
+    if !repair {
+        return Err(KvsError::DuplicateGeneration { gen });
+    }
+
+    let base = files.last().map_or(0, |(gen, _)| *gen);
+    for (i, (gen, file)) in files.iter().enumerate().rev() {
+        let new_gen = base + 1 + i as u64;
+        warn!(
+            "Renumbering generation {} in {} to {}",
+            gen,
+            file.display(),
+            new_gen
+        );
+        fs.rename(file, &db_path(path, new_gen))?;
+    }
+    Ok(())
+}
+
+// ========================= Index snapshot =========================
+
+const SNAPSHOT_FILE: &str = "index.snapshot";
+/// Leads a compressed snapshot, which is otherwise an LZ4 block of the JSON
+/// one. JSON starts with `{`, so uncompressed snapshots never match.
+const SNAPSHOT_MAGIC: &[u8] = b"\0kvi";
+
+/// The index of a closed store, with the generations it was loaded from.
+#[derive(Serialize, Deserialize)]
+struct IndexSnapshot {
+    case_insensitive: bool,
+    last_version: u64,
+    /// Generation numbers and file sizes, oldest first.
+    generations: Vec<(u64, u64)>,
+    entries: Vec<(String, CommandOffset)>,
+    #[serde(default)]
+    expiries: Vec<(String, u64)>,
+}
+
+/// Returns the generation numbers and file sizes of the log, oldest first.
+fn generation_sizes(fs: &dyn FileSystem, path: &Path) -> Result<Vec<(u64, u64)>> {
+    generations(fs, path)?
+        .into_iter()
+        .map(|gen| Ok((gen, fs.size(&db_path(path, gen))?)))
+        .collect()
+}
+
+fn write_snapshot(
+    fs: &dyn FileSystem,
+    path: &Path,
+    snapshot: &IndexSnapshot,
+    compress: bool,
+) -> Result<()> {
+    let json = serde_json::to_vec(snapshot)?;
+    let data = if compress {
+        [SNAPSHOT_MAGIC, &lz4_flex::compress_prepend_size(&json)].concat()
+    } else {
+        json
+    };
+    // Written aside and renamed, so a crash never leaves a partial snapshot
+    let tmp_path = path.join(format!("{}.tmp", SNAPSHOT_FILE));
+    let mut file = fs.create(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_data()?;
+    fs.rename(&tmp_path, &path.join(SNAPSHOT_FILE))?;
+    Ok(())
+}
+
+/// Reads the index snapshot if `KvStoreOptions::index_snapshot` is set and
+/// the snapshot still matches the oldest generations of `gens`.
+///
+/// A missing, stale or unreadable snapshot is not an error, the log is then
+/// replayed in full.
+fn load_snapshot(
+    fs: &dyn FileSystem,
+    path: &Path,
+    gens: &[u64],
+    options: &KvStoreOptions,
+) -> Option<IndexSnapshot> {
+    let file = path.join(SNAPSHOT_FILE);
+    if !options.index_snapshot || options.append_only || !fs.exists(&file) {
+        return None;
+    }
+    let snapshot = fs
+        .read(&file)
+        .map_err(KvsError::from)
+        .and_then(|data| match data.strip_prefix(SNAPSHOT_MAGIC) {
+            Some(compressed) => lz4_flex::decompress_size_prepended(compressed)
+                .map_err(|e| KvsError::from(io::Error::new(io::ErrorKind::InvalidData, e))),
+            None => Ok(data),
+        })
+        .and_then(|json| Ok(serde_json::from_slice::<IndexSnapshot>(&json)?));
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!(
+                "Ignoring unreadable index snapshot {}: {}",
+                file.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let current = gens
+        .iter()
+        .take(snapshot.generations.len())
+        .map(|&gen| Ok((gen, fs.size(&db_path(path, gen))?)))
+        .collect::<io::Result<Vec<_>>>();
+    match current {
+        Ok(current)
+            if current == snapshot.generations
+                && snapshot.case_insensitive == options.case_insensitive =>
+        {
+            Some(snapshot)
+        }
+        _ => {
+            warn!("Ignoring stale index snapshot {}", file.display());
+            None
+        }
+    }
+}
+
+// ========================= Compression =========================
+
+/// Leads a compressed generation. Records start with `{`, so uncompressed
+/// generations never match.
+///
+/// The records follow as deflate streams of `COMPRESSED_BLOCK_SIZE` bytes each,
+/// the last one shorter. Then come the offset in the file of each block, the
+/// length of the records and the number of blocks, as little endian `u64`s.
+const COMPRESSED_MAGIC: &[u8] = b"\0kvb";
+
+/// Length of the records compressed into each block.
+const COMPRESSED_BLOCK_SIZE: u64 = 64 * 1024;
+
+fn compress_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.compress", gen))
+}
+
+/// Opens a generation, which reads its records block by block if it is
+/// compressed.
+fn open_log(fs: &dyn FileSystem, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+    let mut file = fs.open(path)?;
+    let mut magic = vec![];
+    (&mut file)
+        .take(COMPRESSED_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    if magic != COMPRESSED_MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(file);
+    }
+    open_compressed(file)
+}
+
+#[cfg(feature = "compression")]
+fn open_compressed(file: Box<dyn FileHandle>) -> io::Result<Box<dyn FileHandle>> {
+    Ok(Box::new(BlockFile::open(file)?))
+}
+
+#[cfg(not(feature = "compression"))]
+fn open_compressed(_file: Box<dyn FileHandle>) -> io::Result<Box<dyn FileHandle>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        KvsError::CompressionDisabled.to_string(),
+    ))
+}
+
+/// Deflates a value for a `SetCompressed` record.
+#[cfg(feature = "compression")]
+fn deflate_value(value: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(value)?;
+    Ok(encoder.finish()?)
+}
+
+#[cfg(not(feature = "compression"))]
+fn deflate_value(_value: &[u8]) -> Result<Vec<u8>> {
+    Err(KvsError::CompressionDisabled)
+}
+
+/// Inflates the value of a `SetCompressed` record.
+#[cfg(feature = "compression")]
+fn inflate_value(value: &[u8]) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    DeflateDecoder::new(value).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(not(feature = "compression"))]
+fn inflate_value(_value: &[u8]) -> Result<Vec<u8>> {
+    Err(KvsError::CompressionDisabled)
+}
+
+/// Starts the thread compressing the generations sent to the returned channel.
+///
+/// It holds the writer weakly, so it stops once the store is dropped.
+fn spawn_compressor(
+    writer: Weak<Mutex<KvStoreWriter>>,
+    fs: Arc<dyn FileSystem>,
+    path: &Path,
+) -> Sender<u64> {
+    let (sender, receiver) = mpsc::channel();
+    let path = path.to_owned();
+    thread::spawn(move || {
+        for gen in receiver {
+            if let Err(e) = compress_generation(&*fs, &path, gen, &writer) {
+                warn!("Failed to compress generation {}: {}", gen, e);
+            }
+        }
+    });
+    sender
+}
+
+/// A compaction started by `KvStoreWriter::begin_compaction`, whose live
+/// records are copied without holding the writer lock.
+struct PendingCompaction {
+    /// The generation the live records are copied to.
+    gen: u64,
+    writer: PosBufWriter<LogFile>,
+    reader: KvStoreReader,
+    /// The index entries when the compaction began.
+    entries: Vec<(String, CommandOffset)>,
+    /// The generations to compact.
+    gens: Vec<u64>,
+    options: KvStoreOptions,
+    /// When the compaction began, and the size of the log then.
+    started: Instant,
+    bytes_before: u64,
+}
+
+impl PendingCompaction {
+    fn copy_live(&mut self) -> Result<Vec<(String, CommandOffset, Option<u64>)>> {
+        let history = self.reader.version_history(&self.gens, &self.options)?;
+        self.reader.copy_live(
+            self.entries.iter().cloned().map(Ok),
+            &history,
+            self.options.verify_compaction,
+            self.gen,
+            &mut self.writer,
+            &mut HashMap::new(),
+        )
+    }
+}
+
+/// The thread started by `spawn_compactor`.
+struct Compactor {
+    signal: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Asks the thread for a compaction.
+    fn signal(&self) {
+        if let Some(signal) = &self.signal {
+            let _ = signal.send(());
+        }
+    }
+}
 
-                    *pos = compact_writer.pos;
-                    *gen = current_gen - 1;
-                    Ok(buffer)
-                })?;
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // The thread stops once the compaction in progress ends
+        self.signal.take();
+        if let Some(thread) = self.thread.take() {
+            // The thread drops the writer itself when it held the last handle
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
 
-            compact_writer.write_all(&buffer)?;
+/// Starts the thread running the compactions of `CompactionPolicy::Background`,
+/// notifying `done` after each one.
+///
+/// It holds the writer weakly, so it stops once the store is dropped.
+fn spawn_compactor(writer: Weak<Mutex<KvStoreWriter>>, done: Arc<Condvar>) -> Compactor {
+    let (signal, signals) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        for () in signals {
+            if let Err(e) = compact_in_background(&writer) {
+                warn!("Failed to compact the log in the background: {}", e);
+            }
+            done.notify_all();
         }
-        compact_writer.flush()?;
+    });
+    Compactor {
+        signal: Some(signal),
+        thread: Some(thread),
+    }
+}
 
-        let stale_gens = generations(&self.path)?
-            .into_iter()
-            .filter(|gen| *gen <= self.current_gen - 2)
-            .collect::<Vec<u64>>();
+/// Runs a background compaction, only holding the writer lock to begin and
+/// finish it.
+///
+/// If the store is dropped meanwhile, the compacted generation is removed
+/// instead of swapped in.
+fn compact_in_background(writer: &Weak<Mutex<KvStoreWriter>>) -> Result<()> {
+    let compaction = match writer.upgrade() {
+        Some(writer) => writer.lock().unwrap().begin_compaction()?,
+        None => return Ok(()),
+    };
+    let mut compaction = match compaction {
+        Some(compaction) => compaction,
+        None => return Ok(()),
+    };
+    let moved = compaction.copy_live();
+    match writer.upgrade() {
+        Some(writer) => writer.lock().unwrap().finish_compaction(compaction, moved),
+        None => {
+            let fs = Arc::clone(&compaction.reader.fs);
+            let path = db_path(&compaction.reader.path, compaction.gen);
+            drop(compaction);
+            fs.remove_file(&path)?;
+            Ok(())
+        }
+    }
+}
 
-        for ref gen in stale_gens {
-            let path = db_path(&self.path, *gen);
-            self.reader.remove_reader(gen);
-            fs::remove_file(path)?;
+/// A thread running a task periodically, stopped and joined on drop.
+struct Periodic {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            // The thread drops the writer itself when it held the last handle
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
         }
+    }
+}
 
-        Ok(())
+/// Starts a thread running `task` every `interval`, until it returns false or
+/// the returned `Periodic` is dropped.
+fn spawn_periodic(interval: Duration, mut task: impl FnMut() -> bool + Send + 'static) -> Periodic {
+    let (stop, stopped) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            if !task() {
+                return;
+            }
+        }
+    });
+    Periodic {
+        stop,
+        thread: Some(thread),
     }
 }
 
-fn db_path(path: &PathBuf, gen: u64) -> PathBuf {
-    let file_name = format!("{}.Error", gen);
-    path.join(file_name)
+/// Starts the thread writing the stats of the store to `file`.
+///
+/// It holds the writer weakly, so it stops once the store is dropped.
+fn spawn_stats_writer(
+    writer: Weak<Mutex<KvStoreWriter>>,
+    fs: Arc<dyn FileSystem>,
+    file: StatsFile,
+) -> Periodic {
+    spawn_periodic(file.interval, move || {
+        let stats = match writer.upgrade() {
+            Some(writer) => writer.lock().unwrap().stats(),
+            None => return false,
+        };
+        if let Err(e) = stats.and_then(|stats| write_stats(&*fs, &file.path, &stats)) {
+            warn!(
+                "Failed to write the stats to {}: {}",
+                file.path.display(),
+                e
+            );
+        }
+        true
+    })
 }
 
-fn new_db_log(path: &PathBuf) -> Result<(BufWriter<File>, BufReader<File>)> {
-    let file = OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(true)
-        .open(&path)?;
+/// Starts the thread of `SyncPolicy::Interval`, syncing the log every
+/// `interval`.
+///
+/// It holds the writer weakly, so it stops once the store is dropped.
+fn spawn_syncer(writer: Weak<Mutex<KvStoreWriter>>, interval: Duration) -> Periodic {
+    spawn_periodic(interval, move || {
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return false,
+        };
+        let mut writer = writer.lock().unwrap();
+        if let Err(e) = writer.sync() {
+            warn!("Failed to sync generation {}: {}", writer.current_gen, e);
+        }
+        true
+    })
+}
 
-    let writer = BufWriter::new(file.try_clone()?);
-    let reader = BufReader::new(file);
+/// Starts the thread checking the free space of the file system holding
+/// `path` every `check.interval`.
+fn spawn_space_checker(
+    fs: Arc<dyn FileSystem>,
+    path: &Path,
+    check: DiskSpaceCheck,
+    low_space: Arc<AtomicBool>,
+) -> Periodic {
+    let path = path.to_owned();
+    spawn_periodic(check.interval, move || {
+        check_disk_space(&*fs, &path, &check, &low_space);
+        true
+    })
+}
 
-    Ok((writer, reader))
+/// Sets `low_space` to whether the file system holding `path` has less free
+/// space than `check` asks for, logging when that changes.
+fn check_disk_space(
+    fs: &dyn FileSystem,
+    path: &Path,
+    check: &DiskSpaceCheck,
+    low_space: &AtomicBool,
+) {
+    let available = match fs.available_space(path) {
+        Ok(Some(available)) => available,
+        Ok(None) => return,
+        Err(e) => {
+            warn!(
+                "Failed to check the free space of {}: {}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    let low = available < check.min_free_bytes;
+    if low_space.swap(low, Ordering::SeqCst) == low {
+        return;
+    }
+    if low {
+        warn!(
+            "Only {} bytes left on the file system of {}, under the {} bytes wanted",
+            available,
+            path.display(),
+            check.min_free_bytes
+        );
+    } else {
+        info!(
+            "{} bytes free again on the file system of {}",
+            available,
+            path.display()
+        );
+    }
 }
 
-fn generations(path: &PathBuf) -> Result<Vec<u64>> {
-    let mut gens = fs::read_dir(path)?
-        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("Error".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|str| str.trim_end_matches(".Error"))
-                .map(str::parse::<u64>)
+/// Writes `stats` to a temporary file renamed over `path`, so readers never
+/// see a partial file.
+fn write_stats(fs: &dyn FileSystem, path: &Path, stats: &KvStats) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    fs.create(&temp)?
+        .write_all(&serde_json::to_vec_pretty(stats)?)?;
+    fs.rename(&temp, path)?;
+    Ok(())
+}
+
+/// Compresses a sealed generation into a temporary file, then swaps it in
+/// under the writer lock, unless compaction removed the generation meanwhile.
+///
+/// Readers that already opened the generation keep reading the old file, whose
+/// records sit at the same offsets as in the decompressed one.
+fn compress_generation(
+    fs: &dyn FileSystem,
+    path: &Path,
+    gen: u64,
+    writer: &Weak<Mutex<KvStoreWriter>>,
+) -> Result<()> {
+    let log = db_path(path, gen);
+    let temp = compress_path(path, gen);
+    let mut records = fs.open(&log)?;
+    let mut file = fs.create(&temp)?;
+    file.write_all(COMPRESSED_MAGIC)?;
+    let mut offsets = vec![];
+    let mut offset = COMPRESSED_MAGIC.len() as u64;
+    let mut len = 0;
+    let mut block = vec![];
+    loop {
+        block.clear();
+        (&mut records)
+            .take(COMPRESSED_BLOCK_SIZE)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        let compressed = deflate_value(&block)?;
+        file.write_all(&compressed)?;
+        offsets.push(offset);
+        offset += compressed.len() as u64;
+        len += block.len() as u64;
+    }
+    for offset in &offsets {
+        file.write_all(&offset.to_le_bytes())?;
+    }
+    file.write_all(&len.to_le_bytes())?;
+    file.write_all(&(offsets.len() as u64).to_le_bytes())?;
+    file.sync_data()?;
+
+    let writer = writer.upgrade();
+    let _guard = writer.as_ref().map(|writer| writer.lock().unwrap());
+    // Not worth it for records that do not compress
+    if writer.is_some() && fs.exists(&log) && file.size()? < len {
+        fs.rename(&temp, &log)?;
+    } else {
+        fs.remove_file(&temp)?;
+    }
+    Ok(())
+}
+
+/// Removes the temporary files of compressions interrupted by a crash.
+fn remove_stale_compressions(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    for file in fs.list_files(path)? {
+        if file.extension() == Some("compress".as_ref()) {
+            fs.remove_file(&file)?;
+        }
+    }
+    Ok(())
+}
+
+/// The records of a compressed generation, decompressed a block at a time.
+#[cfg(feature = "compression")]
+struct BlockFile {
+    /// Shared by the clones, which seek it before every block they read.
+    file: Arc<Mutex<Box<dyn FileHandle>>>,
+    /// The offset of each block in the file, then the offset of the table.
+    offsets: Arc<Vec<u64>>,
+    len: u64,
+    pos: u64,
+    /// The index and records of the block read last.
+    block: Option<(usize, Vec<u8>)>,
+}
+
+#[cfg(feature = "compression")]
+impl BlockFile {
+    /// Reads the table of blocks at the end of `file`.
+    fn open(mut file: Box<dyn FileHandle>) -> io::Result<Self> {
+        let corrupt =
+            || io::Error::new(io::ErrorKind::InvalidData, "corrupt compressed generation");
+        let read_u64 = |file: &mut Box<dyn FileHandle>| -> io::Result<u64> {
+            let mut bytes = [0; 8];
+            file.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let size = file.size()?;
+        file.seek(SeekFrom::Start(size.checked_sub(16).ok_or_else(corrupt)?))?;
+        let len = read_u64(&mut file)?;
+        let count = read_u64(&mut file)?;
+        let table = count
+            .checked_mul(8)
+            .and_then(|table_len| (size - 16).checked_sub(table_len))
+            .filter(|table| *table >= COMPRESSED_MAGIC.len() as u64)
+            .ok_or_else(corrupt)?;
+        if count != len.div_ceil(COMPRESSED_BLOCK_SIZE) {
+            return Err(corrupt());
+        }
+        file.seek(SeekFrom::Start(table))?;
+        let mut offsets = (0..count)
+            .map(|_| read_u64(&mut file))
+            .collect::<io::Result<Vec<_>>>()?;
+        offsets.push(table);
+        if offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(corrupt());
+        }
+        Ok(BlockFile {
+            file: Arc::new(Mutex::new(file)),
+            offsets: Arc::new(offsets),
+            len,
+            pos: 0,
+            block: None,
         })
-        .flatten()
-        .collect::<Vec<u64>>();
+    }
+
+    /// Decompresses block `index`, unless it was read last.
+    fn load(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.block.as_ref().map(|(loaded, _)| *loaded) != Some(index) {
+            let (start, end) = (self.offsets[index], self.offsets[index + 1]);
+            let mut compressed = vec![0; (end - start) as usize];
+            {
+                let mut file = self.file.lock().unwrap();
+                file.seek(SeekFrom::Start(start))?;
+                file.read_exact(&mut compressed)?;
+            }
+            let mut records = Vec::with_capacity(COMPRESSED_BLOCK_SIZE as usize);
+            DeflateDecoder::new(&compressed[..]).read_to_end(&mut records)?;
+            let expected =
+                (self.len - index as u64 * COMPRESSED_BLOCK_SIZE).min(COMPRESSED_BLOCK_SIZE);
+            if records.len() as u64 != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupt compressed block",
+                ));
+            }
+            self.block = Some((index, records));
+        }
+        Ok(&self.block.as_ref().unwrap().1)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Read for BlockFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let index = (self.pos / COMPRESSED_BLOCK_SIZE) as usize;
+        let start = (self.pos % COMPRESSED_BLOCK_SIZE) as usize;
+        let n = (&self.load(index)?[start..]).read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Write for BlockFile {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "compressed generations are read only",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Seek for BlockFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(feature = "compression")]
+impl FileHandle for BlockFile {
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_len(&self, _len: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "compressed generations are read only",
+        ))
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(BlockFile {
+            file: Arc::clone(&self.file),
+            offsets: Arc::clone(&self.offsets),
+            len: self.len,
+            pos: 0,
+            block: None,
+        }))
+    }
+}
+
+// ========================= Format =========================
+
+/// Format of the log written by this version, recorded in `kvs.format` next to
+/// the log directory.
+///
+/// - 0: logs from before the file existed, whose records may lack versions.
+/// - 1: every `Set` record carries a version.
+/// - 2: like 1, with `LogEncoding::Binary` records.
+///
+/// `SetBytes` records, only written for binary values, and `SetCompressed`
+/// records, only written with `KvStoreOptions::compress_values`, need no new
+/// format: versions from before them fail to parse them instead of misreading
+/// them.
+const LOG_FORMAT: u32 = 1;
+/// Format of a log written with `LogEncoding::Binary`.
+const BINARY_LOG_FORMAT: u32 = 2;
+
+fn format_path(path: &Path) -> PathBuf {
+    path.with_extension("format")
+}
+
+/// Present while the rewritten generations of an upgrade replace the old ones.
+fn upgrade_marker(path: &Path) -> PathBuf {
+    path.with_extension("upgrade")
+}
+
+/// The marker left by `KvStore::clear` until the generations before the one it
+/// names are removed.
+fn clear_marker(path: &Path) -> PathBuf {
+    path.with_extension("clear")
+}
+
+/// The highest version handed out when generations were last removed, by
+/// compaction or `KvStore::clear`.
+fn version_mark(path: &Path) -> PathBuf {
+    path.with_extension("version")
+}
+
+/// Reads the version mark of the log in `path`, 0 if it has none.
+fn read_version_mark(fs: &dyn FileSystem, path: &Path) -> Result<u64> {
+    let mark = version_mark(path);
+    if !fs.exists(&mark) {
+        return Ok(0);
+    }
+    String::from_utf8(fs.read(&mark)?)
+        .ok()
+        .and_then(|version| version.trim().parse().ok())
+        .ok_or_else(|| KvsError::StringError(format!("invalid version mark {}", mark.display())))
+}
+
+/// Removes the generations cleared by an interrupted `KvStore::clear`.
+fn finish_clear(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    let marker = clear_marker(path);
+    if !fs.exists(&marker) {
+        return Ok(());
+    }
+    let first_gen = String::from_utf8(fs.read(&marker)?)
+        .ok()
+        .and_then(|gen| gen.trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            KvsError::StringError(format!("invalid clear marker {}", marker.display()))
+        })?;
+    for (gen, file) in generation_files(fs, path)? {
+        if gen < first_gen {
+            fs.remove_file(&file)?;
+        }
+    }
+    fs.remove_file(&marker)?;
+    Ok(())
+}
 
-    gens.sort_unstable();
-    Ok(gens)
+fn upgrade_path(path: &Path, gen: u64) -> PathBuf {
+    path.join(format!("{}.upgrade", gen))
 }
 
+/// Reads the format of the log in `path`, `None` if it has none yet.
+fn read_format(fs: &dyn FileSystem, path: &Path) -> Result<Option<u32>> {
+    match fs.read(&format_path(path)) {
+        Ok(format) => {
+            let format = String::from_utf8_lossy(&format);
+            let format = format
+                .trim()
+                .parse()
+                .map_err(|_| KvsError::StringError(format!("invalid log format {:?}", format)))?;
+            Ok(Some(format))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the encoding of the records of the log in `path`.
+fn read_encoding(fs: &dyn FileSystem, path: &Path) -> Result<LogEncoding> {
+    match read_format(fs, path)? {
+        Some(BINARY_LOG_FORMAT) => Ok(LogEncoding::Binary),
+        _ => Ok(LogEncoding::Json),
+    }
+}
+
+/// Checks the format of the log in `path`, upgrading it if allowed, and
+/// returns the encoding of its records.
+///
+/// A new log is marked with the current format of `options.log_encoding`.
+fn check_format(fs: &dyn FileSystem, path: &Path, options: &KvStoreOptions) -> Result<LogEncoding> {
+    finish_upgrade(fs, path)?;
+    let format = match read_format(fs, path)? {
+        Some(BINARY_LOG_FORMAT) => return Ok(LogEncoding::Binary),
+        Some(format) => format,
+        None if generations(fs, path)?.is_empty() => {
+            write_format(fs, path, options.log_encoding)?;
+            return Ok(options.log_encoding);
+        }
+        None => 0,
+    };
+    if format > LOG_FORMAT {
+        return Err(KvsError::Unsupported(format!("log format {}", format)));
+    }
+    if format < LOG_FORMAT {
+        if options.allow_upgrade {
+            upgrade(fs, path, format)?;
+        } else {
+            warn!(
+                "{} has log format {}, older than {}, open it with \
+                 KvStoreOptions::allow_upgrade to rewrite it",
+                path.display(),
+                format,
+                LOG_FORMAT
+            );
+        }
+    }
+    Ok(LogEncoding::Json)
+}
+
+fn write_format(fs: &dyn FileSystem, path: &Path, encoding: LogEncoding) -> Result<()> {
+    let format = match encoding {
+        LogEncoding::Json => LOG_FORMAT,
+        LogEncoding::Binary => BINARY_LOG_FORMAT,
+    };
+    replace_file(fs, &format_path(path), format.to_string().as_bytes())
+}
+
+/// Writes `contents` to a temporary file renamed to `path`, so a crash leaves
+/// either the old or the new contents.
+fn replace_file(fs: &dyn FileSystem, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = fs.create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_data()?;
+    fs.rename(&temp, path)?;
+    Ok(())
+}
+
+/// Rewrites every generation in the current JSON format.
+///
+/// Every `Set` is renumbered in log order, as older logs mix records without
+/// versions with versions counted from 1. Deltas are written as full values,
+/// since the records they point to move. The rewritten
+/// generations are synced next to the old ones before the `kvs.upgrade` marker
+/// commits them, then `finish_upgrade` moves them into place.
+fn upgrade(fs: &dyn FileSystem, path: &Path, format: u32) -> Result<()> {
+    info!(
+        "Upgrading {} from log format {} to {}",
+        path.display(),
+        format,
+        LOG_FORMAT
+    );
+    let mut last_version = 0;
+    for gen in generations(fs, path)? {
+        let reader = BufReader::new(open_log(fs, &db_path(path, gen))?);
+        let mut writer = BufWriter::new(fs.create(&upgrade_path(path, gen))?);
+        for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+            let cmd = match cmd {
+                Ok(cmd) => cmd,
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            let (key, value, modified_at) = match cmd {
+                Command::Remove { .. }
+                | Command::SetBytes { .. }
+                | Command::SetCompressed { .. } => {
+                    serde_json::to_writer(&mut writer, &cmd)?;
+                    continue;
+                }
+                Command::Set {
+                    key,
+                    value,
+                    modified_at,
+                    ..
+                } => (key, value, modified_at),
+                Command::Delta {
+                    key,
+                    base,
+                    diff,
+                    modified_at,
+                    ..
+                } => (
+                    key,
+                    diff.apply(&read_unindexed_value(fs, path, &base)?)?,
+                    modified_at,
+                ),
+            };
+            last_version += 1;
+            let cmd = Command::Set {
+                key,
+                value,
+                version: last_version,
+                modified_at,
+                expires_at: None,
+            };
+            serde_json::to_writer(&mut writer, &cmd)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+    }
+    replace_file(fs, &upgrade_marker(path), &[])?;
+    finish_upgrade(fs, path)
+}
+
+/// Moves the generations rewritten by an upgrade into place if the upgrade was
+/// committed, or removes them otherwise.
+fn finish_upgrade(fs: &dyn FileSystem, path: &Path) -> Result<()> {
+    let committed = fs.exists(&upgrade_marker(path));
+    for file in fs.list_files(path)? {
+        if file.extension() != Some("upgrade".as_ref()) {
+            continue;
+        }
+        let gen = file
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|stem| stem.parse::<u64>().ok());
+        match gen {
+            Some(gen) if committed => fs.rename(&file, &db_path(path, gen))?,
+            _ => fs.remove_file(&file)?,
+        }
+    }
+    if committed {
+        write_format(fs, path, LogEncoding::Json)?;
+        fs.remove_file(&upgrade_marker(path))?;
+    }
+    Ok(())
+}
+
+/// Reads the value at `offset` straight from the log files, for use before the
+/// store is opened.
+fn read_unindexed_value(
+    fs: &dyn FileSystem,
+    path: &Path,
+    offset: &CommandOffset,
+) -> Result<String> {
+    let mut file = open_log(fs, &db_path(path, offset.gen))?;
+    file.seek(SeekFrom::Start(offset.pos))?;
+    let mut buffer = vec![0u8; offset.len as usize];
+    file.read_exact(&mut buffer)?;
+    match serde_json::from_slice(&buffer)? {
+        Command::Set { value, .. } => Ok(value),
+        Command::Delta { base, diff, .. } => diff.apply(&read_unindexed_value(fs, path, &base)?),
+        Command::SetBytes { value, .. } => Ok(String::from_utf8(value)?),
+        Command::SetCompressed { value, .. } => Ok(String::from_utf8(inflate_value(&value)?)?),
+        Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+    }
+}
+
+/// Loads the commands of a generation into the index.
+///
+/// Returns the end position of the last complete record and the largest
+/// version found.
 fn load_index(
     gen: u64,
-    reader: &mut BufReader<File>,
-    index: &mut HashMap<String, CommandOffset>,
-) -> Result<()> {
+    reader: &mut LogReader,
+    index: &mut dyn Index,
+    options: &KvStoreOptions,
+) -> Result<(u64, u64)> {
     let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let mut last_version = 0;
+    let now = now_millis();
+    let mut stream = Records::new(options.log_encoding, reader);
     while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
+        let new_pos = stream.byte_offset();
+
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // A record torn by a failed write, everything before it is intact.
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(e.into()),
+        };
+        last_version = last_version.max(cmd.version());
 
-        match cmd? {
-            Command::Set { key, value: _ } => {
-                index.insert(key, From::from((gen, pos..new_pos)));
+        match cmd {
+            _ if options.append_only => {}
+            // A key that expired since is absent, like after a removal
+            Command::Set {
+                key,
+                expires_at: Some(expires_at),
+                ..
+            } if expires_at <= now => {
+                index.remove(&options.normalize_key(key))?;
+            }
+            Command::Set {
+                key, expires_at, ..
+            } => {
+                let key = options.normalize_key(key);
+                index.set_expiry(&key, expires_at);
+                index.insert(key, From::from((gen, pos..new_pos)))?;
+            }
+            Command::Delta { key, .. }
+            | Command::SetBytes { key, .. }
+            | Command::SetCompressed { key, .. } => {
+                let key = options.normalize_key(key);
+                index.set_expiry(&key, None);
+                index.insert(key, From::from((gen, pos..new_pos)))?;
             }
             Command::Remove { key } => {
-                index.remove(&key);
+                index.remove(&options.normalize_key(key))?;
             }
         }
 
         pos = new_pos;
     }
 
-    Ok(())
+    Ok((pos, last_version))
+}
+
+impl LogEncoding {
+    /// Encodes `cmd` as one record.
+    fn encode(self, cmd: &Command) -> Result<Vec<u8>> {
+        match self {
+            LogEncoding::Json => Ok(serde_json::to_vec(cmd)?),
+            LogEncoding::Binary => {
+                let payload = binary_options().serialize(&BinaryRecord::from(cmd))?;
+                let len = u32::try_from(payload.len())
+                    .map_err(|_| KvsError::StringError("record too large".to_owned()))?;
+                let mut record = Vec::with_capacity(4 + payload.len());
+                record.extend_from_slice(&len.to_le_bytes());
+                record.extend_from_slice(&payload);
+                Ok(record)
+            }
+        }
+    }
+
+    /// Writes `cmd` to `writer` as one record.
+    fn write<W: Write>(self, writer: &mut W, cmd: &Command) -> Result<()> {
+        match self {
+            LogEncoding::Json => serde_json::to_writer(writer, cmd)?,
+            LogEncoding::Binary => writer.write_all(&self.encode(cmd)?)?,
+        }
+        Ok(())
+    }
+
+    /// Decodes one whole record.
+    fn decode(self, record: &[u8]) -> Result<Command> {
+        match self {
+            LogEncoding::Json => Ok(serde_json::from_slice(record)?),
+            LogEncoding::Binary => {
+                let payload = record
+                    .get(4..)
+                    .filter(|payload| record[..4] == (payload.len() as u32).to_le_bytes())
+                    .ok_or(KvsError::UnexpectedCommandType)?;
+                let record: BinaryRecord = binary_options().deserialize(payload)?;
+                Ok(record.into())
+            }
+        }
+    }
+}
+
+/// Bincode with variable length integers, which keeps the small numbers of a
+/// record small.
+fn binary_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// The records of a log read one after the other, like a `StreamDeserializer`
+/// of JSON records.
+enum Records<R: Read> {
+    Json(StreamDeserializer<'static, IoRead<R>, Command>),
+    Binary { reader: R, pos: u64 },
+}
+
+impl<R: Read> Records<R> {
+    fn new(encoding: LogEncoding, reader: R) -> Self {
+        match encoding {
+            LogEncoding::Json => Records::Json(Deserializer::from_reader(reader).into_iter()),
+            LogEncoding::Binary => Records::Binary { reader, pos: 0 },
+        }
+    }
+
+    /// Position after the last record read.
+    fn byte_offset(&self) -> u64 {
+        match self {
+            Records::Json(stream) => stream.byte_offset() as u64,
+            Records::Binary { pos, .. } => *pos,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = std::result::Result<Command, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (reader, pos) = match self {
+            Records::Json(stream) => return stream.next().map(|cmd| Ok(cmd?)),
+            Records::Binary { reader, pos } => (reader, pos),
+        };
+        let mut len = [0; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match reader.read(&mut len[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err(RecordError::Torn)),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(RecordError::Io(e))),
+            }
+        }
+        let len = u32::from_le_bytes(len) as u64;
+        // Read through `take` rather than into a buffer of `len` bytes, as a
+        // torn length may be huge
+        let mut payload = vec![];
+        if let Err(e) = reader.take(len).read_to_end(&mut payload) {
+            return Some(Err(RecordError::Io(e)));
+        }
+        if payload.len() as u64 != len {
+            return Some(Err(RecordError::Torn));
+        }
+        *pos += 4 + len;
+        Some(
+            binary_options()
+                .deserialize::<BinaryRecord>(&payload)
+                .map(Command::from)
+                .map_err(RecordError::Binary),
+        )
+    }
+}
+
+/// Why the next record of a log could not be read.
+#[derive(Debug)]
+enum RecordError {
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+    /// The log ends inside a binary record.
+    Torn,
+    Io(io::Error),
+}
+
+impl RecordError {
+    /// Whether the log ends inside the record, as after a failed write.
+    fn is_eof(&self) -> bool {
+        match self {
+            RecordError::Json(e) => e.is_eof(),
+            RecordError::Torn => true,
+            _ => false,
+        }
+    }
+
+    fn is_io(&self) -> bool {
+        match self {
+            RecordError::Json(e) => e.is_io(),
+            RecordError::Io(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl From<serde_json::Error> for RecordError {
+    fn from(err: serde_json::Error) -> Self {
+        RecordError::Json(err)
+    }
+}
+
+impl From<RecordError> for KvsError {
+    fn from(err: RecordError) -> Self {
+        match err {
+            RecordError::Json(e) => e.into(),
+            RecordError::Binary(e) => e.into(),
+            RecordError::Torn => io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+            RecordError::Io(e) => e.into(),
+        }
+    }
+}
+
+/// A `Command` as written by `LogEncoding::Binary`. Bincode is not
+/// self-describing, so every field is written, defaults included.
+#[derive(Serialize, Deserialize)]
+enum BinaryRecord<'a> {
+    Set {
+        key: Cow<'a, str>,
+        value: Cow<'a, str>,
+        version: u64,
+        modified_at: u64,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Cow<'a, str>,
+    },
+    Delta {
+        key: Cow<'a, str>,
+        base: CommandOffset,
+        depth: u32,
+        diff: Cow<'a, Diff>,
+        version: u64,
+        modified_at: u64,
+    },
+    SetBytes {
+        key: Cow<'a, str>,
+        value: Cow<'a, [u8]>,
+        version: u64,
+        modified_at: u64,
+    },
+    SetCompressed {
+        key: Cow<'a, str>,
+        value: Cow<'a, [u8]>,
+        version: u64,
+        modified_at: u64,
+    },
+}
+
+impl<'a> From<&'a Command> for BinaryRecord<'a> {
+    fn from(cmd: &'a Command) -> Self {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                version,
+                modified_at,
+                expires_at,
+            } => BinaryRecord::Set {
+                key: key.into(),
+                value: value.into(),
+                version: *version,
+                modified_at: *modified_at,
+                expires_at: *expires_at,
+            },
+            Command::Remove { key } => BinaryRecord::Remove { key: key.into() },
+            Command::Delta {
+                key,
+                base,
+                depth,
+                diff,
+                version,
+                modified_at,
+            } => BinaryRecord::Delta {
+                key: key.into(),
+                base: *base,
+                depth: *depth,
+                diff: Cow::Borrowed(diff),
+                version: *version,
+                modified_at: *modified_at,
+            },
+            Command::SetBytes {
+                key,
+                value,
+                version,
+                modified_at,
+            } => BinaryRecord::SetBytes {
+                key: key.into(),
+                value: value.into(),
+                version: *version,
+                modified_at: *modified_at,
+            },
+            Command::SetCompressed {
+                key,
+                value,
+                version,
+                modified_at,
+            } => BinaryRecord::SetCompressed {
+                key: key.into(),
+                value: value.into(),
+                version: *version,
+                modified_at: *modified_at,
+            },
+        }
+    }
+}
+
+impl From<BinaryRecord<'_>> for Command {
+    fn from(record: BinaryRecord) -> Self {
+        match record {
+            BinaryRecord::Set {
+                key,
+                value,
+                version,
+                modified_at,
+                expires_at,
+            } => Command::Set {
+                key: key.into_owned(),
+                value: value.into_owned(),
+                version,
+                modified_at,
+                expires_at,
+            },
+            BinaryRecord::Remove { key } => Command::Remove {
+                key: key.into_owned(),
+            },
+            BinaryRecord::Delta {
+                key,
+                base,
+                depth,
+                diff,
+                version,
+                modified_at,
+            } => Command::Delta {
+                key: key.into_owned(),
+                base,
+                depth,
+                diff: diff.into_owned(),
+                version,
+                modified_at,
+            },
+            BinaryRecord::SetBytes {
+                key,
+                value,
+                version,
+                modified_at,
+            } => Command::SetBytes {
+                key: key.into_owned(),
+                value: value.into_owned(),
+                version,
+                modified_at,
+            },
+            BinaryRecord::SetCompressed {
+                key,
+                value,
+                version,
+                modified_at,
+            } => Command::SetCompressed {
+                key: key.into_owned(),
+                value: value.into_owned(),
+                version,
+                modified_at,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "is_unversioned")]
+        version: u64,
+        #[serde(default, skip_serializing_if = "is_unversioned")]
+        modified_at: u64,
+        /// Milliseconds since the Unix epoch after which the key is absent.
+        /// Records without it never expire.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+    /// Sets `key` to the value at `base` patched by `diff`. `depth` counts the
+    /// deltas down to the last full value.
+    Delta {
+        key: String,
+        base: CommandOffset,
+        depth: u32,
+        diff: Diff,
+        #[serde(default, skip_serializing_if = "is_unversioned")]
+        version: u64,
+        #[serde(default, skip_serializing_if = "is_unversioned")]
+        modified_at: u64,
+    },
+    /// Sets `key` to a value that is not UTF-8, written by `KvStore::set_bytes`.
+    /// UTF-8 values are always written as `Set`, so only stores holding binary
+    /// values have these records.
+    SetBytes {
+        key: String,
+        value: Vec<u8>,
+        version: u64,
+        modified_at: u64,
+    },
+    /// Sets `key` to a value deflated by `KvStoreOptions::compress_values`,
+    /// UTF-8 or not once inflated.
+    SetCompressed {
+        key: String,
+        value: Vec<u8>,
+        version: u64,
+        modified_at: u64,
+    },
 }
 
-#[derive(Debug)]
-struct CommandOffset {
-    gen: u64,
-    pos: u64,
-    len: u64,
+/// Records written before versions and modification times were recorded have
+/// them at 0.
+fn is_unversioned(version: &u64) -> bool {
+    *version == 0
 }
 
-impl From<(u64, Range<u64>)> for CommandOffset {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
-        CommandOffset {
-            gen,
-            pos: range.start,
-            len: range.end - range.start,
+impl Command {
+    fn is_delta(&self) -> bool {
+        matches!(self, Command::Delta { .. })
+    }
+
+    fn version(&self) -> u64 {
+        match self {
+            Command::Set { version, .. }
+            | Command::Delta { version, .. }
+            | Command::SetBytes { version, .. }
+            | Command::SetCompressed { version, .. } => *version,
+            Command::Remove { .. } => 0,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch when the record was written.
+    fn modified_at(&self) -> u64 {
+        match self {
+            Command::Set { modified_at, .. }
+            | Command::Delta { modified_at, .. }
+            | Command::SetBytes { modified_at, .. }
+            | Command::SetCompressed { modified_at, .. } => *modified_at,
+            Command::Remove { .. } => 0,
         }
     }
+
+    /// Turns a `Set` or `Delta` into a `Set` of `key` to `value` that keeps its
+    /// version, modification time and expiration.
+    fn into_set(self, key: String, value: String) -> Command {
+        let expires_at = match self {
+            Command::Set { expires_at, .. } => expires_at,
+            _ => None,
+        };
+        Command::Set {
+            key,
+            value,
+            version: self.version(),
+            modified_at: self.modified_at(),
+            expires_at,
+        }
+    }
+}
+
+/// Replaces the middle of a value, keeping `prefix` leading and `suffix`
+/// trailing bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Diff {
+    prefix: usize,
+    suffix: usize,
+    insert: String,
+}
+
+impl Diff {
+    fn new(old: &str, new: &str) -> Self {
+        let mut prefix = old
+            .bytes()
+            .zip(new.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !new.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+        let mut suffix = old[prefix..]
+            .bytes()
+            .rev()
+            .zip(new[prefix..].bytes().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        while !new.is_char_boundary(new.len() - suffix) {
+            suffix -= 1;
+        }
+        Diff {
+            prefix,
+            suffix,
+            insert: new[prefix..new.len() - suffix].to_owned(),
+        }
+    }
+
+    /// Patches `base`, failing if the diff does not fit it, e.g. a corrupted
+    /// record.
+    fn apply(&self, base: &str) -> Result<String> {
+        let (prefix, suffix) = base
+            .len()
+            .checked_sub(self.suffix)
+            .filter(|&end| end >= self.prefix)
+            .and_then(|end| Some((base.get(..self.prefix)?, base.get(end..)?)))
+            .ok_or_else(|| {
+                KvsError::StringError(format!(
+                    "delta keeping {} leading and {} trailing bytes does not fit a base of {} bytes",
+                    self.prefix,
+                    self.suffix,
+                    base.len()
+                ))
+            })?;
+        let mut value = String::with_capacity(self.prefix + self.insert.len() + self.suffix);
+        value.push_str(prefix);
+        value.push_str(&self.insert);
+        value.push_str(suffix);
+        Ok(value)
+    }
 }
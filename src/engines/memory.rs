@@ -0,0 +1,124 @@
+use super::{KvsEngine, KvsReader, Transform};
+use crate::{KvsError, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// An engine that keeps every key in a `HashMap` and writes nothing to disk.
+///
+/// Nothing survives dropping the last clone, which suits tests and ephemeral
+/// caches. Every operation runs under one lock, so each is atomic.
+///
+/// ```rust
+/// # use unifier::{KvsEngine, KvsReader, MemoryKvsEngine};
+/// let engine = MemoryKvsEngine::new();
+/// engine.set("key".to_owned(), "value".to_owned()).unwrap();
+/// assert_eq!(engine.get("key".to_owned()).unwrap(), Some("value".to_owned()));
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine(Arc<RwLock<HashMap<String, String>>>);
+
+impl MemoryKvsEngine {
+    /// Creates an empty `MemoryKvsEngine`.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+}
+
+impl KvsReader for MemoryKvsEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.read().unwrap().get(&key).cloned())
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.0.read().unwrap().contains_key(&key))
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    /// Inserts every pair under one write lock, so readers see all or none.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.0.write().unwrap().extend(pairs);
+        Ok(())
+    }
+
+    fn set_returning(&self, key: String, value: String) -> Result<Option<String>> {
+        Ok(self.0.write().unwrap().insert(key, value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0
+            .write()
+            .unwrap()
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn remove_returning(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.write().unwrap().remove(&key))
+    }
+
+    /// Copies the keys under the read lock, so the list is an atomic snapshot.
+    fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.0.read().unwrap().keys().cloned().collect())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.0.read().unwrap().len())
+    }
+
+    /// Walks every entry and sorts the matches, like `IndexKind::Hash`.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs: Vec<_> = self
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.0.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        let mut map = self.0.write().unwrap();
+        let new = f(map.get(&key).cloned());
+        match &new {
+            Some(value) => map.insert(key, value.clone()),
+            None => map.remove(&key),
+        };
+        Ok(new)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut map = self.0.write().unwrap();
+        if map.get(&key) != expected.as_ref() {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+        Ok(true)
+    }
+}
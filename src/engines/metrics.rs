@@ -0,0 +1,130 @@
+use super::timed::Timings;
+use super::{EngineOp, LatencyHistogram};
+use crate::KvsError;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Hooks a `KvStore` calls as it serves operations, to export counts and
+/// latencies to a monitoring system without wrapping the store.
+///
+/// Every method does nothing by default, so an implementation overrides only
+/// the ones it needs. A store is given one through `KvStoreOptions::metrics`;
+/// without one it does not even read the clock.
+///
+/// Hooks run on the thread making the call, compactions under the writer lock,
+/// so they should be quick, e.g. bump an atomic counter.
+///
+/// # Example
+///
+/// ```
+/// # use unifier::{KvStore, KvStoreOptions, KvsEngine, Metrics};
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use tempfile::TempDir;
+/// #[derive(Debug)]
+/// struct SlowSets;
+///
+/// impl Metrics for SlowSets {
+///     fn on_set(&self, latency: Duration) {
+///         if latency > Duration::from_millis(100) {
+///             eprintln!("slow set: {:?}", latency);
+///         }
+///     }
+/// }
+///
+/// # let temp_dir = TempDir::new().unwrap();
+/// let options = KvStoreOptions {
+///     metrics: Some(Arc::new(SlowSets)),
+///     ..KvStoreOptions::default()
+/// };
+/// let store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+/// store.set("key".to_owned(), "value".to_owned()).unwrap();
+/// ```
+pub trait Metrics: Send + Sync + fmt::Debug {
+    /// Called after a `get`, with whether the key was found.
+    fn on_get(&self, _latency: Duration, _found: bool) {}
+
+    /// Called after a `set`, `set_if_version` or `set_deferred`.
+    fn on_set(&self, _latency: Duration) {}
+
+    /// Called after a `remove` of an existing key.
+    fn on_remove(&self, _latency: Duration) {}
+
+    /// Called after a compaction, whether it was started by `compact`, by the
+    /// store on its own or in the background, with the size of the log before
+    /// and after.
+    fn on_compaction(&self, _bytes_before: u64, _bytes_after: u64, _duration: Duration) {}
+
+    /// Called instead of the hook of `op` when it fails, a `remove` of a
+    /// missing key included.
+    fn on_error(&self, _op: EngineOp, _err: &KvsError) {}
+}
+
+/// `Metrics` kept in memory with atomic counters, e.g. for tests or to be
+/// scraped by a monitoring endpoint.
+///
+/// Latencies are recorded in the histograms of `TimedEngine`. Only
+/// `EngineOp::Get`, `Set`, `Remove` and `Compact` are reported by a store.
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+    timings: [Timings; EngineOp::COUNT],
+    errors: [AtomicU64; EngineOp::COUNT],
+    misses: AtomicU64,
+    bytes_reclaimed: AtomicU64,
+}
+
+impl AtomicMetrics {
+    /// Creates counters at zero.
+    pub fn new() -> Self {
+        AtomicMetrics::default()
+    }
+
+    /// Returns a snapshot of the latencies of `op` recorded so far, failed
+    /// calls excluded.
+    pub fn histogram(&self, op: EngineOp) -> LatencyHistogram {
+        self.timings[op as usize].snapshot()
+    }
+
+    /// Returns the number of failed calls of `op`.
+    pub fn errors(&self, op: EngineOp) -> u64 {
+        self.errors[op as usize].load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of gets that did not find their key.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bytes reclaimed by compactions.
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_reclaimed.load(Ordering::Relaxed)
+    }
+}
+
+impl Metrics for AtomicMetrics {
+    fn on_get(&self, latency: Duration, found: bool) {
+        self.timings[EngineOp::Get as usize].record(latency);
+        if !found {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn on_set(&self, latency: Duration) {
+        self.timings[EngineOp::Set as usize].record(latency);
+    }
+
+    fn on_remove(&self, latency: Duration) {
+        self.timings[EngineOp::Remove as usize].record(latency);
+    }
+
+    fn on_compaction(&self, bytes_before: u64, bytes_after: u64, duration: Duration) {
+        self.timings[EngineOp::Compact as usize].record(duration);
+        let reclaimed = bytes_before.saturating_sub(bytes_after);
+        self.bytes_reclaimed.fetch_add(reclaimed, Ordering::Relaxed);
+    }
+
+    fn on_error(&self, op: EngineOp, _err: &KvsError) {
+        self.errors[op as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -1,18 +1,109 @@
 //! This module provides various key value storage engines.
 
-use crate::Result;
+use crate::{KvsError, Result};
+
+/// Trait for the read portion of a key value storage engine.
+///
+/// Read-only stores such as `ReplicaKvStore` implement only this trait, so they
+/// cannot be written to directly.
+pub trait KvsReader: Clone + Send + 'static {
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Gets the value of a given key, along with where it was read from.
+    ///
+    /// Engines without a value cache always report `ReadSource::Disk`.
+    fn get_with_source(&self, key: String) -> Result<Option<(String, ReadSource)>> {
+        Ok(self.get(key)?.map(|value| (value, ReadSource::Disk)))
+    }
+
+    /// Returns whether a given key exists.
+    ///
+    /// Engines that can answer without reading the value override this.
+    fn contains_key(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+/// Where `KvsReader::get_with_source` found a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSource {
+    /// The value was served from the in-memory value cache.
+    Cache,
+    /// The value was read from the storage.
+    Disk,
+}
 
 /// Trait for a key value storage engine.
-pub trait KvsEngine: Clone + Send + 'static {
+pub trait KvsEngine: KvsReader {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
     fn set(&self, key: String, value: String) -> Result<()>;
 
-    /// Gets the string value of a given string key.
+    /// Returns the name of the engine, reported to clients by `KvsServer`.
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Compacts the storage, returning the number of bytes reclaimed on disk.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Engines that do not compact return 0.
+    fn compact(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Flushes buffered writes and syncs them to disk, so they survive a
+    /// power loss.
+    ///
+    /// Engines that sync every write before returning do nothing.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets the values of many keys at once.
+    ///
+    /// Engines that batch writes persist all of them together, which is much
+    /// faster than a `set` per key for bulk loads. Others set them one by one.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the value of a string key like `set`, returning its new version.
+    ///
+    /// Every write to the engine gets a larger version than the ones before, so
+    /// a version identifies one write of a key. Engines that do not version keys
+    /// return 0.
+    fn set_versioned(&self, key: String, value: String) -> Result<u64> {
+        self.set(key, value).map(|_| 0)
+    }
+
+    /// Sets the value of a string key if its current version is `version`,
+    /// returning its new version. A missing key has version 0.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::VersionMismatch` if the key has another version, or
+    /// `KvsError::Unsupported` if the engine does not version keys.
+    fn set_if_version(&self, _key: String, _value: String, _version: u64) -> Result<u64> {
+        Err(KvsError::Unsupported("versioned writes".to_owned()))
+    }
+
+    /// Sets the value of a string key like `set`, returning its previous value
+    /// or `None` if the key did not exist.
+    ///
+    /// The default reads the key before setting it, so a write in between is
+    /// not seen. Engines override it to do both atomically.
+    fn set_returning(&self, key: String, value: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(previous)
+    }
 
     /// Removes a given key.
     ///
@@ -20,10 +111,177 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Removes a given key like `remove`, returning its previous value. A
+    /// missing key returns `None` instead of `KvsError::KeyNotFound`.
+    ///
+    /// Like `set_returning`, the default reads the key before removing it.
+    fn remove_returning(&self, key: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        match self.remove(key) {
+            Ok(()) | Err(KvsError::KeyNotFound) => Ok(previous),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns a snapshot of all keys currently stored.
+    ///
+    /// The order of the returned keys is unspecified, and keys written after
+    /// the call returns are not listed. Whether the list is consistent with
+    /// writes running during the call depends on the engine.
+    fn keys(&self) -> Result<Vec<String>>;
+
+    /// Returns the number of keys currently stored. Removed keys are not
+    /// counted, however many records they left behind.
+    ///
+    /// Engines that keep a count answer without listing the keys.
+    fn len(&self) -> Result<usize> {
+        Ok(self.keys()?.len())
+    }
+
+    /// Returns whether no key is stored.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns the key/value pairs whose keys start with `prefix`, ordered by
+    /// key.
+    ///
+    /// Engines without an ordered index list every key to find them.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut keys = self.keys()?;
+        keys.retain(|key| key.starts_with(prefix));
+        keys.sort_unstable();
+        let mut pairs = vec![];
+        for key in keys {
+            // Keys removed since they were listed are skipped
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Removes every key.
+    ///
+    /// Once it returns, reopening the storage finds no key, even after a
+    /// crash.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine cannot drop all its
+    /// keys at once.
+    fn clear(&self) -> Result<()> {
+        Err(KvsError::Unsupported("clear".to_owned()))
+    }
+
+    /// Returns at most `max` changes written at or after `from`, in the order
+    /// they were written, and the position to read the next ones from.
+    ///
+    /// Start from `LogPosition::default()` to read the whole log, which replays
+    /// to the current state of the engine.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::LogCompacted` if compaction has removed the
+    /// generation `from` points into, or `KvsError::Unsupported` if the engine
+    /// keeps no log to follow.
+    fn changes(&self, _from: LogPosition, _max: usize) -> Result<(Vec<Change>, LogPosition)> {
+        Err(KvsError::Unsupported("following the log".to_owned()))
+    }
+
+    /// Atomically replaces the value of a key with `f` applied to it.
+    ///
+    /// `f` receives the current value, or `None` if the key does not exist. The
+    /// key is set to the returned value, or removed if `f` returns `None`.
+    /// Returns the new value.
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>>;
+
+    /// Runs `ops` in order, returning the result of each.
+    ///
+    /// Every op sees the writes of the ops before it. A `remove` of a missing
+    /// key is not an error here, it reports `OpResult::Removed(false)`.
+    ///
+    /// Engines that can group the writes override this, e.g. to hold their
+    /// writer across the batch. Others run the ops one by one, so a failed op
+    /// leaves the ones before it applied.
+    fn batch(&self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        ops.into_iter()
+            .map(|op| match op {
+                Op::Get(key) => self.get(key).map(OpResult::Value),
+                Op::Set(key, value) => self.set(key, value).map(|_| OpResult::Set),
+                Op::Remove(key) => match self.remove(key) {
+                    Ok(()) => Ok(OpResult::Removed(true)),
+                    Err(KvsError::KeyNotFound) => Ok(OpResult::Removed(false)),
+                    Err(e) => Err(e),
+                },
+            })
+            .collect()
+    }
+
+    /// Atomically sets a key to `new`, or removes it if `new` is `None`, if its
+    /// current value is `expected`. `None` expects the key to be absent.
+    ///
+    /// Returns whether the swap happened. Removing an absent key that was
+    /// expected absent succeeds without writing anything.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine has no atomic swap.
+    fn compare_and_swap(
+        &self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(KvsError::Unsupported("compare and swap".to_owned()))
+    }
+}
+
+/// A transformation of the value of a key, applied by `KvsEngine::update`.
+pub type Transform = fn(Option<String>) -> Option<String>;
+
+/// One operation of a `KvsEngine::batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Gets the value of a key.
+    Get(String),
+    /// Sets a key to a value.
+    Set(String, String),
+    /// Removes a key.
+    Remove(String),
+}
+
+/// The result of an `Op`, in the order of the batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpResult {
+    /// The value read by a `Get`, `None` if the key does not exist.
+    Value(Option<String>),
+    /// A `Set` was applied.
+    Set,
+    /// Whether a `Remove` found its key.
+    Removed(bool),
 }
 
+mod file_system;
+mod index;
 mod kvs;
+mod memory;
+mod metrics;
+mod replica;
 mod sled;
+mod timed;
 
-pub use self::kvs::KvStore;
+pub use self::file_system::{FileHandle, FileSystem, MemFileSystem, OsFileSystem};
+pub use self::index::{IndexKind, ShardHash};
+pub use self::kvs::{
+    AggResult, Aggregation, Change, Checkpoint, CompactionPolicy, CompactionReport,
+    CompactionStats, DiskSpaceCheck, GenerationReclaim, KvStats, KvStore, KvStoreOptions,
+    LogEncoding, LogIter, LogPosition, LowSpaceAction, Mutation, NonInteger, RecoveryMode,
+    RecoveryReport, ScrubStats, Scrubber, StatsFile, SyncPolicy, TieredCompaction,
+};
+pub use self::memory::MemoryKvsEngine;
+pub use self::metrics::{AtomicMetrics, Metrics};
+pub use self::replica::ReplicaKvStore;
 pub use self::sled::SledKvsEngine;
+pub use self::timed::{EngineOp, LatencyHistogram, TimedEngine};
@@ -0,0 +1,55 @@
+use super::{KvStore, KvsReader, ReadSource};
+use crate::Result;
+use std::path::PathBuf;
+
+/// A read-only replica of a `KvStore`.
+///
+/// It follows a leader by applying the records shipped with `KvStore::ship_log`.
+/// It implements `KvsReader` but not `KvsEngine`, so it cannot be written to
+/// directly.
+///
+/// # Example
+///
+/// ```
+/// # use unifier::{KvStore, KvsEngine, KvsReader, LogPosition, ReplicaKvStore};
+/// # use tempfile::TempDir;
+/// # let (leader_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+/// let leader = KvStore::open(leader_dir.path()).unwrap();
+/// let replica = ReplicaKvStore::open(replica_dir.path()).unwrap();
+///
+/// leader.set("key".to_string(), "value".to_string()).unwrap();
+/// let (records, _next) = leader.ship_log(LogPosition::default()).unwrap();
+/// replica.apply(&records).unwrap();
+///
+/// assert_eq!(replica.get("key".to_string()).unwrap(), Some("value".to_string()));
+/// ```
+#[derive(Clone)]
+pub struct ReplicaKvStore(KvStore);
+
+impl ReplicaKvStore {
+    /// Open the replica at a given path.
+    pub fn open(path: impl Into<PathBuf>) -> Result<ReplicaKvStore> {
+        Ok(ReplicaKvStore(KvStore::open(path)?))
+    }
+
+    /// Applies records shipped from the leader.
+    ///
+    /// Records must be applied in the order they were shipped.
+    pub fn apply(&self, records: &[u8]) -> Result<()> {
+        self.0.replay(records)
+    }
+}
+
+impl KvsReader for ReplicaKvStore {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn get_with_source(&self, key: String) -> Result<Option<(String, ReadSource)>> {
+        self.0.get_with_source(key)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.0.contains_key(key)
+    }
+}
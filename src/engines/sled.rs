@@ -1,6 +1,7 @@
-use super::KvsEngine;
+use super::{KvsEngine, KvsReader, Op, OpResult, Transform};
 use crate::{KvsError, Result};
-use sled::{Db, Tree};
+use sled::{Batch, Db, Tree};
+use std::collections::HashMap;
 
 /// Wrapper of `sled::Db`
 #[derive(Clone)]
@@ -13,7 +14,38 @@ impl SledKvsEngine {
     }
 }
 
+impl KvsReader for SledKvsEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        Ok(tree
+            .get(key)?
+            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        Ok(tree.contains_key(key)?)
+    }
+}
+
 impl KvsEngine for SledKvsEngine {
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    /// Sled reclaims space by itself, so this only flushes it.
+    fn compact(&self) -> Result<u64> {
+        self.0.flush()?;
+        Ok(0)
+    }
+
     fn set(&self, key: String, value: String) -> Result<()> {
         let tree: &Tree = &self.0;
         tree.insert(key, value.into_bytes()).map(|_| ())?;
@@ -21,13 +53,21 @@ impl KvsEngine for SledKvsEngine {
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.into_bytes(), value.into_bytes());
+        }
         let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.0.clear()?;
+        self.0.flush()?;
+        Ok(())
     }
 
     fn remove(&self, key: String) -> Result<()> {
@@ -36,4 +76,126 @@ impl KvsEngine for SledKvsEngine {
         tree.flush().expect("tree flush!");
         Ok(())
     }
+
+    /// Uses the previous value that sled returns from `insert`.
+    fn set_returning(&self, key: String, value: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        let previous = tree.insert(key, value.into_bytes())?;
+        tree.flush()?;
+        previous
+            .map(|i_vec| Ok(String::from_utf8(AsRef::<[u8]>::as_ref(&i_vec).to_vec())?))
+            .transpose()
+    }
+
+    /// Uses the previous value that sled returns from `remove`.
+    fn remove_returning(&self, key: String) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        let previous = tree.remove(key)?;
+        if previous.is_some() {
+            tree.flush()?;
+        }
+        previous
+            .map(|i_vec| Ok(String::from_utf8(AsRef::<[u8]>::as_ref(&i_vec).to_vec())?))
+            .transpose()
+    }
+
+    /// Iterates the tree, which is not an atomic snapshot: keys written or
+    /// removed during the call may or may not be listed.
+    fn keys(&self) -> Result<Vec<String>> {
+        let tree: &Tree = &self.0;
+        tree.iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(AsRef::<[u8]>::as_ref(&key?).to_vec())?))
+            .collect()
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.0.len())
+    }
+
+    /// Uses the native prefix scan of sled, which seeks to the prefix instead
+    /// of listing every key. Like `keys`, it is not an atomic snapshot.
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let tree: &Tree = &self.0;
+        tree.scan_prefix(prefix)
+            .map(|pair| {
+                let (key, value) = pair?;
+                Ok((
+                    String::from_utf8(AsRef::<[u8]>::as_ref(&key).to_vec())?,
+                    String::from_utf8(AsRef::<[u8]>::as_ref(&value).to_vec())?,
+                ))
+            })
+            .collect()
+    }
+
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        let tree: &Tree = &self.0;
+        loop {
+            let old = tree.get(&key)?;
+            let value = old
+                .as_ref()
+                .map(|i_vec| String::from_utf8(AsRef::<[u8]>::as_ref(i_vec).to_vec()))
+                .transpose()?;
+            let new = f(value);
+            let swapped = tree.compare_and_swap(&key, old, new.as_ref().map(String::as_bytes))?;
+            // Retry if the key changed since it was read
+            if swapped.is_ok() {
+                tree.flush()?;
+                return Ok(new);
+            }
+        }
+    }
+
+    /// Applies the writes atomically as one sled `Batch`. Reads of keys the
+    /// batch wrote are answered from its pending writes.
+    fn batch(&self, ops: Vec<Op>) -> Result<Vec<OpResult>> {
+        let tree: &Tree = &self.0;
+        let mut batch = Batch::default();
+        let mut pending: HashMap<String, Option<String>> = HashMap::new();
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            let result = match op {
+                Op::Get(key) => match pending.get(&key) {
+                    Some(value) => OpResult::Value(value.clone()),
+                    None => OpResult::Value(self.get(key)?),
+                },
+                Op::Set(key, value) => {
+                    batch.insert(key.as_bytes(), value.as_bytes());
+                    pending.insert(key, Some(value));
+                    OpResult::Set
+                }
+                Op::Remove(key) => {
+                    let existed = match pending.get(&key) {
+                        Some(value) => value.is_some(),
+                        None => tree.contains_key(&key)?,
+                    };
+                    batch.remove(key.as_bytes());
+                    pending.insert(key, None);
+                    OpResult::Removed(existed)
+                }
+            };
+            results.push(result);
+        }
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        Ok(results)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        let swapped = tree.compare_and_swap(
+            key,
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        if swapped.is_ok() {
+            tree.flush()?;
+        }
+        Ok(swapped.is_ok())
+    }
 }
@@ -0,0 +1,266 @@
+use super::{Change, KvsEngine, KvsReader, LogPosition, ReadSource, Transform};
+use crate::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of histogram buckets, the last one takes every longer latency.
+const BUCKETS: usize = 32;
+
+/// An engine wrapper recording the latency of every operation of the inner
+/// engine.
+///
+/// Clones share their histograms, so the wrapper can be handed to a server and
+/// read from another thread.
+///
+/// # Example
+///
+/// ```
+/// # use unifier::{EngineOp, KvStore, KvsEngine, TimedEngine};
+/// # use tempfile::TempDir;
+/// # let temp_dir = TempDir::new().unwrap();
+/// let engine = TimedEngine::new(KvStore::open(temp_dir.path()).unwrap());
+/// engine.set("key".to_string(), "value".to_string()).unwrap();
+/// assert_eq!(engine.histogram(EngineOp::Set).count(), 1);
+/// ```
+#[derive(Clone)]
+pub struct TimedEngine<E> {
+    inner: E,
+    timings: Arc<[Timings; EngineOp::COUNT]>,
+}
+
+/// The operations timed by `TimedEngine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EngineOp {
+    /// `get`, `get_with_source` and `contains_key`.
+    Get,
+    /// `set`, `set_versioned` and `set_if_version`.
+    Set,
+    /// `set_many`, one sample per batch.
+    SetMany,
+    /// `remove`.
+    Remove,
+    /// `keys`, `len` and `scan_prefix`.
+    Keys,
+    /// `update` and `compare_and_swap`.
+    Update,
+    /// `compact`.
+    Compact,
+}
+
+impl EngineOp {
+    pub(super) const COUNT: usize = 7;
+}
+
+impl<E> TimedEngine<E> {
+    /// Wraps `inner`, with empty histograms.
+    pub fn new(inner: E) -> Self {
+        TimedEngine {
+            inner,
+            timings: Arc::default(),
+        }
+    }
+
+    /// Returns the wrapped engine. Calls made on it directly are not timed.
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    /// Returns a snapshot of the latencies of `op` recorded so far, failed
+    /// calls included.
+    pub fn histogram(&self, op: EngineOp) -> LatencyHistogram {
+        self.timings[op as usize].snapshot()
+    }
+
+    fn time<R>(&self, op: EngineOp, f: impl FnOnce(&E) -> R) -> R {
+        let started = Instant::now();
+        let res = f(&self.inner);
+        self.timings[op as usize].record(started.elapsed());
+        res
+    }
+}
+
+impl<E: KvsReader> KvsReader for TimedEngine<E> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.time(EngineOp::Get, |inner| inner.get(key))
+    }
+
+    fn get_with_source(&self, key: String) -> Result<Option<(String, ReadSource)>> {
+        self.time(EngineOp::Get, |inner| inner.get_with_source(key))
+    }
+
+    fn contains_key(&self, key: String) -> Result<bool> {
+        self.time(EngineOp::Get, |inner| inner.contains_key(key))
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for TimedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.time(EngineOp::Set, |inner| inner.set(key, value))
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    /// Not timed, since syncing depends on the disk more than on the engine.
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    /// Not timed, it is not one of the operations of `EngineOp`.
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    /// Not timed, it is not one of the operations of `EngineOp`.
+    fn changes(&self, from: LogPosition, max: usize) -> Result<(Vec<Change>, LogPosition)> {
+        self.inner.changes(from, max)
+    }
+
+    fn compact(&self) -> Result<u64> {
+        self.time(EngineOp::Compact, |inner| inner.compact())
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.time(EngineOp::SetMany, |inner| inner.set_many(pairs))
+    }
+
+    fn set_versioned(&self, key: String, value: String) -> Result<u64> {
+        self.time(EngineOp::Set, |inner| inner.set_versioned(key, value))
+    }
+
+    fn set_if_version(&self, key: String, value: String, version: u64) -> Result<u64> {
+        self.time(EngineOp::Set, |inner| {
+            inner.set_if_version(key, value, version)
+        })
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.time(EngineOp::Remove, |inner| inner.remove(key))
+    }
+
+    fn set_returning(&self, key: String, value: String) -> Result<Option<String>> {
+        self.time(EngineOp::Set, |inner| inner.set_returning(key, value))
+    }
+
+    fn remove_returning(&self, key: String) -> Result<Option<String>> {
+        self.time(EngineOp::Remove, |inner| inner.remove_returning(key))
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.time(EngineOp::Keys, |inner| inner.keys())
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.time(EngineOp::Keys, |inner| inner.len())
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.time(EngineOp::Keys, |inner| inner.scan_prefix(prefix))
+    }
+
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        self.time(EngineOp::Update, |inner| inner.update(key, f))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.time(EngineOp::Update, |inner| {
+            inner.compare_and_swap(key, expected, new)
+        })
+    }
+}
+
+/// Latencies of one operation, counted in power of two buckets of
+/// microseconds.
+#[derive(Debug, Default)]
+pub(super) struct Timings {
+    buckets: [AtomicU64; BUCKETS],
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl Timings {
+    pub(super) fn record(&self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[bucket(us)].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Index of the bucket of a latency of `us` microseconds.
+fn bucket(us: u64) -> usize {
+    let log2 = (u64::BITS - us.leading_zeros()) as usize;
+    log2.min(BUCKETS - 1)
+}
+
+/// The latencies of an operation recorded by a `TimedEngine` or an
+/// `AtomicMetrics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: Vec<u64>,
+    total_us: u64,
+    max_us: u64,
+}
+
+impl LatencyHistogram {
+    /// Number of recorded calls.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Counts of calls per bucket. Bucket 0 holds the calls under 1µs, and
+    /// bucket `i` those from 2^(i-1)µs up to 2^iµs.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Mean latency, zero without calls.
+    pub fn mean(&self) -> Duration {
+        match self.count() {
+            0 => Duration::ZERO,
+            count => Duration::from_micros(self.total_us / count),
+        }
+    }
+
+    /// Longest latency.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us)
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, for `q` in `0..=1`.
+    /// Zero without calls.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(1 << i).min(self.max());
+            }
+        }
+        self.max()
+    }
+}
@@ -1,5 +1,8 @@
+// `failure_derive` expands its impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
+use crate::protocol::{ServerError, ServerErrorKind};
 use failure::Fail;
-use sled;
 use std::io;
 use std::string::FromUtf8Error;
 
@@ -12,6 +15,15 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Encoding or decoding error of a binary log record.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// No space left on the device.
+    ///
+    /// The failed record has been rolled back, so the store stays usable once
+    /// space is freed.
+    #[fail(display = "No space left on device")]
+    DiskFull,
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -25,20 +37,119 @@ pub enum KvsError {
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// Iteration cursor does not exist or has expired.
+    #[fail(display = "Cursor not found")]
+    CursorNotFound,
+    /// Several log files claim the same generation number.
+    #[fail(display = "Duplicate generation {}", gen)]
+    DuplicateGeneration {
+        /// The generation number claimed twice.
+        gen: u64,
+    },
+    /// The requested log position has been removed by compaction.
+    #[fail(display = "Log position has been compacted")]
+    LogCompacted,
+    /// No transform is registered under the requested name.
+    #[fail(display = "Unknown transform: {}", _0)]
+    UnknownTransform(String),
+    /// The deadline of a request passed before it completed.
+    #[fail(display = "deadline exceeded")]
+    DeadlineExceeded,
+    /// Lookups and removals are not supported by an append-only `KvStore`.
+    #[fail(display = "Operation not supported by an append-only store")]
+    AppendOnly,
+    /// A write with the same dedup id is still being applied.
+    #[fail(display = "Duplicate request in progress")]
+    DuplicateRequest,
+    /// A record read back does not match what was written.
+    #[fail(display = "Corrupted record at {}:{}", gen, pos)]
+    Corruption {
+        /// The generation of the record.
+        gen: u64,
+        /// The byte offset of the record inside the generation.
+        pos: u64,
+    },
+    /// A conditional write found the key at another version.
+    #[fail(display = "Version mismatch, the current version is {}", current)]
+    VersionMismatch {
+        /// The current version of the key.
+        current: u64,
+    },
+    /// The engine does not support the operation.
+    #[fail(display = "Unsupported operation: {}", _0)]
+    Unsupported(String),
+    /// The key starts with the prefix reserved for internal bookkeeping.
+    #[fail(display = "Key {} uses a reserved prefix", _0)]
+    ReservedKey(String),
+    /// The admin token of a request is missing or wrong.
+    #[fail(display = "Unauthorized")]
+    Unauthorized,
+    /// The server is read-only and rejects writes.
+    #[fail(display = "read only")]
+    ReadOnly,
+    /// The encoded request is larger than the server accepts.
+    #[fail(
+        display = "Request of {} bytes exceeds the limit of {} bytes",
+        size, max
+    )]
+    RequestTooLarge {
+        /// Size of the encoded request. A server refusing a request before
+        /// reading all of it reports one byte more than `max`.
+        size: u64,
+        /// Largest request the server accepts.
+        max: u64,
+    },
+    /// The store reads or writes compressed records, but was built without
+    /// the `compression` feature.
+    #[fail(display = "Compression needs the `compression` feature")]
+    CompressionDisabled,
+    /// A value aggregated or incremented as an integer is not one.
+    #[fail(display = "Value of key {} is not an integer", _0)]
+    NotAnInteger(String),
+    /// An error response of the server, for the kinds of `ServerError` without
+    /// a variant of their own.
+    #[fail(display = "{}", _0)]
+    Server(ServerError),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
 }
 
+impl From<ServerError> for KvsError {
+    fn from(err: ServerError) -> Self {
+        match err.kind {
+            ServerErrorKind::KeyNotFound => KvsError::KeyNotFound,
+            ServerErrorKind::Unauthorized => KvsError::Unauthorized,
+            ServerErrorKind::ReadOnly => KvsError::ReadOnly,
+            ServerErrorKind::DeadlineExceeded => KvsError::DeadlineExceeded,
+            _ => KvsError::Server(err),
+        }
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from(err: io::Error) -> Self {
-        KvsError::Io(err)
+        if err.kind() == io::ErrorKind::StorageFull {
+            KvsError::DiskFull
+        } else {
+            KvsError::Io(err)
+        }
     }
 }
 
 impl From<serde_json::Error> for KvsError {
     fn from(err: serde_json::Error) -> Self {
-        KvsError::Serde(err)
+        if err.is_io() {
+            KvsError::from(io::Error::from(err))
+        } else {
+            KvsError::Serde(err)
+        }
+    }
+}
+
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> Self {
+        KvsError::Bincode(err)
     }
 }
 
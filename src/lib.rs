@@ -4,14 +4,34 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+#[cfg(feature = "tokio")]
+pub use asynchronous::{AsyncKvsClient, AsyncKvsEngine, AsyncKvsServer, TokioEngine};
+pub use client::{
+    ChangeStream, ClientStats, CoalescingClient, KvsClient, KvsClientPool, NotificationStream,
+    PooledClient,
+};
+pub use engines::{
+    AggResult, Aggregation, AtomicMetrics, Change, Checkpoint, CompactionPolicy, CompactionReport,
+    CompactionStats, DiskSpaceCheck, EngineOp, FileHandle, FileSystem, GenerationReclaim,
+    IndexKind, KvStats, KvStore, KvStoreOptions, KvsEngine, KvsReader, LatencyHistogram,
+    LogEncoding, LogIter, LogPosition, LowSpaceAction, MemFileSystem, MemoryKvsEngine, Metrics,
+    Mutation, NonInteger, Op, OpResult, OsFileSystem, ReadSource, RecoveryMode, RecoveryReport,
+    ReplicaKvStore, ScrubStats, Scrubber, ShardHash, SledKvsEngine, StatsFile, SyncPolicy,
+    TieredCompaction, TimedEngine, Transform,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use protocol::{
+    Compression, ConnectionStats, Notification, ServerError, ServerErrorKind, ServerInfo,
+    ServerStats, WatchEvent,
+};
+pub use server::{KvsServer, MirrorPolicy, ServerHandle, WireProtocol};
 
+#[cfg(feature = "tokio")]
+mod asynchronous;
 mod client;
 mod engines;
 mod error;
 mod protocol;
 mod server;
 pub mod thread_pool;
+mod wire;
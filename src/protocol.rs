@@ -1,26 +1,365 @@
+use crate::{Change, KvsError, LogPosition};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the protocol spoken by this crate, reported in `ServerInfo`.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// A request or response tagged with the trace id of the request.
+///
+/// The server logs the trace id with the request and echoes it in the response.
+/// Requests may carry a deadline in milliseconds since the Unix epoch, and a
+/// dedup id the server uses to apply a retried write once.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Traced<T> {
+    pub trace_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_id: Option<String>,
+    pub body: T,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    /// Fetches the `ServerInfo`, offering the compression algorithms the
+    /// client supports, in order of preference.
+    Hello {
+        #[serde(default)]
+        compression: Vec<Compression>,
+    },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    SetIfVersion {
+        key: String,
+        value: String,
+        version: u64,
+    },
+    Remove {
+        key: String,
+    },
+    IterOpen,
+    IterNext {
+        cursor: u64,
+        count: usize,
+    },
+    IterClose {
+        cursor: u64,
+    },
+    ScanFilter {
+        prefix: String,
+        value_contains: String,
+    },
+    Watch {
+        key: String,
+    },
+    Unwatch {
+        key: String,
+    },
+    /// Reads at most `max` changes from the log of the engine, starting at
+    /// `from`.
+    Changes {
+        from: LogPosition,
+        max: usize,
+    },
+    Apply {
+        key: String,
+        fn_name: String,
+    },
+    Stats,
+    AdminDrain {
+        token: String,
+    },
+    AdminReadOnly {
+        token: String,
+        read_only: bool,
+    },
+    AdminCompact {
+        token: String,
+    },
+}
+
+impl Request {
+    /// Name of the request, as written to the access log.
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Hello { .. } => "Hello",
+            Request::Get { .. } => "Get",
+            Request::Set { .. } => "Set",
+            Request::SetIfVersion { .. } => "SetIfVersion",
+            Request::Remove { .. } => "Remove",
+            Request::IterOpen => "IterOpen",
+            Request::IterNext { .. } => "IterNext",
+            Request::IterClose { .. } => "IterClose",
+            Request::ScanFilter { .. } => "ScanFilter",
+            Request::Watch { .. } => "Watch",
+            Request::Unwatch { .. } => "Unwatch",
+            Request::Changes { .. } => "Changes",
+            Request::Apply { .. } => "Apply",
+            Request::Stats => "Stats",
+            Request::AdminDrain { .. } => "AdminDrain",
+            Request::AdminReadOnly { .. } => "AdminReadOnly",
+            Request::AdminCompact { .. } => "AdminCompact",
+        }
+    }
+
+    /// The key the request reads or writes, if any.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Request::Get { key }
+            | Request::Set { key, .. }
+            | Request::SetIfVersion { key, .. }
+            | Request::Remove { key }
+            | Request::Watch { key }
+            | Request::Unwatch { key }
+            | Request::Apply { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// A frame sent by the server: either the response to a request or a
+/// notification pushed for a watched key.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Frame<T> {
+    Response(Traced<T>),
+    Notification(Notification),
+}
+
+/// A change of a watched key, pushed by the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    /// The key that changed.
+    pub key: String,
+    /// What happened to the key.
+    pub event: WatchEvent,
+}
+
+/// The kind of change reported by a `Notification`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The key was set to the given value.
+    Set(String),
+    /// The key was set to a value that is not UTF-8, by `KvStore::set_bytes`.
+    /// Only reported by the change log of `KvsEngine::changes`, not by watches.
+    SetBytes(Vec<u8>),
+    /// The key was removed.
+    Removed,
+}
+
+/// An error response of a server, with a category that tells the client what
+/// went wrong without parsing the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerError {
+    /// The category of the error.
+    pub kind: ServerErrorKind,
+    /// The error as displayed by the server.
+    pub message: String,
+}
+
+/// The category of a `ServerError`.
+///
+/// The client turns the kinds that have a `KvsError` variant of their own back
+/// into it, and the others into `KvsError::Server`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerErrorKind {
+    /// The key does not exist.
+    KeyNotFound,
+    /// The request cannot be served as sent, e.g. it names an unknown
+    /// transform or cursor, or is too large. Retrying it fails again.
+    BadRequest,
+    /// The request lost a race with another write, e.g. a conditional write
+    /// found the key at another version.
+    Conflict,
+    /// The admin token of the request is missing or wrong.
+    Unauthorized,
+    /// The server is read-only and rejects writes.
+    ReadOnly,
+    /// The deadline of the request passed before it completed.
+    DeadlineExceeded,
+    /// The server failed to serve a valid request, e.g. on a disk error.
+    Internal,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<KvsError> for ServerError {
+    fn from(err: KvsError) -> Self {
+        let kind = match &err {
+            // Relayed as the server received it
+            KvsError::Server(err) => return err.clone(),
+            KvsError::KeyNotFound => ServerErrorKind::KeyNotFound,
+            KvsError::CursorNotFound
+            | KvsError::LogCompacted
+            | KvsError::UnknownTransform(_)
+            | KvsError::AppendOnly
+            | KvsError::Unsupported(_)
+            | KvsError::ReservedKey(_)
+            | KvsError::RequestTooLarge { .. }
+            | KvsError::NotAnInteger(_) => ServerErrorKind::BadRequest,
+            KvsError::VersionMismatch { .. } | KvsError::DuplicateRequest => {
+                ServerErrorKind::Conflict
+            }
+            KvsError::Unauthorized => ServerErrorKind::Unauthorized,
+            KvsError::ReadOnly => ServerErrorKind::ReadOnly,
+            KvsError::DeadlineExceeded => ServerErrorKind::DeadlineExceeded,
+            _ => ServerErrorKind::Internal,
+        };
+        ServerError {
+            kind,
+            message: err.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ServerError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
-    Ok(()),
-    Err(String),
+    /// The new version of the key.
+    Ok(u64),
+    Err(ServerError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    /// The key did not exist.
+    NotFound,
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IterOpenResponse {
+    Ok(u64),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IterNextResponse {
+    Ok(Vec<(String, String)>),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum IterCloseResponse {
+    Ok(()),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse {
+    /// The matching pairs, ordered by key.
+    Ok(Vec<(String, String)>),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChangesResponse {
+    /// The changes read, and the position to read the next ones from.
+    Ok(Vec<Change>, LogPosition),
+    /// The requested position has been removed by compaction.
+    Compacted,
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ApplyResponse {
+    Ok(Option<String>),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AdminResponse {
+    Ok(()),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactResponse {
+    /// The number of bytes reclaimed.
+    Ok(u64),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(ServerStats),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HelloResponse {
+    Ok(ServerInfo),
+    Err(ServerError),
+}
+
+/// What a `KvsServer` tells clients on connect.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Version of the protocol spoken by the server.
+    pub protocol_version: u32,
+    /// Largest encoded request the server accepts.
+    pub max_request_bytes: u64,
+    /// Whether admin requests need a token. Other requests never do.
+    pub auth_required: bool,
+    /// Name of the storage engine, e.g. `"kvs"`.
+    pub engine: String,
+    /// Compression of the frames that follow the handshake, if the server
+    /// accepted one offered by the client.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+/// Compression of the frames of a connection, negotiated on connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// LZ4, cheap on CPU with a fair ratio.
+    Lz4,
+}
+
+/// Statistics of a running `KvsServer`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// Connection lifecycle counters.
+    pub connections: ConnectionStats,
+}
+
+/// Connection lifecycle counters of a `KvsServer`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    /// Connections currently open.
+    pub open: u64,
+    /// Connections accepted since the server started.
+    pub accepted: u64,
+    /// Connections closed since the server started, including those closed
+    /// due to an error.
+    pub closed: u64,
+    /// Connections closed due to an error, e.g. a malformed request.
+    pub errored: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Ok(()),
+    Err(ServerError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum UnwatchResponse {
+    Ok(()),
+    Err(ServerError),
 }
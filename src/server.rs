@@ -1,72 +1,1284 @@
-use crate::protocol::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::protocol::{
+    AdminResponse, ApplyResponse, ChangesResponse, CompactResponse, Compression, ConnectionStats,
+    Frame, GetResponse, HelloResponse, IterCloseResponse, IterNextResponse, IterOpenResponse,
+    Notification, RemoveResponse, Request, ScanResponse, ServerError, ServerInfo, ServerStats,
+    SetResponse, StatsResponse, Traced, UnwatchResponse, WatchEvent, WatchResponse,
+    PROTOCOL_VERSION,
+};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use crate::wire::{is_frame_too_large, CompressionSwitch, FrameLimit, WireReader, WireWriter};
+use crate::{KvsEngine, KvsError, Result, Transform};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Deserializer, Value};
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_CURSOR_TIMEOUT: Duration = Duration::from_secs(60);
+/// Number of recent dedup ids whose outcome is remembered.
+const DEDUP_CAPACITY: usize = 4096;
+/// How often the accept loop and `ServerHandle::drain` check for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_REQUEST_BYTES: u64 = 64 * 1024 * 1024;
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
+    shared: Shared,
+    mirror: Mirroring,
+}
+
+/// State shared by all connections of a server.
+struct Shared {
+    cursors: CursorTable,
+    watches: WatchTable,
+    transforms: HashMap<String, Transform>,
+    lifecycle: Arc<Lifecycle>,
+    dedup: DedupTable,
+    admin_token: Option<String>,
+    max_request_bytes: u64,
+    access_log: Option<AccessLog>,
+    protocol: WireProtocol,
+    compression: Option<Compression>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+        KvsServer {
+            engine,
+            pool,
+            shared: Shared {
+                cursors: CursorTable::new(DEFAULT_CURSOR_TIMEOUT),
+                watches: WatchTable::default(),
+                transforms: HashMap::new(),
+                lifecycle: Arc::default(),
+                dedup: DedupTable::default(),
+                admin_token: None,
+                max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+                access_log: None,
+                protocol: WireProtocol::default(),
+                compression: None,
+            },
+            mirror: Mirroring::default(),
+        }
+    }
+
+    /// Applies every write to `mirror` as well, e.g. to warm up a store for a
+    /// live migration.
+    ///
+    /// Reads are served from the primary engine only. A write is applied to the
+    /// mirror after it succeeds on the primary, and writes are serialized while
+    /// a mirror is set, so the mirror applies them in the order of the primary.
+    /// Removing a key the mirror does not have is not an error.
+    pub fn with_mirror<M: KvsEngine>(mut self, mirror: M) -> Self {
+        self.mirror.engine = Some(Box::new(mirror));
+        self
+    }
+
+    /// Sets how mirror errors affect requests.
+    ///
+    /// Defaults to `MirrorPolicy::BestEffort`.
+    pub fn with_mirror_policy(mut self, policy: MirrorPolicy) -> Self {
+        self.mirror.policy = policy;
+        self
+    }
+
+    /// Sets how long an idle iteration cursor is kept before it is discarded.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn with_cursor_timeout(mut self, timeout: Duration) -> Self {
+        self.shared.cursors = CursorTable::new(timeout);
+        self
+    }
+
+    /// Registers a transform that clients can apply to a key by `name`.
+    ///
+    /// The transform runs through `KvsEngine::update`, so it is atomic with
+    /// respect to other writes.
+    pub fn with_transform(mut self, name: impl Into<String>, f: Transform) -> Self {
+        self.shared.transforms.insert(name.into(), f);
+        self
+    }
+
+    /// Enables admin requests, which must carry `token`.
+    ///
+    /// Admin requests are refused unless a token is set.
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.shared.admin_token = Some(token.into());
+        self
+    }
+
+    /// Sets the largest encoded request the server accepts, reported to clients
+    /// on connect. Larger requests fail with `KvsError::RequestTooLarge`.
+    ///
+    /// Defaults to 64 MiB.
+    pub fn with_max_request_bytes(mut self, max: u64) -> Self {
+        self.shared.max_request_bytes = max;
+        self
+    }
+
+    /// Writes a line per request to the file at `path`, separate from the debug
+    /// logs, for auditing.
+    ///
+    /// Each line is a JSON object with the time in milliseconds since the Unix
+    /// epoch, the peer, the request, the first 8 bytes of the SHA-256 of its
+    /// key in hex, the response variant and the latency in microseconds. Keys
+    /// are hashed rather than logged. The file is appended to, and moved to
+    /// `<path>.1` once it would grow past `max_bytes`, replacing the previous
+    /// one.
+    pub fn with_access_log(mut self, path: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        self.shared.access_log = Some(AccessLog::open(path.into(), max_bytes)?);
+        Ok(self)
+    }
+
+    /// Sets the protocol spoken on connections.
+    ///
+    /// Defaults to `WireProtocol::Binary`, the one of `KvsClient`.
+    pub fn with_protocol(mut self, protocol: WireProtocol) -> Self {
+        self.shared.protocol = protocol;
+        self
+    }
+
+    /// Compresses the connections of clients that offer `compression` on
+    /// connect, for slow links. Other clients stay uncompressed.
+    ///
+    /// Only applies to `WireProtocol::Binary`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.shared.compression = Some(compression);
+        self
+    }
+
+    /// Starts the server read-only: it serves reads and rejects writes with
+    /// `KvsError::ReadOnly`, e.g. for a replica waiting to be promoted.
+    ///
+    /// The mode can be switched later with `ServerHandle::set_read_only` or an
+    /// admin request.
+    pub fn with_read_only(self, read_only: bool) -> Self {
+        self.shared
+            .lifecycle
+            .read_only
+            .store(read_only, Ordering::SeqCst);
+        self
+    }
+
+    /// Returns a handle to control the server once it runs.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            lifecycle: Arc::clone(&self.shared.lifecycle),
+        }
     }
 
     /// Run the server listening on the given address.
+    ///
+    /// It returns once the server has been drained or shut down and its last
+    /// connection closed, after flushing the engine.
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
+        // Accepts are polled, so that draining can close the listener
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(self.shared);
+        let lifecycle = Arc::clone(&shared.lifecycle);
+        let _ = lifecycle.local_addr.set(listener.local_addr()?);
+        lifecycle.listening.store(true, Ordering::SeqCst);
+        while !lifecycle.draining.load(Ordering::SeqCst) {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(false) {
+                error!("Connection failed: {}", e);
+                continue;
+            }
+            lifecycle.connections.accept();
             let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
+            let shared = Arc::clone(&shared);
+            let mirror = self.mirror.clone();
+            self.pool.spawn(move || {
+                if let Err(e) = serve(engine, shared, mirror, stream) {
+                    error!("Error on serving client: {}", e);
                 }
-                Err(e) => error!("Connection failed: {}", e),
             })
         }
+
+        drop(listener);
+        lifecycle.listening.store(false, Ordering::SeqCst);
+        info!(
+            "Draining {} connections",
+            lifecycle.connections.snapshot().open
+        );
+        while lifecycle.connections.snapshot().open > 0 {
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.engine.flush()
+    }
+}
+
+/// A handle to control a running `KvsServer`, returned by `KvsServer::handle`.
+#[derive(Clone)]
+pub struct ServerHandle {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl ServerHandle {
+    /// Stops accepting connections and waits at most `timeout` for the open
+    /// ones to close, e.g. to shift traffic away before a restart.
+    ///
+    /// New connections are refused once it returns, while open ones are still
+    /// served. Returns whether every connection closed in time. `KvsServer::run`
+    /// returns after the last one closes.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        self.lifecycle.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let listening = self.lifecycle.listening.load(Ordering::SeqCst);
+            if !listening && self.lifecycle.connections.snapshot().open == 0 {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                // Wait for the listener anyway, so new connections are refused
+                while self.lifecycle.listening.load(Ordering::SeqCst) {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Stops the server: stops accepting connections like `drain`, and closes
+    /// the open ones once their request in progress is answered.
+    ///
+    /// It returns right away. `KvsServer::run` returns after the last
+    /// connection closed and the engine is flushed.
+    pub fn shutdown(&self) {
+        self.lifecycle.draining.store(true, Ordering::SeqCst);
+        self.lifecycle.shutting_down.store(true, Ordering::SeqCst);
+        for stream in self.lifecycle.streams.lock().unwrap().values() {
+            // Ends the read of the next request, responses can still be sent
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Returns the address the server listens on, `None` until it runs.
+    ///
+    /// It tells the port picked by the system for a server bound to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.lifecycle.local_addr.get().copied()
+    }
+
+    /// Switches the server between serving writes and rejecting them, e.g. to
+    /// fence the old primary during a failover.
+    ///
+    /// Writes already running complete.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.lifecycle.read_only.store(read_only, Ordering::SeqCst);
+    }
+}
+
+/// Connections, accept state and write mode of a server.
+#[derive(Default)]
+struct Lifecycle {
+    connections: ConnectionCounters,
+    draining: AtomicBool,
+    shutting_down: AtomicBool,
+    listening: AtomicBool,
+    local_addr: OnceLock<SocketAddr>,
+    read_only: AtomicBool,
+    /// The open connections by id, closed by `ServerHandle::shutdown`.
+    streams: Mutex<HashMap<u64, TcpStream>>,
+}
+
+impl Lifecycle {
+    /// Registers an open connection, closing it right away if the server is
+    /// shutting down.
+    fn open(&self, conn: u64, tcp: &TcpStream) -> Result<()> {
+        let stream = tcp.try_clone()?;
+        let mut streams = self.streams.lock().unwrap();
+        // Checked under the lock, so `shutdown` either sees the stream or is
+        // seen here
+        if self.shutting_down.load(Ordering::SeqCst) {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        streams.insert(conn, stream);
         Ok(())
     }
+
+    fn close(&self, conn: u64) {
+        self.streams.lock().unwrap().remove(&conn);
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only.load(Ordering::SeqCst) {
+            Err(KvsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    shared: Arc<Shared>,
+    mirror: Mirroring,
+    tcp: TcpStream,
+) -> Result<()> {
+    let conn = shared.watches.connect();
+    let res = shared
+        .lifecycle
+        .open(conn, &tcp)
+        .and_then(|()| match shared.protocol {
+            WireProtocol::Binary => serve_requests(engine, &shared, &mirror, conn, tcp),
+            WireProtocol::Text => serve_text(engine, &shared, &mirror, tcp),
+        });
+    shared.lifecycle.close(conn);
+    shared.watches.disconnect(conn);
+    shared.lifecycle.connections.close(res.is_err());
+    res
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+fn serve_requests<E: KvsEngine>(
+    engine: E,
+    shared: &Shared,
+    mirror: &Mirroring,
+    conn: u64,
+    tcp: TcpStream,
+) -> Result<()> {
+    let Shared {
+        cursors,
+        watches,
+        transforms,
+        lifecycle,
+        dedup,
+        admin_token,
+        max_request_bytes,
+        access_log,
+        protocol: _,
+        compression: accepted,
+    } = shared;
     let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    let compression = CompressionSwitch::default();
+    // Refilled before each frame, which fails to read once it is used up
+    let budget = Rc::new(Cell::new(0));
+    let reader = FrameLimit::new(
+        WireReader::new(BufReader::new(&tcp), Arc::clone(&compression))
+            .with_max_frame(*max_request_bytes),
+        Rc::clone(&budget),
+    );
+    let writer: ConnWriter = Arc::new(Mutex::new(WireWriter::new(
+        BufWriter::new(tcp.try_clone()?),
+        Arc::clone(&compression),
+    )));
+    // Frames are self-delimiting JSON values, so the deserializer keeps reading
+    // until a whole request is in, however the bytes were split into segments
+    let mut req_reader = Deserializer::from_reader(reader).into_iter::<Traced<Request>>();
+    // Started on the first watch
+    let mut notifications = None;
 
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-        };};
-    }
+    loop {
+        budget.set(*max_request_bytes);
+        let req = match req_reader.next() {
+            Some(Ok(req)) => req,
+            Some(Err(e)) if e.io_error_kind() == Some(io::ErrorKind::InvalidData) => {
+                let e = io::Error::from(e);
+                if !is_frame_too_large(&e) {
+                    return Err(e.into());
+                }
+                // The rest of the frame is left unread, so no request can follow
+                let e = KvsError::RequestTooLarge {
+                    size: *max_request_bytes + 1,
+                    max: *max_request_bytes,
+                };
+                let resp = Rejected::Err(e.into());
+                let mut writer = writer.lock().unwrap();
+                let frame = Frame::Response(Traced {
+                    trace_id: String::new(),
+                    deadline: None,
+                    dedup_id: None,
+                    body: &resp,
+                });
+                serde_json::to_writer(&mut *writer, &frame)?;
+                writer.flush()?;
+                debug!("Request too large from {}", peer_addr);
+                return Ok(());
+            }
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        };
+        let Traced {
+            trace_id,
+            deadline,
+            dedup_id,
+            body: req,
+        } = req;
+        debug!(
+            "[{}] Receive request from {}: {:?}",
+            trace_id, peer_addr, req
+        );
+        let deadline = deadline.map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+        let started = Instant::now();
+        let op = req.op();
+        let key_hash = access_log.as_ref().and(req.key()).map(hash_key);
+
+        macro_rules! send_resp {
+            ($resp:expr) => {{
+                let resp = $resp;
+                if let Some(access_log) = access_log {
+                    access_log.record(&peer_addr, op, key_hash.as_deref(), &resp, started);
+                }
+                {
+                    let mut writer = writer.lock().unwrap();
+                    let frame = Frame::Response(Traced {
+                        trace_id: trace_id.clone(),
+                        deadline: None,
+                        dedup_id: None,
+                        body: &resp,
+                    });
+                    serde_json::to_writer(&mut *writer, &frame)?;
+                    writer.flush()?;
+                }
+                debug!("[{}] Response sent to {}: {:?}", trace_id, peer_addr, resp);
+            }};
+        }
 
-    for req in req_reader {
-        let req = req?;
-        debug!("Receive request from {}: {:?}", peer_addr, req);
         match req {
-            Request::Get { key } => send_resp!(match engine.get(key) {
+            Request::Hello {
+                compression: offered,
+            } => {
+                // A connection already compressed keeps its compression
+                let chosen = compression
+                    .get()
+                    .copied()
+                    .or(accepted.filter(|accepted| offered.contains(accepted)));
+                let info = ServerInfo {
+                    protocol_version: PROTOCOL_VERSION,
+                    max_request_bytes: *max_request_bytes,
+                    auth_required: admin_token.is_some(),
+                    engine: engine.name().to_owned(),
+                    compression: chosen,
+                };
+                let resp = match within(deadline, || Ok(info)) {
+                    Ok(info) => HelloResponse::Ok(info),
+                    Err(e) => HelloResponse::Err(e.into()),
+                };
+                let switch = matches!(resp, HelloResponse::Ok(_));
+                send_resp!(resp);
+                // Frames after the response are compressed both ways
+                if let (true, Some(chosen)) = (switch, chosen) {
+                    let _ = compression.set(chosen);
+                }
+            }
+            Request::Get { key } => send_resp!(match within(deadline, || engine.get(key)) {
                 Ok(value) => GetResponse::Ok(value),
-                Err(e) => GetResponse::Err(format!("{}", e)),
-            }),
-            Request::Set { key, value } => send_resp!(match engine.set(key, value) {
-                Ok(_) => SetResponse::Ok(()),
-                Err(e) => SetResponse::Err(format!("{}", e)),
+                Err(e) => GetResponse::Err(e.into()),
             }),
-            Request::Remove { key } => send_resp!(match engine.remove(key) {
-                Ok(_) => RemoveResponse::Ok(()),
-                Err(e) => RemoveResponse::Err(format!("{}", e)),
+            Request::Set { key, value } => {
+                let res = dedup.run(dedup_id, || {
+                    within(deadline, || {
+                        lifecycle.check_writable()?;
+                        let _order = mirror.order();
+                        let version = engine.set_versioned(key.clone(), value.clone())?;
+                        watches.notify(key.clone(), WatchEvent::Set(value.clone()));
+                        mirror.set(key, value)?;
+                        Ok(version)
+                    })
+                });
+                send_resp!(match res {
+                    Ok(version) => SetResponse::Ok(version),
+                    Err(e) => SetResponse::Err(e.into()),
+                })
+            }
+            Request::SetIfVersion {
+                key,
+                value,
+                version,
+            } => {
+                let res = dedup.run(dedup_id, || {
+                    within(deadline, || {
+                        lifecycle.check_writable()?;
+                        let _order = mirror.order();
+                        let version = engine.set_if_version(key.clone(), value.clone(), version)?;
+                        watches.notify(key.clone(), WatchEvent::Set(value.clone()));
+                        mirror.set(key, value)?;
+                        Ok(version)
+                    })
+                });
+                send_resp!(match res {
+                    Ok(version) => SetResponse::Ok(version),
+                    Err(e) => SetResponse::Err(e.into()),
+                })
+            }
+            Request::Remove { key } => {
+                let res = dedup.run(dedup_id, || {
+                    within(deadline, || {
+                        lifecycle.check_writable()?;
+                        let _order = mirror.order();
+                        engine.remove(key.clone())?;
+                        watches.notify(key.clone(), WatchEvent::Removed);
+                        mirror.remove(key)
+                    })
+                });
+                send_resp!(match res {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(KvsError::KeyNotFound) => RemoveResponse::NotFound,
+                    Err(e) => RemoveResponse::Err(e.into()),
+                })
+            }
+            Request::IterOpen => {
+                let res = within(deadline, || engine.keys().map(|keys| cursors.open(keys)));
+                send_resp!(match res {
+                    Ok(cursor) => IterOpenResponse::Ok(cursor),
+                    Err(e) => IterOpenResponse::Err(e.into()),
+                })
+            }
+            Request::IterNext { cursor, count } => {
+                let res = within(deadline, || cursors.next(&engine, cursor, count));
+                send_resp!(match res {
+                    Ok(pairs) => IterNextResponse::Ok(pairs),
+                    Err(e) => IterNextResponse::Err(e.into()),
+                })
+            }
+            Request::IterClose { cursor } => {
+                send_resp!(match within(deadline, || cursors.close(cursor)) {
+                    Ok(_) => IterCloseResponse::Ok(()),
+                    Err(e) => IterCloseResponse::Err(e.into()),
+                })
+            }
+            Request::ScanFilter {
+                prefix,
+                value_contains,
+            } => {
+                let res = within(deadline, || scan_filter(&engine, &prefix, &value_contains));
+                send_resp!(match res {
+                    Ok(pairs) => ScanResponse::Ok(pairs),
+                    Err(e) => ScanResponse::Err(e.into()),
+                })
+            }
+            Request::Changes { from, max } => {
+                send_resp!(match within(deadline, || engine.changes(from, max)) {
+                    Ok((changes, next)) => ChangesResponse::Ok(changes, next),
+                    Err(KvsError::LogCompacted) => ChangesResponse::Compacted,
+                    Err(e) => ChangesResponse::Err(e.into()),
+                })
+            }
+            Request::Apply { key, fn_name } => {
+                let res = dedup.run(dedup_id, || {
+                    within(deadline, || {
+                        lifecycle.check_writable()?;
+                        let f = *transforms
+                            .get(&fn_name)
+                            .ok_or(KvsError::UnknownTransform(fn_name))?;
+                        let _order = mirror.order();
+                        let value = engine.update(key.clone(), f)?;
+                        match value.clone() {
+                            Some(value) => {
+                                watches.notify(key.clone(), WatchEvent::Set(value.clone()));
+                                mirror.set(key, value)?;
+                            }
+                            None => {
+                                watches.notify(key.clone(), WatchEvent::Removed);
+                                mirror.remove(key)?;
+                            }
+                        }
+                        Ok(value)
+                    })
+                });
+                send_resp!(match res {
+                    Ok(value) => ApplyResponse::Ok(value),
+                    Err(e) => ApplyResponse::Err(e.into()),
+                })
+            }
+            Request::Stats => {
+                let stats = ServerStats {
+                    connections: lifecycle.connections.snapshot(),
+                };
+                send_resp!(match within(deadline, || Ok(stats)) {
+                    Ok(stats) => StatsResponse::Ok(stats),
+                    Err(e) => StatsResponse::Err(e.into()),
+                })
+            }
+            Request::AdminDrain { token } => {
+                let res = within(deadline, || {
+                    authorize(admin_token, &token)?;
+                    info!("[{}] Draining on request from {}", trace_id, peer_addr);
+                    lifecycle.draining.store(true, Ordering::SeqCst);
+                    Ok(())
+                });
+                send_resp!(match res {
+                    Ok(_) => AdminResponse::Ok(()),
+                    Err(e) => AdminResponse::Err(e.into()),
+                })
+            }
+            Request::AdminReadOnly { token, read_only } => {
+                let res = within(deadline, || {
+                    authorize(admin_token, &token)?;
+                    info!(
+                        "[{}] Switching read-only to {} on request from {}",
+                        trace_id, read_only, peer_addr
+                    );
+                    lifecycle.read_only.store(read_only, Ordering::SeqCst);
+                    Ok(())
+                });
+                send_resp!(match res {
+                    Ok(_) => AdminResponse::Ok(()),
+                    Err(e) => AdminResponse::Err(e.into()),
+                })
+            }
+            Request::AdminCompact { token } => {
+                let res = within(deadline, || {
+                    authorize(admin_token, &token)?;
+                    info!("[{}] Compacting on request from {}", trace_id, peer_addr);
+                    engine.compact()
+                });
+                send_resp!(match res {
+                    Ok(reclaimed) => CompactResponse::Ok(reclaimed),
+                    Err(e) => CompactResponse::Err(e.into()),
+                })
+            }
+            Request::Watch { key } => {
+                let res = within(deadline, || {
+                    let queue = notifications
+                        .get_or_insert_with(|| notification_queue(Arc::clone(&writer)));
+                    watches.watch(key, conn, queue);
+                    Ok(())
+                });
+                send_resp!(match res {
+                    Ok(_) => WatchResponse::Ok(()),
+                    Err(e) => WatchResponse::Err(e.into()),
+                })
+            }
+            Request::Unwatch { key } => {
+                let res = within(deadline, || {
+                    watches.unwatch(&key, conn);
+                    Ok(())
+                });
+                send_resp!(match res {
+                    Ok(_) => UnwatchResponse::Ok(()),
+                    Err(e) => UnwatchResponse::Err(e.into()),
+                })
+            }
+        };
+    }
+    Ok(())
+}
+
+/// The response to a request refused before it is dispatched, which every
+/// response type decodes as its `Err` variant.
+#[derive(Debug, Serialize)]
+enum Rejected {
+    Err(ServerError),
+}
+
+/// Runs `op` unless `deadline` has passed, and fails it if the deadline passed
+/// while it ran.
+fn within<T, F: FnOnce() -> Result<T>>(deadline: Option<SystemTime>, op: F) -> Result<T> {
+    let expired = || deadline.is_some_and(|deadline| SystemTime::now() > deadline);
+    if expired() {
+        return Err(KvsError::DeadlineExceeded);
+    }
+    let res = op()?;
+    if expired() {
+        return Err(KvsError::DeadlineExceeded);
+    }
+    Ok(res)
+}
+
+/// Returns the pairs whose key starts with `prefix` and whose value contains
+/// `value_contains`, ordered by key.
+fn scan_filter<E: KvsEngine>(
+    engine: &E,
+    prefix: &str,
+    value_contains: &str,
+) -> Result<Vec<(String, String)>> {
+    let mut pairs = engine.scan_prefix(prefix)?;
+    pairs.retain(|(_, value)| value.contains(value_contains));
+    Ok(pairs)
+}
+
+/// Checks the token of an admin request.
+fn authorize(admin_token: &Option<String>, token: &str) -> Result<()> {
+    match admin_token {
+        Some(admin_token) if admin_token == token => Ok(()),
+        _ => Err(KvsError::Unauthorized),
+    }
+}
+
+// ========================= Text protocol =========================
+
+/// The protocol a `KvsServer` speaks on its connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WireProtocol {
+    /// JSON frames, spoken by `KvsClient`.
+    #[default]
+    Binary,
+    /// Lines of text, for poking at the server by hand over `telnet`.
+    ///
+    /// Requests are `GET <key>`, `SET <key> <value>`, `DEL <key>` and `QUIT`,
+    /// with case-insensitive commands. The value of a `SET` is the rest of the
+    /// line. Replies are one line each: the value quoted as a JSON string or
+    /// `(nil)` for a `GET`, `OK`, `NOT_FOUND` for a `DEL` of a missing key, or
+    /// `ERR <message>`. A line longer than the request limit is answered with
+    /// an `ERR` and closes the connection.
+    Text,
+}
+
+fn serve_text<E: KvsEngine>(
+    engine: E,
+    shared: &Shared,
+    mirror: &Mirroring,
+    tcp: TcpStream,
+) -> Result<()> {
+    let Shared {
+        watches,
+        lifecycle,
+        max_request_bytes,
+        ..
+    } = shared;
+    let peer_addr = tcp.peer_addr()?;
+    let mut reader = BufReader::new(&tcp);
+    let mut writer = BufWriter::new(&tcp);
+
+    loop {
+        let mut line = vec![];
+        // One byte past the limit tells a line too long from one just at it
+        let n = (&mut reader)
+            .take(*max_request_bytes + 1)
+            .read_until(b'\n', &mut line)?;
+        if n == 0 {
+            break;
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if n as u64 > *max_request_bytes {
+            writeln!(
+                writer,
+                "ERR line exceeds the limit of {} bytes",
+                max_request_bytes
+            )?;
+            writer.flush()?;
+            break;
+        }
+        let line = String::from_utf8(line)?;
+        let line = line.trim_end_matches('\r');
+        debug!("Receive text request from {}: {}", peer_addr, line);
+        let (cmd, args) = match line.trim_start().split_once(char::is_whitespace) {
+            Some((cmd, args)) => (cmd, args.trim_start()),
+            None => (line.trim(), ""),
+        };
+        let res = match cmd.to_uppercase().as_str() {
+            "" => continue,
+            "QUIT" => break,
+            // Quoted, so that no value reads as `(nil)` or spans lines
+            "GET" if !args.is_empty() => {
+                engine.get(args.to_owned()).and_then(|value| match value {
+                    Some(value) => Ok(serde_json::to_string(&value)?),
+                    None => Ok("(nil)".to_owned()),
+                })
+            }
+            "SET" => match args.split_once(char::is_whitespace) {
+                Some((key, value)) => within(None, || {
+                    lifecycle.check_writable()?;
+                    let (key, value) = (key.to_owned(), value.to_owned());
+                    let _order = mirror.order();
+                    engine.set(key.clone(), value.clone())?;
+                    watches.notify(key.clone(), WatchEvent::Set(value.clone()));
+                    mirror.set(key, value)?;
+                    Ok("OK".to_owned())
+                }),
+                None => Err(KvsError::StringError("usage: SET <key> <value>".to_owned())),
+            },
+            "DEL" if !args.is_empty() => within(None, || {
+                lifecycle.check_writable()?;
+                let _order = mirror.order();
+                engine.remove(args.to_owned())?;
+                watches.notify(args.to_owned(), WatchEvent::Removed);
+                mirror.remove(args.to_owned())?;
+                Ok("OK".to_owned())
             }),
+            "GET" | "DEL" => Err(KvsError::StringError(format!("usage: {} <key>", cmd))),
+            _ => Err(KvsError::StringError(format!("unknown command {}", cmd))),
+        };
+        let reply = match res {
+            Ok(reply) => reply,
+            Err(KvsError::KeyNotFound) => "NOT_FOUND".to_owned(),
+            Err(e) => format!("ERR {}", e),
         };
+        writeln!(writer, "{}", reply)?;
+        writer.flush()?;
     }
     Ok(())
 }
+
+// ========================= Access log =========================
+
+/// A line of the access log.
+#[derive(Serialize)]
+struct AccessLogEntry<'a> {
+    timestamp_ms: u64,
+    peer: String,
+    op: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_hash: Option<&'a str>,
+    result: String,
+    latency_us: u64,
+}
+
+/// The access log of a server, rotated by size.
+struct AccessLog {
+    path: PathBuf,
+    max_bytes: u64,
+    /// The file and its length.
+    file: Mutex<(File, u64)>,
+}
+
+impl AccessLog {
+    fn open(path: PathBuf, max_bytes: u64) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(AccessLog {
+            path,
+            max_bytes,
+            file: Mutex::new((file, len)),
+        })
+    }
+
+    /// Logs a request answered with `resp`. Failures are logged but do not fail
+    /// the request.
+    fn record<T: Serialize>(
+        &self,
+        peer: &SocketAddr,
+        op: &str,
+        key_hash: Option<&str>,
+        resp: &T,
+        started: Instant,
+    ) {
+        // The variant of the response, e.g. `Ok` or `Err`
+        let result = match serde_json::to_value(resp) {
+            Ok(Value::String(variant)) => variant,
+            Ok(Value::Object(fields)) => fields.keys().next().cloned().unwrap_or_default(),
+            _ => String::new(),
+        };
+        let entry = AccessLogEntry {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            peer: peer.to_string(),
+            op,
+            key_hash,
+            result,
+            latency_us: started.elapsed().as_micros() as u64,
+        };
+        if let Err(e) = self.write(&entry) {
+            error!("Failed to write the access log: {}", e);
+        }
+    }
+
+    fn write(&self, entry: &AccessLogEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&self.path, rotated)?;
+            *file = (
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+                0,
+            );
+        }
+        file.0.write_all(&line)?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Hashes a key for the access log, which must not reveal keys.
+///
+/// SHA-256 is fixed by its specification, unlike `DefaultHasher`, so the logs
+/// of every build agree on the hash of a key. The first 8 bytes are enough to
+/// correlate requests.
+fn hash_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// ========================= Stats =========================
+
+#[derive(Default)]
+struct ConnectionCounters {
+    open: AtomicU64,
+    accepted: AtomicU64,
+    closed: AtomicU64,
+    errored: AtomicU64,
+}
+
+impl ConnectionCounters {
+    fn accept(&self) {
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        self.open.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn close(&self, errored: bool) {
+        if errored {
+            self.errored.fetch_add(1, Ordering::SeqCst);
+        }
+        self.closed.fetch_add(1, Ordering::SeqCst);
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn snapshot(&self) -> ConnectionStats {
+        ConnectionStats {
+            open: self.open.load(Ordering::SeqCst),
+            accepted: self.accepted.load(Ordering::SeqCst),
+            closed: self.closed.load(Ordering::SeqCst),
+            errored: self.errored.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// ========================= Dedup =========================
+
+/// Outcomes of the writes with the most recent dedup ids, so a write retried
+/// by a client is applied once.
+#[derive(Default)]
+struct DedupTable {
+    inner: Mutex<DedupInner>,
+}
+
+#[derive(Default)]
+struct DedupInner {
+    /// `None` while the write is still being applied.
+    outcomes: HashMap<String, Option<std::result::Result<Value, ServerError>>>,
+    order: VecDeque<String>,
+}
+
+impl DedupTable {
+    /// Runs `op` unless a write with the same `id` ran before, in which case
+    /// its outcome is returned again.
+    fn run<T, F>(&self, id: Option<String>, op: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Result<T>,
+    {
+        let id = match id {
+            Some(id) => id,
+            None => return op(),
+        };
+        {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.outcomes.get(&id) {
+                Some(Some(Ok(value))) => return Ok(serde_json::from_value(value.clone())?),
+                Some(Some(Err(err))) => return Err(err.clone().into()),
+                Some(None) => return Err(KvsError::DuplicateRequest),
+                None => {}
+            }
+            if inner.order.len() >= DEDUP_CAPACITY {
+                if let Some(oldest) = inner.order.pop_front() {
+                    inner.outcomes.remove(&oldest);
+                }
+            }
+            inner.outcomes.insert(id.clone(), None);
+            inner.order.push_back(id.clone());
+        }
+
+        // Kept as it is sent, so a retry gets the same response
+        let res = op().map_err(ServerError::from);
+        let outcome = match &res {
+            Ok(value) => Ok(serde_json::to_value(value)?),
+            Err(err) => Err(err.clone()),
+        };
+        // The id may have been evicted by newer ones meanwhile
+        if let Some(slot) = self.inner.lock().unwrap().outcomes.get_mut(&id) {
+            *slot = Some(outcome);
+        }
+        res.map_err(KvsError::from)
+    }
+}
+
+// ========================= Cursors =========================
+
+/// Server-wide table of open iteration cursors.
+///
+/// A cursor is a snapshot of the keys present when it was opened. It is not tied
+/// to the connection that opened it, so a client may resume it after reconnecting.
+/// Cursors left idle for longer than `timeout` are garbage-collected.
+struct CursorTable {
+    timeout: Duration,
+    inner: Mutex<Cursors>,
+}
+
+struct Cursors {
+    next_id: u64,
+    open: HashMap<u64, Cursor>,
+}
+
+struct Cursor {
+    remaining: VecDeque<String>,
+    last_used: Instant,
+}
+
+impl CursorTable {
+    fn new(timeout: Duration) -> Self {
+        CursorTable {
+            timeout,
+            inner: Mutex::new(Cursors {
+                next_id: 1,
+                open: HashMap::new(),
+            }),
+        }
+    }
+
+    fn open(&self, mut keys: Vec<String>) -> u64 {
+        keys.sort_unstable();
+        let mut cursors = self.inner.lock().unwrap();
+        self.expire(&mut cursors);
+        let id = cursors.next_id;
+        cursors.next_id += 1;
+        cursors.open.insert(
+            id,
+            Cursor {
+                remaining: keys.into(),
+                last_used: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// Returns up to `count` key/value pairs from the cursor.
+    ///
+    /// Keys removed after the cursor was opened are skipped, and further keys are
+    /// taken in their place, so an empty batch means the cursor is exhausted.
+    fn next<E: KvsEngine>(
+        &self,
+        engine: &E,
+        id: u64,
+        count: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::with_capacity(count);
+        while pairs.len() < count {
+            let keys = self.take(id, count - pairs.len())?;
+            if keys.is_empty() {
+                break;
+            }
+            for key in keys {
+                if let Some(value) = engine.get(key.clone())? {
+                    pairs.push((key, value));
+                }
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn take(&self, id: u64, count: usize) -> Result<Vec<String>> {
+        let mut cursors = self.inner.lock().unwrap();
+        self.expire(&mut cursors);
+        let cursor = cursors.open.get_mut(&id).ok_or(KvsError::CursorNotFound)?;
+        cursor.last_used = Instant::now();
+        let count = count.min(cursor.remaining.len());
+        Ok(cursor.remaining.drain(..count).collect())
+    }
+
+    fn close(&self, id: u64) -> Result<()> {
+        let mut cursors = self.inner.lock().unwrap();
+        self.expire(&mut cursors);
+        cursors
+            .open
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(KvsError::CursorNotFound)
+    }
+
+    fn expire(&self, cursors: &mut Cursors) {
+        let timeout = self.timeout;
+        cursors
+            .open
+            .retain(|_, cursor| cursor.last_used.elapsed() < timeout);
+    }
+}
+
+// ========================= Watches =========================
+
+type ConnWriter = Arc<Mutex<WireWriter<BufWriter<TcpStream>>>>;
+
+/// Server-wide table of watched keys.
+///
+/// Notifications are queued to the watching connection, whose own thread writes
+/// them out, so a slow watcher does not hold up the thread serving the change.
+#[derive(Default)]
+struct WatchTable {
+    next_conn: AtomicU64,
+    watchers: Mutex<HashMap<String, HashMap<u64, Sender<Notification>>>>,
+}
+
+impl WatchTable {
+    fn connect(&self) -> u64 {
+        self.next_conn.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn disconnect(&self, conn: u64) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|_, conns| {
+            conns.remove(&conn);
+            !conns.is_empty()
+        });
+    }
+
+    fn watch(&self, key: String, conn: u64, queue: &Sender<Notification>) {
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.entry(key).or_default().insert(conn, queue.clone());
+    }
+
+    fn unwatch(&self, key: &str, conn: u64) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if let Some(conns) = watchers.get_mut(key) {
+            conns.remove(&conn);
+            if conns.is_empty() {
+                watchers.remove(key);
+            }
+        }
+    }
+
+    /// Queues a notification to every connection watching `key`.
+    fn notify(&self, key: String, event: WatchEvent) {
+        if let Some(conns) = self.watchers.lock().unwrap().get(&key) {
+            for queue in conns.values() {
+                let _ = queue.send(Notification {
+                    key: key.clone(),
+                    event: event.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Starts the thread writing the notifications queued to a connection.
+///
+/// It ends once the connection is done and unwatched everything.
+fn notification_queue(writer: ConnWriter) -> Sender<Notification> {
+    let (tx, rx) = mpsc::channel::<Notification>();
+    thread::spawn(move || {
+        for notification in rx {
+            let frame: Frame<()> = Frame::Notification(notification);
+            let mut writer = writer.lock().unwrap();
+            let res = serde_json::to_writer(&mut *writer, &frame)
+                .map_err(KvsError::from)
+                .and_then(|_| Ok(writer.flush()?));
+            if let Err(e) = res {
+                error!("Error on pushing notification: {}", e);
+            }
+        }
+    });
+    tx
+}
+
+// ========================= Mirror =========================
+
+/// How a `KvsServer` treats errors of its mirror engine.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorPolicy {
+    /// Mirror errors are logged, the request still succeeds.
+    #[default]
+    BestEffort,
+    /// Mirror errors fail the request. The write is kept on the primary.
+    Strict,
+}
+
+/// The write half of a `KvsEngine`, object-safe so the mirror engine does not
+/// show up in the type of `KvsServer`.
+trait Mirror: Send {
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    fn remove(&self, key: String) -> Result<()>;
+
+    fn box_clone(&self) -> Box<dyn Mirror>;
+}
+
+impl<E: KvsEngine> Mirror for E {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key)
+    }
+
+    fn box_clone(&self) -> Box<dyn Mirror> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Default)]
+struct Mirroring {
+    engine: Option<Box<dyn Mirror>>,
+    policy: MirrorPolicy,
+    /// Held across the write to the primary and to the mirror.
+    order: Arc<Mutex<()>>,
+}
+
+impl Clone for Mirroring {
+    fn clone(&self) -> Self {
+        Mirroring {
+            engine: self.engine.as_ref().map(|engine| engine.box_clone()),
+            policy: self.policy,
+            order: Arc::clone(&self.order),
+        }
+    }
+}
+
+impl Mirroring {
+    /// Serializes a write with the others, if there is a mirror to keep in
+    /// the order of the primary.
+    fn order(&self) -> Option<MutexGuard<'_, ()>> {
+        self.engine.as_ref().map(|_| self.order.lock().unwrap())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.apply(|engine| engine.set(key, value))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.apply(|engine| engine.remove(key))
+    }
+
+    fn apply<F: FnOnce(&dyn Mirror) -> Result<()>>(&self, write: F) -> Result<()> {
+        let engine = match &self.engine {
+            Some(engine) => engine,
+            None => return Ok(()),
+        };
+        match write(&**engine) {
+            Ok(_) | Err(KvsError::KeyNotFound) => Ok(()),
+            Err(e) => {
+                error!("Error on mirroring write: {}", e);
+                match self.policy {
+                    MirrorPolicy::BestEffort => Ok(()),
+                    MirrorPolicy::Strict => Err(e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::protocol::Compression;
+use std::cell::Cell;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::{Arc, OnceLock};
+
+/// Largest ratio of decompressed to compressed size of an LZ4 block, used to
+/// refuse blocks claiming a huge size before allocating it.
+const MAX_LZ4_RATIO: usize = 255;
+
+/// The compression of a connection, shared by its reader and writer.
+///
+/// Connections start uncompressed and switch once the `Hello` handshake agreed
+/// on an algorithm, in both directions at the same frame.
+pub type CompressionSwitch = Arc<OnceLock<Compression>>;
+
+/// Reads the frames of a connection, decompressing them once its
+/// `CompressionSwitch` is set.
+///
+/// Compressed frames are sent as blocks: the length of the block as a
+/// little-endian `u32`, then the frame compressed with its size prepended.
+pub struct WireReader<R> {
+    inner: R,
+    compression: CompressionSwitch,
+    block: Vec<u8>,
+    pos: usize,
+    max_frame: Option<usize>,
+}
+
+impl<R: Read> WireReader<R> {
+    pub fn new(inner: R, compression: CompressionSwitch) -> Self {
+        WireReader {
+            inner,
+            compression,
+            block: vec![],
+            pos: 0,
+            max_frame: None,
+        }
+    }
+
+    /// Refuses compressed blocks holding a frame of more than `max` bytes
+    /// with a `FrameTooLarge` error, before reading them.
+    pub fn with_max_frame(mut self, max: u64) -> Self {
+        self.max_frame = usize::try_from(max).ok();
+        self
+    }
+
+    /// Reads the next block, returning false at the end of the stream.
+    fn fill_block(&mut self) -> io::Result<bool> {
+        let mut len = [0; 4];
+        if self.inner.read(&mut len[..1])? == 0 {
+            return Ok(false);
+        }
+        self.inner.read_exact(&mut len[1..])?;
+        let len = u32::from_le_bytes(len) as usize;
+        // The worst case of LZ4 plus the prepended size
+        if let Some(max) = self.max_frame {
+            if len > max.saturating_add(max / 255).saturating_add(20) {
+                return Err(frame_too_large());
+            }
+        }
+        let mut data = vec![0; len];
+        self.inner.read_exact(&mut data)?;
+        let size = match data.get(..4) {
+            Some(size) => u32::from_le_bytes(size.try_into().unwrap()) as usize,
+            None => return Err(invalid_block("truncated block")),
+        };
+        if self.max_frame.is_some_and(|max| size > max) {
+            return Err(frame_too_large());
+        }
+        if size > data.len().saturating_mul(MAX_LZ4_RATIO) {
+            return Err(invalid_block("block size out of range"));
+        }
+        self.block = lz4_flex::decompress_size_prepended(&data)
+            .map_err(|e| invalid_block(&e.to_string()))?;
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for WireReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.compression.get().is_none() {
+            return self.inner.read(buf);
+        }
+        while self.pos == self.block.len() {
+            if !self.fill_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Fails reads once a frame has taken its whole budget of bytes, so a frame
+/// over the limit is refused before it is read whole.
+///
+/// The budget is shared with the reader of the frames, which refills it before
+/// each frame.
+pub struct FrameLimit<R> {
+    inner: R,
+    budget: Rc<Cell<u64>>,
+}
+
+impl<R: Read> FrameLimit<R> {
+    pub fn new(inner: R, budget: Rc<Cell<u64>>) -> Self {
+        FrameLimit { inner, budget }
+    }
+}
+
+impl<R: Read> Read for FrameLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let budget = self.budget.get();
+        if budget == 0 {
+            return Err(frame_too_large());
+        }
+        let len = buf.len().min(usize::try_from(budget).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..len])?;
+        self.budget.set(budget - n as u64);
+        Ok(n)
+    }
+}
+
+/// The error of a frame refused by `FrameLimit` or `WireReader::with_max_frame`.
+#[derive(Debug)]
+pub struct FrameTooLarge;
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "frame too large")
+    }
+}
+
+impl Error for FrameTooLarge {}
+
+/// Whether `e` refused a frame over the limit.
+pub fn is_frame_too_large(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<FrameTooLarge>())
+}
+
+fn frame_too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, FrameTooLarge)
+}
+
+/// Writes the frames of a connection, compressing them once its
+/// `CompressionSwitch` is set.
+///
+/// A compressed frame is sent as one block on `flush`, so every frame must be
+/// flushed on its own.
+pub struct WireWriter<W> {
+    inner: W,
+    compression: CompressionSwitch,
+    frame: Vec<u8>,
+}
+
+impl<W: Write> WireWriter<W> {
+    pub fn new(inner: W, compression: CompressionSwitch) -> Self {
+        WireWriter {
+            inner,
+            compression,
+            frame: vec![],
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: Write> Write for WireWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.compression.get().is_none() {
+            return self.inner.write(buf);
+        }
+        self.frame.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.frame.is_empty() {
+            let block = lz4_flex::compress_prepend_size(&self.frame);
+            self.frame.clear();
+            let len = u32::try_from(block.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+            self.inner.write_all(&len.to_le_bytes())?;
+            self.inner.write_all(&block)?;
+        }
+        self.inner.flush()
+    }
+}
+
+fn invalid_block(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid compressed frame: {}", msg),
+    )
+}
@@ -0,0 +1,144 @@
+#![cfg(feature = "tokio")]
+
+use std::net::SocketAddr;
+use std::thread;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::task;
+use unifier::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use unifier::{
+    AsyncKvsClient, AsyncKvsEngine, AsyncKvsServer, KvStore, KvsClient, KvsError, KvsReader,
+    KvsServer, Result, ServerErrorKind, TokioEngine,
+};
+
+// Spawn an `AsyncKvsServer` backed by a `KvStore` in a temporary directory on
+// a port picked by the system.
+async fn start_async_server() -> Result<(TempDir, SocketAddr)> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(AsyncKvsServer::new(store).serve(listener));
+    Ok((temp_dir, addr))
+}
+
+// The engine runs on the blocking pool and keeps its errors.
+#[tokio::test]
+async fn tokio_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = TokioEngine::new(KvStore::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned()).await?;
+    engine.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert_eq!(
+        engine.get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    engine.remove("key1".to_owned()).await?;
+    assert_eq!(engine.get("key1".to_owned()).await?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // Concurrent tasks share the engine
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            let engine = engine.clone();
+            tokio::spawn(async move { engine.set(format!("key{}", i), i.to_string()).await })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    for i in 0..16 {
+        let value = engine.get_ref().get(format!("key{}", i))?;
+        assert_eq!(value, Some(i.to_string()));
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_and_server() -> Result<()> {
+    let (_temp_dir, addr) = start_async_server().await?;
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    assert_eq!(client.server_info().engine, "kvs");
+
+    let first = client.set("key1".to_owned(), "value1".to_owned()).await?;
+    let second = client.set("key1".to_owned(), "value2".to_owned()).await?;
+    assert!(second > first);
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    assert!(client.remove("key1".to_owned()).await?);
+    assert!(!client.remove("key1".to_owned()).await?);
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+
+    // Many connections at once, each served by its own task
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            tokio::spawn(async move {
+                let mut client = AsyncKvsClient::connect(addr).await?;
+                for j in 0..20 {
+                    client.set(format!("key{}_{}", i, j), j.to_string()).await?;
+                }
+                Result::Ok(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    for i in 0..16 {
+        let value = client.get(format!("key{}_19", i)).await?;
+        assert_eq!(value, Some("19".to_owned()));
+    }
+    Ok(())
+}
+
+// Both servers speak the same protocol, so either client talks to either server.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_and_blocking_interoperate() -> Result<()> {
+    let (_temp_dir, addr) = start_async_server().await?;
+    task::spawn_blocking(move || -> Result<()> {
+        let mut client = KvsClient::connect(addr)?;
+        client.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        match client.stats() {
+            Err(KvsError::Server(e)) => {
+                assert_eq!(e.kind, ServerErrorKind::BadRequest);
+                assert!(e.message.contains("Stats"), "{}", e);
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+        // The connection is still usable after a refused request
+        assert!(client.remove("key1".to_owned())?);
+        Ok(())
+    })
+    .await
+    .unwrap()?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.handle();
+    thread::spawn(move || server.run("127.0.0.1:0").unwrap());
+    let addr = loop {
+        match handle.local_addr() {
+            Some(addr) => break addr,
+            None => task::yield_now().await,
+        }
+    };
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        client.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert!(client.remove("key1".to_owned()).await?);
+    handle.shutdown();
+    Ok(())
+}
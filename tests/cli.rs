@@ -6,42 +6,43 @@ use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
+use unifier::KvsClient;
 
-// `kvs-client` with no args should exit with a non-zero code.
+// `unifier-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
     let temp_dir = TempDir::new().unwrap();
-    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+    let mut cmd = Command::cargo_bin("unifier-client").unwrap();
     cmd.current_dir(&temp_dir).assert().failure();
 }
 
 #[test]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -50,37 +51,37 @@ fn client_cli_invalid_get() {
 #[test]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -89,63 +90,75 @@ fn client_cli_invalid_set() {
 #[test]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 }
 
+// A connection failure exits with another code than a missing key.
+#[test]
+fn client_cli_connection_failure() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("unifier-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2);
+}
+
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 }
 
-// `kvs-client -V` should print the version
+// `unifier-client -V` should print the version
 #[test]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
-    let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    let mut cmd = Command::cargo_bin("unifier-client").unwrap();
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
-// `kvs-server -V` should print the version
+// `unifier-server -V` should print the version
 #[test]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
-    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    let mut cmd = Command::cargo_bin("unifier-server").unwrap();
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -155,15 +168,16 @@ fn server_cli_version() {
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
-    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut cmd = Command::cargo_bin("unifier-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,121 +185,212 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_request_trace_id() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("unifier-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .env("RUST_LOG", "debug")
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect("127.0.0.1:4006").unwrap();
+    client
+        .trace("trace-1234")
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap();
+    assert_eq!(client.last_trace_id(), Some("trace-1234"));
+    // Requests without a supplied trace id get a fresh one
+    client.get("key1".to_owned()).unwrap();
+    let generated = client.last_trace_id().unwrap().to_owned();
+    assert_ne!(generated, "trace-1234");
+    client.get("key1".to_owned()).unwrap();
+    assert_ne!(client.last_trace_id(), Some(generated.as_str()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("[trace-1234] Receive request"));
+    assert!(content.contains("[trace-1234] Response sent"));
+    assert!(content.contains(&format!("[{}] Receive request", generated)));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
         let temp_dir = TempDir::new().unwrap();
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut cmd = Command::cargo_bin("unifier-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        let mut cmd = Command::cargo_bin("unifier-server").unwrap();
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("created with the sled engine"));
     }
 
     // kvs first, sled second
     {
         let temp_dir = TempDir::new().unwrap();
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        let mut cmd = Command::cargo_bin("unifier-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        let mut cmd = Command::cargo_bin("unifier-server").unwrap();
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("created with the kvs engine"));
+    }
 
-        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+    // an unreadable marker is not guessed over
+    {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("engine"), "rocksdb").unwrap();
+        let mut cmd = Command::cargo_bin("unifier-server").unwrap();
+        cmd.args(["--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
-            .failure();
+            .failure()
+            .stderr(contains("Invalid engine file"));
     }
 }
 
+// The engine of the first start is kept by later starts, with or without
+// `--engine`.
+#[test]
+fn cli_engine_persisted() {
+    let temp_dir = TempDir::new().unwrap();
+    let start = |args: &[&str]| {
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::cargo_bin("unifier-server")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4008"])
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        // Still running, so the engine was accepted
+        assert!(child.try_wait().unwrap().is_none());
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+        fs::read_to_string(&stderr_path).unwrap()
+    };
+
+    // First start
+    let stderr = start(&["--engine", "sled"]);
+    assert!(stderr.contains("Storage engine: sled"), "{}", stderr);
+    let marker = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+    assert_eq!(marker, "sled");
+
+    // Matching restarts
+    let stderr = start(&["--engine", "sled"]);
+    assert!(stderr.contains("Storage engine: sled"), "{}", stderr);
+    let stderr = start(&[]);
+    assert!(stderr.contains("Storage engine: sled"), "{}", stderr);
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = Command::cargo_bin("unifier-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout("value1\n");
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout("value2\n");
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stdout("Key not found\n")
+        .stderr(is_empty());
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stderr(contains("Key not found"));
+        .code(1)
+        .stdout("Key not found\n")
+        .stderr(is_empty());
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -296,32 +401,33 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     // Reopen and check value
     let (sender, receiver) = mpsc::sync_channel(0);
-    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut server = Command::cargo_bin("unifier-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
-    Command::cargo_bin("kvs-client")
+    Command::cargo_bin("unifier-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(contains("Key not found"));
+        .code(1)
+        .stdout("Key not found\n");
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -1,7 +1,18 @@
-use std::sync::{Arc, Barrier};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
-use unifier::{KvStore, KvsEngine, Result};
+use unifier::{
+    AggResult, Aggregation, AtomicMetrics, Checkpoint, CompactionPolicy, CompactionStats,
+    DiskSpaceCheck, EngineOp, GenerationReclaim, IndexKind, KvStats, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, KvsReader, LogEncoding, LogPosition, LowSpaceAction, MemFileSystem,
+    MemoryKvsEngine, Mutation, NonInteger, Op, OpResult, ReadSource, RecoveryMode, RecoveryReport,
+    ReplicaKvStore, Result, Scrubber, ShardHash, SledKvsEngine, StatsFile, SyncPolicy,
+    TieredCompaction, TimedEngine,
+};
 use walkdir::WalkDir;
 
 // Should get previously stored value
@@ -63,6 +74,32 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Deferred sets should be readable before an explicit flush and persist on drop
+#[test]
+fn set_deferred_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..100 {
+        store.set_deferred(format!("key{}", i), format!("value{}", i))?;
+    }
+    let other = store.clone();
+    assert_eq!(other.get("key42".to_owned())?, Some("value42".to_owned()));
+    store.set_deferred("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    drop(other);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    for i in 2..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -81,131 +118,3491 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// The in-memory engine follows the same semantics as the on-disk ones, and its
+// clones share one map
 #[test]
-fn compaction() -> Result<()> {
+fn memory_engine() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    let clone = engine.clone();
+    thread::spawn(move || clone.set("key2".to_owned(), "value3".to_owned()))
+        .join()
+        .unwrap()?;
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.keys()?, vec!["key2".to_owned()]);
+    Ok(())
+}
+
+// A record torn by a failed write should be discarded on open
+#[test]
+fn torn_write_is_discarded() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    // Simulate a short write by appending half of a record to the log
+    let log = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .max_by_key(|entry| entry.metadata().unwrap().len())
+        .expect("no log file")
+        .into_path();
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(br#"{"Set":{"key":"key3","va"#)?;
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
-        }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
 
-        drop(store);
-        // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
-        }
-        return Ok(());
+    Ok(())
+}
+
+// Reading a record of the active generation should not move where the next one
+// is appended, even when the read stops short of the end of the log
+#[test]
+fn reads_do_not_move_appends() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let large = "v".repeat(1 << 16);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), large.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    for _ in 0..2 {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
     }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(large));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
 
-    panic!("No compaction detected");
+    Ok(())
 }
 
+// A strict store syncing every write refuses to open where `fsync` may not
+// reach the disk
 #[test]
-fn concurrent_set() -> Result<()> {
+fn strict_durability_refuses_network_fs() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
+    let fs = MemFileSystem::new();
+    let open = |strict_durability, sync_policy| {
+        KvStore::open_with_fs(
+            temp_dir.path(),
+            KvStoreOptions {
+                strict_durability,
+                sync_policy,
+                ..KvStoreOptions::default()
+            },
+            Arc::new(fs.clone()),
+        )
+    };
+
+    fs.set_fs_type(Some("nfs"));
+    match open(true, SyncPolicy::EveryWrite) {
+        Err(KvsError::Unsupported(msg)) => assert!(msg.contains("nfs"), "{}", msg),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("opened a strict store on nfs"),
     }
-    barrier.wait();
+    // Without the option, or with a policy that may lose writes anyway, it
+    // only warns
+    open(false, SyncPolicy::EveryWrite)?.set("key1".to_owned(), "value1".to_owned())?;
+    drop(open(true, SyncPolicy::Never)?);
+    drop(open(true, SyncPolicy::Interval(Duration::from_secs(1)))?);
 
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    fs.set_fs_type(Some("ext4"));
+    let store = open(true, SyncPolicy::EveryWrite)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Writes survive a power loss once flushed, at once with `SyncPolicy::EveryWrite`,
+// or after an interval with `SyncPolicy::Interval`
+#[test]
+fn flush_survives_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = MemFileSystem::new();
+    let open = |sync_policy| {
+        KvStore::open_with_fs(
+            temp_dir.path(),
+            KvStoreOptions {
+                sync_policy,
+                ..KvStoreOptions::default()
+            },
+            Arc::new(fs.clone()),
+        )
+    };
+
+    let store = open(SyncPolicy::Never)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    fs.crash(0);
+    let store = open(SyncPolicy::EveryWrite)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Only handed to the OS, so lost
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    fs.crash(0);
+    let store = open(SyncPolicy::Interval(Duration::from_millis(20)))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    drop(store);
+    fs.crash(0);
+    let store = open(SyncPolicy::Never)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// The keys and lengths of the records of a generation, in log order
+fn generation_records(dir: &TempDir, gen: u64) -> Vec<(String, u64)> {
+    let data = fs::read(dir.path().join("kvs.db").join(format!("{}.log", gen))).unwrap();
+    let mut stream = serde_json::Deserializer::from_slice(&data).into_iter::<serde_json::Value>();
+    let mut records = vec![];
+    let mut pos = 0;
+    while let Some(record) = stream.next() {
+        let record = record.unwrap();
+        let (_, command) = record.as_object().unwrap().iter().next().unwrap();
+        let key = command["key"].as_str().unwrap().to_owned();
+        records.push((key, (stream.byte_offset() - pos) as u64));
+        pos = stream.byte_offset();
     }
+    records
+}
 
-    // Open from disk again and check persistent data
+// Compaction reports the live and dead bytes of every generation it removed
+#[test]
+fn compaction_reclaim_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                compaction: CompactionPolicy::Disabled,
+                ..KvStoreOptions::default()
+            },
+        )
+    };
+    let value = "v".repeat(50);
+
+    // Generation 1 keeps key7 and key8, generation 2 the second key0 and
+    // key1 to key6, and generation 3 only holds a removal
+    let store = open()?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    let store = open()?;
+    for i in 0..7 {
+        store.set(format!("key{}", i), value.clone())?;
     }
+    store.set("key0".to_owned(), value.clone())?;
+    drop(store);
+    let store = open()?;
+    store.remove("key9".to_owned())?;
+
+    let gen1 = generation_records(&temp_dir, 1);
+    let gen2 = generation_records(&temp_dir, 2);
+    let gen3 = generation_records(&temp_dir, 3);
+    let size = |records: &[(String, u64)]| records.iter().map(|(_, len)| len).sum::<u64>();
+    let live1 = gen1[7].1 + gen1[8].1;
+    let live2 = size(&gen2[1..]);
+    assert_eq!(gen2.len(), 8);
 
+    let report = store.compact_full()?;
+    let expected = vec![
+        (
+            1,
+            GenerationReclaim {
+                live: live1,
+                dead: size(&gen1) - live1,
+            },
+        ),
+        (
+            2,
+            GenerationReclaim {
+                live: live2,
+                dead: size(&gen2) - live2,
+            },
+        ),
+        (
+            3,
+            GenerationReclaim {
+                live: 0,
+                dead: size(&gen3),
+            },
+        ),
+    ];
+    assert_eq!(report.generations.into_iter().collect::<Vec<_>>(), expected);
+    assert_eq!(report.written, live1 + live2);
+
+    for i in 0..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value.clone()));
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
     Ok(())
 }
 
+// Compaction reports the log size around it, and the store adds up the reports
+// of every compaction since it was opened
 #[test]
-fn concurrent_get() -> Result<()> {
+fn compaction_stats() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    for round in 0..5 {
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+    }
+    assert_eq!(store.stats()?.compaction, CompactionStats::default());
+
+    let report = store.compact_full()?;
+    assert!(report.bytes_after < report.bytes_before);
+    assert_eq!(report.records_retained, 200);
+    assert_eq!(report.bytes_after, store.stats()?.log_bytes);
+
     for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+        store.set(format!("key{}", i), "new".to_owned())?;
     }
+    let second = store.compact_full()?;
+    let stats = store.stats()?.compaction;
+    assert_eq!(stats.compactions, 2);
+    assert_eq!(
+        stats.bytes_before,
+        report.bytes_before + second.bytes_before
+    );
+    assert_eq!(stats.bytes_after, report.bytes_after + second.bytes_after);
+    assert_eq!(stats.records_retained, 400);
+    assert_eq!(stats.duration, report.duration + second.duration);
+    Ok(())
+}
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+// Records not synced before a crash are lost, and a torn one is cut off on open
+#[test]
+fn crash_and_disk_full_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = MemFileSystem::new();
+    let open = || {
+        KvStore::open_with_fs(
+            temp_dir.path(),
+            KvStoreOptions::default(),
+            Arc::new(fs.clone()),
+        )
+    };
+
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    fs.sync_all();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    fs.crash(10);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // A write cut short by a full disk is rolled back
+    fs.set_write_budget(Some(5));
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    fs.set_write_budget(None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Running out of space fails with `KvsError::DiskFull`, and the record cut
+// short by it is truncated away, for single writes and batches alike
+#[test]
+fn disk_full_rolls_back_partial_record() -> Result<()> {
+    assert!(matches!(
+        KvsError::from(io::Error::from(io::ErrorKind::StorageFull)),
+        KvsError::DiskFull
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = MemFileSystem::new();
+    let open = || {
+        KvStore::open_with_fs(
+            temp_dir.path(),
+            KvStoreOptions {
+                recovery: RecoveryMode::Resync,
+                ..KvStoreOptions::default()
+            },
+            Arc::new(fs.clone()),
+        )
+    };
+
+    let store = open()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log_bytes = store.stats()?.log_bytes;
+
+    fs.set_write_budget(Some(5));
+    assert!(matches!(
+        store.set("key2".to_owned(), "value2".to_owned()),
+        Err(KvsError::DiskFull)
+    ));
+    assert_eq!(store.stats()?.log_bytes, log_bytes);
+    fs.set_write_budget(Some(20));
+    assert!(matches!(
+        store.set_many(vec![
+            ("key3".to_owned(), "value3".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]),
+        Err(KvsError::DiskFull)
+    ));
+    assert_eq!(store.stats()?.log_bytes, log_bytes);
+    fs.set_write_budget(None);
+
+    for key in ["key2", "key3", "key4"] {
+        assert_eq!(store.get(key.to_owned())?, None);
     }
-    for handle in handles {
-        handle.join().unwrap();
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    // Nothing torn is left for recovery to skip
+    let store = open()?;
+    assert_eq!(store.recovery_report().skipped_bytes, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// Writes are refused while the free space is under the threshold, and resume
+// once a later check finds enough
+#[test]
+fn low_disk_space_refuses_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = MemFileSystem::new();
+    fs.set_available_space(Some(1 << 10));
+    let options = |action| KvStoreOptions {
+        disk_space_check: Some(DiskSpaceCheck {
+            min_free_bytes: 1 << 20,
+            action,
+            interval: Duration::from_millis(10),
+        }),
+        ..KvStoreOptions::default()
+    };
+    let wait_for_check = || thread::sleep(Duration::from_millis(100));
+
+    // Checked at open already
+    let store = KvStore::open_with_fs(
+        temp_dir.path(),
+        options(LowSpaceAction::RefuseWrites),
+        Arc::new(fs.clone()),
+    )?;
+    assert!(matches!(
+        store.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::DiskFull)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    fs.set_available_space(Some(1 << 30));
+    wait_for_check();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fs.set_available_space(Some(1 << 10));
+    wait_for_check();
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::DiskFull)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // Only warned about
+    let store = KvStore::open_with_fs(
+        temp_dir.path(),
+        options(LowSpaceAction::Warn),
+        Arc::new(fs.clone()),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The generation written by compaction is compressed and still readable
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_cold_generations() -> Result<()> {
+    let dir_size = |dir: &TempDir| -> u64 {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let fill = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            store.set(format!("key{}", i % 100), format!("value{}", i).repeat(20))?;
+        }
+        store.compact()?;
+        Ok(())
+    };
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(&KvStore::open(plain_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compress_cold_generations: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    fill(&store)?;
+
+    // Compression happens in the background
+    let mut waited = Duration::from_secs(0);
+    while dir_size(&temp_dir) * 2 > dir_size(&plain_dir) {
+        assert!(
+            waited < Duration::from_secs(10),
+            "generation not compressed"
+        );
+        thread::sleep(Duration::from_millis(10));
+        waited += Duration::from_millis(10);
     }
 
-    // Open from disk again and check persistent data
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", 900 + key_id).repeat(20))
+            );
+        }
+        Ok(())
+    };
+    check(&store.clone())?;
+    store.set("key0".to_owned(), "value900".repeat(20))?;
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
-            }
-        });
-        handles.push(handle);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    Ok(())
+}
+
+// A compressed generation of many blocks reads records within a block, across
+// block boundaries and spanning several blocks
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_generation_blocks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compress_cold_generations: true,
+        ..KvStoreOptions::default()
+    };
+    let value = |i: usize| format!("value{} ", i).repeat(i % 50 + 1);
+    let large = "large value ".repeat(20_000);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), value(i))?;
+        if i == 1000 {
+            store.set("large".to_owned(), large.clone())?;
+        }
     }
-    for handle in handles {
-        handle.join().unwrap();
+    store.compact()?;
+
+    // Compression happens in the background, poll for the magic of the format
+    let compressed = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .any(|entry| fs::read(entry.path()).unwrap().starts_with(b"\0kvb"))
+    };
+    let mut waited = Duration::from_secs(0);
+    while !compressed() {
+        assert!(
+            waited < Duration::from_secs(10),
+            "generation not compressed"
+        );
+        thread::sleep(Duration::from_millis(10));
+        waited += Duration::from_millis(10);
     }
 
+    let check = |store: &KvStore| -> Result<()> {
+        for i in (0..2000).rev() {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        Ok(())
+    };
+    check(&store.clone())?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+// A compressed index snapshot loads the same index as an uncompressed one, and
+// generations written after it are replayed on top of it
+#[test]
+fn compressed_index_snapshot() -> Result<()> {
+    let fill = |store: &KvStore| -> Result<()> {
+        for i in 0..2000 {
+            store.set(format!("key{}", i % 500), format!("value{}", i))?;
+        }
+        store.compact()?;
+        for i in (0..500).step_by(7) {
+            store.remove(format!("key{}", i))?;
+        }
+        Ok(())
+    };
+    let entries = |store: &KvStore| -> Result<Vec<(String, Option<String>)>> {
+        let mut keys = store.keys()?;
+        keys.sort();
+        keys.into_iter()
+            .map(|key| Ok((key.clone(), store.get(key)?)))
+            .collect()
+    };
+    let snapshot_path = |dir: &TempDir| dir.path().join("kvs.db").join("index.snapshot");
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain_options = KvStoreOptions {
+        index_snapshot: true,
+        ..KvStoreOptions::default()
+    };
+    fill(&KvStore::open_with_options(
+        plain_dir.path(),
+        plain_options.clone(),
+    )?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compress_index_snapshot: true,
+        ..plain_options.clone()
+    };
+    fill(&KvStore::open_with_options(
+        temp_dir.path(),
+        options.clone(),
+    )?)?;
+
+    let plain = fs::read(snapshot_path(&plain_dir))?;
+    let compressed = fs::read(snapshot_path(&temp_dir))?;
+    let json: serde_json::Value = serde_json::from_slice(&plain)?;
+    assert_eq!(json["entries"].as_array().unwrap().len(), 500 - 72);
+    assert!(compressed.starts_with(b"\0kvi"));
+    assert!(compressed.len() < plain.len() / 2);
+
+    let expected = entries(&KvStore::open(plain_dir.path())?)?;
+    assert_eq!(expected.len(), 500 - 72);
+    let plain_store = KvStore::open_with_options(plain_dir.path(), plain_options)?;
+    assert_eq!(entries(&plain_store)?, expected);
+    drop(plain_store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(entries(&store)?, expected);
+    store.set("key0".to_owned(), "back".to_owned())?;
+    drop(store);
+
+    // An older snapshot misses the last generations, they are replayed
+    let old_snapshot = fs::read(snapshot_path(&temp_dir))?;
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+    fs::write(snapshot_path(&temp_dir), old_snapshot)?;
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("back".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.compact()?;
+    drop(store);
+
+    // A snapshot that no longer matches the log is ignored
+    let stale = fs::read(snapshot_path(&temp_dir))?;
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key2".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    drop(store);
+    fs::write(snapshot_path(&temp_dir), stale)?;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key0".to_owned())?, Some("back".to_owned()));
+    Ok(())
+}
+
+// Large values are compressed as they are written, read back through every
+// path, and mix with uncompressed records when the option changes between opens
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_values() -> Result<()> {
+    let log_size = |dir: &TempDir| -> u64 {
+        WalkDir::new(dir.path().join("kvs.db"))
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let document = |i: usize| format!("{{\"id\":{},\"tags\":[\"a\",\"b\"]}}", i).repeat(50);
+    let blob: Vec<u8> = (0..2000).map(|i| [0xff, 0xfe, i as u8][i % 3]).collect();
+    let fill = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            store.set(format!("doc{}", i), document(i))?;
+        }
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set_bytes("blob".to_owned(), blob.clone())?;
+        Ok(())
+    };
+
+    for log_encoding in [LogEncoding::Json, LogEncoding::Binary] {
+        let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+        let plain_options = KvStoreOptions {
+            log_encoding,
+            ..KvStoreOptions::default()
+        };
+        fill(&KvStore::open_with_options(
+            plain_dir.path(),
+            plain_options.clone(),
+        )?)?;
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            compress_values: true,
+            ..plain_options.clone()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        fill(&store)?;
+        assert!(log_size(&temp_dir) * 4 < log_size(&plain_dir));
+
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("doc7".to_owned())?, Some(document(7)));
+            assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob.clone()));
+            assert_eq!(
+                store.get_bytes("doc8".to_owned())?,
+                Some(document(8).into_bytes())
+            );
+            let docs = store.scan("doc".to_owned().."doe".to_owned())?;
+            assert!(docs.len() >= 100);
+            assert!(docs
+                .iter()
+                .all(|(key, value)| *value == document(key[3..].parse().unwrap())));
+            Ok(())
+        };
+        check(&store)?;
+
+        // Reopened without the option, old records are still inflated and new
+        // ones are written plain
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), plain_options)?;
+        check(&store)?;
+        let size = log_size(&temp_dir);
+        store.set("doc100".to_owned(), document(100))?;
+        assert!(log_size(&temp_dir) - size > document(100).len() as u64);
+        assert_eq!(store.get("doc100".to_owned())?, Some(document(100)));
+        store.compact()?;
+        check(&store)?;
+        drop(store);
+        check(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+    }
+    Ok(())
+}
+
+// Without the `compression` feature, compression options are refused and a
+// compressed value fails to read with a clear error
+#[cfg(not(feature = "compression"))]
+#[test]
+fn compression_disabled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for options in [
+        KvStoreOptions {
+            compress_values: true,
+            ..KvStoreOptions::default()
+        },
+        KvStoreOptions {
+            compress_cold_generations: true,
+            ..KvStoreOptions::default()
+        },
+    ] {
+        assert!(matches!(
+            KvStore::open_with_options(temp_dir.path(), options),
+            Err(KvsError::CompressionDisabled)
+        ));
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    // Replace the record with the one a build with the feature would write
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.unwrap();
+        if fs::read_to_string(entry.path()).is_ok_and(|log| log.contains("\"Set\"")) {
+            fs::write(
+                entry.path(),
+                "{\"SetCompressed\":{\"key\":\"key\",\"value\":[43,75,204,41,77,5,0],\
+                 \"version\":1,\"modified_at\":0}}",
+            )?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.get("key".to_owned()),
+        Err(KvsError::CompressionDisabled)
+    ));
+    Ok(())
+}
+
+// An index entry pointing at a `remove` record, as a corrupt snapshot may hold,
+// fails the read instead of panicking
+#[test]
+fn indexed_remove_record_is_an_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index_snapshot: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let snapshot_path = temp_dir.path().join("kvs.db").join("index.snapshot");
+    let mut snapshot: serde_json::Value = serde_json::from_slice(&fs::read(&snapshot_path)?)?;
+    let gen = snapshot["entries"][0][1]["gen"].as_u64().unwrap();
+    let records = generation_records(&temp_dir, gen);
+    assert_eq!(records[1].0, "key1");
+    let remove =
+        serde_json::json!(["key1", {"gen": gen, "pos": records[0].1, "len": records[1].1}]);
+    snapshot["entries"].as_array_mut().unwrap().push(remove);
+    fs::write(&snapshot_path, serde_json::to_vec(&snapshot)?)?;
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::UnexpectedCommandType)
+    ));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A failed compaction keeps the old generations, and a finished one survives a
+// crash right after it
+#[test]
+fn compaction_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fs = MemFileSystem::new();
+    let open = || {
+        KvStore::open_with_fs(
+            temp_dir.path(),
+            KvStoreOptions::default(),
+            Arc::new(fs.clone()),
+        )
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", 90 + key_id))
+            );
+        }
+        Ok(())
+    };
+
+    let store = open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i % 10), format!("value{}", i))?;
+    }
+    fs.sync_all();
+
+    fs.set_write_budget(Some(100));
+    assert!(store.compact().is_err());
+    fs.set_write_budget(None);
+    check(&store)?;
+    drop(store);
+    fs.crash(0);
+
+    let store = open()?;
+    check(&store)?;
+    assert!(store.compact()? > 0);
+    drop(store);
+    fs.crash(0);
+
+    let store = open()?;
+    check(&store)?;
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+fn check_reads_across_compaction(options: KvStoreOptions) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let db = temp_dir.path().join("kvs.db");
+    let logs = || -> Result<Vec<String>> {
+        let mut logs = vec![];
+        for entry in fs::read_dir(&db)? {
+            let name = entry?.file_name().into_string().unwrap();
+            if name.ends_with(".log") {
+                logs.push(name);
+            }
+        }
+        Ok(logs)
+    };
+
+    // The clone has the first generation open when it is compacted away
+    let reader = store.clone();
+    store.set("key".to_owned(), "0".to_owned())?;
+    assert_eq!(reader.get("key".to_owned())?, Some("0".to_owned()));
+    let first_logs = logs()?;
+    for i in 1..=50 {
+        store.set("key".to_owned(), i.to_string())?;
+        store.compact()?;
+        assert_eq!(
+            reader.get_with_source("key".to_owned())?,
+            Some((i.to_string(), ReadSource::Disk))
+        );
+    }
+    assert!(first_logs.iter().all(|log| !logs().unwrap().contains(log)));
+
+    // Readers never see a key missing or going back while it is relocated
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let (reader, stop) = (store.clone(), Arc::clone(&stop));
+            thread::spawn(move || -> Result<()> {
+                let mut last = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let value = reader.get("key".to_owned())?.expect("key went missing");
+                    let value: u32 = value.parse().unwrap();
+                    assert!(value >= last, "read {} after {}", value, last);
+                    last = value;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for i in 51..=300 {
+        store.set("key".to_owned(), i.to_string())?;
+        store.set(format!("filler{}", i % 16), "x".repeat(64))?;
+        if i % 10 == 0 {
+            store.compact()?;
+        }
+        assert_eq!(store.clone().get("key".to_owned())?, Some(i.to_string()));
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
+// A clone reads a key from its relocated offset right after a compaction moved
+// it, whether the compaction runs in the foreground or in the background
+#[test]
+fn reads_across_compaction() -> Result<()> {
+    check_reads_across_compaction(KvStoreOptions::default())?;
+    check_reads_across_compaction(KvStoreOptions {
+        compaction: CompactionPolicy::Background,
+        compaction_threshold: 1024,
+        ..KvStoreOptions::default()
+    })
+}
+
+// A clone reading while compaction removes generations on another thread reads
+// every value, and drops its handles to the removed files at its next read
+#[cfg(target_os = "linux")]
+#[test]
+fn compaction_releases_removed_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), i.to_string())?;
+    }
+    let removed_logs = || {
+        fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|fd| fs::read_link(fd.unwrap().path()).ok())
+            .map(|target| target.to_string_lossy().into_owned())
+            .filter(|target| {
+                target.starts_with(&*temp_dir.path().to_string_lossy())
+                    && target.ends_with(".log (deleted)")
+            })
+            .count()
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let (reader, stop) = (store.clone(), Arc::clone(&stop));
+        thread::spawn(move || -> Result<KvStore> {
+            while !stop.load(Ordering::SeqCst) {
+                for i in 0..100 {
+                    let value = reader.get(format!("key{}", i))?;
+                    assert_eq!(value, Some(i.to_string()));
+                }
+            }
+            Ok(reader)
+        })
+    };
+    for round in 0..20 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), i.to_string())?;
+        }
+        store.compact()?;
+        if round % 5 == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    stop.store(true, Ordering::SeqCst);
+    let reader = reader.join().unwrap()?;
+
+    // Each handle lets go of removed logs on its next read.
+    assert_eq!(reader.get("key0".to_owned())?, Some("0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("1".to_owned()));
+    assert_eq!(removed_logs(), 0);
+    Ok(())
+}
+
+fn check_read_your_writes(options: KvStoreOptions) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // The number of the last write that returned, published after it returns
+    let written = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let (reader, written, stop) = (store.clone(), Arc::clone(&written), Arc::clone(&stop));
+            thread::spawn(move || -> Result<()> {
+                while !stop.load(Ordering::SeqCst) {
+                    let i = written.load(Ordering::SeqCst);
+                    if i == 0 {
+                        continue;
+                    }
+                    // Later writes may have set the key again, never an older value
+                    let value = reader.get(format!("key{}", i % 50))?;
+                    let n: u64 = value.expect("write not visible").parse().unwrap();
+                    assert!(n >= i && n % 50 == i % 50, "read {} after {}", n, i);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for i in 1..=2000u64 {
+        if i % 3 == 0 {
+            store.set_deferred(format!("key{}", i % 50), i.to_string())?;
+        } else {
+            store.set(format!("key{}", i % 50), i.to_string())?;
+        }
+        written.store(i, Ordering::SeqCst);
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    // After a barrier, the records are in the log files themselves. Compacting
+    // first waits for a background compaction, which could remove a log file
+    // while it is read.
+    store.compact()?;
+    store.set_deferred("last".to_owned(), "barrier".to_owned())?;
+    store.barrier()?;
+    let logs = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| fs::read_to_string(entry.path()).unwrap())
+        .collect::<String>();
+    assert!(logs.contains("barrier"));
+    Ok(())
+}
+
+// Every write is visible to reads through other clones once it returns, while
+// compactions relocate the keys
+#[test]
+fn read_your_writes_across_clones() -> Result<()> {
+    check_read_your_writes(KvStoreOptions {
+        compaction_threshold: 1024,
+        ..KvStoreOptions::default()
+    })?;
+    check_read_your_writes(KvStoreOptions {
+        compaction: CompactionPolicy::Background,
+        compaction_threshold: 1024,
+        ..KvStoreOptions::default()
+    })
+}
+
+fn check_keep_versions(options: KvStoreOptions) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = |i| format!("{}{}", "value ".repeat(20), i);
+    for i in 1..=5 {
+        store.set("key".to_owned(), value(i))?;
+        for j in 0..20 {
+            store.set(format!("other{}", j), value(i))?;
+        }
+    }
+    store.set("gone".to_owned(), value(1))?;
+    store.remove("gone".to_owned())?;
+    store.set("gone".to_owned(), value(2))?;
+    assert_eq!(store.get_version("key".to_owned(), 1)?, Some(value(4)));
+
+    store.compact()?;
+    for store in [
+        store,
+        KvStore::open_with_options(temp_dir.path(), options.clone())?,
+    ] {
+        assert_eq!(store.get_version("key".to_owned(), 0)?, Some(value(5)));
+        assert_eq!(store.get_version("key".to_owned(), 1)?, Some(value(4)));
+        assert_eq!(store.get_version("key".to_owned(), 2)?, None);
+        assert_eq!(store.get("key".to_owned())?, Some(value(5)));
+        assert_eq!(store.get_version("other7".to_owned(), 1)?, Some(value(4)));
+        // Versions before a removal are dropped
+        assert_eq!(store.get_version("gone".to_owned(), 0)?, Some(value(2)));
+        assert_eq!(store.get_version("gone".to_owned(), 1)?, None);
+        assert_eq!(store.get_version("missing".to_owned(), 0)?, None);
+    }
+    Ok(())
+}
+
+// Compaction keeps the configured number of versions of each key, in the
+// foreground, in the background and for delta records
+#[test]
+fn compaction_keeps_versions() -> Result<()> {
+    let options = KvStoreOptions {
+        keep_versions: 2,
+        ..KvStoreOptions::default()
+    };
+    check_keep_versions(options.clone())?;
+    check_keep_versions(KvStoreOptions {
+        compaction: CompactionPolicy::Background,
+        compaction_threshold: 1024,
+        ..options.clone()
+    })?;
+    check_keep_versions(KvStoreOptions {
+        deltas: true,
+        ..options
+    })
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// `ReplicaKvStore` only implements `KvsReader`, so `set`/`remove` are not
+// available on it at all; it can only change by applying shipped records.
+#[test]
+fn replica_applies_shipped_log() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader = KvStore::open(leader_dir.path())?;
+    let replica = ReplicaKvStore::open(replica_dir.path())?;
+
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    let (records, pos) = leader.ship_log(LogPosition::default())?;
+    replica.apply(&records)?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Ship only what was written since the last position
+    leader.set("key1".to_owned(), "value3".to_owned())?;
+    leader.remove("key2".to_owned())?;
+    let (records, next) = leader.ship_log(pos)?;
+    assert!(next > pos);
+    replica.apply(&records)?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, None);
+
+    // Nothing new to ship
+    let (records, _) = leader.ship_log(next)?;
+    assert!(records.is_empty());
+
+    // Open from disk again and check persistent data
+    drop(replica);
+    let replica = ReplicaKvStore::open(replica_dir.path())?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(replica.get("key2".to_owned())?, None);
+
+    // A position inside a compacted generation can not be resumed
+    leader.compact()?;
+    match leader.ship_log(next) {
+        Err(KvsError::LogCompacted) => {}
+        _ => panic!("expected LogCompacted"),
+    }
+
+    Ok(())
+}
+
+// Run the basic operations, compaction and ordered queries against every index backend
+#[test]
+fn index_backends() -> Result<()> {
+    for kind in [
+        IndexKind::Hash,
+        IndexKind::BTree,
+        IndexKind::Trie,
+        IndexKind::Spill { max_entries: 2 },
+        IndexKind::Sharded {
+            shards: 3,
+            hash: ShardHash::default(),
+        },
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            index: kind,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        assert_eq!(store.first_key()?, None);
+        assert_eq!(store.last_key()?, None);
+        for key in ["b", "ab", "a", "abc", "c"] {
+            store.set(key.to_owned(), format!("{}-1", key))?;
+        }
+        store.set("ab".to_owned(), "ab-2".to_owned())?;
+        store.remove("c".to_owned())?;
+        assert!(matches!(
+            store.remove("c".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+
+        assert_eq!(store.get("ab".to_owned())?, Some("ab-2".to_owned()));
+        assert_eq!(store.get("c".to_owned())?, None);
+        assert_eq!(store.first_key()?, Some("a".to_owned()));
+        assert_eq!(store.last_key()?, Some("b".to_owned()));
+        assert_eq!(
+            store.scan("ab".to_owned().."b".to_owned())?,
+            vec![
+                ("ab".to_owned(), "ab-2".to_owned()),
+                ("abc".to_owned(), "abc-1".to_owned()),
+            ]
+        );
+        assert_eq!(store.scan(..)?.len(), 4);
+
+        store.compact()?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let mut keys = store.keys()?;
+        keys.sort();
+        assert_eq!(keys, vec!["a", "ab", "abc", "b"]);
+        assert_eq!(store.get("a".to_owned())?, Some("a-1".to_owned()));
+        assert_eq!(store.get("ab".to_owned())?, Some("ab-2".to_owned()));
+        assert_eq!(store.scan("abc".to_owned()..)?.len(), 2);
+    }
+
+    Ok(())
+}
+
+// Tiered compaction merges adjacent small generations and leaves large ones alone
+#[test]
+fn tiered_compaction_merges_small_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        tiered_compaction: TieredCompaction {
+            small_generation_size: 1024,
+            min_generations: 2,
+        },
+        ..KvStoreOptions::default()
+    };
+    // Sizes of the generation files, keyed by generation number
+    let gen_sizes = || {
+        fs::read_dir(temp_dir.path().join("kvs.db"))
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let gen = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+                (gen, fs::metadata(&path).unwrap().len())
+            })
+            .collect::<std::collections::BTreeMap<u64, u64>>()
+    };
+
+    // One large generation, then a small generation per reopen
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("big{}", i), "x".repeat(100))?;
+    }
+    drop(store);
+    for i in 0..4 {
+        let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set(format!("small{}", i), format!("{}", i))?;
+        store.set("shared".to_owned(), format!("{}", i))?;
+        if i == 2 {
+            store.remove("big0".to_owned())?;
+        }
+    }
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let before = gen_sizes();
+    assert_eq!(
+        before.keys().copied().collect::<Vec<_>>(),
+        vec![1, 2, 3, 4, 5, 6]
+    );
+    store.compact_tiered()?;
+    let after = gen_sizes();
+    assert_eq!(after.keys().copied().collect::<Vec<_>>(), vec![1, 7, 8]);
+    assert_eq!(after[&1], before[&1]);
+    assert!(after[&7] < (2..=5).map(|gen| before[&gen]).sum());
+
+    // The large generation is never merged
+    store.compact_tiered()?;
+    assert_eq!(gen_sizes()[&1], before[&1]);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("big0".to_owned())?, None);
+    assert_eq!(store.get("big1".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.get("shared".to_owned())?, Some("3".to_owned()));
+    for i in 0..4 {
+        assert_eq!(store.get(format!("small{}", i))?, Some(format!("{}", i)));
+    }
+
+    Ok(())
+}
+
+// Keys set with a TTL read as absent once expired, across reopens and both
+// kinds of compaction
+#[test]
+fn ttl_expiration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        tiered_compaction: TieredCompaction {
+            small_generation_size: 1024,
+            min_generations: 2,
+        },
+        ..KvStoreOptions::default()
+    };
+    let open = || KvStore::open_with_options(temp_dir.path(), options.clone());
+    // A large generation the tiered compaction leaves alone
+    let store = open()?;
+    for i in 0..100 {
+        store.set(format!("big{}", i), "x".repeat(100))?;
+    }
+    drop(store);
+
+    let store = open()?;
+    let ttl = Duration::from_millis(500);
+    store.set_with_ttl("session".to_owned(), "abc".to_owned(), ttl)?;
+    store.set_with_ttl("big0".to_owned(), "short".to_owned(), ttl)?;
+    store.set_with_ttl("renewed".to_owned(), "old".to_owned(), ttl)?;
+    store.set("renewed".to_owned(), "new".to_owned())?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "kept".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("session".to_owned())?, Some("abc".to_owned()));
+    assert!(store.contains_key("big0".to_owned())?);
+
+    thread::sleep(Duration::from_millis(700));
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert!(!store.contains_key("big0".to_owned())?);
+    assert_eq!(store.get("renewed".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some("kept".to_owned()));
+    assert!(!store.keys()?.contains(&"session".to_owned()));
+    assert!(!store.keys()?.contains(&"big0".to_owned()));
+    assert!(matches!(
+        store.remove("session".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    // Merging the small generations must not bring back the permanent `big0`
+    let store = open()?;
+    assert_eq!(store.get("big0".to_owned())?, None);
+    store.compact_tiered()?;
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("big0".to_owned())?, None);
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.get("big1".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(store.get("long".to_owned())?, Some("kept".to_owned()));
+
+    // A full compaction drops the expired records
+    store.compact()?;
+    let mut keys = vec![];
+    for entry in fs::read_dir(temp_dir.path().join("kvs.db"))? {
+        let path = entry?.path();
+        let gen = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+        keys.extend(
+            generation_records(&temp_dir, gen)
+                .into_iter()
+                .map(|(key, _)| key),
+        );
+    }
+    assert!(!keys.contains(&"session".to_owned()));
+    assert!(!keys.contains(&"big0".to_owned()));
+    assert!(keys.contains(&"long".to_owned()));
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("long".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(store.get("renewed".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// Keys spilled out of a memory-capped index stay retrievable, also across compaction
+#[test]
+fn spilled_index_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        index: IndexKind::Spill { max_entries: 16 },
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).step_by(3) {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    for i in (0..1000).step_by(7) {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            let expected = if i % 7 == 0 {
+                None
+            } else if i % 3 == 0 {
+                Some(format!("new{}", i))
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check(&store)?;
+    assert_eq!(store.keys()?.len(), 1000 - 143);
+    Ok(())
+}
+
+// Incremental updates are stored as deltas and compaction writes full values again
+#[test]
+fn value_deltas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        deltas: true,
+        ..KvStoreOptions::default()
+    };
+    let log_contents = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| fs::read_to_string(entry.path()).unwrap())
+            .collect::<String>()
+    };
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut document = "lorem ipsum ".repeat(100);
+    store.set("doc".to_owned(), document.clone())?;
+    for i in 0..10 {
+        document.push_str(&format!("line {}\n", i));
+        if i == 5 {
+            document.replace_range(..5, "LOREM");
+        }
+        store.set("doc".to_owned(), document.clone())?;
+        assert_eq!(store.get("doc".to_owned())?, Some(document.clone()));
+    }
+    // Ten more full copies of the document would take far more space, even
+    // counting the version and modification time every record carries
+    assert!(log_contents().len() < document.len() * 4);
+    // Except for the edit at both ends, which is stored in full
+    assert_eq!(log_contents().matches("\"Delta\"").count(), 9);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("doc".to_owned())?, Some(document.clone()));
+
+    store.compact()?;
+    assert_eq!(log_contents().matches("\"Delta\"").count(), 0);
+    assert_eq!(store.get("doc".to_owned())?, Some(document.clone()));
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("doc".to_owned())?, Some(document));
+    Ok(())
+}
+
+// A delta that does not fit its base is an error, not a panic
+#[test]
+fn corrupt_delta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        deltas: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let document = "lorem ipsum ".repeat(100);
+    store.set("doc".to_owned(), document.clone())?;
+    store.set("doc".to_owned(), document + "line")?;
+    drop(store);
+
+    // Keep more bytes than the base has, without moving any record
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.unwrap().into_path();
+        if path.extension() == Some("log".as_ref()) {
+            let contents = fs::read_to_string(&path)?;
+            fs::write(
+                &path,
+                contents.replace("\"prefix\":1200", "\"prefix\":9999"),
+            )?;
+        }
+    }
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(store.get("doc".to_owned()).is_err());
+    assert!(store.iter()?.any(|pair| pair.is_err()));
+    Ok(())
+}
+
+// A key written while a background compaction runs stays readable once the
+// compaction removes the generations its earlier records lived in
+#[test]
+fn deltas_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        deltas: true,
+        compaction: CompactionPolicy::Background,
+        compaction_threshold: 1,
+        compaction_ratio: 0.0,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let mut document = "lorem ipsum ".repeat(100);
+    for i in 0..200 {
+        document.push_str(&format!("line {}\n", i));
+        store.set("doc".to_owned(), document.clone())?;
+        assert_eq!(store.get("doc".to_owned())?, Some(document.clone()));
+    }
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("doc".to_owned())?, Some(document));
+    Ok(())
+}
+
+#[test]
+fn case_insensitive_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        case_insensitive: true,
+        ..KvStoreOptions::default()
+    };
+
+    // Written without the option first, so recovery has to normalize
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("Bar".to_owned(), "old".to_owned())?;
+    store.set("BAR".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("Foo".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("foo".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("FOO".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("bar".to_owned())?, Some("new".to_owned()));
+    store.set("fOO".to_owned(), "other".to_owned())?;
+    assert_eq!(store.get("Foo".to_owned())?, Some("other".to_owned()));
+
+    let mut keys = store.keys()?;
+    keys.sort();
+    assert_eq!(keys, vec!["bar", "foo"]);
+    drop(store);
+
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("FOO".to_owned())?, Some("other".to_owned()));
+    store.remove("BaR".to_owned())?;
+    assert_eq!(store.get("bar".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn duplicate_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "2".to_owned())?;
+    drop(store);
+
+    // `02.log` claims generation 2 as well
+    fs::write(
+        temp_dir.path().join("kvs.db").join("02.log"),
+        r#"{"Set":{"key":"key","value":"3"}}"#,
+    )?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::DuplicateGeneration { gen: 2 })
+    ));
+
+    let options = KvStoreOptions {
+        repair_generations: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    // Ordered by file name, `2.log` is the newer one
+    assert_eq!(store.get("key".to_owned())?, Some("2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// Logs written with the old `.Error` extension are renamed and still read
+#[test]
+fn legacy_error_extension_migrated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = temp_dir.path().join("kvs.db");
+    fs::create_dir_all(&db)?;
+    fs::write(
+        db.join("1.Error"),
+        concat!(
+            r#"{"Set":{"key":"a","value":"1"}}"#,
+            r#"{"Set":{"key":"b","value":"2"}}"#,
+        ),
+    )?;
+    fs::write(db.join("2.Error"), r#"{"Remove":{"key":"b"}}"#)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    store.set("c".to_owned(), "3".to_owned())?;
+    drop(store);
+    assert!(!db.join("1.Error").exists());
+    assert!(!db.join("2.Error").exists());
+    assert!(db.join("1.log").exists());
+    assert!(db.join("2.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+// An append-only store writes without indexing and reads back through `iter`
+#[test]
+fn append_only_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        append_only: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for i in 0..10000 {
+        store.set(format!("event{}", i), format!("payload{}", i))?;
+    }
+
+    // Nothing is held in the index
+    assert_eq!(store.first_key()?, None);
+    assert!(matches!(
+        store.get("event0".to_owned()),
+        Err(KvsError::AppendOnly)
+    ));
+    assert!(matches!(
+        store.remove("event0".to_owned()),
+        Err(KvsError::AppendOnly)
+    ));
+    assert!(matches!(store.scan(..), Err(KvsError::AppendOnly)));
+
+    // Compaction must not drop the unindexed records
+    store.compact()?;
+    let check = |store: &KvStore| -> Result<()> {
+        let mut count = 0;
+        for (i, pair) in store.iter()?.enumerate() {
+            assert_eq!(pair?, (format!("event{}", i), format!("payload{}", i)));
+            count += 1;
+        }
+        assert_eq!(count, 10000);
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.first_key()?, None);
+    check(&store)?;
+    Ok(())
+}
+
+// Compaction with verification enabled keeps every value
+#[test]
+fn verified_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        verify_compaction: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open_with_options(temp_dir.path(), options)?)
+}
+
+// Repeated reads are served from the value cache until the key is written
+#[test]
+fn value_cache_source() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            value_cache: 2,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    let read = |key: &str| store.get_with_source(key.to_owned());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(read("key1")?, Some(("value1".to_owned(), ReadSource::Disk)));
+    assert_eq!(
+        read("key1")?,
+        Some(("value1".to_owned(), ReadSource::Cache))
+    );
+
+    // Writes invalidate the cached value
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(read("key1")?, Some(("value2".to_owned(), ReadSource::Disk)));
+    assert_eq!(
+        read("key1")?,
+        Some(("value2".to_owned(), ReadSource::Cache))
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(read("key1")?, None);
+
+    // The least recently read value is evicted
+    for key in ["key1", "key2", "key3"] {
+        store.set(key.to_owned(), key.to_owned())?;
+        assert_eq!(read(key)?, Some((key.to_owned(), ReadSource::Disk)));
+    }
+    assert_eq!(read("key3")?, Some(("key3".to_owned(), ReadSource::Cache)));
+    assert_eq!(read("key1")?, Some(("key1".to_owned(), ReadSource::Disk)));
+
+    // Without a cache every read goes to disk
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for _ in 0..2 {
+        assert_eq!(
+            store.get_with_source("key1".to_owned())?,
+            Some(("value1".to_owned(), ReadSource::Disk))
+        );
+    }
+    Ok(())
+}
+
+// The default shard hash spreads sequential keys evenly
+#[test]
+fn sharded_index_balance() -> Result<()> {
+    for hash in [ShardHash::AHash, ShardHash::Sip, ShardHash::Fnv] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                index: IndexKind::Sharded { shards: 16, hash },
+                ..KvStoreOptions::default()
+            },
+        )?;
+        let pairs = (0..16_000)
+            .map(|i| (i.to_string(), "v".to_owned()))
+            .collect();
+        store.set_many(pairs)?;
+
+        let counts = store.shard_key_counts().expect("sharded index");
+        assert_eq!(counts.len(), 16);
+        assert_eq!(counts.iter().sum::<usize>(), 16_000);
+        if hash == ShardHash::default() {
+            // Within 10% of the mean of 1000 keys per shard
+            for count in counts {
+                assert!((900..=1100).contains(&count), "unbalanced shard {}", count);
+            }
+        }
+        assert_eq!(store.get("12345".to_owned())?, Some("v".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(KvStore::open(temp_dir.path())?.shard_key_counts(), None);
+    Ok(())
+}
+
+// The compaction threshold is taken from the options
+#[test]
+fn compaction_threshold_option() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |compaction_threshold| {
+        KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                compaction_threshold,
+                ..KvStoreOptions::default()
+            },
+        )
+    };
+    let value = "x".repeat(1024);
+
+    // A large threshold lets more than the default 4 MiB go stale
+    let store = open(64 * 1024 * 1024)?;
+    for _ in 0..6 * 1024 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    assert!(store.uncompacted_bytes() > 6 * 1024 * 1024);
+    drop(store);
+
+    // A small one compacts every few writes
+    let store = open(16 * 1024)?;
+    store.compact()?;
+    for _ in 0..1024 {
+        store.set("key".to_owned(), value.clone())?;
+        assert!(store.uncompacted_bytes() < 16 * 1024);
+    }
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Compaction waits for stale records to take up the ratio of the log, however
+// far past the threshold they are
+#[test]
+fn compaction_ratio_option() -> Result<()> {
+    let value = "x".repeat(1024);
+    let open = |temp_dir: &TempDir, compaction_ratio| {
+        KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                compaction_threshold: 16 * 1024,
+                compaction_ratio,
+                ..KvStoreOptions::default()
+            },
+        )
+    };
+    // 1 MiB of live records, then overwrites of one key
+    let fill = |store: &KvStore| -> Result<Vec<u64>> {
+        for i in 0..1024 {
+            store.set(format!("live{}", i), value.clone())?;
+        }
+        (0..1536)
+            .map(|_| {
+                store.set("key".to_owned(), value.clone())?;
+                Ok(store.uncompacted_bytes())
+            })
+            .collect()
+    };
+
+    // Half the log must be stale, so about 1 MiB
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir, 0.5)?;
+    let uncompacted = fill(&store)?;
+    let peak = *uncompacted.iter().max().unwrap();
+    assert!(peak > 1000 * 1024, "{}", peak);
+    assert!(peak < 1200 * 1024, "{}", peak);
+    assert!(*uncompacted.last().unwrap() < peak);
+
+    // With a small live set the same threshold and ratio compact early
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir, 0.5)?;
+    for _ in 0..1536 {
+        store.set("key".to_owned(), value.clone())?;
+        assert!(store.uncompacted_bytes() < 32 * 1024);
+    }
+
+    // Without a ratio the threshold alone decides
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(&temp_dir, 0.0)?;
+    let uncompacted = fill(&store)?;
+    assert!(uncompacted.iter().all(|&bytes| bytes < 16 * 1024));
+    assert_eq!(store.get("live0".to_owned())?, Some(value.clone()));
+    Ok(())
+}
+
+// With compaction disabled the log grows past the threshold until compacted manually
+#[test]
+fn compaction_disabled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            compaction: CompactionPolicy::Disabled,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    let db_dir = temp_dir.path().join("kvs.db");
+    let dir_size = || -> Result<u64> {
+        let mut len = 0;
+        for entry in fs::read_dir(&db_dir)? {
+            len += entry?.metadata()?.len();
+        }
+        Ok(len)
+    };
+
+    let value = "x".repeat(1024);
+    for _ in 0..6 * 1024 {
+        store.set("key".to_owned(), value.clone())?;
+    }
+    assert_eq!(fs::read_dir(&db_dir)?.count(), 1);
+    assert!(dir_size()? > 6 * 1024 * 1024);
+    assert!(store.uncompacted_bytes() > 4 * 1024 * 1024);
+
+    store.compact()?;
+    assert!(dir_size()? < 64 * 1024);
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Versions survive compaction and reopening
+#[test]
+fn versioned_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let first = store.set_versioned("key".to_owned(), "value1".to_owned())?;
+    let version = store.set_versioned("key".to_owned(), "value2".to_owned())?;
+    assert!(version > first);
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.set_if_version("key".to_owned(), "value3".to_owned(), first),
+        Err(KvsError::VersionMismatch { current }) if current == version
+    ));
+    let next = store.set_if_version("key".to_owned(), "value3".to_owned(), version)?;
+    assert!(next > version);
+    assert!(store.set_versioned("other".to_owned(), "value".to_owned())? > next);
+    assert_eq!(store.get("key".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Versions keep increasing across a reopen after compaction or `clear` removed
+// the records holding the highest of them
+#[test]
+fn versions_survive_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_versioned("kept".to_owned(), "value".to_owned())?;
+    let removed = store.set_versioned("gone".to_owned(), "value".to_owned())?;
+    store.remove("gone".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let version = store.set_versioned("gone".to_owned(), "again".to_owned())?;
+    assert!(version > removed, "version {} reused", version);
+    // A stale version from before the removal does not match the new write
+    assert!(matches!(
+        store.set_if_version("gone".to_owned(), "stale".to_owned(), removed),
+        Err(KvsError::VersionMismatch { .. })
+    ));
+
+    store.clear()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_versioned("gone".to_owned(), "cleared".to_owned())? > version);
+    Ok(())
+}
+
+// Writes to keys with the reserved prefix are rejected
+#[test]
+fn reserved_key_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let reserved = "__kvs:meta".to_owned();
+    assert!(matches!(
+        store.set(reserved.clone(), "value".to_owned()),
+        Err(KvsError::ReservedKey(_))
+    ));
+    assert!(matches!(
+        store.set_deferred(reserved.clone(), "value".to_owned()),
+        Err(KvsError::ReservedKey(_))
+    ));
+    assert!(matches!(
+        store.remove(reserved.clone()),
+        Err(KvsError::ReservedKey(_))
+    ));
+    assert_eq!(store.get(reserved)?, None);
+
+    store.set("key:__kvs:".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        store.get("key:__kvs:".to_owned())?,
+        Some("value".to_owned())
+    );
+
+    // The prefix is configurable
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            reserved_prefix: "sys/".to_owned(),
+            ..KvStoreOptions::default()
+        },
+    )?;
+    store.set("__kvs:meta".to_owned(), "value".to_owned())?;
+    assert!(matches!(
+        store.set("sys/meta".to_owned(), "value".to_owned()),
+        Err(KvsError::ReservedKey(_))
+    ));
+    Ok(())
+}
+
+// `get_at` reads the value a key had at a checkpoint
+#[test]
+fn get_at_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    let checkpoint: Checkpoint = store.checkpoint()?;
+
+    store.set("key".to_owned(), "new".to_owned())?;
+    store.remove("removed".to_owned())?;
+    store.set("later".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        store.get_at("key".to_owned(), &checkpoint)?,
+        Some("old".to_owned())
+    );
+    assert_eq!(
+        store.get_at("removed".to_owned(), &checkpoint)?,
+        Some("value".to_owned())
+    );
+    assert_eq!(store.get_at("later".to_owned(), &checkpoint)?, None);
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+
+    // A checkpoint taken later sees the later writes
+    let latest = store.checkpoint()?;
+    assert!(latest.position() > checkpoint.position());
+    assert_eq!(
+        store.get_at("key".to_owned(), &latest)?,
+        Some("new".to_owned())
+    );
+    assert_eq!(store.get_at("removed".to_owned(), &latest)?, None);
+
+    store.compact()?;
+    assert!(matches!(
+        store.get_at("key".to_owned(), &checkpoint),
+        Err(KvsError::LogCompacted)
+    ));
+    Ok(())
+}
+
+// `modified_at` advances with each write and survives a reopen
+#[test]
+fn modified_timestamp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.modified_at("key".to_owned())?, None);
+
+    store.set("key".to_owned(), "old".to_owned())?;
+    let first = store
+        .modified_at("key".to_owned())?
+        .expect("timestamp missing");
+    thread::sleep(Duration::from_millis(10));
+    store.set("key".to_owned(), "new".to_owned())?;
+    let second = store
+        .modified_at("key".to_owned())?
+        .expect("timestamp missing");
+    assert!(second > first);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.modified_at("key".to_owned())?, Some(second));
+    store.remove("key".to_owned())?;
+    assert_eq!(store.modified_at("key".to_owned())?, None);
+    Ok(())
+}
+
+// `remove_many` removes existing keys with one flush and skips missing ones
+#[test]
+fn remove_many_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    let keys = vec!["key1", "key3", "key3", "missing", "key5"];
+    let removed = store.remove_many(keys.into_iter().map(str::to_owned).collect())?;
+    assert_eq!(removed, 3);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.remove_many(vec!["key1".to_owned()])?, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let mut keys = store.keys()?;
+    keys.sort();
+    assert_eq!(
+        keys,
+        ["key0", "key2", "key4", "key6", "key7", "key8", "key9"]
+    );
+    Ok(())
+}
+
+// Aggregates only cover the keys under the prefix
+#[test]
+fn aggregate_over_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("visits:a".to_owned(), "3".to_owned())?;
+    store.set("visits:b".to_owned(), "-5".to_owned())?;
+    store.set("visits:c".to_owned(), "12".to_owned())?;
+    store.set("visits:note".to_owned(), "n/a".to_owned())?;
+    store.set("visitz".to_owned(), "100".to_owned())?;
+
+    let prefix = || "visits:".to_owned();
+    assert_eq!(
+        store.aggregate(prefix(), Aggregation::Count)?,
+        AggResult::Count(4)
+    );
+    assert_eq!(
+        store.aggregate(prefix(), Aggregation::Sum(NonInteger::Skip))?,
+        AggResult::Sum(10)
+    );
+    assert_eq!(
+        store.aggregate(prefix(), Aggregation::Min(NonInteger::Skip))?,
+        AggResult::Min(Some(-5))
+    );
+    assert_eq!(
+        store.aggregate(prefix(), Aggregation::Max(NonInteger::Skip))?,
+        AggResult::Max(Some(12))
+    );
+    match store.aggregate(prefix(), Aggregation::Sum(NonInteger::Error)) {
+        Err(KvsError::NotAnInteger(key)) => assert_eq!(key, "visits:note"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(
+        store.aggregate("missing:".to_owned(), Aggregation::Max(NonInteger::Error))?,
+        AggResult::Max(None)
+    );
+
+    Ok(())
+}
+
+fn check_set_many<E: KvsEngine>(engine: &E) -> Result<()> {
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    engine.set_many(pairs)?;
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// `set_many` writes a batch with one flush, compacting midway if needed
+#[test]
+fn set_many_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_set_many(&SledKvsEngine::new(db))?;
+    check_set_many(&MemoryKvsEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_ratio: 0.0,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    check_set_many(&store)?;
+    assert!(store
+        .set_many(vec![("__kvs:id:x".to_owned(), "1".to_owned())])
+        .is_err());
+
+    // The second batch makes 4.5 MiB stale, past the compaction threshold.
+    // The log never gets half stale, so only the threshold is in play
+    let batch = |value: &str| -> Vec<(String, String)> {
+        (0..4608)
+            .map(|i| (format!("big{}", i), value.repeat(1024)))
+            .collect()
+    };
+    store.set_many(batch("a"))?;
+    store.set_many(batch("b"))?;
+    assert!(store.uncompacted_bytes() < 1 << 20);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in (0..4608).step_by(97) {
+        assert_eq!(store.get(format!("big{}", i))?, Some("b".repeat(1024)));
+    }
+    check_set_many(&store)?;
+    Ok(())
+}
+
+fn check_contains_key<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert!(!engine.contains_key("key".to_owned())?);
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert!(engine.contains_key("key".to_owned())?);
+    engine.remove("key".to_owned())?;
+    assert!(!engine.contains_key("key".to_owned())?);
+    Ok(())
+}
+
+// `contains_key` follows sets and removes on every engine
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_contains_key(&SledKvsEngine::new(db))?;
+    check_contains_key(&MemoryKvsEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_contains_key(&store)?;
+    // The index answers even for a deferred write that is not flushed yet
+    store.set_deferred("late".to_owned(), "value".to_owned())?;
+    assert!(store.contains_key("late".to_owned())?);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("late".to_owned())?);
+    assert!(!store.contains_key("key".to_owned())?);
+    Ok(())
+}
+
+fn check_batch<E: KvsEngine>(engine: &E) -> Result<Vec<OpResult>> {
+    engine.set("kept".to_owned(), "old".to_owned())?;
+    engine.set("gone".to_owned(), "old".to_owned())?;
+    let results = engine.batch(vec![
+        Op::Get("kept".to_owned()),
+        Op::Set("kept".to_owned(), "new".to_owned()),
+        Op::Get("kept".to_owned()),
+        Op::Remove("gone".to_owned()),
+        Op::Get("gone".to_owned()),
+        Op::Remove("gone".to_owned()),
+        Op::Set("added".to_owned(), "value".to_owned()),
+        Op::Remove("added".to_owned()),
+        Op::Set("added".to_owned(), "again".to_owned()),
+        Op::Get("added".to_owned()),
+        Op::Get("missing".to_owned()),
+    ])?;
+    assert_eq!(engine.get("kept".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("gone".to_owned())?, None);
+    assert_eq!(engine.get("added".to_owned())?, Some("again".to_owned()));
+    Ok(results)
+}
+
+// `batch` gives the same results on every engine, whether it overrides the
+// default implementation or not
+#[test]
+fn batch_conformance() -> Result<()> {
+    let expected = vec![
+        OpResult::Value(Some("old".to_owned())),
+        OpResult::Set,
+        OpResult::Value(Some("new".to_owned())),
+        OpResult::Removed(true),
+        OpResult::Value(None),
+        OpResult::Removed(false),
+        OpResult::Set,
+        OpResult::Removed(true),
+        OpResult::Set,
+        OpResult::Value(Some("again".to_owned())),
+        OpResult::Value(None),
+    ];
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    assert_eq!(check_batch(&SledKvsEngine::new(db))?, expected);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(check_batch(&store)?, expected);
+    assert!(store
+        .batch(vec![
+            Op::Set("key".to_owned(), "value".to_owned()),
+            Op::Set("__kvs:id:x".to_owned(), "1".to_owned()),
+        ])
+        .is_err());
+    assert_eq!(store.get("key".to_owned())?, None);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("kept".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get("added".to_owned())?, Some("again".to_owned()));
+
+    // `TimedEngine` keeps the default implementation
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = TimedEngine::new(KvStore::open(temp_dir.path())?);
+    assert_eq!(check_batch(&engine)?, expected);
+    Ok(())
+}
+
+fn check_len<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert!(engine.is_empty()?);
+    for i in 0..3 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    engine.set("key0".to_owned(), "other".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.len()?, 2);
+    assert!(!engine.is_empty()?);
+    Ok(())
+}
+
+// `len` counts live keys, not the records written for them
+#[test]
+fn len_counts_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_len(&SledKvsEngine::new(db))?;
+    check_len(&MemoryKvsEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_len(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len()?, 2);
+    Ok(())
+}
+
+fn check_scan_prefix<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in [
+        "user:1",
+        "user:1:name",
+        "user:12",
+        "user:2",
+        "users",
+        "use",
+        "a",
+    ] {
+        engine.set(key.to_owned(), format!("{}-value", key))?;
+    }
+    engine.remove("user:2".to_owned())?;
+    let keys = |prefix: &str| -> Result<Vec<String>> {
+        Ok(engine
+            .scan_prefix(prefix)?
+            .into_iter()
+            .map(|(key, value)| {
+                assert_eq!(value, format!("{}-value", key));
+                key
+            })
+            .collect())
+    };
+    assert_eq!(keys("user:1:")?, vec!["user:1:name"]);
+    assert_eq!(keys("user:1")?, vec!["user:1", "user:12", "user:1:name"]);
+    assert_eq!(keys("user:")?, vec!["user:1", "user:12", "user:1:name"]);
+    assert_eq!(
+        keys("user")?,
+        vec!["user:1", "user:12", "user:1:name", "users"]
+    );
+    assert_eq!(keys("use")?.len(), 5);
+    assert_eq!(keys("")?.len(), 6);
+    assert!(keys("user:2")?.is_empty());
+    assert!(keys("v")?.is_empty());
+    Ok(())
+}
+
+// `scan_prefix` returns the keys under a prefix, not those of a longer sibling
+#[test]
+fn scan_prefix_with_overlapping_prefixes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_scan_prefix(&SledKvsEngine::new(db))?;
+    check_scan_prefix(&MemoryKvsEngine::new())?;
+
+    for index in [IndexKind::Hash, IndexKind::BTree, IndexKind::Trie] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            index,
+            ..KvStoreOptions::default()
+        };
+        check_scan_prefix(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+    }
+    Ok(())
+}
+
+fn check_clear<E: KvsEngine>(engine: &E) -> Result<()> {
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine.clear()?;
+    for i in 0..100 {
+        assert_eq!(engine.get(format!("key{}", i))?, None);
+    }
+    assert!(engine.is_empty()?);
+    engine.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(engine.get("key0".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// `clear` drops every key, and they stay gone after reopening
+#[test]
+fn clear_removes_all_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_clear(&SledKvsEngine::new(db))?;
+    check_clear(&MemoryKvsEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_clear(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key0".to_owned()]);
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    drop(store);
+
+    // A clear interrupted after its marker is finished on open
+    let db = temp_dir.path().join("kvs.db");
+    let mut gens: Vec<u64> = fs::read_dir(&db)?
+        .filter_map(|entry| {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            name.strip_suffix(".log").map(|gen| gen.parse().unwrap())
+        })
+        .collect();
+    gens.sort_unstable();
+    let next_gen = gens.last().unwrap() + 1;
+    fs::write(db.join(format!("{}.log", next_gen)), "")?;
+    fs::write(temp_dir.path().join("kvs.clear"), next_gen.to_string())?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+    assert!(!temp_dir.path().join("kvs.clear").exists());
+    Ok(())
+}
+
+// `keys` lists the store at one point in time while a writer keeps going
+#[test]
+fn keys_point_in_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..1000 {
+                store.set(format!("key{:04}", i), "value".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+
+    // Keys are written in order, so every snapshot holds the first few of them
+    while !writer.is_finished() {
+        let mut keys = store.keys()?;
+        keys.sort();
+        let expected = (0..keys.len())
+            .map(|i| format!("key{:04}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.keys()?.len(), 1000);
+    Ok(())
+}
+
+fn check_compare_and_swap<E: KvsEngine>(engine: &E) -> Result<()> {
+    let lock = || "lock".to_owned();
+    assert!(!engine.compare_and_swap(lock(), Some("a".to_owned()), None)?);
+    assert!(engine.compare_and_swap(lock(), None, None)?);
+    assert!(engine.compare_and_swap(lock(), None, Some("a".to_owned()))?);
+    assert!(!engine.compare_and_swap(lock(), None, Some("b".to_owned()))?);
+    assert_eq!(engine.get(lock())?, Some("a".to_owned()));
+    assert!(engine.compare_and_swap(lock(), Some("a".to_owned()), None)?);
+    assert_eq!(engine.get(lock())?, None);
+
+    // Two threads race to take the lock, exactly one gets it every round
+    for round in 0..50 {
+        let barrier = Arc::new(Barrier::new(2));
+        let handles = (0..2)
+            .map(|id| {
+                let engine = engine.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || -> Result<bool> {
+                    barrier.wait();
+                    engine.compare_and_swap(lock(), None, Some(format!("holder{}", id)))
+                })
+            })
+            .collect::<Vec<_>>();
+        let won = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(won.iter().filter(|won| **won).count(), 1, "round {}", round);
+        let holder = format!("holder{}", won.iter().position(|won| *won).unwrap());
+        assert!(engine.compare_and_swap(lock(), Some(holder), None)?);
+    }
+    Ok(())
+}
+
+// `compare_and_swap` swaps only on the expected value, and is atomic on both
+// engines
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_compare_and_swap(&SledKvsEngine::new(db))?;
+    check_compare_and_swap(&MemoryKvsEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+fn check_returning_previous<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = || "key".to_owned();
+    assert_eq!(engine.set_returning(key(), "value1".to_owned())?, None);
+    assert_eq!(
+        engine.set_returning(key(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get(key())?, Some("value2".to_owned()));
+    assert_eq!(engine.remove_returning(key())?, Some("value2".to_owned()));
+    assert_eq!(engine.remove_returning(key())?, None);
+    assert_eq!(engine.get(key())?, None);
+    Ok(())
+}
+
+// `set_returning` and `remove_returning` return the previous value, like the
+// methods of `HashMap`
+#[test]
+fn returning_previous_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    check_returning_previous(&SledKvsEngine::new(db))?;
+    check_returning_previous(&MemoryKvsEngine::new())?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_returning_previous(&KvStore::open(temp_dir.path())?)?;
+
+    // The previous record is the base of the delta written over it
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        deltas: true,
+        ..KvStoreOptions::default()
+    };
+    check_returning_previous(&KvStore::open_with_options(temp_dir.path(), options)?)?;
+    Ok(())
+}
+
+// `next_id` hands out increasing ids per namespace, never twice across reopens
+#[test]
+fn monotonic_ids() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.next_id("orders".to_owned())?, 1);
+    assert_eq!(store.next_id("orders".to_owned())?, 2);
+    assert_eq!(store.next_id("users".to_owned())?, 1);
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let mut ids = vec![];
+                for _ in 0..50 {
+                    ids.push(store.next_id("orders".to_owned()).unwrap());
+                }
+                ids
+            })
+        })
+        .collect();
+    let mut ids = vec![];
+    for handle in handles {
+        let thread_ids = handle.join().unwrap();
+        assert!(thread_ids.windows(2).all(|w| w[0] < w[1]));
+        ids.extend(thread_ids);
+    }
+    ids.sort_unstable();
+    assert_eq!(ids, (3..403).collect::<Vec<_>>());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.next_id("orders".to_owned())?, 403);
+    assert_eq!(store.next_id("users".to_owned())?, 2);
+
+    let options = KvStoreOptions {
+        reserved_prefix: String::new(),
+        ..KvStoreOptions::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert!(matches!(
+        store.next_id("orders".to_owned()),
+        Err(KvsError::Unsupported(_))
+    ));
+    Ok(())
+}
+
+// `clear` keeps the `next_id` counters, so ids keep increasing after it, also
+// once the store is reopened
+#[test]
+fn ids_survive_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.next_id("orders".to_owned())?, 1);
+    assert_eq!(store.next_id("orders".to_owned())?, 2);
+
+    store.clear()?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.next_id("orders".to_owned())?, 3);
+    store.clear()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.next_id("orders".to_owned())?, 4);
+    Ok(())
+}
+
+// `export` writes every live entry, and `import` loads them into another store,
+// next_id counters included
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set_deferred("deferred".to_owned(), "unflushed".to_owned())?;
+    assert_eq!(store.next_id("orders".to_owned())?, 1);
+
+    let mut backup = vec![];
+    store.export(&mut backup)?;
+    let lines = String::from_utf8(backup.clone())?;
+    assert_eq!(lines.lines().count(), 2501);
+    assert!(lines.contains(r#"{"key":"deferred","value":"unflushed"}"#));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::open(temp_dir.path())?;
+    restored.set("key1".to_owned(), "kept".to_owned())?;
+    restored.import(&mut backup.as_slice())?;
+    assert_eq!(
+        restored.get("key0".to_owned())?,
+        Some("overwritten".to_owned())
+    );
+    assert_eq!(restored.get("key1".to_owned())?, Some("kept".to_owned()));
+    assert_eq!(
+        restored.get("key2499".to_owned())?,
+        Some("value2499".to_owned())
+    );
+    assert_eq!(
+        restored.get("deferred".to_owned())?,
+        Some("unflushed".to_owned())
+    );
+    assert_eq!(restored.next_id("orders".to_owned())?, 2);
+
+    assert!(matches!(
+        restored.import(&mut &b"{\"key\":1}\n"[..]),
+        Err(KvsError::Serde(_))
+    ));
+    assert!(matches!(
+        restored.import(&mut &b"{\"key\":\"a\"}\n"[..]),
+        Err(KvsError::StringError(_))
+    ));
+    Ok(())
+}
+
+fn check_binary_values(options: KvStoreOptions) -> Result<()> {
+    let blob = vec![0xff, 0x00, 0xfe, 0x80];
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set_bytes("nul".to_owned(), b"a\0b".to_vec())?;
+    store.set_bytes("blob".to_owned(), blob.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+    store.set_bytes("text".to_owned(), blob.clone())?;
+    store.set("blob2".to_owned(), "value".to_owned())?;
+    store.set_bytes("blob2".to_owned(), vec![0xc3])?;
+
+    // UTF-8 values read as strings too, binary ones only as bytes
+    assert_eq!(store.get("nul".to_owned())?, Some("a\0b".to_owned()));
+    assert_eq!(store.get_bytes("nul".to_owned())?, Some(b"a\0b".to_vec()));
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob.clone()));
+    assert!(matches!(
+        store.get("blob".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get_bytes("missing".to_owned())?, None);
+
+    // A string set over a binary value cannot be a delta of it
+    store.set("blob2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("blob2".to_owned())?, Some("value2".to_owned()));
+
+    let mut backup = vec![];
+    store.export(&mut backup)?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get_bytes("blob".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get_bytes("text".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get("nul".to_owned())?, Some("a\0b".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::open_with_options(temp_dir.path(), options)?;
+    restored.import(&mut backup.as_slice())?;
+    assert_eq!(restored.get_bytes("blob".to_owned())?, Some(blob));
+    assert_eq!(restored.get("nul".to_owned())?, Some("a\0b".to_owned()));
+    Ok(())
+}
+
+// Values that are not UTF-8 round trip through `set_bytes` and `get_bytes`,
+// compaction, reopening and export, in both log encodings
+#[test]
+fn binary_values() -> Result<()> {
+    check_binary_values(KvStoreOptions::default())?;
+    check_binary_values(KvStoreOptions {
+        log_encoding: LogEncoding::Binary,
+        deltas: true,
+        ..KvStoreOptions::default()
+    })
+}
+
+// Concurrent increments are never lost, and non-integer values are refused
+#[test]
+fn atomic_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("hits".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("hits".to_owned(), -7)?, -2);
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    store.increment("counter".to_owned(), 1)?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+
+    store.set("name".to_owned(), "alice".to_owned())?;
+    match store.increment("name".to_owned(), 1) {
+        Err(KvsError::NotAnInteger(key)) => assert_eq!(key, "name"),
+        res => panic!("unexpected {:?}", res),
+    }
+    store.set("big".to_owned(), i64::MAX.to_string())?;
+    assert!(store.increment("big".to_owned(), 1).is_err());
+    assert_eq!(store.get("big".to_owned())?, Some(i64::MAX.to_string()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("counter".to_owned(), 0)?, 800);
+    Ok(())
+}
+
+// `get_or_insert_with` calls its closure once per absent key, however many
+// threads race to insert it, and they all get the inserted value
+#[test]
+fn get_or_insert_with_runs_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("present".to_owned(), "old".to_owned())?;
+    let value = store.get_or_insert_with("present".to_owned(), || panic!("key is present"))?;
+    assert_eq!(value, "old");
+
+    let calls = Arc::new(AtomicU64::new(0));
+    for round in 0..20 {
+        let key = format!("key{}", round);
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8)
+            .map(|id| {
+                let (store, calls, barrier) =
+                    (store.clone(), Arc::clone(&calls), Arc::clone(&barrier));
+                let key = key.clone();
+                thread::spawn(move || -> Result<String> {
+                    barrier.wait();
+                    store.get_or_insert_with(key, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        format!("thread{}", id)
+                    })
+                })
+            })
+            .collect();
+        let values = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(calls.load(Ordering::SeqCst), round + 1, "round {}", round);
+        assert!(values.iter().all(|value| *value == values[0]));
+        assert_eq!(store.get(key)?, Some(values[0].clone()));
+    }
+    Ok(())
+}
+
+// Setting a key to the value it holds writes nothing
+#[test]
+fn set_if_changed_skips_same_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_changed("key1".to_owned(), "value1".to_owned())?);
+    let stats = store.stats()?;
+    assert!(!store.set_if_changed("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.stats()?.log_bytes, stats.log_bytes);
+    assert_eq!(store.uncompacted_bytes(), 0);
+
+    assert!(store.set_if_changed("key1".to_owned(), "value2".to_owned())?);
+    assert!(store.stats()?.log_bytes > stats.log_bytes);
+    assert!(store.uncompacted_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // An expiring key is made permanent even with the same value
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    assert!(store.set_if_changed("key2".to_owned(), "value".to_owned())?);
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The stats file is rewritten periodically and stops being written once the
+// store is dropped
+#[test]
+fn periodic_stats_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stats_path = temp_dir.path().join("stats.json");
+    let options = KvStoreOptions {
+        stats_file: Some(StatsFile {
+            path: stats_path.clone(),
+            interval: Duration::from_millis(20),
+        }),
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut stats = None;
+    for _ in 0..250 {
+        thread::sleep(Duration::from_millis(20));
+        if let Ok(data) = fs::read(&stats_path) {
+            let written: KvStats = serde_json::from_slice(&data)?;
+            if written.keys == 9 {
+                stats = Some(written);
+                break;
+            }
+        }
+    }
+    let stats = stats.expect("stats file not written");
+    assert_eq!(stats.generations, 1);
+    assert!(stats.log_bytes > 0);
+    assert!(stats.uncompacted_bytes > 0);
+    assert_eq!(stats.last_version, store.stats()?.last_version);
+
+    drop(store);
+    fs::remove_file(&stats_path)?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(!stats_path.exists());
+    Ok(())
+}
+
+// A log from before versions is rewritten in the current format when allowed
+#[test]
+fn upgrade_old_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = temp_dir.path().join("kvs.db");
+    fs::create_dir_all(&db)?;
+    let old_log = concat!(
+        r#"{"Set":{"key":"a","value":"1"}}"#,
+        r#"{"Set":{"key":"b","value":"2"}}"#,
+        r#"{"Remove":{"key":"b"}}"#,
+        r#"{"Set":{"key":"a","value":"3"}}"#,
+    );
+    fs::write(db.join("1.log"), old_log)?;
+    // Left behind by an upgrade that crashed before committing
+    fs::write(db.join("1.upgrade"), "partial")?;
+
+    // Without the option the old log is served as is
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    drop(store);
+    assert!(!temp_dir.path().join("kvs.format").exists());
+    assert!(!db.join("1.upgrade").exists());
+    assert_eq!(fs::read_to_string(db.join("1.log"))?, old_log);
+
+    let options = KvStoreOptions {
+        allow_upgrade: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(fs::read_to_string(temp_dir.path().join("kvs.format"))?, "1");
+    assert_eq!(
+        fs::read_to_string(db.join("1.log"))?
+            .matches("version")
+            .count(),
+        3
+    );
+    assert_eq!(store.get("a".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    store.set_if_version("a".to_owned(), "4".to_owned(), 3)?;
+    drop(store);
+
+    // A current store is marked and left alone
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    Ok(())
+}
+
+// A binary log serves the same operations as a JSON one in less space, and
+// keeps its encoding across opens
+#[test]
+fn binary_log_encoding() -> Result<()> {
+    let fill = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            store.set(format!("key{}", i % 200), format!("value{}", i))?;
+        }
+        for i in (0..200).step_by(3) {
+            store.remove(format!("key{}", i))?;
+        }
+        store.set_with_ttl(
+            "expiring".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        Ok(())
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..200 {
+            let expected = (i % 3 != 0).then(|| format!("value{}", 800 + i));
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        assert_eq!(store.get("expiring".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    };
+    let log_bytes = |dir: &TempDir| -> u64 {
+        WalkDir::new(dir.path().join("kvs.db"))
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    let json_dir = TempDir::new().expect("unable to create temporary working directory");
+    fill(&KvStore::open(json_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        log_encoding: LogEncoding::Binary,
+        deltas: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    fill(&store)?;
+    check(&store)?;
+    drop(store);
+    assert_eq!(fs::read_to_string(temp_dir.path().join("kvs.format"))?, "2");
+    assert!(log_bytes(&temp_dir) < log_bytes(&json_dir));
+
+    // Opened without the option, the log is still read as binary, and a torn
+    // record at its end is dropped
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    store.set("torn".to_owned(), "value".to_owned())?;
+    drop(store);
+    let last = WalkDir::new(temp_dir.path().join("kvs.db"))
+        .into_iter()
+        .map(|entry| entry.unwrap().into_path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max_by_key(|path| {
+            path.file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        })
+        .unwrap();
+    let len = fs::metadata(&last)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&last)?
+        .set_len(len - 2)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+    assert_eq!(store.get("torn".to_owned())?, None);
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    // Records are shipped as JSON, for replicas of any encoding
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica = ReplicaKvStore::open(replica_dir.path())?;
+    let (records, _) = store.ship_log(LogPosition::default())?;
+    replica.apply(&records)?;
+    assert_eq!(replica.get("key1".to_owned())?, Some("value801".to_owned()));
+    assert_eq!(replica.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// A corrupt generation is moved aside and the store opens with the others
+#[test]
+fn quarantine_corrupt_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = temp_dir.path().join("kvs.db");
+    fs::create_dir_all(&db)?;
+    fs::write(
+        db.join("1.log"),
+        concat!(
+            r#"{"Set":{"key":"a","value":"1"}}"#,
+            r#"{"Set":{"key":"b","value":"1"}}"#,
+        ),
+    )?;
+    let corrupt = concat!(
+        r#"{"Set":{"key":"a","value":"2"}}"#,
+        r#"{"Set":{"key":"b",#garbage#}}"#,
+        r#"{"Set":{"key":"c","value":"2"}}"#,
+    );
+    fs::write(db.join("2.log"), corrupt)?;
+    // A torn record at the end is not corruption
+    fs::write(
+        db.join("3.log"),
+        concat!(r#"{"Set":{"key":"d","value":"3"}}"#, r#"{"Set":{"key""#),
+    )?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let options = KvStoreOptions {
+        recovery: RecoveryMode::Quarantine,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    assert_eq!(store.recovery_report().quarantined, vec![2]);
+    assert!(!db.join("2.log").exists());
+    assert_eq!(
+        fs::read_to_string(db.join("corrupt").join("2.log"))?,
+        corrupt
+    );
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("c".to_owned())?, None);
+    assert_eq!(store.get("d".to_owned())?, Some("3".to_owned()));
+    store.set("e".to_owned(), "4".to_owned())?;
+    drop(store);
+
+    // A later corruption of the same generation number keeps the first copy
+    fs::write(db.join("2.log"), "#")?;
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.recovery_report().quarantined, vec![2]);
+    assert_eq!(fs::read_to_string(db.join("corrupt").join("2.1.log"))?, "#");
+    assert_eq!(store.get("e".to_owned())?, Some("4".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().quarantined.is_empty());
+    assert_eq!(store.get("d".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+// Resync drops the junk between records and keeps the records around it
+#[test]
+fn resync_skips_junk_between_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = temp_dir.path().join("kvs.db");
+    fs::create_dir_all(&db)?;
+    let junk = r##"#junk{"Set":{"key":"#"##;
+    fs::write(
+        db.join("1.log"),
+        [
+            r#"{"Set":{"key":"a","value":"1"}}"#,
+            junk,
+            r#"{"Set":{"key":"b","value":"2"}}"#,
+        ]
+        .concat(),
+    )?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    let options = KvStoreOptions {
+        recovery: RecoveryMode::Resync,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.recovery_report().resynced, vec![1]);
+    assert_eq!(store.recovery_report().skipped_bytes, junk.len() as u64);
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    drop(store);
+
+    // The generation was rewritten without the junk
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report(), &RecoveryReport::default());
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+// The scrubber reads every record back and reports the corrupt ones
+#[test]
+fn scrubber_detects_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let wait_for_pass = |scrubber: &Scrubber| loop {
+        let stats = scrubber.stats();
+        if stats.passes > 0 {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let scrubber = store.spawn_scrubber(1000);
+    let stats = wait_for_pass(&scrubber);
+    assert!(stats.records >= 10);
+    assert_eq!(stats.corruptions, 0);
+    drop(scrubber);
+
+    // Flip a byte inside the record of key5, as a failing disk would
+    let log = WalkDir::new(temp_dir.path().join("kvs.db"))
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .max_by_key(|path| fs::metadata(path).unwrap().len())
+        .expect("no log file");
+    let mut contents = fs::read(&log)?;
+    let pos = String::from_utf8_lossy(&contents)
+        .find("\"key5\"")
+        .expect("record not found");
+    contents[pos + 3] = b'z';
+    fs::write(&log, contents)?;
+
+    let scrubber = store.spawn_scrubber(1000);
+    let stats = wait_for_pass(&scrubber);
+    assert!(stats.corruptions >= 1);
+    // A get would have returned the value without noticing
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// The on-commit callback receives every committed write in order
+#[test]
+fn on_commit_callback() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let writes = Arc::new(Mutex::new(vec![]));
+    {
+        let writes = Arc::clone(&writes);
+        store.set_on_commit(move |key, mutation| {
+            writes
+                .lock()
+                .unwrap()
+                .push((key.to_owned(), mutation.clone()))
+        });
+    }
+
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("a".to_owned())?;
+    store.set_deferred("c".to_owned(), "3".to_owned())?;
+    store.set("d".to_owned(), "4".to_owned())?;
+    store.set("e".to_owned(), "5".to_owned())?;
+    store.remove_many(vec!["e".to_owned(), "b".to_owned(), "d".to_owned()])?;
+
+    let set = |value: &str, version| Mutation::Set {
+        value: value.to_owned(),
+        version,
+    };
+    let expected = vec![
+        ("a".to_owned(), set("1", 1)),
+        ("b".to_owned(), set("2", 2)),
+        ("a".to_owned(), Mutation::Remove),
+        ("c".to_owned(), set("3", 3)),
+        ("d".to_owned(), set("4", 4)),
+        ("e".to_owned(), set("5", 5)),
+        ("e".to_owned(), Mutation::Remove),
+        ("b".to_owned(), Mutation::Remove),
+        ("d".to_owned(), Mutation::Remove),
+    ];
+    for _ in 0..100 {
+        if writes.lock().unwrap().len() >= expected.len() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(*writes.lock().unwrap(), expected);
+    Ok(())
+}
+
+// A callback writing back into the store does not hold up the writers, even
+// past any queue size
+#[test]
+fn on_commit_writes_back() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let writer = store.clone();
+    store.set_on_commit(move |key, mutation| {
+        if let (false, Mutation::Set { .. }) = (key.starts_with("seen:"), mutation) {
+            writer.set(format!("seen:{}", key), String::new()).unwrap();
+        }
+    });
+
+    let (done, finished) = std::sync::mpsc::channel();
+    {
+        let store = store.clone();
+        thread::spawn(move || {
+            let res = (0..2000).try_for_each(|i| store.set(format!("key{}", i), i.to_string()));
+            done.send(res).unwrap();
+        });
+    }
+    finished
+        .recv_timeout(Duration::from_secs(60))
+        .expect("writers blocked on the callback")?;
+    for _ in 0..500 {
+        if store.get("seen:key1999".to_owned())?.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(store.get("seen:key1999".to_owned())?, Some(String::new()));
+    Ok(())
+}
+
+// Records written and read with direct I/O round-trip, across block boundaries
+#[cfg(target_os = "linux")]
+#[test]
+fn direct_io_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        direct_io: true,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let value = |i: usize| format!("{}-{}", i, "v".repeat(i * 97 % 5000));
+    for i in 0..100 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    for i in 100..150 {
+        store.set_deferred(format!("key{}", i), value(i))?;
+    }
+    store.remove("key7".to_owned())?;
+    for i in (0..150).filter(|i| *i != 7) {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    assert_eq!(store.get("key7".to_owned())?, None);
+
+    store.compact()?;
+    store.set("key0".to_owned(), "after compaction".to_owned())?;
+    drop(store);
+
+    // The log holds no padding, so it reads back without direct I/O too
+    for options in [options, KvStoreOptions::default()] {
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(
+            store.get("key0".to_owned())?,
+            Some("after compaction".to_owned())
+        );
+        for i in (1..150).filter(|i| *i != 7) {
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+        }
+    }
+    Ok(())
+}
+
+// The index memory estimate grows linearly with the number of keys
+#[test]
+fn index_memory_estimate() -> Result<()> {
+    for index in [
+        IndexKind::Hash,
+        IndexKind::BTree,
+        IndexKind::Trie,
+        IndexKind::Spill { max_entries: 100 },
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            index,
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.index_memory_bytes()?, 0);
+        let mut estimates = vec![];
+        for round in 0..2 {
+            for i in round * 1000..(round + 1) * 1000 {
+                store.set_deferred(format!("key{:06}", i), "value".to_owned())?;
+            }
+            estimates.push(store.index_memory_bytes()?);
+        }
+        if let IndexKind::Spill { .. } = index {
+            // Spilled entries don't count
+            assert_eq!(estimates[0], estimates[1]);
+        } else {
+            assert!(estimates[0] > 1000 * 9);
+            let ratio = estimates[1] as f64 / estimates[0] as f64;
+            assert!((1.8..2.2).contains(&ratio), "{:?}: {}", index, ratio);
+        }
+    }
+    Ok(())
+}
+
+fn record_timings<E: KvsEngine>(engine: TimedEngine<E>) -> Result<()> {
+    for i in 0..10 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..20 {
+        engine.get(format!("key{}", i))?;
+    }
+    engine.remove("key0".to_owned())?;
+    // Failed calls are timed too
+    assert!(engine.remove("key0".to_owned()).is_err());
+
+    let clone = engine.clone();
+    assert_eq!(clone.histogram(EngineOp::Set).count(), 10);
+    assert_eq!(clone.histogram(EngineOp::Get).count(), 20);
+    assert_eq!(clone.histogram(EngineOp::Remove).count(), 2);
+    assert_eq!(clone.histogram(EngineOp::Update).count(), 0);
+
+    let sets = engine.histogram(EngineOp::Set);
+    assert_eq!(sets.buckets().iter().sum::<u64>(), 10);
+    assert!(sets.mean() <= sets.max());
+    assert!(sets.quantile(0.5) <= sets.quantile(1.0));
+    assert!(sets.quantile(1.0) <= sets.max());
+    Ok(())
+}
+
+// Timings are recorded per operation for any engine
+#[test]
+fn timed_engine_histograms() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    record_timings(TimedEngine::new(KvStore::open(temp_dir.path())?))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    record_timings(TimedEngine::new(SledKvsEngine::new(db)))
+}
+
+// The metrics of the options hear of every get, set, remove and compaction
+#[test]
+fn metrics_hooks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics = Arc::new(AtomicMetrics::new());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            metrics: Some(metrics.clone()),
+            compaction: CompactionPolicy::Disabled,
+            ..KvStoreOptions::default()
+        },
+    )?;
+
+    for i in 0..10 {
+        store.set(format!("key{}", i % 5), i.to_string())?;
+    }
+    store.set_deferred("deferred".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("6".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, None);
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.set("__kvs:id".to_owned(), "1".to_owned()).is_err());
+    store.compact()?;
+
+    assert_eq!(metrics.histogram(EngineOp::Set).count(), 11);
+    assert_eq!(metrics.errors(EngineOp::Set), 1);
+    assert_eq!(metrics.histogram(EngineOp::Get).count(), 2);
+    assert_eq!(metrics.misses(), 1);
+    assert_eq!(metrics.histogram(EngineOp::Remove).count(), 1);
+    assert_eq!(metrics.errors(EngineOp::Remove), 1);
+    assert_eq!(metrics.histogram(EngineOp::Compact).count(), 1);
+    let stats = store.stats()?.compaction;
+    assert_eq!(
+        metrics.bytes_reclaimed(),
+        stats.bytes_before - stats.bytes_after
+    );
+    assert!(metrics.bytes_reclaimed() > 0);
+
+    // Background compactions are reported too
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics = Arc::new(AtomicMetrics::new());
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            metrics: Some(metrics.clone()),
+            compaction: CompactionPolicy::Background,
+            compaction_threshold: 1024,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    for i in 0..100 {
+        store.set("key".to_owned(), i.to_string())?;
+    }
+    for _ in 0..500 {
+        if metrics.histogram(EngineOp::Compact).count() > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(metrics.histogram(EngineOp::Compact).count() > 0);
     Ok(())
 }
@@ -0,0 +1,1446 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+use unifier::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use unifier::{
+    ChangeStream, CoalescingClient, Compression, ConnectionStats, KvStore, KvsClient,
+    KvsClientPool, KvsEngine, KvsError, KvsReader, KvsServer, LogPosition, Notification, Result,
+    ServerError, ServerErrorKind, SledKvsEngine, Transform, WatchEvent, WireProtocol,
+};
+use walkdir::WalkDir;
+
+// Spawn a `KvsServer` backed by a `KvStore` in a temporary directory and wait
+// until it accepts connections.
+fn start_server(addr: &'static str) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    spawn_server(KvsServer::new(store, SharedQueueThreadPool::new(4)?), addr);
+    Ok(temp_dir)
+}
+
+fn spawn_server<E: KvsEngine, P: ThreadPool + Send + 'static>(
+    server: KvsServer<E, P>,
+    addr: &'static str,
+) {
+    thread::spawn(move || server.run(addr).unwrap());
+    for _ in 0..100 {
+        if KvsClient::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("server did not start on {}", addr);
+}
+
+#[test]
+fn cursor_iteration() -> Result<()> {
+    let addr = "127.0.0.1:4100";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..25 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let cursor = client.iter_open()?;
+    let mut seen = HashMap::new();
+    let batch = client.iter_next(cursor, 10)?;
+    assert_eq!(batch.len(), 10);
+    seen.extend(batch);
+
+    // Resume the same cursor from a new connection
+    drop(client);
+    let mut client = KvsClient::connect(addr)?;
+    loop {
+        let batch = client.iter_next(cursor, 10)?;
+        if batch.is_empty() {
+            break;
+        }
+        assert!(batch.len() <= 10);
+        seen.extend(batch);
+    }
+    assert_eq!(seen.len(), 25);
+    for i in 0..25 {
+        assert_eq!(seen[&format!("key{}", i)], format!("value{}", i));
+    }
+
+    client.iter_close(cursor)?;
+    assert!(client.iter_next(cursor, 10).is_err());
+    assert!(client.iter_close(cursor).is_err());
+    Ok(())
+}
+
+#[test]
+fn cursor_expires_when_idle() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .with_cursor_timeout(Duration::from_millis(100));
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let cursor = client.iter_open()?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(client.iter_next(cursor, 1).is_err());
+    Ok(())
+}
+
+#[test]
+fn cursor_skips_removed_batch() -> Result<()> {
+    let addr = "127.0.0.1:4132";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..30 {
+        client.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+
+    let cursor = client.iter_open()?;
+    assert_eq!(client.iter_next(cursor, 10)?.len(), 10);
+
+    // Remove the whole next batch; the cursor must carry on past it
+    for i in 10..20 {
+        client.remove(format!("key{:02}", i))?;
+    }
+    let batch = client.iter_next(cursor, 10)?;
+    assert_eq!(
+        batch,
+        (20..30)
+            .map(|i| (format!("key{:02}", i), format!("value{}", i)))
+            .collect::<Vec<_>>()
+    );
+    assert!(client.iter_next(cursor, 10)?.is_empty());
+    Ok(())
+}
+
+// Connections are served by the pool side by side, so clients that keep their
+// connection idle do not hold up the others
+#[test]
+fn concurrent_clients() -> Result<()> {
+    let addr = "127.0.0.1:4129";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // A worker serves one connection until it closes
+    spawn_server(KvsServer::new(store, SharedQueueThreadPool::new(40)?), addr);
+    let idle = (0..3)
+        .map(|_| TcpStream::connect(addr))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let barrier = Arc::new(Barrier::new(32));
+    let clients = (0..32)
+        .map(|i| {
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                barrier.wait();
+                for j in 0..10 {
+                    let key = format!("key{}-{}", i, j);
+                    client.set(key.clone(), format!("value{}", j))?;
+                    assert_eq!(client.get(key)?, Some(format!("value{}", j)));
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for client in clients {
+        client.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.scan("key".to_owned(), String::new())?.len(), 320);
+    drop(idle);
+    Ok(())
+}
+
+#[test]
+fn watch_receives_notifications() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    let _temp_dir = start_server(addr)?;
+
+    let mut watcher = KvsClient::connect(addr)?;
+    let mut stream = watcher.watch("key".to_owned())?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    client.set("key".to_owned(), "value1".to_owned())?;
+    client.remove("key".to_owned())?;
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        stream.recv_timeout(timeout),
+        Some(Notification {
+            key: "key".to_owned(),
+            event: WatchEvent::Set("value1".to_owned()),
+        })
+    );
+    assert_eq!(
+        stream.recv_timeout(timeout),
+        Some(Notification {
+            key: "key".to_owned(),
+            event: WatchEvent::Removed,
+        })
+    );
+
+    // Requests on the watching connection still get their responses
+    assert_eq!(watcher.get("other".to_owned())?, Some("value".to_owned()));
+
+    watcher.unwatch("key".to_owned())?;
+    client.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(stream.next(), None);
+    Ok(())
+}
+
+// A watcher that stops reading must not hold up the writers it is notified of
+#[test]
+fn slow_watcher_does_not_block_writers() -> Result<()> {
+    let addr = "127.0.0.1:4133";
+    let _temp_dir = start_server(addr)?;
+
+    let mut watcher = TcpStream::connect(addr)?;
+    watcher.write_all(br#"{"trace_id":"watch","body":{"Watch":{"key":"key"}}}"#)?;
+    let mut frames = serde_json::Deserializer::from_reader(watcher.try_clone()?)
+        .into_iter::<serde_json::Value>();
+    assert!(frames.next().unwrap()?["Response"]["body"]["Ok"].is_null());
+
+    // Far more notifications than the socket buffers hold
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let res = KvsClient::connect(addr).and_then(|mut client| {
+            for i in 0..100 {
+                client.set("key".to_owned(), format!("{}{}", i, "x".repeat(64 * 1024)))?;
+            }
+            Ok(())
+        });
+        done.send(res).unwrap();
+    });
+    finished
+        .recv_timeout(Duration::from_secs(60))
+        .expect("writer blocked on the watcher")?;
+    drop(watcher);
+    Ok(())
+}
+
+#[test]
+fn mirror_applies_writes() -> Result<()> {
+    let addr = "127.0.0.1:4103";
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mirror_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    let mirror = SledKvsEngine::new(sled::open(mirror_dir.path())?);
+    let server =
+        KvsServer::new(primary.clone(), SharedQueueThreadPool::new(2)?).with_mirror(mirror.clone());
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..20 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.set("key3".to_owned(), "new".to_owned())?;
+    client.remove("key5".to_owned())?;
+
+    let mut keys = primary.keys()?;
+    keys.sort();
+    let mut mirrored = mirror.keys()?;
+    mirrored.sort();
+    assert_eq!(keys, mirrored);
+    assert_eq!(keys.len(), 19);
+    for key in keys {
+        assert_eq!(primary.get(key.clone())?, mirror.get(key)?);
+    }
+    assert_eq!(mirror.get("key3".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// A mirror whose writes take a varying time, so that concurrent writes would
+// reach it out of order if nothing kept them in the order of the primary
+#[derive(Clone)]
+struct SlowMirror {
+    store: KvStore,
+}
+
+impl KvsReader for SlowMirror {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+}
+
+impl KvsEngine for SlowMirror {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        thread::sleep(Duration::from_millis(value.len() as u64 % 3));
+        self.store.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.store.keys()
+    }
+
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        self.store.update(key, f)
+    }
+}
+
+// Concurrent writes to the same key land on the mirror in the order of the
+// primary
+#[test]
+fn mirror_follows_concurrent_writers() -> Result<()> {
+    let addr = "127.0.0.1:4134";
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mirror_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    let mirror = SlowMirror {
+        store: KvStore::open(mirror_dir.path())?,
+    };
+    let server =
+        KvsServer::new(primary.clone(), SharedQueueThreadPool::new(8)?).with_mirror(mirror.clone());
+    spawn_server(server, addr);
+
+    for round in 0..5 {
+        let barrier = Arc::new(Barrier::new(8));
+        let writers = (0..8)
+            .map(|i| {
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || -> Result<()> {
+                    let mut client = KvsClient::connect(addr)?;
+                    barrier.wait();
+                    for j in 0..20 {
+                        client.set("key".to_owned(), format!("{}{}", i, "x".repeat(j)))?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        assert_eq!(
+            primary.get("key".to_owned())?,
+            mirror.get("key".to_owned())?,
+            "round {}",
+            round
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn deadline_exceeded_short_circuits() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    let _temp_dir = start_server(addr)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let past = SystemTime::now() - Duration::from_secs(1);
+    let err = client
+        .deadline(past)
+        .set("key".to_owned(), "value".to_owned())
+        .unwrap_err();
+    assert_eq!(err.to_string(), "deadline exceeded");
+    // The write was never applied
+    assert_eq!(client.get("key".to_owned())?, None);
+
+    let future = SystemTime::now() + Duration::from_secs(60);
+    client
+        .deadline(future)
+        .set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn apply_registered_transform() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .with_transform("uppercase", |value| value.map(|value| value.to_uppercase()))
+    .with_transform("clear", |_| None);
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        client.apply("key".to_owned(), "uppercase".to_owned())?,
+        Some("VALUE".to_owned())
+    );
+    assert_eq!(client.get("key".to_owned())?, Some("VALUE".to_owned()));
+
+    // Missing keys are passed as `None`
+    assert_eq!(
+        client.apply("missing".to_owned(), "uppercase".to_owned())?,
+        None
+    );
+    assert_eq!(client.get("missing".to_owned())?, None);
+
+    assert_eq!(client.apply("key".to_owned(), "clear".to_owned())?, None);
+    assert_eq!(client.get("key".to_owned())?, None);
+
+    assert!(client
+        .apply("key".to_owned(), "unknown".to_owned())
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn connection_lifecycle_stats() -> Result<()> {
+    let addr = "127.0.0.1:4106";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(8)?,
+    );
+    spawn_server(server, addr);
+
+    // Wait until `closed` connections are accounted for and only `client`
+    // remains open
+    let settle = |client: &mut KvsClient, closed: u64| -> Result<ConnectionStats> {
+        for _ in 0..100 {
+            let stats = client.stats()?.connections;
+            if stats.open == 1 && stats.closed >= closed {
+                return Ok(stats);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("connections did not close");
+    };
+
+    let mut client = KvsClient::connect(addr)?;
+    let base = settle(&mut client, 0)?;
+    assert_eq!(base.accepted, base.closed + 1);
+
+    let mut clients = (0..3)
+        .map(|_| KvsClient::connect(addr))
+        .collect::<Result<Vec<_>>>()?;
+    for c in &mut clients {
+        c.set("key".to_owned(), "value".to_owned())?;
+    }
+    let stats = client.stats()?.connections;
+    assert_eq!(stats.open, 4);
+    assert_eq!(stats.accepted, base.accepted + 3);
+    assert_eq!(stats.closed, base.closed);
+
+    drop(clients);
+    let stats = settle(&mut client, base.closed + 3)?;
+    assert_eq!(stats.accepted, base.accepted + 3);
+    assert_eq!(stats.closed, base.closed + 3);
+    assert_eq!(stats.errored, base.errored);
+
+    // A malformed request closes the connection with an error
+    let mut tcp = TcpStream::connect(addr)?;
+    tcp.write_all(b"not a request")?;
+    tcp.shutdown(Shutdown::Write)?;
+    let stats = settle(&mut client, base.closed + 4)?;
+    assert_eq!(stats.accepted, base.accepted + 4);
+    assert_eq!(stats.closed, base.closed + 4);
+    assert_eq!(stats.errored, base.errored + 1);
+    Ok(())
+}
+
+// Forward connections from `addr` to `upstream`, swallowing the first response
+// and dropping the connection on the connections numbered in `flaky`.
+fn spawn_flaky_proxy(addr: &'static str, upstream: &'static str, flaky: &'static [usize]) {
+    let listener = TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        for (i, client) in listener.incoming().enumerate() {
+            let client = client.unwrap();
+            let server = TcpStream::connect(upstream).unwrap();
+            let (mut client_reader, mut server_writer) =
+                (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || io::copy(&mut client_reader, &mut server_writer));
+            let (mut server_reader, mut client_writer) = (server, client);
+            thread::spawn(move || {
+                if flaky.contains(&i) {
+                    // Pass the response to the hello of `connect`, then drop the
+                    // connection once the next request has been applied and
+                    // its response comes back
+                    let mut frames = serde_json::Deserializer::from_reader(server_reader)
+                        .into_iter::<serde_json::Value>();
+                    if let Some(Ok(hello)) = frames.next() {
+                        let _ = serde_json::to_writer(&mut client_writer, &hello);
+                        let _ = frames.next();
+                    }
+                    let _ = client_writer.shutdown(Shutdown::Both);
+                } else {
+                    let _ = io::copy(&mut server_reader, &mut client_writer);
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn idempotent_write_retried_once() -> Result<()> {
+    let (addr, upstream) = ("127.0.0.1:4107", "127.0.0.1:4108");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(8)?,
+    )
+    .with_transform("incr", |value| {
+        let n: u64 = value.map_or(0, |value| value.parse().unwrap());
+        Some((n + 1).to_string())
+    });
+    spawn_server(server, upstream);
+    spawn_flaky_proxy(addr, upstream, &[0, 2]);
+
+    // The ack of the first attempt is lost, the retry is answered with its outcome
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(
+        client
+            .idempotent()
+            .apply("counter".to_owned(), "incr".to_owned())?,
+        Some("1".to_owned())
+    );
+    assert_eq!(client.get("counter".to_owned())?, Some("1".to_owned()));
+
+    // A write not marked idempotent surfaces the error instead of retrying
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client
+        .apply("counter".to_owned(), "incr".to_owned())
+        .is_err());
+    // The next request reconnects
+    assert_eq!(client.get("counter".to_owned())?, Some("2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn set_returns_version_for_conditional_writes() -> Result<()> {
+    let addr = "127.0.0.1:4109";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    let version = client.set("key".to_owned(), "value1".to_owned())?;
+    let next = client.set_if_version("key".to_owned(), "value2".to_owned(), version)?;
+    assert!(next > version);
+    assert_eq!(client.get("key".to_owned())?, Some("value2".to_owned()));
+
+    // The version returned first is stale now
+    assert!(client
+        .set_if_version("key".to_owned(), "value3".to_owned(), version)
+        .is_err());
+    assert_eq!(client.get("key".to_owned())?, Some("value2".to_owned()));
+
+    // Version 0 only matches a missing key
+    client.set_if_version("new".to_owned(), "value".to_owned(), 0)?;
+    assert!(client
+        .set_if_version("new".to_owned(), "value".to_owned(), 0)
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn drain_refuses_new_connections() -> Result<()> {
+    let addr = "127.0.0.1:4110";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_admin_token("secret");
+    let handle = server.handle();
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert!(client.admin_drain("wrong").is_err());
+
+    let drained = thread::spawn(move || handle.drain(Duration::from_secs(10)));
+    // The listener closes shortly after draining starts
+    for _ in 0..100 {
+        if KvsClient::connect(addr).is_err() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(KvsClient::connect(addr).is_err());
+
+    // The open connection is still served
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.set("key".to_owned(), "other".to_owned())?;
+    assert!(!drained.is_finished());
+    drop(client);
+    assert!(drained.join().unwrap());
+    Ok(())
+}
+
+#[test]
+fn admin_drain_request() -> Result<()> {
+    let addr = "127.0.0.1:4111";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_admin_token("secret");
+    let handle = server.handle();
+    let running = thread::spawn(move || server.run(addr));
+    let mut client = loop {
+        if let Ok(client) = KvsClient::connect(addr) {
+            break client;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    client.admin_drain("secret")?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    drop(client);
+    assert!(handle.drain(Duration::from_secs(10)));
+    running.join().unwrap()?;
+    assert!(KvsClient::connect(addr).is_err());
+    Ok(())
+}
+
+// Shutting down closes idle connections, so `run` returns without waiting for
+// clients to leave
+#[test]
+fn shutdown_stops_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    );
+    let handle = server.handle();
+    let (done, stopped) = mpsc::channel();
+    thread::spawn(move || done.send(server.run("127.0.0.1:0")));
+    let addr = loop {
+        if let Some(addr) = handle.local_addr() {
+            break addr;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.set("other".to_owned(), "value".to_owned())?;
+    assert!(client.remove("other".to_owned())?);
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    handle.shutdown();
+    stopped
+        .recv_timeout(Duration::from_secs(10))
+        .expect("server did not stop")?;
+    assert!(client.get("key".to_owned()).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, None);
+    Ok(())
+}
+
+// A `KvStore` that counts the gets it serves, each taking a while.
+#[derive(Clone)]
+struct SlowGets {
+    store: KvStore,
+    gets: Arc<AtomicUsize>,
+}
+
+impl KvsReader for SlowGets {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(200));
+        self.store.get(key)
+    }
+}
+
+impl KvsEngine for SlowGets {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.store.keys()
+    }
+
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        self.store.update(key, f)
+    }
+}
+
+#[test]
+fn coalesced_gets() -> Result<()> {
+    let addr = "127.0.0.1:4112";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gets = Arc::new(AtomicUsize::new(0));
+    let engine = SlowGets {
+        store: KvStore::open(temp_dir.path())?,
+        gets: Arc::clone(&gets),
+    };
+    spawn_server(KvsServer::new(engine, SharedQueueThreadPool::new(4)?), addr);
+    let client = Arc::new(CoalescingClient::new(KvsClient::connect(addr)?));
+    client.set("hot".to_owned(), "value".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(16));
+    let handles: Vec<_> = (0..16)
+        .map(|_| {
+            let (client, barrier) = (Arc::clone(&client), Arc::clone(&barrier));
+            thread::spawn(move || {
+                barrier.wait();
+                client.get("hot".to_owned())
+            })
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap()?, Some("value".to_owned()));
+    }
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+
+    // A get after a write does not share a request sent before it
+    client.set("hot".to_owned(), "other".to_owned())?;
+    assert_eq!(client.get("hot".to_owned())?, Some("other".to_owned()));
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[test]
+fn client_stats_count_retries_and_timeouts() -> Result<()> {
+    let (addr, upstream) = ("127.0.0.1:4126", "127.0.0.1:4127");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SlowGets {
+        store: KvStore::open(temp_dir.path())?,
+        gets: Arc::new(AtomicUsize::new(0)),
+    };
+    spawn_server(
+        KvsServer::new(engine, SharedQueueThreadPool::new(4)?),
+        upstream,
+    );
+    spawn_flaky_proxy(addr, upstream, &[0]);
+
+    // The response to the get is lost, it is retried on a new connection
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    let stats = client.client_stats();
+    assert_eq!(stats.requests, 3);
+    assert_eq!(stats.responses, 2);
+    assert_eq!(stats.retries, 1);
+    assert_eq!(stats.reconnects, 1);
+    assert_eq!(stats.timeouts, 0);
+
+    // A get slower than the timeout fails without being retried
+    client.set_timeout(Some(Duration::from_millis(50)))?;
+    match client.get("key".to_owned()) {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let stats = client.client_stats();
+    assert_eq!(stats.requests, 4);
+    assert_eq!(stats.responses, 2);
+    assert_eq!(stats.timeouts, 1);
+    assert_eq!(stats.retries, 1);
+
+    // The next request opens a new connection
+    client.set_timeout(None)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let stats = client.client_stats();
+    assert_eq!(stats.requests, 5);
+    assert_eq!(stats.responses, 3);
+    assert_eq!(stats.reconnects, 2);
+    Ok(())
+}
+
+#[test]
+fn read_only_rejects_writes() -> Result<()> {
+    let addr = "127.0.0.1:4113";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_admin_token("secret");
+    let handle = server.handle();
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert!(client.admin_read_only("wrong", true).is_err());
+    client.admin_read_only("secret", true)?;
+
+    let is_read_only = |res: Result<_>| matches!(res, Err(e) if e.to_string() == "read only");
+    assert!(is_read_only(
+        client.set("key".to_owned(), "other".to_owned()).map(|_| ())
+    ));
+    assert!(is_read_only(client.remove("key".to_owned()).map(|_| ())));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    client.admin_read_only("secret", false)?;
+    client.set("key".to_owned(), "other".to_owned())?;
+    handle.set_read_only(true);
+    assert!(is_read_only(client.remove("key".to_owned()).map(|_| ())));
+    handle.set_read_only(false);
+    client.remove("key".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn server_info_on_connect() -> Result<()> {
+    let addr = "127.0.0.1:4114";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_admin_token("secret")
+    .with_max_request_bytes(1024);
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    let info = client.server_info().clone();
+    assert_eq!(info.max_request_bytes, 1024);
+    assert!(info.auth_required);
+    assert_eq!(info.engine, "kvs");
+
+    // An oversized request is refused before anything is sent
+    let res = client.set("key".to_owned(), "x".repeat(2048));
+    assert!(matches!(
+        res,
+        Err(KvsError::RequestTooLarge { max: 1024, .. })
+    ));
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    // The server enforces the limit too
+    let mut stream = TcpStream::connect(addr)?;
+    let request = format!(
+        r#"{{"trace_id":"big","body":{{"Set":{{"key":"key","value":"{}"}}}}}}"#,
+        "x".repeat(2048)
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut frames = serde_json::Deserializer::from_reader(stream).into_iter::<serde_json::Value>();
+    let frame = frames.next().unwrap()?;
+    let err = &frame["Response"]["body"]["Err"];
+    assert_eq!(err["kind"], "BadRequest");
+    let msg = err["message"].as_str().unwrap();
+    assert!(msg.contains("exceeds the limit of 1024 bytes"), "{}", msg);
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The server refuses a frame as soon as it crosses the limit, without waiting
+// for the rest of it
+#[test]
+fn oversized_frame_refused_early() -> Result<()> {
+    let addr = "127.0.0.1:4135";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_max_request_bytes(1024)
+    .with_compression(Compression::Lz4);
+    spawn_server(server, addr);
+
+    // The value never ends, so a server reading the whole frame never answers
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.write_all(br#"{"trace_id":"big","body":{"Set":{"key":"key","value":""#)?;
+    stream.write_all(&[b'x'; 4096])?;
+    let mut frames = serde_json::Deserializer::from_reader(stream).into_iter::<serde_json::Value>();
+    let frame = frames.next().unwrap()?;
+    let err = &frame["Response"]["body"]["Err"];
+    assert_eq!(err["kind"], "BadRequest");
+    let msg = err["message"].as_str().unwrap();
+    assert!(msg.contains("exceeds the limit of 1024 bytes"), "{}", msg);
+    // The rest of the frame is not read, so the connection is closed, reset
+    // if the unread bytes arrived
+    assert!(!matches!(frames.next(), Some(Ok(_))));
+
+    // A compressed block is refused on its length, before it is read
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.write_all(br#"{"trace_id":"hello","body":{"Hello":{"compression":["Lz4"]}}}"#)?;
+    let mut frames =
+        serde_json::Deserializer::from_reader(&stream).into_iter::<serde_json::Value>();
+    let frame = frames.next().unwrap()?;
+    assert_eq!(frame["Response"]["body"]["Ok"]["compression"], "Lz4");
+    stream.write_all(&u32::MAX.to_le_bytes())?;
+    let mut len = [0; 4];
+    io::Read::read_exact(&mut stream, &mut len)?;
+    let mut block = vec![0; u32::from_le_bytes(len) as usize];
+    io::Read::read_exact(&mut stream, &mut block)?;
+    let frame: serde_json::Value =
+        serde_json::from_slice(&lz4_flex::decompress_size_prepended(&block).unwrap())?;
+    assert_eq!(frame["Response"]["body"]["Err"]["kind"], "BadRequest");
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Remote compaction reclaims the stale records and reports how many bytes.
+#[test]
+fn admin_compact_request() -> Result<()> {
+    let addr = "127.0.0.1:4115";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Count only generation files; compaction also rewrites the version mark
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_admin_token("secret");
+    spawn_server(server, addr);
+    let mut client = KvsClient::connect(addr)?;
+
+    for i in 0..100 {
+        client.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let before = dir_size();
+    assert!(client.admin_compact("wrong").is_err());
+    let reclaimed = client.admin_compact("secret")?;
+    assert!(reclaimed > 0);
+    assert_eq!(dir_size(), before - reclaimed);
+    assert_eq!(client.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Removing reports whether the key existed, and only a failed exchange is an error
+#[test]
+fn remove_reports_missing_keys() -> Result<()> {
+    let (addr, upstream) = ("127.0.0.1:4116", "127.0.0.1:4117");
+    let _temp_dir = start_server(upstream)?;
+    spawn_flaky_proxy(addr, upstream, &[1]);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert!(client.remove("key".to_owned())?);
+    assert!(!client.remove("key".to_owned())?);
+
+    // The connection drops before the response arrives
+    client.set("key".to_owned(), "value".to_owned())?;
+    let mut flaky = KvsClient::connect(addr)?;
+    assert!(flaky.remove("key".to_owned()).is_err());
+    assert_eq!(client.get("key".to_owned())?, None);
+    Ok(())
+}
+
+// A `KvStore` whose reads fail, as on a broken disk.
+#[derive(Clone)]
+struct BrokenReads(KvStore);
+
+impl KvsReader for BrokenReads {
+    fn get(&self, _key: String) -> Result<Option<String>> {
+        Err(io::Error::other("disk on fire").into())
+    }
+}
+
+impl KvsEngine for BrokenReads {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.0.keys()
+    }
+
+    fn update(&self, key: String, f: Transform) -> Result<Option<String>> {
+        self.0.update(key, f)
+    }
+}
+
+// Errors arrive with their category, and come back as the matching `KvsError`
+#[test]
+fn structured_error_responses() -> Result<()> {
+    let addr = "127.0.0.1:4130";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .with_admin_token("secret");
+    let handle = server.handle();
+    spawn_server(server, addr);
+    let mut client = KvsClient::connect(addr)?;
+    fn kind<T: std::fmt::Debug>(res: Result<T>) -> ServerErrorKind {
+        match res {
+            Err(KvsError::Server(e)) => e.kind,
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    // Values and plain successes
+    let version = client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(client.get("missing".to_owned())?, None);
+    // A missing key is not an error of the request
+    assert!(!client.remove("missing".to_owned())?);
+
+    assert_eq!(
+        kind(client.apply("key".to_owned(), "unknown".to_owned())),
+        ServerErrorKind::BadRequest
+    );
+    assert_eq!(kind(client.iter_next(42, 1)), ServerErrorKind::BadRequest);
+    assert_eq!(
+        kind(client.set_if_version("key".to_owned(), "other".to_owned(), version + 1)),
+        ServerErrorKind::Conflict
+    );
+    assert!(matches!(
+        client.admin_compact("wrong"),
+        Err(KvsError::Unauthorized)
+    ));
+    let err = client
+        .deadline(SystemTime::now() - Duration::from_secs(1))
+        .get("key".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::DeadlineExceeded));
+    handle.set_read_only(true);
+    assert!(matches!(
+        client.set("key".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    handle.shutdown();
+
+    let (addr, temp_dir) = ("127.0.0.1:4131", TempDir::new().unwrap());
+    let engine = BrokenReads(KvStore::open(temp_dir.path())?);
+    spawn_server(KvsServer::new(engine, SharedQueueThreadPool::new(2)?), addr);
+    let mut client = KvsClient::connect(addr)?;
+    match client.get("key".to_owned()) {
+        Err(KvsError::Server(e)) => {
+            assert_eq!(e.kind, ServerErrorKind::Internal);
+            assert_eq!(e.message, "disk on fire");
+        }
+        res => panic!("unexpected {:?}", res),
+    }
+    // The connection stays usable
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    // Kinds with a `KvsError` of their own map back to it
+    let err = KvsError::from(ServerError::from(KvsError::KeyNotFound));
+    assert!(matches!(err, KvsError::KeyNotFound));
+    Ok(())
+}
+
+// Every request is written to the access log as a JSON line with a hashed key
+#[test]
+fn access_log_lines() -> Result<()> {
+    let addr = "127.0.0.1:4118";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("access.log");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_access_log(&log_path, 1024)?;
+    spawn_server(server, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("secret-key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        client.get("secret-key".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert!(!client.remove("other-key".to_owned())?);
+    for _ in 0..10 {
+        client.get("secret-key".to_owned())?;
+    }
+
+    // The log was rotated once it passed 1 KiB
+    let rotated = fs::read_to_string(temp_dir.path().join("access.log.1"))?;
+    let current = fs::read_to_string(&log_path)?;
+    assert!(current.len() <= 1024);
+    assert!(!rotated.contains("secret-key") && !current.contains("secret-key"));
+    let lines = rotated
+        .lines()
+        .chain(current.lines())
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect::<Vec<serde_json::Value>>();
+    // The first line is the hello of `spawn_server` checking the server is up
+    let lines = &lines[1..];
+    let ops = lines
+        .iter()
+        .map(|line| {
+            (
+                line["op"].as_str().unwrap(),
+                line["result"].as_str().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        &ops[..4],
+        &[
+            ("Hello", "Ok"),
+            ("Set", "Ok"),
+            ("Get", "Ok"),
+            ("Remove", "NotFound")
+        ]
+    );
+    assert_eq!(lines.len(), 14);
+    let peer = lines[0]["peer"].as_str().unwrap();
+    for line in lines.iter() {
+        assert_eq!(line["peer"], peer);
+        assert!(line["timestamp_ms"].as_u64().unwrap() > 0);
+        assert!(line["latency_us"].is_u64());
+    }
+    assert!(lines[0].get("key_hash").is_none());
+    // The hash does not depend on the build
+    assert_eq!(lines[1]["key_hash"], "85dbe15d75ef9308");
+    assert_eq!(lines[1]["key_hash"], lines[2]["key_hash"]);
+    assert_ne!(lines[2]["key_hash"], lines[3]["key_hash"]);
+    Ok(())
+}
+
+// More threads than connections take turns on the connections of the pool
+#[test]
+fn client_pool_shares_connections() -> Result<()> {
+    let addr = "127.0.0.1:4119";
+    let _temp_dir = start_server(addr)?;
+    let pool = Arc::new(KvsClientPool::connect(addr, 2)?);
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..25 {
+                    let key = format!("key{}-{}", t, i);
+                    pool.set(key.clone(), format!("value{}", i))?;
+                    let mut client = pool.checkout();
+                    assert_eq!(client.get(key.clone())?, Some(format!("value{}", i)));
+                    assert!(client.remove(key)?);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(pool.idle(), 2);
+
+    // Only the connections of the pool were opened, besides the startup probe
+    let stats = pool.checkout().stats()?;
+    assert_eq!(stats.connections.accepted, 3);
+
+    // An exhausted pool times out until a connection is checked in
+    let first = pool.checkout();
+    let _second = pool.checkout();
+    match pool.checkout_timeout(Duration::from_millis(50)) {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+        Err(e) => panic!("unexpected error {}", e),
+        Ok(_) => panic!("checked out more connections than the pool has"),
+    }
+    drop(first);
+    pool.checkout_timeout(Duration::from_millis(50))?;
+    Ok(())
+}
+
+// The text protocol answers plain lines, as typed over telnet
+#[test]
+fn text_protocol_over_socket() -> Result<()> {
+    let addr = "127.0.0.1:4120";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_protocol(WireProtocol::Text)
+    .with_max_request_bytes(64);
+    let handle = server.handle();
+    thread::spawn(move || server.run(addr).unwrap());
+    let stream = (0..100)
+        .find_map(|_| {
+            TcpStream::connect(addr)
+                .map_err(|_| thread::sleep(Duration::from_millis(10)))
+                .ok()
+        })
+        .expect("server did not start");
+
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut send = |line: &str| -> Result<String> {
+        write!(writer, "{}\r\n", line)?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        Ok(reply.trim_end().to_owned())
+    };
+    assert_eq!(send("GET foo")?, "(nil)");
+    assert_eq!(send("SET foo hello world")?, "OK");
+    assert_eq!(send("get foo")?, r#""hello world""#);
+    // A stored value never reads as a missing one
+    assert_eq!(send("SET nil (nil)")?, "OK");
+    assert_eq!(send("GET nil")?, r#""(nil)""#);
+    assert_eq!(send("DEL foo")?, "OK");
+    assert_eq!(send("DEL foo")?, "NOT_FOUND");
+    assert_eq!(send("SET foo")?, "ERR usage: SET <key> <value>");
+    assert_eq!(send("PING")?, "ERR unknown command PING");
+
+    handle.set_read_only(true);
+    assert_eq!(send("SET foo bar")?, "ERR read only");
+    assert_eq!(send("GET foo")?, "(nil)");
+
+    write!(writer, "QUIT\r\n")?;
+    let mut rest = String::new();
+    io::Read::read_to_string(&mut reader, &mut rest)?;
+    assert_eq!(rest, "");
+
+    // A line past the request limit is refused without reading the rest of it
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    write!(writer, "SET foo {}\r\n", "x".repeat(100))?;
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    assert_eq!(reply.trim_end(), "ERR line exceeds the limit of 64 bytes");
+    Ok(())
+}
+
+// Requests split across many TCP segments are reassembled before being served
+#[test]
+fn request_split_across_reads() -> Result<()> {
+    let addr = "127.0.0.1:4121";
+    let _temp_dir = start_server(addr)?;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+
+    let requests = concat!(
+        r#"{"trace_id":"split-set","body":{"Set":{"key":"key","value":"value"}}}"#,
+        r#"{"trace_id":"split-get","body":{"Get":{"key":"key"}}}"#,
+    );
+    for byte in requests.as_bytes() {
+        stream.write_all(&[*byte])?;
+        stream.flush()?;
+        thread::sleep(Duration::from_micros(200));
+    }
+
+    let mut frames = serde_json::Deserializer::from_reader(stream).into_iter::<serde_json::Value>();
+    let set = frames.next().unwrap()?;
+    assert_eq!(set["Response"]["trace_id"], "split-set");
+    assert!(set["Response"]["body"]["Ok"].is_u64(), "{}", set);
+    let get = frames.next().unwrap()?;
+    assert_eq!(get["Response"]["trace_id"], "split-get");
+    assert_eq!(get["Response"]["body"]["Ok"], "value");
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The server only returns the pairs under the prefix whose value matches
+#[test]
+fn scan_filters_values_on_server() -> Result<()> {
+    let addr = "127.0.0.1:4122";
+    let _temp_dir = start_server(addr)?;
+    let mut client = KvsClient::connect(addr)?;
+    for (key, value) in [
+        ("user:1", "alice admin"),
+        ("user:2", "bob"),
+        ("user:3", "carol admin"),
+        ("group:1", "admin"),
+        ("user:4", "dave"),
+    ] {
+        client.set(key.to_owned(), value.to_owned())?;
+    }
+    client.remove("user:3".to_owned())?;
+
+    assert_eq!(
+        client.scan("user:".to_owned(), "admin".to_owned())?,
+        vec![("user:1".to_owned(), "alice admin".to_owned())]
+    );
+    assert_eq!(
+        client
+            .scan("user:".to_owned(), String::new())?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>(),
+        vec!["user:1", "user:2", "user:4"]
+    );
+    assert_eq!(client.scan(String::new(), "admin".to_owned())?.len(), 2);
+    assert!(client
+        .scan("missing:".to_owned(), String::new())?
+        .is_empty());
+    Ok(())
+}
+
+// A reader counting the bytes read through it into `count`.
+struct Counting<R> {
+    inner: R,
+    count: Arc<AtomicUsize>,
+}
+
+impl<R: io::Read> io::Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+// Forward connections from `addr` to `upstream`, returning the number of bytes
+// passed both ways.
+fn spawn_counting_proxy(addr: &'static str, upstream: &'static str) -> Arc<AtomicUsize> {
+    let listener = TcpListener::bind(addr).unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let total = Arc::clone(&count);
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            let server = TcpStream::connect(upstream).unwrap();
+            for (reader, mut writer) in [
+                (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                (server, client),
+            ] {
+                let mut reader = Counting {
+                    inner: reader,
+                    count: Arc::clone(&count),
+                };
+                thread::spawn(move || {
+                    let _ = io::copy(&mut reader, &mut writer);
+                    let _ = writer.shutdown(Shutdown::Both);
+                });
+            }
+        }
+    });
+    total
+}
+
+// Clients offering compression get it, and send far fewer bytes for the same
+// requests
+#[test]
+fn negotiated_compression() -> Result<()> {
+    let upstream = "127.0.0.1:4123";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    )
+    .with_compression(Compression::Lz4);
+    spawn_server(server, upstream);
+    let plain_bytes = spawn_counting_proxy("127.0.0.1:4124", upstream);
+    let compressed_bytes = spawn_counting_proxy("127.0.0.1:4125", upstream);
+
+    let value = (0..20_000)
+        .map(|i| format!("value{} ", i % 100))
+        .collect::<String>();
+    let mut plain = KvsClient::connect("127.0.0.1:4124")?;
+    assert_eq!(plain.server_info().compression, None);
+    let mut compressed = KvsClient::connect_compressed("127.0.0.1:4125", Compression::Lz4)?;
+    assert_eq!(compressed.server_info().compression, Some(Compression::Lz4));
+    for client in [&mut plain, &mut compressed] {
+        client.set("key".to_owned(), value.clone())?;
+        assert_eq!(client.get("key".to_owned())?, Some(value.clone()));
+        assert_eq!(client.get("missing".to_owned())?, None);
+    }
+    // Watch notifications share the compressed connection
+    let notifications = compressed.watch("key".to_owned())?;
+    plain.set("key".to_owned(), "new".to_owned())?;
+    assert_eq!(
+        notifications.recv_timeout(Duration::from_secs(5)),
+        Some(Notification {
+            key: "key".to_owned(),
+            event: WatchEvent::Set("new".to_owned()),
+        })
+    );
+    assert_eq!(compressed.get("key".to_owned())?, Some("new".to_owned()));
+
+    let (plain_bytes, compressed_bytes) = (
+        plain_bytes.load(Ordering::SeqCst),
+        compressed_bytes.load(Ordering::SeqCst),
+    );
+    assert!(plain_bytes > 2 * value.len(), "{}", plain_bytes);
+    assert!(
+        compressed_bytes * 10 < plain_bytes,
+        "{} compressed, {} plain",
+        compressed_bytes,
+        plain_bytes
+    );
+    Ok(())
+}
+
+// A follower sees every write in order, resumes from its checkpoint, and starts
+// over once compaction removed it
+#[test]
+fn follow_change_log() -> Result<()> {
+    let addr = "127.0.0.1:4128";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    spawn_server(
+        KvsServer::new(store.clone(), SharedQueueThreadPool::new(4)?),
+        addr,
+    );
+    let mut client = KvsClient::connect(addr)?;
+    let mut stream = client.follow(LogPosition::default())?;
+
+    let mut expected = vec![];
+    for i in 0..20 {
+        client.set(format!("key{}", i % 5), format!("value{}", i))?;
+        expected.push((
+            format!("key{}", i % 5),
+            WatchEvent::Set(format!("value{}", i)),
+        ));
+    }
+    client.remove("key3".to_owned())?;
+    expected.push(("key3".to_owned(), WatchEvent::Removed));
+
+    let timeout = Duration::from_secs(5);
+    let next = |stream: &mut ChangeStream| -> Result<(String, WatchEvent)> {
+        let change = stream.recv_timeout(timeout)?.expect("change");
+        Ok((change.key, change.event))
+    };
+    for (key, event) in &expected {
+        assert_eq!(next(&mut stream)?, (key.clone(), event.clone()));
+    }
+    assert!(stream.recv_timeout(Duration::from_millis(100))?.is_none());
+
+    // Resume from the checkpoint on a new stream
+    let checkpoint = stream.position();
+    drop(stream);
+    client.set("key0".to_owned(), "late".to_owned())?;
+    let mut stream = client.follow(checkpoint)?;
+    assert_eq!(
+        next(&mut stream)?,
+        ("key0".to_owned(), WatchEvent::Set("late".to_owned()))
+    );
+    assert!(stream.recv_timeout(Duration::from_millis(100))?.is_none());
+
+    // Compaction removes the checkpoint, the stream replays the log from its
+    // start, which is the current state
+    let checkpoint = stream.position();
+    store.compact()?;
+    let mut stream = client.follow(checkpoint)?;
+    assert!(matches!(stream.next(), Some(Err(KvsError::LogCompacted))));
+    let mut state = HashMap::new();
+    while let Some(change) = stream.recv_timeout(Duration::from_millis(100))? {
+        match change.event {
+            WatchEvent::Set(value) => state.insert(change.key, value),
+            WatchEvent::Removed => state.remove(&change.key),
+            WatchEvent::SetBytes(_) => panic!("no binary value was set"),
+        };
+    }
+    let mut keys = client.scan(String::new(), String::new())?;
+    keys.sort();
+    let mut replayed = state.into_iter().collect::<Vec<_>>();
+    replayed.sort();
+    assert_eq!(replayed, keys);
+    assert_eq!(replayed.len(), 4);
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! Concurrency stress test of `KvStore`.
+//!
+//! Threads run seeded random operations against clones of one store, each
+//! recording its writes in a shared oracle. The store must match the oracle
+//! afterwards, and again after reopening.
+//!
+//! `STRESS_SEED` and `STRESS_SECS` override the seed and the duration. Run it
+//! longer under `--release` to shake out rare races:
+//!
+//! ```text
+//! STRESS_SECS=60 cargo test --release --test stress
+//! ```
+
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use unifier::{CompactionPolicy, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsReader, Result};
+
+const THREADS: usize = 8;
+const KEYS_PER_THREAD: usize = 64;
+const DEFAULT_SEED: u64 = 0x5eed;
+const DEFAULT_SECS: u64 = 2;
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .map(|value| value.parse().expect("invalid number"))
+        .unwrap_or(default)
+}
+
+fn thread_rng(seed: u64, thread: usize) -> StdRng {
+    let mut bytes = [0; 32];
+    bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    bytes[8..16].copy_from_slice(&(thread as u64).to_le_bytes());
+    StdRng::from_seed(bytes)
+}
+
+// Each thread writes only its own keys, so the oracle agrees with the store on
+// the order of writes to every key. Reads cover the keys of all threads.
+fn run_worker(
+    store: KvStore,
+    oracle: Arc<Mutex<HashMap<String, String>>>,
+    mut rng: StdRng,
+    thread: usize,
+    until: Instant,
+) -> Result<u64> {
+    let own_key = |id: usize| format!("t{}-key{}", thread, id);
+    let mut ops = 0;
+    while Instant::now() < until {
+        ops += 1;
+        match rng.gen_range(0, 100) {
+            0..=44 => {
+                let key = own_key(rng.gen_range(0, KEYS_PER_THREAD));
+                let value = format!("{}-{}", key, rng.gen::<u32>());
+                store.set(key.clone(), value.clone())?;
+                oracle.lock().unwrap().insert(key, value);
+            }
+            45..=59 => {
+                let key = own_key(rng.gen_range(0, KEYS_PER_THREAD));
+                let expected = oracle.lock().unwrap().remove(&key);
+                match store.remove(key.clone()) {
+                    Ok(()) => assert!(expected.is_some(), "removed missing {}", key),
+                    Err(KvsError::KeyNotFound) => assert!(expected.is_none(), "lost {}", key),
+                    Err(e) => return Err(e),
+                }
+            }
+            60..=84 => {
+                // Only this thread writes its keys, so the oracle is current
+                let key = own_key(rng.gen_range(0, KEYS_PER_THREAD));
+                let expected = oracle.lock().unwrap().get(&key).cloned();
+                assert_eq!(store.get(key)?, expected);
+            }
+            85..=98 => {
+                let key = format!(
+                    "t{}-key{}",
+                    rng.gen_range(0, THREADS),
+                    rng.gen_range(0, KEYS_PER_THREAD)
+                );
+                if let Some(value) = store.get(key.clone())? {
+                    assert!(value.starts_with(&key), "{} read {}", key, value);
+                }
+            }
+            _ => {
+                store.compact()?;
+            }
+        }
+    }
+    Ok(ops)
+}
+
+fn check_against_oracle(store: &KvStore, oracle: &HashMap<String, String>) -> Result<()> {
+    let mut keys = store.keys()?;
+    keys.sort();
+    let mut expected_keys: Vec<_> = oracle.keys().cloned().collect();
+    expected_keys.sort();
+    assert_eq!(keys, expected_keys);
+    for (key, value) in oracle {
+        assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+// Runs the workers against a store opened with `options` until the deadline,
+// then checks it against the oracle before and after reopening.
+fn run_stress(options: KvStoreOptions) -> Result<()> {
+    let seed = env_or("STRESS_SEED", DEFAULT_SEED);
+    let until = Instant::now() + Duration::from_secs(env_or("STRESS_SECS", DEFAULT_SECS));
+    println!("stress seed: {}", seed);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    let oracle = Arc::new(Mutex::new(HashMap::new()));
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let store = store.clone();
+            let oracle = Arc::clone(&oracle);
+            let rng = thread_rng(seed, thread);
+            thread::spawn(move || run_worker(store, oracle, rng, thread, until))
+        })
+        .collect();
+    let mut ops = 0;
+    for handle in handles {
+        ops += handle.join().expect("stress worker panicked")?;
+    }
+    println!("stress operations: {}", ops);
+
+    let oracle = oracle.lock().unwrap();
+    check_against_oracle(&store, &oracle)?;
+    drop(store);
+    check_against_oracle(
+        &KvStore::open_with_options(temp_dir.path(), options)?,
+        &oracle,
+    )
+}
+
+#[test]
+fn concurrent_random_operations() -> Result<()> {
+    run_stress(KvStoreOptions::default())
+}
+
+// A low threshold keeps a background compaction running under the writes most
+// of the time, racing their index updates with its swap.
+#[test]
+fn concurrent_writes_during_background_compaction() -> Result<()> {
+    run_stress(KvStoreOptions {
+        compaction: CompactionPolicy::Background,
+        compaction_threshold: 16 * 1024,
+        ..KvStoreOptions::default()
+    })
+}